@@ -1,4 +1,12 @@
-use super::{cpu::Trap, memory::{address::Addressable, rom::ROM, Size, DRAM}};
+use std::any::Any;
+
+use crate::{fdt::FdtWriter, replay::Journal, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable}};
+
+use super::{
+    cpu::Trap,
+    devices::{clint::Clint, plic::Plic, virtio::{VirtioDevice, VirtioMmio, VIRTIO_BASE, VIRTIO_SIZE, VIRTIO_SLOTS}, Device, Dma},
+    memory::{address::Addressable, rom::ROM, Size, DRAM},
+};
 
 /// The address which the ROM starts.
 pub const ROM_BASE: u64 = 0x1000;
@@ -12,6 +20,22 @@ pub const DRAM_BASE: u64 = 0x8000_0000;
 pub struct Bus {
     rom: ROM,
     dram: DRAM,
+    /// The interrupt controller, which every machine has, and which the other devices' interrupt
+    /// lines go to.
+    plic: Plic,
+    /// The interruptor which raises the hart's timer and software interrupts, which every
+    /// machine also has.
+    clint: Clint,
+    /// The devices whose registers are mapped outside of memory.
+    devices: Vec<Box<dyn Device>>,
+    /// Records or replays the nondeterministic inputs of the devices on the bus.
+    journal: Journal,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
@@ -19,6 +43,10 @@ impl Bus {
         Self {
             rom: ROM::new(),
             dram: DRAM::new(1024 * 1024 * 1024),
+            plic: Plic::new(),
+            clint: Clint::new(),
+            devices: vec![],
+            journal: Journal::new(),
         }
     }
 
@@ -29,38 +57,255 @@ impl Bus {
     pub fn dram(&mut self) -> &mut DRAM {
         &mut self.dram
     }
+
+    pub fn journal(&mut self) -> &mut Journal {
+        &mut self.journal
+    }
+
+    pub fn plic(&self) -> &Plic {
+        &self.plic
+    }
+
+    pub fn clint(&self) -> &Clint {
+        &self.clint
+    }
+
+    /// Attaches a device, whose registers mustn't overlap those of any other device.
+    pub fn attach(&mut self, device: Box<dyn Device>) {
+        let range = device.base()..device.base() + device.size();
+        let (plic, clint): (&dyn Device, &dyn Device) = (&self.plic, &self.clint);
+        assert!(
+            self.devices.iter().map(|d| d.as_ref()).chain([plic, clint])
+                .all(|d| d.base() + d.size() <= range.start || range.end <= d.base()),
+            "the registers of {:?} overlap another device", device
+        );
+        self.devices.push(device);
+        self.update_interrupts();
+    }
+
+    /// Attaches a virtio device in the first free virtio-mmio slot, returning the slot, or `None`
+    /// if they are all taken.
+    pub fn attach_virtio(&mut self, device: Box<dyn VirtioDevice>) -> Option<usize> {
+        let slot = (0..VIRTIO_SLOTS).find(|&slot| self.device(VIRTIO_BASE + slot as u64 * VIRTIO_SIZE).is_none())?;
+        self.attach(Box::new(VirtioMmio::new(slot, device)));
+        Some(slot)
+    }
+
+    /// Finds an attached device of a particular kind, such as the framebuffer to take a
+    /// screenshot of.
+    pub fn find<T: Device>(&self) -> Option<&T> {
+        self.devices.iter().find_map(|device| (device.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    /// Lets the devices check for input from the host.
+    pub fn poll(&mut self) {
+        let Self { dram, devices, journal, .. } = self;
+        for device in devices.iter_mut() {
+            device.poll(&mut Dma::new(dram, journal));
+        }
+        self.update_interrupts();
+    }
+
+    /// Indicates if the PLIC is raising the hart's external interrupt.
+    pub fn external_interrupt(&self) -> bool {
+        self.plic.interrupting()
+    }
+
+    /// Indicates if the CLINT is raising the hart's timer interrupt.
+    pub fn timer_interrupt(&self) -> bool {
+        self.clint.timer_interrupt(self.journal.clock())
+    }
+
+    /// Indicates if the CLINT is raising the hart's software interrupt.
+    pub fn software_interrupt(&self) -> bool {
+        self.clint.software_interrupt()
+    }
+
+    /// The CLINT's time, which the `time` CSR reads.
+    pub fn time(&self) -> u64 {
+        self.clint.time(self.journal.clock())
+    }
+
+    /// Passes the interrupt lines of the devices to the PLIC. A device's lines only change when
+    /// it's polled or its registers are accessed, so this follows each of those.
+    fn update_interrupts(&self) {
+        let lines = self.devices.iter().fold(0, |lines, device| lines | device.interrupts());
+        self.plic.set_lines(lines);
+    }
+
+    /// Finds the device whose registers include an address.
+    fn device(&self, addr: u64) -> Option<&dyn Device> {
+        let (plic, clint): (&dyn Device, &dyn Device) = (&self.plic, &self.clint);
+        self.devices.iter()
+            .map(|d| d.as_ref())
+            .chain([plic, clint])
+            .find(|d| (d.base()..d.base() + d.size()).contains(&addr))
+    }
+
+    /// Describes the memory and devices on the bus in the device tree, as children of the root.
+    pub fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("memory@{:x}", DRAM_BASE))
+            .property_string("device_type", "memory")
+            .property_reg(&[(DRAM_BASE, self.dram.size())])
+            .end_node();
+        self.clint.describe(fdt);
+        self.plic.describe(fdt);
+        for device in &self.devices {
+            device.describe(fdt);
+        }
+    }
+
+    /// Replaces the journal, to start recording or replaying inputs.
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = journal;
+    }
 }
 
 impl Addressable for Bus {
+    /// DRAM is checked first, as nearly every access goes to it.
     fn read(&self, addr: u64, size: Size) -> Result<u64, Trap> {
         match addr {
+            DRAM_BASE.. => self.dram.read(addr, size),
             ROM_BASE..ROM_END => self.rom.read(addr, size),
-            _ => self.dram.read(addr, size)
+            _ => match self.device(addr) {
+                Some(device) => {
+                    // The CLINT's time follows the clock.
+                    self.clint.set_clock(self.journal.clock());
+                    // Reading a register can clear an interrupt, such as by claiming it.
+                    let value = device.read(addr - device.base(), size);
+                    self.update_interrupts();
+                    value
+                },
+                None => Err(Trap::LoadAccessFault),
+            },
         }
     }
 
-    fn write(&mut self, addr: u64, size: Size, data: Vec<u8>) -> Result<(), Trap> {
+    fn write(&mut self, addr: u64, size: Size, value: u64) -> Result<(), Trap> {
         match addr {
-            ROM_BASE..ROM_END => self.rom.write(addr, size, data),
-            _ => self.dram.write(addr, size, data),
+            DRAM_BASE.. => self.dram.write(addr, size, value),
+            ROM_BASE..ROM_END => self.rom.write(addr, size, value),
+            _ => {
+                let Self { dram, plic, clint, devices, journal, .. } = self;
+                let (plic, clint): (&mut dyn Device, &mut dyn Device) = (plic, clint);
+                let device = devices.iter_mut()
+                    .map(|d| d.as_mut())
+                    .chain([plic, clint])
+                    .find(|d| (d.base()..d.base() + d.size()).contains(&addr));
+                let result = match device {
+                    Some(device) => {
+                        let offset = addr - device.base();
+                        device.write(offset, size, value, &mut Dma::new(dram, journal))
+                    },
+                    None => Err(Trap::StoreAccessFault),
+                };
+                self.update_interrupts();
+                result
+            },
         }
     }
 
     fn size(&self) -> u64 {
-        2^64-1
+        u64::MAX
     }
 
     fn contains(&self, addr: u64) -> bool {
-        self.rom.contains(addr) || self.dram.contains(addr)
+        self.rom.contains(addr) || self.dram.contains(addr) || self.device(addr).is_some()
+    }
+}
+
+impl Snapshotable for Bus {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        self.rom.save(snapshot);
+        self.dram.save(snapshot);
+        self.plic.save(snapshot);
+        self.clint.save(snapshot);
+        for device in &self.devices {
+            device.save(snapshot);
+        }
+    }
+
+    /// The machine must have the same devices attached as when the snapshot was saved.
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.rom.restore(snapshot)?;
+        self.dram.restore(snapshot)?;
+        self.plic.restore(snapshot)?;
+        self.clint.restore(snapshot)?;
+        for device in self.devices.iter_mut() {
+            device.restore(snapshot)?;
+        }
+        self.update_interrupts();
+        Ok(())
     }
 }
 
 #[cfg(test)]
-mod test {
-    use crate::components::{cpu::Trap, memory::{address::Addressable, Size}};
+pub mod test {
+    use std::fs;
+
+    use crate::components::{
+        cpu::Trap,
+        devices::{
+            plic::PLIC_BASE,
+            rtc::{Epoch, Rtc, RTC_BASE, RTC_IRQ},
+            virtio::{block::DiskMode, Block, VIRTIO_BASE, VIRTIO_SIZE},
+            Device, Dma,
+        },
+        memory::{address::Addressable, Size},
+    };
+    use crate::fdt::{self, test::properties, Chosen, FdtWriter, PLIC_PHANDLE};
+    use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable};
 
     use super::Bus;
 
+    /// Where the doorbell's register is, and the PLIC source which it raises.
+    pub const DOORBELL_BASE: u64 = 0x2000_0000;
+    pub const DOORBELL_IRQ: u32 = 20;
+
+    /// A device whose interrupt line is raised or lowered by writing 1 or 0 to its register.
+    #[derive(Debug, Default)]
+    pub struct Doorbell {
+        raised: bool,
+    }
+
+    impl Doorbell {
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    impl Device for Doorbell {
+        fn base(&self) -> u64 {
+            DOORBELL_BASE
+        }
+
+        fn size(&self) -> u64 {
+            4
+        }
+
+        fn read(&self, _offset: u64, _size: Size) -> Result<u64, Trap> {
+            Ok(self.raised as u64)
+        }
+
+        fn write(&mut self, _offset: u64, _size: Size, value: u64, _dma: &mut Dma) -> Result<(), Trap> {
+            self.raised = value & 1 != 0;
+            Ok(())
+        }
+
+        fn describe(&self, _fdt: &mut FdtWriter) {}
+
+        fn interrupts(&self) -> u64 {
+            (self.raised as u64) << DOORBELL_IRQ
+        }
+    }
+
+    impl Snapshotable for Doorbell {
+        fn save(&self, _snapshot: &mut SnapshotWriter) {}
+
+        fn restore(&mut self, _snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+            Ok(())
+        }
+    }
 
     #[test]
     fn it_fails_for_invalid_addresses() {
@@ -77,4 +322,71 @@ mod test {
     fn it_reads_from_the_correct_device() {
 
     }
+
+    #[test]
+    fn it_attaches_virtio_devices() {
+        let path = std::env::temp_dir().join(format!("rv64-bus-disk-{}", std::process::id()));
+        fs::write(&path, [0; 1024]).unwrap();
+        let disk = || Box::new(Block::open(&path, DiskMode::ReadOnly).unwrap());
+
+        let mut bus = Bus::new();
+        assert_eq!(bus.attach_virtio(disk()), Some(0));
+        assert_eq!(bus.attach_virtio(disk()), Some(1));
+        let _ = fs::remove_file(&path);
+
+        // The registers of each slot follow those of the one before.
+        assert_eq!(bus.read_u32(VIRTIO_BASE), Ok(0x7472_6976));
+        assert_eq!(bus.read_u32(VIRTIO_BASE + VIRTIO_SIZE + 8), Ok(2));
+        assert!(bus.read_u32(VIRTIO_BASE + 2 * VIRTIO_SIZE).is_err());
+        assert!(bus.write_u32(VIRTIO_BASE + 0x70, 1).is_ok());
+
+        let properties = properties(&fdt::generate(&bus, &Chosen::default()));
+        assert_eq!(properties["/virtio_mmio@10002000/compatible"], b"virtio,mmio\0");
+        assert_eq!(properties["/virtio_mmio@10002000/interrupts"], [0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn it_routes_interrupts_to_the_plic() {
+        let mut bus = Bus::new();
+        bus.attach(Box::new(Doorbell::new()));
+        bus.write_u32(PLIC_BASE + 4 * DOORBELL_IRQ as u64, 1).unwrap();
+        bus.write_u32(PLIC_BASE + 0x2000, 1 << DOORBELL_IRQ).unwrap();
+        assert!(!bus.external_interrupt());
+
+        bus.write_u32(DOORBELL_BASE, 1).unwrap();
+        assert!(bus.external_interrupt());
+        assert_eq!(bus.read_u32(PLIC_BASE + 0x20_0004), Ok(DOORBELL_IRQ));
+        assert!(!bus.external_interrupt());
+
+        // Lowering the line and completing the claim leaves nothing pending.
+        bus.write_u32(DOORBELL_BASE, 0).unwrap();
+        bus.write_u32(PLIC_BASE + 0x20_0004, DOORBELL_IRQ).unwrap();
+        assert!(!bus.external_interrupt());
+
+        let properties = properties(&fdt::generate(&bus, &Chosen::default()));
+        assert_eq!(properties["/interrupt-parent"], PLIC_PHANDLE.to_be_bytes());
+        assert_eq!(properties["/plic@c000000/phandle"], PLIC_PHANDLE.to_be_bytes());
+        assert_eq!(properties["/plic@c000000/interrupts-extended"], [0, 0, 0, 1, 0, 0, 0, 11]);
+    }
+
+    #[test]
+    fn it_raises_the_rtc_alarm_on_the_plic() {
+        let mut bus = Bus::new();
+        bus.attach(Box::new(Rtc::new(Epoch::Fixed(1000))));
+        bus.write_u32(PLIC_BASE + 4 * RTC_IRQ as u64, 1).unwrap();
+        bus.write_u32(PLIC_BASE + 0x2000, 1 << RTC_IRQ).unwrap();
+        assert!(!bus.external_interrupt());
+
+        // The alarm goes off as soon as it's set for a time which has passed.
+        bus.write_u32(RTC_BASE + 0x10, 1).unwrap();
+        bus.write_u32(RTC_BASE + 0x08, 0).unwrap();
+        assert!(bus.external_interrupt());
+        assert_eq!(bus.read_u32(PLIC_BASE + 0x20_0004), Ok(RTC_IRQ));
+        assert!(!bus.external_interrupt());
+
+        // Clearing the alarm's interrupt and completing the claim leaves nothing pending.
+        bus.write_u32(RTC_BASE + 0x1c, 1).unwrap();
+        bus.write_u32(PLIC_BASE + 0x20_0004, RTC_IRQ).unwrap();
+        assert!(!bus.external_interrupt());
+    }
 }
\ No newline at end of file
@@ -1,16 +1,29 @@
 #![allow(dead_code, unused_variables)]
 
-use crate::{isa::Instruction, util::{get_bits, sign_extend_64, unsigned_32}};
+use num_enum::TryFromPrimitive;
 
-use super::{bus::DRAM_BASE, memory::{registers::Register::*, RegisterFile, Size, MMU}};
+use crate::{isa::{csr, decode::ITypeParams, Instruction}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable}, syscall::{linux::initial_stack, semihosting, Outcome, Semihosting, SyscallHandler}, trace::{Commit, MemAccess, Tracer}, util::{get_bits, sign_extend_64, unsigned_32}};
 
-#[derive(Clone, PartialEq, Eq)]
+use super::{bus::DRAM_BASE, devices::POLL_INTERVAL, memory::{address::Addressable, registers::Register::*, RegisterFile, Size, MMU}};
+
+/// The extensions which the CPU implements, as the ISA string of the device tree.
+pub const ISA: &str = "rv64im";
+/// The value of `misa`: a 64-bit hart with the I and M extensions.
+const MISA_VALUE: u64 = 2 << 62 | 1 << 8 | 1 << 12;
+/// The bit of `misa` for user mode, which only exists when there's a user space to run it in.
+const MISA_USER: u64 = 1 << 20;
+/// The bit of `mcause` which is set for interrupts.
+const INTERRUPT_CAUSE: u64 = 1 << 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
 pub enum Xlen {
 	Bit32,
 	Bit64
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
 pub enum PrivilegeMode {
 	User,
 	Supervisor,
@@ -18,7 +31,7 @@ pub enum PrivilegeMode {
 	Machine
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Trap {
 	Breakpoint,
     EnvironmentCallFromMMode,
@@ -45,6 +58,44 @@ pub enum Trap {
     UserTimerInterrupt
 }
 
+impl Trap {
+    /// Indicates if the trap is an interrupt, rather than an exception which an instruction
+    /// raised.
+    pub fn is_interrupt(&self) -> bool {
+        self.cause() & INTERRUPT_CAUSE != 0
+    }
+
+    /// The code which `mcause` is set to when the trap is taken.
+    pub fn cause(&self) -> u64 {
+        use Trap::*;
+        match self {
+            InstructionAddressMisaligned => 0,
+            InstructionAccessFault => 1,
+            IllegalInstruction => 2,
+            Breakpoint => 3,
+            LoadAddressMisaligned => 4,
+            LoadAccessFault => 5,
+            StoreAddressMisaligned => 6,
+            StoreAccessFault => 7,
+            EnvironmentCallFromUMode => 8,
+            EnvironmentCallFromSMode => 9,
+            EnvironmentCallFromMMode => 11,
+            InstructionPageFault => 12,
+            LoadPageFault => 13,
+            StorePageFault => 15,
+            UserSoftwareInterrupt => INTERRUPT_CAUSE,
+            SupervisorSoftwareInterrupt => INTERRUPT_CAUSE | 1,
+            MachineSoftwareInterrupt => INTERRUPT_CAUSE | 3,
+            UserTimerInterrupt => INTERRUPT_CAUSE | 4,
+            SupervisorTimerInterrupt => INTERRUPT_CAUSE | 5,
+            MachineTimerInterrupt => INTERRUPT_CAUSE | 7,
+            UserExternalInterrupt => INTERRUPT_CAUSE | 8,
+            SupervisorExternalInterrupt => INTERRUPT_CAUSE | 9,
+            MachineExternalInterrupt => INTERRUPT_CAUSE | 11,
+        }
+    }
+}
+
 pub struct CPU {
     clock: u64,
     xlen: Xlen,
@@ -52,7 +103,23 @@ pub struct CPU {
     pc: u64,
     xregs: RegisterFile<u64>,
     fregs: RegisterFile<f64>, 
+    /// The control and status registers, indexed by their address. Only those which hold state
+    /// are kept here; the others, such as `misa` and `mip`, are worked out when they're read.
+    csrs: Vec<u64>,
     mmu: MMU,
+    /// The effects of the instruction currently being executed.
+    commit: Commit,
+    tracer: Option<Box<dyn Tracer>>,
+    /// Services `ecall`s on the host, in place of an operating system.
+    syscalls: Option<Box<dyn SyscallHandler>>,
+    /// Services semihosting calls on the host, if they are enabled.
+    semihosting: Option<Semihosting>,
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
@@ -64,13 +131,19 @@ impl CPU {
             pc: DRAM_BASE,
             xregs: RegisterFile::new(),
             fregs: RegisterFile::new(),
+            csrs: vec![0; 4096],
             mmu: MMU::new(),
+            commit: Commit::default(),
+            tracer: None,
+            syscalls: None,
+            semihosting: None,
         };
-        // For linux boot
-        // cpu.xregs.write(X11, 0x1020);
-
-        // Initialise stack pointer towards the end of the DRAM.
-        cpu.xregs.write(X2, DRAM_BASE + 1024*1024*1024 - 64);
+        // Start with an empty process stack at the end of the DRAM, with no arguments.
+        let top = DRAM_BASE + cpu.mmu.bus().dram().size();
+        let sp = initial_stack(&mut cpu.mmu, top, &[], &[], &[]).expect("the stack is in DRAM");
+        cpu.xregs.write(X2, sp);
+        // A trap return before any trap stays in machine mode.
+        cpu.csrs[csr::MSTATUS as usize] = csr::MSTATUS_MPP;
         cpu
     }
 
@@ -79,16 +152,85 @@ impl CPU {
         &mut self.mmu
     }
 
+    /// Sets the tracer which receives every instruction that the CPU retires.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Sets the handler which services environment calls on the host.
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler>) {
+        self.syscalls = Some(handler);
+    }
+
+    /// Enables semihosting, so that `ebreak`s which are part of a semihosting call are serviced
+    /// on the host rather than raising a breakpoint.
+    pub fn set_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+
+    pub fn privilege_mode(&self) -> PrivilegeMode {
+        self.pmode
+    }
+
+    /// Indicates if the hart can run in user mode. That's only when it runs a program in a user
+    /// space, as nothing translates the addresses of user mode otherwise.
+    fn has_user_mode(&self) -> bool {
+        self.mmu.user_space().is_some()
+    }
+
+    /// Switches the privilege mode of the CPU and its MMU.
+    pub fn set_privilege_mode(&mut self, mode: PrivilegeMode) {
+        self.pmode = mode;
+        self.mmu.set_privilege_mode(mode);
+    }
+
+    /// Retrieves the address of the next instruction to be executed.
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    /// Moves the program counter, such as from a debugger.
+    pub fn set_pc(&mut self, addr: u64) {
+        self.pc = addr;
+    }
+
+    /// Reads an integer register by its number.
+    pub fn read_xreg(&self, num: u8) -> u64 {
+        self.xregs.read_num(num)
+    }
+
+    /// Writes an integer register by its number, such as from a debugger. The write isn't
+    /// recorded as part of an instruction's effects.
+    pub fn set_xreg(&mut self, num: u8, value: u64) {
+        self.xregs.write_num(num, value);
+    }
+
+    /// Retrieves the effects of the most recently retired instruction.
+    pub fn last_commit(&self) -> &Commit {
+        &self.commit
+    }
+
+    /// Retrieves the number of cycles which the CPU has executed.
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
     /// Begins the execution of the CPU.
     pub fn run(&mut self) {
-        loop {
+        while !self.run_for(u64::MAX) {}
+    }
+
+    /// Executes up to a number of instructions, returning whether the program stopped first.
+    pub fn run_for(&mut self, count: u64) -> bool {
+        for _ in 0..count {
             if self.pc == 0 {
                 println!("stopped execution with exit code: {}", self.xregs.read(X10));
-                return;
+                return true;
             }
 
             self.tick();
-        } 
+        }
+        false
     }
 
     /// Retrieves the current address stored in the program counter.
@@ -106,6 +248,17 @@ impl CPU {
         self.pc = self.pc.wrapping_add(amount);
     }
 
+    /// Retrieves the address of the instruction being executed. The program counter has already
+    /// been moved past it when the instruction was fetched.
+    fn inst_pc(&self) -> u64 {
+        self.pc.wrapping_sub(4)
+    }
+
+    /// Jumps to an offset relative to the address of the instruction being executed.
+    fn branch(&mut self, offset: i32) {
+        self.update_pc(self.inst_pc().wrapping_add(offset as u64));
+    }
+
     /// Increments the clock by 1. Cycles back to 0 if it exceeds 2^64-1.
     fn incr_clock(&mut self) {
         self.clock = self.clock.wrapping_add(1);
@@ -123,53 +276,271 @@ impl CPU {
         Instruction::decode(inst)
     }
 
-    /// Handles a trap which has been generated by something in the machine.
-    fn handle_trap(&self, trap: Trap) {
-        println!("{:#?}", trap);
-        unimplemented!("trap handler not yet implemented")
+    /// Passes an `ecall` to the syscall handler, raising the trap if there isn't one or it
+    /// doesn't service the call.
+    fn environment_call(&mut self, trap: Trap) -> Result<(), Trap> {
+        let Some(mut handler) = self.syscalls.take() else {
+            return Err(trap);
+        };
+        let outcome = handler.ecall(self, &trap);
+        self.syscalls = Some(handler);
+        self.complete_call(outcome, trap)
+    }
+
+    /// Services a semihosting call if semihosting is enabled and the `ebreak` being executed is
+    /// surrounded by the semihosting hints, or raises a breakpoint.
+    fn breakpoint(&mut self) -> Result<(), Trap> {
+        let pc = self.inst_pc();
+        let is_call = self.semihosting.is_some()
+            && self.mmu.load_u32(pc.wrapping_sub(4)) == Ok(semihosting::ENTRY_NOP)
+            && self.mmu.load_u32(pc.wrapping_add(4)) == Ok(semihosting::EXIT_NOP);
+        if !is_call {
+            return Err(Trap::Breakpoint);
+        }
+
+        let mut semihosting = self.semihosting.take().unwrap();
+        let outcome = semihosting.call(self);
+        self.semihosting = Some(semihosting);
+        self.complete_call(outcome, Trap::Breakpoint)
+    }
+
+    /// Returns from a call which was serviced on the host, or raises the trap if it wasn't.
+    fn complete_call(&mut self, outcome: Outcome, trap: Trap) -> Result<(), Trap> {
+        match outcome {
+            Outcome::Return(value) => {
+                self.write_xreg(X10 as u8, value);
+                Ok(())
+            },
+            Outcome::Exit(code) => {
+                self.xregs.write(X10, code);
+                self.update_pc(0);
+                Ok(())
+            },
+            Outcome::Unhandled => Err(trap),
+        }
+    }
+
+    /// Takes a trap into machine mode, at the handler which `mtvec` points to. Without a handler,
+    /// the trap goes to address 0, which stops the machine.
+    fn handle_trap(&mut self, trap: Trap) {
+        let cause = trap.cause();
+        // An exception returns to the instruction which raised it, and an interrupt to the one
+        // which it was taken in place of.
+        let epc = match trap.is_interrupt() {
+            true => self.pc,
+            false => self.inst_pc(),
+        };
+        let tvec = self.csrs[csr::MTVEC as usize];
+        if tvec == 0 {
+            eprintln!("{:?} at {:#x} with no trap handler", trap, epc);
+        }
+
+        self.csrs[csr::MEPC as usize] = epc;
+        self.csrs[csr::MCAUSE as usize] = cause;
+        self.csrs[csr::MTVAL as usize] = 0;
+        let status = self.csrs[csr::MSTATUS as usize];
+        let mpie = match status & csr::MSTATUS_MIE != 0 {
+            true => csr::MSTATUS_MPIE,
+            false => 0,
+        };
+        self.csrs[csr::MSTATUS as usize] = mpie | (self.pmode as u64) << 11;
+        self.set_privilege_mode(PrivilegeMode::Machine);
+
+        // In vectored mode, interrupts go to an entry for their cause.
+        let base = tvec & !0b11;
+        match tvec & 0b11 == 1 && trap.is_interrupt() {
+            true => self.update_pc(base.wrapping_add(4 * (cause & !INTERRUPT_CAUSE))),
+            false => self.update_pc(base),
+        }
     }
 
     /// Handles an exception that has been thrown by something in the machine.
-    fn handle_exception(&self, excep: Trap) {
+    fn handle_exception(&mut self, excep: Trap) {
         self.handle_trap(excep);
     }
 
-    /// Performs one tick of the cpu execution. This includes performing one cycle, and handling any
-    /// interrupts and exceptions that may have occurred.
-    fn tick(&mut self) {
-        self.incr_clock();
+    /// Returns from a trap handler, to the privilege mode and the address which the trap was
+    /// taken from.
+    fn trap_return(&mut self) -> Result<(), Trap> {
+        if self.pmode != PrivilegeMode::Machine {
+            return Err(Trap::IllegalInstruction);
+        }
+        let status = self.csrs[csr::MSTATUS as usize];
+        let mode = PrivilegeMode::try_from(((status & csr::MSTATUS_MPP) >> 11) as u8).unwrap();
+        let mie = match status & csr::MSTATUS_MPIE != 0 {
+            true => csr::MSTATUS_MIE,
+            false => 0,
+        };
+        // The previous mode becomes the least privileged one there is.
+        let mpp = match self.has_user_mode() {
+            true => 0,
+            false => csr::MSTATUS_MPP,
+        };
+        self.csrs[csr::MSTATUS as usize] = mie | csr::MSTATUS_MPIE | mpp;
+        self.set_privilege_mode(mode);
+        self.update_pc(self.csrs[csr::MEPC as usize]);
+        Ok(())
+    }
+
+    /// The interrupts which are pending, as the bits of `mip`.
+    fn pending_interrupts(&mut self) -> u64 {
+        let bus = self.mmu.bus();
+        [
+            (bus.external_interrupt(), csr::MEI),
+            (bus.software_interrupt(), csr::MSI),
+            (bus.timer_interrupt(), csr::MTI),
+        ]
+            .into_iter()
+            .filter(|(pending, _)| *pending)
+            .fold(0, |pending, (_, bit)| pending | bit)
+    }
+
+    /// Finds the interrupt to take before the next instruction, if there's one which is pending
+    /// and enabled. External interrupts come before software interrupts, and then timers.
+    fn pending_interrupt(&mut self) -> Option<Trap> {
+        let enabled = self.csrs[csr::MIE as usize];
+        if enabled == 0 {
+            return None;
+        }
+        // Machine interrupts are always enabled in the less privileged modes.
+        if self.pmode == PrivilegeMode::Machine && self.csrs[csr::MSTATUS as usize] & csr::MSTATUS_MIE == 0 {
+            return None;
+        }
+        let pending = self.pending_interrupts() & enabled;
+        [
+            (csr::MEI, Trap::MachineExternalInterrupt),
+            (csr::MSI, Trap::MachineSoftwareInterrupt),
+            (csr::MTI, Trap::MachineTimerInterrupt),
+        ]
+            .into_iter()
+            .find(|(bit, _)| pending & bit != 0)
+            .map(|(_, trap)| trap)
+    }
 
-        let result = self.cycle();
+    /// Reads a CSR, or returns `None` if the CPU doesn't implement it.
+    fn read_csr(&mut self, addr: u16) -> Option<u64> {
+        Some(match addr {
+            csr::MISA => match self.has_user_mode() {
+                true => MISA_VALUE | MISA_USER,
+                false => MISA_VALUE,
+            },
+            csr::MVENDORID | csr::MARCHID | csr::MIMPID | csr::MHARTID => 0,
+            // Every instruction takes a cycle, so the counters both follow the clock.
+            csr::CYCLE | csr::INSTRET | csr::MCYCLE | csr::MINSTRET => self.clock,
+            csr::TIME => self.mmu.bus().time(),
+            csr::MIP => self.pending_interrupts(),
+            csr::MSTATUS | csr::MIE | csr::MTVEC | csr::MSCRATCH | csr::MEPC | csr::MCAUSE |
+            csr::MTVAL => self.csrs[addr as usize],
+            _ => return None,
+        })
+    }
+
+    /// Writes a CSR which the CPU implements, keeping the fields which can't be written.
+    fn write_csr(&mut self, addr: u16, value: u64) {
+        let value = match addr {
+            csr::MSTATUS => {
+                // The previous mode is either user or machine mode, as there's no supervisor, and
+                // it's always machine mode without user mode.
+                let mpp = match value & csr::MSTATUS_MPP {
+                    0 if self.has_user_mode() => 0,
+                    _ => csr::MSTATUS_MPP,
+                };
+                value & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE) | mpp
+            },
+            csr::MIE => value & (csr::MSI | csr::MTI | csr::MEI),
+            // Only the direct and vectored modes exist.
+            csr::MTVEC => value & !0b10,
+            // Instructions are 4-byte aligned without the C extension.
+            csr::MEPC => value & !0b11,
+            csr::MSCRATCH | csr::MCAUSE | csr::MTVAL => value,
+            // The others are read-only, or their state is held elsewhere, like the clock.
+            _ => return,
+        };
+        self.csrs[addr as usize] = value;
+    }
+
+    /// Reads and then updates a CSR, as an instruction of the Zicsr extension does. Writing a CSR
+    /// which is read-only or more privileged than the current mode is an illegal instruction.
+    fn access_csr(&mut self, params: ITypeParams, write: bool, update: impl Fn(u64) -> u64) -> Result<(), Trap> {
+        let addr = (params.imm & 0xfff) as u16;
+        if (addr >> 8) & 0b11 > self.pmode as u16 || (write && addr >> 10 == 0b11) {
+            return Err(Trap::IllegalInstruction);
+        }
+        let old = self.read_csr(addr).ok_or(Trap::IllegalInstruction)?;
+        if write {
+            self.write_csr(addr, update(old));
+        }
+        self.write_xreg(params.rd, old);
+        Ok(())
+    }
+
+    /// Performs one tick of the cpu execution. This includes performing one cycle, and handling any
+    /// interrupts and exceptions that may have occurred. Returns the exception which the
+    /// instruction raised, if it raised one, after it has been taken.
+    pub fn tick(&mut self) -> Option<Trap> {
+        let result = self.step();
         // For now, stop execution if address 0x0000_0000 is reached        
         
-        match result {
-            Err(e) => self.handle_exception(e),
-            Ok(_) => {},
+        if let Err(e) = result {
+            self.handle_exception(e);
+            return Some(e);
+        }
+        None
+    }
+
+    /// Executes a single instruction, returning any trap which it raised instead of handling it.
+    /// An interrupt is taken in place of the instruction, as the guest's handler deals with it.
+    pub fn step(&mut self) -> Result<(), Trap> {
+        self.incr_clock();
+        self.mmu.bus().journal().set_clock(self.clock);
+        if self.clock.is_multiple_of(POLL_INTERVAL) {
+            self.mmu.bus().poll();
         }
+        if let Some(interrupt) = self.pending_interrupt() {
+            self.handle_trap(interrupt);
+            return Ok(());
+        }
+        self.cycle()
     }
 
     /// Performs the fetch, decode, execute stages to complete the current cycle of execution.
     fn cycle(&mut self) -> Result<(), Trap> {
-        let fetched_inst = self.fetch();
-        let raw_inst = match fetched_inst {
-            Err(e) => return Err(e),
-            Ok(inst) => inst,
-        };
-        println!("{:x}:       {:X}", self.pc, raw_inst);
+        let pc = self.pc;
+        let raw_inst = self.fetch()?;
         let inst: Instruction = self.decode(raw_inst as u32);
-        match inst {
-            Instruction::UNDEF => return Err(Trap::IllegalInstruction),
-            _ => {},
-        };
-        let result = self.execute(inst);
-        
-        result
+        if inst == Instruction::UNDEF {
+            return Err(Trap::IllegalInstruction);
+        }
+        self.commit.begin(self.pmode as u8, pc, raw_inst as u32);
+        self.execute(inst)?;
+        self.trace();
+        Ok(())
+    }
+
+    /// Passes the effects of the retired instruction to the tracer. Tracing stops if the tracer
+    /// fails, rather than stopping the machine.
+    fn trace(&mut self) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(e) = tracer.commit(&self.commit) {
+                eprintln!("stopped tracing: {}", e);
+                self.tracer = None;
+            }
+        }
+    }
+
+    /// Writes to an integer register, recording the write for the tracer. Writes to x0 have no
+    /// effect, so they are not recorded.
+    fn write_xreg(&mut self, rd: u8, value: u64) {
+        self.xregs.write_num(rd, value);
+        if rd != 0 {
+            self.commit.reg_writes.push((rd, value));
+        }
     }
 
     /// Executes a decoded instruction.
     fn execute(&mut self, inst: Instruction) -> Result<(), Trap> {
         use Instruction::*;
-        return match inst {
+        match inst {
             // UNDEF: Undefined instruction.
             UNDEF => Err(Trap::IllegalInstruction),
 
@@ -179,49 +550,49 @@ impl CPU {
             ADD(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(params.rd, a + b);
+                self.write_xreg(params.rd, a.wrapping_add(b));
                 Ok(())
             }
             SUB(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(params.rd, a - b);
+                self.write_xreg(params.rd, a.wrapping_sub(b));
                 Ok(())
             },
             XOR(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(params.rd, a ^ b);
+                self.write_xreg(params.rd, a ^ b);
                 Ok(())
             },
             OR(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(params.rd, a | b);
+                self.write_xreg(params.rd, a | b);
                 Ok(())
             },
             AND(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(params.rd, a * b);
+                self.write_xreg(params.rd, a & b);
                 Ok(())
             },
             SLL(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(params.rd, a << b);
+                self.write_xreg(params.rd, a << b);
                 Ok(())
             },
             SRL(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(params.rd, a >> b);
+                self.write_xreg(params.rd, a >> b);
                 Ok(())
             },
             SRA(params) => {
                 let a = self.xregs.read_num(params.rs1) as i64;
                 let b = self.xregs.read_num(params.rs2) as i64;
-                self.xregs.write_num(params.rd, (a >> b) as u64);
+                self.write_xreg(params.rd, (a >> b) as u64);
                 Ok(())
             },
             SLT(params) => {
@@ -229,7 +600,7 @@ impl CPU {
                 let b = self.xregs.read_num(params.rs2);
                 let result = ((a as i64) < (b as i64)) as u64;
                 assert!(result == 0 || result == 1);
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             SLTU(params) => {
@@ -237,13 +608,13 @@ impl CPU {
                 let b = self.xregs.read_num(params.rs2);
                 let result = (a < b) as u64;
                 assert!(result == 0 || result == 1);
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             ADDW(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(
+                self.write_xreg(
                     params.rd, 
                     (sign_extend_64(unsigned_32(a), 32) + 
                      sign_extend_64(unsigned_32(b), 32)) as u64
@@ -253,7 +624,7 @@ impl CPU {
             SUBW(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(
+                self.write_xreg(
                     params.rd, 
                     (sign_extend_64(unsigned_32(a), 32) - 
                      sign_extend_64(unsigned_32(b), 32)) as u64
@@ -263,7 +634,7 @@ impl CPU {
             SLLW(params)  => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(
+                self.write_xreg(
                     params.rd, 
                     (sign_extend_64(unsigned_32(a), 32) << 
                      sign_extend_64(unsigned_32(b), 32)) as u64
@@ -273,7 +644,7 @@ impl CPU {
             SRLW(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(
+                self.write_xreg(
                     params.rd, 
                     (sign_extend_64(unsigned_32(a), 32) >> 
                      sign_extend_64(unsigned_32(b), 32)) as u64
@@ -283,7 +654,7 @@ impl CPU {
             SRAW(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let b = self.xregs.read_num(params.rs2);
-                self.xregs.write_num(
+                self.write_xreg(
                     params.rd, 
                     sign_extend_64(
                         (unsigned_32(a) as i64 >> b as i64) as u64, 
@@ -300,57 +671,57 @@ impl CPU {
                 let result = self.xregs
                     .read_num(params.rs1)
                     .wrapping_add(params.imm as u64);
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             XORI(params) => {
                 let result = self.xregs
                     .read_num(params.rs1) ^ params.imm as u64;
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             ORI(params) => {
                 let result = self.xregs
                     .read_num(params.rs1) | params.imm as u64;
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             ANDI(params) => {
                 let result = self.xregs
                     .read_num(params.rs1) & params.imm as u64;
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             SLLI(params) => {
                 let result = self.xregs
-                    .read_num(params.rs1) << get_bits(params.imm, 0, 4);
-                self.xregs.write_num(params.rd, result);
+                    .read_num(params.rs1) << get_bits(params.imm, 0, 5);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             SRLI(params) => {
                 let result = self.xregs
-                    .read_num(params.rs1) >> get_bits(params.imm, 0, 4);
-                self.xregs.write_num(params.rd, result);
+                    .read_num(params.rs1) >> get_bits(params.imm, 0, 5);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             SRAI(params) => {
                 let result = self.xregs
-                    .read_num(params.rs1) as i64 >> get_bits(params.imm, 0, 4) as i64;
-                self.xregs.write_num(params.rd, result as u64);
+                    .read_num(params.rs1) as i64 >> get_bits(params.imm, 0, 5) as i64;
+                self.write_xreg(params.rd, result as u64);
                 Ok(())
             },
             SLTI(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let result = ((a as i64) < (params.imm as i64)) as u64;
                 assert!(result == 0 || result == 1);
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
             SLTIU(params) => {
                 let a = self.xregs.read_num(params.rs1);
                 let result = (a < params.imm as u64) as u64;
                 assert!(result == 0 || result == 1);
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(params.rd, result);
                 Ok(())
             },
 
@@ -358,13 +729,16 @@ impl CPU {
                 let result = unsigned_32(self.xregs
                     .read_num(params.rs1))
                     .wrapping_add(params.imm as u64);
-                self.xregs.write_num(params.rd, result);
+                self.write_xreg(
+                    params.rd, 
+                    sign_extend_64(result, 32) as u64
+                );
                 Ok(())
             },
             SLLIW(params) => {
                 let result = unsigned_32(self.xregs
                     .read_num(params.rs1)) << params.imm as u64;
-                self.xregs.write_num(
+                self.write_xreg(
                     params.rd, 
                     sign_extend_64(result, 32) as u64
                 );
@@ -373,7 +747,7 @@ impl CPU {
             SRLIW(params) => {
                 let result = unsigned_32(self.xregs
                     .read_num(params.rs1)) >> params.imm as u64;
-                self.xregs.write_num(
+                self.write_xreg(
                     params.rd, 
                     sign_extend_64(result, 32) as u64
                 );
@@ -381,8 +755,8 @@ impl CPU {
             },
             SRAIW(params) => {
                 let result = sign_extend_64(unsigned_32(self.xregs
-                    .read_num(params.rs1)), 32) as i64 >> (params.imm as i64);
-                self.xregs.write_num(
+                    .read_num(params.rs1)), 32) >> (params.imm as i64);
+                self.write_xreg(
                     params.rd, 
                     result as u64,
                 );
@@ -403,22 +777,21 @@ impl CPU {
                     .read_num(params.rs1)
                     .wrapping_add(params.imm as u64);
                 let size = match inst {
-                    LB(_) => Size::Byte,
-                    LH(_) => Size::HalfWord,
-                    LW(_) => Size::Word,
+                    LB(_) | LBU(_) => Size::Byte,
+                    LH(_) | LHU(_) => Size::HalfWord,
+                    LW(_) | LWU(_) => Size::Word,
                     LD(_) => Size::DoubleWord,
                     _ => unreachable!()
                 };
-                let data = match self.mmu.load(addr, size) {
-                    Err(e) => return Err(e),
-                    Ok(v) => match inst {
-                        LBU(_) | 
-                        LHU(_) | 
-                        LWU(_) => v,
-                        _ => sign_extend_64(v, size as u8 * 8) as u64
-                    },
+                let value = self.mmu.load(addr, size)?;
+                self.commit.loads.push(MemAccess { addr, size, value });
+                let data = match inst {
+                    LBU(_) | 
+                    LHU(_) | 
+                    LWU(_) => value,
+                    _ => sign_extend_64(value, size as u8 * 8) as u64
                 };
-                self.xregs.write_num(
+                self.write_xreg(
                     params.rd, 
                     data
                 );
@@ -442,11 +815,10 @@ impl CPU {
                     SD(_) => Size::DoubleWord,
                     _ => unreachable!()
                 };
-                let data = self.xregs
-                    .read_num(params.rs2)
-                    .to_le_bytes()[0..size as usize]
-                    .to_vec();
-                self.mmu.store(addr, size, data)
+                let value = self.xregs.read_num(params.rs2) & (u64::MAX >> (64 - size as u64 * 8));
+                self.mmu.store(addr, size, value)?;
+                self.commit.stores.push(MemAccess { addr, size, value });
+                Ok(())
             },
 
             /*
@@ -454,34 +826,34 @@ impl CPU {
              */
             BEQ(params) => {
                 if self.xregs.read_num(params.rs1) == self.xregs.read_num(params.rs2) 
-                    { self.pc = self.pc + params.imm as u64 };
+                    { self.branch(params.imm) };
                 Ok(())
             }
             BNE(params) => {
                 if self.xregs.read_num(params.rs1) != self.xregs.read_num(params.rs2) 
-                    { self.pc = self.pc + params.imm as u64 };
+                    { self.branch(params.imm) };
                 Ok(())
             },
             BLT(params) => {
                 if (self.xregs.read_num(params.rs1) as i64) 
                     < (self.xregs.read_num(params.rs2) as i64)
-                    { self.pc = self.pc + params.imm as u64 };
+                    { self.branch(params.imm) };
                 Ok(())
             },
             BLTU(params) => {
                 if self.xregs.read_num(params.rs1) < self.xregs.read_num(params.rs2)
-                    { self.pc = self.pc + params.imm as u64 };
+                    { self.branch(params.imm) };
                 Ok(())
             },
             BGE(params) => {
                 if (self.xregs.read_num(params.rs1) as i64) 
                     >= (self.xregs.read_num(params.rs2) as i64)
-                    { self.pc = self.pc + params.imm as u64 };
+                    { self.branch(params.imm) };
                 Ok(())
             }
             BGEU(params) => {
                 if self.xregs.read_num(params.rs1) >= self.xregs.read_num(params.rs2)
-                   { self.pc = self.pc + params.imm as u64 };
+                   { self.branch(params.imm) };
                 Ok(())
             },
 
@@ -489,15 +861,15 @@ impl CPU {
              * Jumping
              */
             JAL(params) => {
-                self.xregs.write_num(params.rd, self.pc);
-                self.pc = self.pc.wrapping_add(params.imm as u64);
+                self.write_xreg(params.rd, self.pc);
+                self.branch(params.imm);
                 Ok(())
             },
             JALR(params) => {
-                self.xregs.write_num(params.rd, self.pc + 4);
                 let addr = self.xregs
                     .read_num(params.rs1)
-                    .wrapping_add(params.imm as u64);
+                    .wrapping_add(params.imm as u64) & !1;
+                self.write_xreg(params.rd, self.pc);
                 self.update_pc(addr);
                 Ok(())
             },
@@ -506,11 +878,12 @@ impl CPU {
              * Upper immediates
              */
             LUI(params) => {
-                self.xregs.write_num(params.rd, (params.imm as u64) << 12);
+                self.write_xreg(params.rd, sign_extend_64((params.imm as u64) << 12, 32) as u64);
                 Ok(())
             },
             AUIPC(params) => {
-                self.incr_pc((params.imm as u64) << 12);
+                let offset = sign_extend_64((params.imm as u64) << 12, 32) as u64;
+                self.write_xreg(params.rd, self.inst_pc().wrapping_add(offset));
                 Ok(())
             },
 
@@ -518,30 +891,140 @@ impl CPU {
              * Environment
              */
             ECALL(params) => match self.pmode {
-                PrivilegeMode::User => Err(Trap::EnvironmentCallFromUMode),
-                PrivilegeMode::Supervisor => Err(Trap::EnvironmentCallFromSMode),
-                PrivilegeMode::Machine => Err(Trap::EnvironmentCallFromMMode),
+                PrivilegeMode::User => self.environment_call(Trap::EnvironmentCallFromUMode),
+                PrivilegeMode::Supervisor => self.environment_call(Trap::EnvironmentCallFromSMode),
+                PrivilegeMode::Machine => self.environment_call(Trap::EnvironmentCallFromMMode),
                 PrivilegeMode::Reserved => panic!("Unknown privilege mode"),
             },
-            EBREAK(params) => Err(Trap::Breakpoint),
+            EBREAK(params) => self.breakpoint(),
+            MRET(params) => self.trap_return(),
+            // Waiting for an interrupt is allowed to return straight away.
+            WFI(params) => Ok(()),
+
+            /*
+             * Control and status registers
+             */
+            CSRRW(params) => {
+                let value = self.xregs.read_num(params.rs1);
+                self.access_csr(params, true, |_| value)
+            },
+            CSRRS(params) => {
+                let value = self.xregs.read_num(params.rs1);
+                self.access_csr(params, params.rs1 != 0, |old| old | value)
+            },
+            CSRRC(params) => {
+                let value = self.xregs.read_num(params.rs1);
+                self.access_csr(params, params.rs1 != 0, |old| old & !value)
+            },
+            CSRRWI(params) => self.access_csr(params, true, |_| params.rs1 as u64),
+            CSRRSI(params) => self.access_csr(params, params.rs1 != 0, |old| old | params.rs1 as u64),
+            CSRRCI(params) => self.access_csr(params, params.rs1 != 0, |old| old & !(params.rs1 as u64)),
 
             /*
              * Multiplication extension
              */
-            MUL(params) => unimplemented!(),
-            MULH(params) => unimplemented!(),
-            MULSU(params) => unimplemented!(),
-            MULU(params) => unimplemented!(),
-            DIV(params) => unimplemented!(),
-            DIVU(params) => unimplemented!(),
-            REM(params) => unimplemented!(),
-            REMU(params) => unimplemented!(),
-            MULW(rtype_params) => unimplemented!(),
-            DIVW(rtype_params) => unimplemented!(),
-            DIVWU(rtype_params) => unimplemented!(),
-            REMW(rtype_params) => unimplemented!(),
-            REMWU(rtype_params) => unimplemented!(),
-        };
+            MUL(params) |
+            MULH(params) |
+            MULSU(params) |
+            MULU(params) |
+            DIV(params) |
+            DIVU(params) |
+            REM(params) |
+            REMU(params) => {
+                let a = self.xregs.read_num(params.rs1);
+                let b = self.xregs.read_num(params.rs2);
+                // Division by zero and overflow don't trap, but give the results which the
+                // specification sets out: all ones or the dividend.
+                let result = match inst {
+                    MUL(_) => a.wrapping_mul(b),
+                    MULH(_) => ((a as i64 as i128 * b as i64 as i128) >> 64) as u64,
+                    MULSU(_) => ((a as i64 as i128 * b as i128) >> 64) as u64,
+                    MULU(_) => ((a as u128 * b as u128) >> 64) as u64,
+                    DIV(_) if b == 0 => u64::MAX,
+                    DIV(_) => (a as i64).wrapping_div(b as i64) as u64,
+                    DIVU(_) => a.checked_div(b).unwrap_or(u64::MAX),
+                    REM(_) if b == 0 => a,
+                    REM(_) => (a as i64).wrapping_rem(b as i64) as u64,
+                    REMU(_) => a.checked_rem(b).unwrap_or(a),
+                    _ => unreachable!()
+                };
+                self.write_xreg(params.rd, result);
+                Ok(())
+            },
+            MULW(params) |
+            DIVW(params) |
+            DIVWU(params) |
+            REMW(params) |
+            REMWU(params) => {
+                let a = self.xregs.read_num(params.rs1) as u32;
+                let b = self.xregs.read_num(params.rs2) as u32;
+                let result = match inst {
+                    MULW(_) => a.wrapping_mul(b),
+                    DIVW(_) if b == 0 => u32::MAX,
+                    DIVW(_) => (a as i32).wrapping_div(b as i32) as u32,
+                    DIVWU(_) => a.checked_div(b).unwrap_or(u32::MAX),
+                    REMW(_) if b == 0 => a,
+                    REMW(_) => (a as i32).wrapping_rem(b as i32) as u32,
+                    REMWU(_) => a.checked_rem(b).unwrap_or(a),
+                    _ => unreachable!()
+                };
+                self.write_xreg(params.rd, result as i32 as i64 as u64);
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Snapshotable for CPU {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let section = snapshot.section("cpu");
+        section
+            .u64(self.clock)
+            .u8(self.xlen as u8)
+            .u8(self.pmode as u8)
+            .u64(self.pc);
+        for num in 0..32 {
+            section.u64(self.xregs.read_num(num));
+        }
+        for num in 0..32 {
+            section.u64(self.fregs.read_num(num).to_bits());
+        }
+
+        // Most CSRs are zero, so only the others are saved.
+        let csrs: Vec<(usize, u64)> = self.csrs.iter().copied().enumerate().filter(|(_, v)| *v != 0).collect();
+        let section = snapshot.section("csr");
+        section.u32(csrs.len() as u32);
+        for (addr, value) in csrs {
+            section.u32(addr as u32).u64(value);
+        }
+
+        self.mmu.save(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("cpu")?;
+        self.clock = section.u64()?;
+        self.xlen = Xlen::try_from(section.u8()?).map_err(|_| section.corrupt())?;
+        self.pmode = PrivilegeMode::try_from(section.u8()?).map_err(|_| section.corrupt())?;
+        self.pc = section.u64()?;
+        for num in 0..32 {
+            self.xregs.write_num(num, section.u64()?);
+        }
+        for num in 0..32 {
+            self.fregs.write_num(num, f64::from_bits(section.u64()?));
+        }
+        section.finish()?;
+
+        let mut section = snapshot.section("csr")?;
+        self.csrs.fill(0);
+        for _ in 0..section.u32()? {
+            let addr = section.u32()? as usize;
+            let value = section.u64()?;
+            *self.csrs.get_mut(addr).ok_or_else(|| section.corrupt())? = value;
+        }
+        section.finish()?;
+
+        self.mmu.restore(snapshot)
     }
 }
 
@@ -549,11 +1032,322 @@ impl CPU {
 mod test {
     use num_traits::pow;
 
-    use crate::{components::memory::registers::Register, isa::{decode::{ITypeParams, RTypeParams}, Instruction}};
+    use crate::{asm, components::{bus::test::Doorbell, memory::{registers::Register, Size}}, isa::{csr, decode::{ITypeParams, RTypeParams}, Instruction}, snapshot, trace::MemAccess};
+
+    use super::{PrivilegeMode, Trap, CPU};
+
+    /// Loads a program at the start of DRAM and runs it until it raises a trap, such as `ecall`.
+    fn run(program: Vec<u8>) -> (CPU, Trap) {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(program);
+        let (_, trap) = run_to_trap(&mut cpu);
+        (cpu, trap)
+    }
+
+    /// Steps the CPU until it raises a trap, returning the number of instructions retired.
+    fn run_to_trap(cpu: &mut CPU) -> (u64, Trap) {
+        for count in 0..10_000 {
+            if let Err(trap) = cpu.step() {
+                return (count, trap);
+            }
+        }
+        panic!("program did not raise a trap");
+    }
+
+    #[test]
+    pub fn it_runs_assembled_programs() {
+        let (mut cpu, trap) = run(asm!("addi a0, zero, 5; ecall"));
+        assert_eq!(trap, Trap::EnvironmentCallFromMMode);
+        assert_eq!(cpu.xregs.read(Register::X10), 5);
+        assert_eq!(cpu.pc, 0x8000_0008);
+    }
+
+    #[test]
+    fn it_multiplies_and_divides() {
+        let (cpu, _) = run(asm!("
+            li a0, -6
+            li a1, 4
+            mul s0, a0, a1
+            mulh s1, a0, a1
+            mulhu s2, a0, a1
+            mulhsu s3, a0, a1
+            div s4, a0, a1
+            divu s5, a1, zero
+            rem s6, a0, a1
+            remu s7, a0, zero
+            li a2, -1
+            slli a3, a2, 63
+            div s8, a3, a2
+            rem s9, a3, a2
+            mulw s10, a0, a1
+            divuw s11, a0, a1
+            remw t0, a0, zero
+            ecall
+        "));
+        let reg = |r: u8| cpu.read_xreg(r) as i64;
+        assert_eq!(reg(8), -24);
+        assert_eq!(reg(9), -1);
+        assert_eq!(reg(18), 3);
+        assert_eq!(reg(19), -1);
+        assert_eq!(reg(20), -1);
+        assert_eq!(reg(21), -1);
+        assert_eq!(reg(22), -2);
+        assert_eq!(reg(23), -6);
+        // The most negative number divided by -1 overflows back to itself.
+        assert_eq!(reg(24), i64::MIN);
+        assert_eq!(reg(25), 0);
+        assert_eq!(reg(26), -24);
+        assert_eq!(reg(27), 0x3fff_fffe);
+        assert_eq!(reg(5), -6);
+    }
+
+    #[test]
+    fn it_takes_traps_into_machine_mode() {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("
+                la t0, handler
+                csrw mtvec, t0
+                csrr s0, misa
+                csrr s1, mhartid
+                ecall
+                li t0, 0
+                jr t0
+            handler:
+                csrr a1, mcause
+                csrr a2, mepc
+                addi a2, a2, 4
+                csrw mepc, a2
+                mret
+        "));
+        assert!(cpu.run_for(100));
+        assert_eq!(cpu.read_xreg(8), 2 << 62 | 1 << 8 | 1 << 12);
+        assert_eq!(cpu.read_xreg(9), 0);
+        assert_eq!(cpu.read_xreg(11), 11);
+        assert_eq!(cpu.read_xreg(12), 0x8000_0018);
+
+        // Accessing a CSR which doesn't exist, or writing a read-only one, is illegal.
+        let (_, trap) = run(asm!("csrr a0, 0x7c0"));
+        assert_eq!(trap, Trap::IllegalInstruction);
+        let (_, trap) = run(asm!("csrw mhartid, a0"));
+        assert_eq!(trap, Trap::IllegalInstruction);
+    }
+
+    #[test]
+    fn it_stays_in_machine_mode_without_a_user_space() {
+        // Returning to user mode would leave the hart with no way to translate its addresses.
+        let (cpu, trap) = run(asm!("
+                auipc t0, 0
+                addi t0, t0, 20
+                csrw mepc, t0
+                csrw mstatus, zero
+                mret
+                nop
+        "));
+        assert_eq!(trap, Trap::IllegalInstruction);
+        assert_eq!(cpu.inst_pc(), 0x8000_0018);
+        assert_eq!(cpu.privilege_mode(), PrivilegeMode::Machine);
+        assert_eq!(cpu.csrs[csr::MSTATUS as usize] & csr::MSTATUS_MPP, csr::MSTATUS_MPP);
+    }
+
+    #[test]
+    fn it_takes_interrupts_from_the_plic() {
+        let mut cpu = CPU::new();
+        cpu.mmu().bus().attach(Box::new(Doorbell::new()));
+        cpu.mmu().load_dram_image(asm!("
+                la t0, handler
+                csrw mtvec, t0
+                # Source 20, the doorbell, gets a priority, and is enabled.
+                li t0, 0x0c000050
+                li t1, 1
+                sw t1, 0(t0)
+                li t0, 0x0c002000
+                li t1, 0x100000
+                sw t1, 0(t0)
+                li t1, 0x800
+                csrw mie, t1
+                csrsi mstatus, 8
+                li t0, 0x20000000
+                li t1, 1
+                sw t1, 0(t0)
+            loop:
+                j loop
+            handler:
+                li t0, 0x0c200004
+                lw a0, 0(t0)
+                li t1, 0x20000000
+                sw zero, 0(t1)
+                sw a0, 0(t0)
+                csrr a1, mcause
+                csrr a2, mip
+                li t0, 0
+                jr t0
+        "));
+        assert!(cpu.run_for(100));
+        assert_eq!(cpu.read_xreg(10), 20);
+        assert_eq!(cpu.read_xreg(11), 1 << 63 | 11);
+        assert_eq!(cpu.read_xreg(12), 0);
+    }
+
+    #[test]
+    fn it_takes_timer_interrupts_from_the_clint() {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("
+                la t0, handler
+                csrw mtvec, t0
+                # The timer goes off 20 ticks from now.
+                csrr t0, time
+                addi t0, t0, 20
+                li t1, 0x2004000
+                sd t0, 0(t1)
+                li t0, 0x80
+                csrw mie, t0
+                csrr a2, mip
+                csrsi mstatus, 8
+            loop:
+                j loop
+            handler:
+                csrr a0, mcause
+                csrr a1, mip
+                # Moving the comparator on clears the interrupt.
+                li t0, -1
+                sd t0, 0(t1)
+                csrr a3, mip
+                li t0, 0
+                jr t0
+        "));
+        assert!(cpu.run_for(100));
+        assert_eq!(cpu.read_xreg(10), 1 << 63 | 7);
+        assert_eq!(cpu.read_xreg(11), 1 << 7);
+        assert_eq!(cpu.read_xreg(12), 0);
+        assert_eq!(cpu.read_xreg(13), 0);
+    }
+
+    #[test]
+    pub fn it_executes_branches_and_calls() {
+        let (mut cpu, _) = run(asm!("
+                li a0, 0
+                li a1, 10
+            loop:
+                call add_a1
+                addi a1, a1, -1
+                bnez a1, loop
+                ebreak
+            add_a1:
+                add a0, a0, a1
+                ret
+        "));
+        assert_eq!(cpu.xregs.read(Register::X10), 55);
+        assert_eq!(cpu.xregs.read(Register::X11), 0);
+    }
 
-    use super::CPU;
+    #[test]
+    pub fn it_executes_upper_immediates() {
+        let (mut cpu, _) = run(asm!("
+                li a0, 0x123456789abcdef0
+                li a1, -2048
+                lui a2, 0x80000
+                auipc a3, 0x1
+                li a4, 0xffffffff
+                ecall
+        "));
+        assert_eq!(cpu.xregs.read(Register::X10), 0x1234_5678_9abc_def0);
+        assert_eq!(cpu.xregs.read(Register::X11), -2048i64 as u64);
+        assert_eq!(cpu.xregs.read(Register::X12), 0xffff_ffff_8000_0000);
+        assert_eq!(cpu.xregs.read(Register::X13), 0x8000_0000 + 40 + 0x1000);
+        assert_eq!(cpu.xregs.read(Register::X14), 0xffff_ffff);
+    }
+
+    #[test]
+    pub fn it_loads_and_stores_memory() {
+        let (mut cpu, _) = run(asm!("
+                la t0, data
+                ld a0, 0(t0)
+                lw a1, 4(t0)
+                lwu a2, 4(t0)
+                sb zero, 7(t0)
+                ld a3, (t0)
+                ecall
+            data:
+                .dword 0x8765432112345678
+        "));
+        assert_eq!(cpu.xregs.read(Register::X10), 0x8765_4321_1234_5678);
+        assert_eq!(cpu.xregs.read(Register::X11), 0xffff_ffff_8765_4321);
+        assert_eq!(cpu.xregs.read(Register::X12), 0x8765_4321);
+        assert_eq!(cpu.xregs.read(Register::X13), 0x0065_4321_1234_5678);
+    }
 
     
+    #[test]
+    pub fn it_records_the_effects_of_each_instruction() {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("
+                la t0, data
+                ld a0, 0(t0)
+                sh a0, 2(t0)
+                j data
+            data:
+                .dword 0x1122334455667788
+        "));
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let commit = cpu.last_commit();
+        assert_eq!(commit.pc, 0x8000_0004);
+        assert_eq!(commit.reg_writes, vec![(5, 0x8000_0014)]);
+
+        cpu.step().unwrap();
+        let commit = cpu.last_commit();
+        assert_eq!((commit.pc, commit.raw), (0x8000_0008, 0x0002b503));
+        assert_eq!(commit.reg_writes, vec![(10, 0x1122_3344_5566_7788)]);
+        assert_eq!(commit.loads, vec![MemAccess { addr: 0x8000_0014, size: Size::DoubleWord, value: 0x1122_3344_5566_7788 }]);
+
+        cpu.step().unwrap();
+        let commit = cpu.last_commit();
+        assert!(commit.reg_writes.is_empty());
+        assert_eq!(commit.stores, vec![MemAccess { addr: 0x8000_0016, size: Size::HalfWord, value: 0x7788 }]);
+
+        // Jumps which link to x0 do not record a register write.
+        cpu.step().unwrap();
+        assert!(cpu.last_commit().reg_writes.is_empty());
+    }
+
+    #[test]
+    pub fn it_resumes_from_a_snapshot() {
+        let program = asm!("
+                li a0, 0
+                li a1, 100
+            loop:
+                add a0, a0, a1
+                sd a0, -8(sp)
+                addi a1, a1, -1
+                bnez a1, loop
+                ecall
+        ");
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(program);
+        for _ in 0..50 {
+            cpu.step().unwrap();
+        }
+
+        let mut file = vec![];
+        snapshot::save(&cpu, &mut file).unwrap();
+        // The 1 GiB of DRAM is mostly empty, so the snapshot is tiny.
+        assert!(file.len() < 4096, "snapshot is {} bytes", file.len());
+
+        let mut restored = CPU::new();
+        snapshot::restore(&mut restored, file.as_slice()).unwrap();
+        assert_eq!((restored.pc, restored.clock), (cpu.pc, cpu.clock));
+
+        let (_, trap) = run_to_trap(&mut cpu);
+        let (_, restored_trap) = run_to_trap(&mut restored);
+        assert_eq!(trap, restored_trap);
+        assert_eq!(cpu.xregs.read(Register::X10), 5050);
+        assert_eq!(restored.xregs.read(Register::X10), 5050);
+        assert_eq!(restored.clock, cpu.clock);
+        let sp = cpu.xregs.read(Register::X2);
+        assert_eq!(restored.mmu().load(sp - 8, Size::DoubleWord), Ok(5050));
+    }
+
     #[test]
     pub fn it_executes_slt_and_sltu_correctly() {
         let mut cpu = CPU::new();
@@ -0,0 +1,207 @@
+use std::cell::Cell;
+
+use crate::{
+    components::{cpu::Trap, memory::Size},
+    fdt::{FdtWriter, CPU_INTC_PHANDLE},
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable},
+};
+
+use super::{Device, Dma};
+
+/// The address of the interruptor's registers, as on QEMU's virt machine.
+pub const CLINT_BASE: u64 = 0x0200_0000;
+const CLINT_SIZE: u64 = 0x1_0000;
+/// The causes of the machine software and timer interrupts, which the interruptor raises on the
+/// hart.
+const MACHINE_SOFTWARE: u32 = 3;
+const MACHINE_TIMER: u32 = 7;
+
+/// The registers of the interruptor, as offsets from its base. Each exists once per hart, and
+/// there's only one hart.
+mod reg {
+    /// Bit 0 raises the hart's software interrupt, in a word.
+    pub const MSIP: u64 = 0x0000;
+    /// The time which raises the hart's timer interrupt, in a double word.
+    pub const MTIMECMP: u64 = 0x4000;
+    /// The time, which is shared by the harts, in a double word.
+    pub const MTIME: u64 = 0xbff8;
+}
+
+/// A core-local interruptor, which raises the hart's software interrupt when the guest asks it
+/// to, and its timer interrupt when the time reaches the comparator.
+///
+/// The time counts instructions, as a tick of the timebase each, so it keeps pace with the RTC
+/// and a replay sees the same times. The guest can write it, which moves it by an offset from
+/// the instruction count.
+#[derive(Debug)]
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    /// The difference between the time and the instruction count.
+    offset: u64,
+    /// The instruction count as of the last access, which the bus passes in, as reading a
+    /// register doesn't have the journal to hand.
+    clock: Cell<u64>,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self {
+            msip: false,
+            // The timer doesn't go off until it's set.
+            mtimecmp: u64::MAX,
+            offset: 0,
+            clock: Cell::new(0),
+        }
+    }
+
+    /// Passes in the instruction count, before a register is accessed.
+    pub fn set_clock(&self, clock: u64) {
+        self.clock.set(clock);
+    }
+
+    /// The time, at an instruction count.
+    pub fn time(&self, clock: u64) -> u64 {
+        clock.wrapping_add(self.offset)
+    }
+
+    /// Indicates if the hart's timer interrupt is raised at an instruction count.
+    pub fn timer_interrupt(&self, clock: u64) -> bool {
+        self.time(clock) >= self.mtimecmp
+    }
+
+    /// Indicates if the hart's software interrupt is raised.
+    pub fn software_interrupt(&self) -> bool {
+        self.msip
+    }
+
+    /// Gets the double word which holds a register.
+    fn register(&self, offset: u64) -> u64 {
+        match offset {
+            reg::MSIP => self.msip as u64,
+            reg::MTIMECMP => self.mtimecmp,
+            reg::MTIME => self.time(self.clock.get()),
+            _ => 0,
+        }
+    }
+}
+
+impl Device for Clint {
+    fn base(&self) -> u64 {
+        CLINT_BASE
+    }
+
+    fn size(&self) -> u64 {
+        CLINT_SIZE
+    }
+
+    /// The double words can be accessed whole, or a word at a time.
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        let value = self.register(offset & !7);
+        match size {
+            Size::Word if offset & 3 == 0 => Ok((value >> ((offset & 4) * 8)) as u32 as u64),
+            Size::DoubleWord if offset & 7 == 0 => Ok(value),
+            _ => Err(Trap::LoadAccessFault),
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, dma: &mut Dma) -> Result<(), Trap> {
+        let clock = dma.journal().clock();
+        self.set_clock(clock);
+        let old = self.register(offset & !7);
+        let value = match size {
+            Size::Word if offset & 3 == 0 => {
+                let shift = (offset & 4) * 8;
+                old & !(0xffff_ffff << shift) | (value as u32 as u64) << shift
+            },
+            Size::DoubleWord if offset & 7 == 0 => value,
+            _ => return Err(Trap::StoreAccessFault),
+        };
+        match offset & !7 {
+            reg::MSIP => self.msip = value & 1 != 0,
+            reg::MTIMECMP => self.mtimecmp = value,
+            reg::MTIME => self.offset = value.wrapping_sub(clock),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("clint@{:x}", CLINT_BASE))
+            .property_strings("compatible", &["sifive,clint0", "riscv,clint0"])
+            .property_reg(&[(CLINT_BASE, CLINT_SIZE)])
+            .property_cells("interrupts-extended", &[
+                CPU_INTC_PHANDLE, MACHINE_SOFTWARE,
+                CPU_INTC_PHANDLE, MACHINE_TIMER,
+            ])
+            .end_node();
+    }
+}
+
+impl Snapshotable for Clint {
+    /// The time isn't saved, as it's worked out from the CPU's clock again.
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("clint")
+            .u8(self.msip as u8)
+            .u64(self.mtimecmp)
+            .u64(self.offset);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("clint")?;
+        self.msip = section.u8()? != 0;
+        self.mtimecmp = section.u64()?;
+        self.offset = section.u64()?;
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::components::{
+        cpu::Trap,
+        devices::{Device, Dma},
+        memory::{Size, DRAM},
+    };
+    use crate::replay::Journal;
+
+    use super::{reg, Clint};
+
+    fn write(clint: &mut Clint, clock: u64, offset: u64, size: Size, value: u64) {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        journal.set_clock(clock);
+        clint.write(offset, size, value, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_time_with_the_clock() {
+        let mut clint = Clint::new();
+        clint.set_clock(100);
+        assert_eq!(clint.read(reg::MTIME, Size::DoubleWord), Ok(100));
+        assert!(!clint.timer_interrupt(100));
+
+        // The timer goes off once the time reaches the comparator, which can be set by halves.
+        write(&mut clint, 100, reg::MTIMECMP + 4, Size::Word, 0);
+        write(&mut clint, 100, reg::MTIMECMP, Size::Word, 150);
+        assert_eq!(clint.read(reg::MTIMECMP, Size::DoubleWord), Ok(150));
+        assert!(!clint.timer_interrupt(149));
+        assert!(clint.timer_interrupt(150));
+
+        // Setting the time moves it on from then.
+        write(&mut clint, 120, reg::MTIME, Size::DoubleWord, 1 << 32);
+        assert_eq!(clint.time(130), 1 << 32 | 10);
+        assert_eq!(clint.read(reg::MTIME + 4, Size::Word), Ok(1));
+        assert_eq!(clint.read(reg::MTIME + 2, Size::Word), Err(Trap::LoadAccessFault));
+
+        write(&mut clint, 130, reg::MSIP, Size::Word, 3);
+        assert!(clint.software_interrupt());
+        assert_eq!(clint.read(reg::MSIP, Size::Word), Ok(1));
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    components::{cpu::Trap, memory::{from_le, Size}},
+    fdt::FdtWriter,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable, PAGE_SIZE},
+};
+
+use super::{Device, Dma};
+
+/// The address of the flash, as on QEMU's virt machine.
+pub const FLASH_BASE: u64 = 0x2000_0000;
+/// The largest flash which fits before the framebuffer, which is the size of both of the banks on
+/// QEMU's virt machine.
+pub const FLASH_MAX_SIZE: u64 = 0x400_0000;
+/// The size of a new flash, which is one of QEMU's banks.
+pub const FLASH_DEFAULT_SIZE: u64 = 0x200_0000;
+/// The size of each erase block, as on QEMU's virt machine.
+pub const BLOCK_SIZE: u64 = 0x4_0000;
+
+/// The commands which the flash understands, which tell the driver how to program and erase it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandSet {
+    /// The Intel/Sharp extended command set, with a status register.
+    Intel,
+    /// The AMD/Fujitsu standard command set, with unlock cycles and data polling.
+    Amd,
+}
+
+impl CommandSet {
+    /// The id of the command set in the query table.
+    fn id(self) -> u16 {
+        match self {
+            Self::Intel => 0x0001,
+            Self::Amd => 0x0002,
+        }
+    }
+
+    /// The manufacturer and device ids, as QEMU's flashes give them.
+    fn ids(self) -> [u8; 2] {
+        match self {
+            Self::Intel => [0x89, 0x18],
+            Self::Amd => [0x01, 0x7e],
+        }
+    }
+
+    /// Where the table for the command set follows the main query table.
+    fn extended_table(self) -> usize {
+        match self {
+            Self::Intel => 0x31,
+            Self::Amd => 0x40,
+        }
+    }
+}
+
+/// What a read of the flash returns, and what the next write does, as the driver's commands
+/// move the flash between them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+enum Mode {
+    ReadArray,
+    ReadStatus,
+    ReadId,
+    Query,
+    /// The next write is data to program.
+    Program,
+    /// An Intel block erase is waiting for its confirmation.
+    EraseSetup,
+    /// An Intel lock command is waiting for its second cycle, which is ignored.
+    LockSetup,
+    /// An Intel buffered write is waiting for the number of writes to buffer.
+    BufferCount,
+    BufferData,
+    /// An Intel buffered write is waiting for its confirmation.
+    BufferConfirm,
+    /// An AMD command is part of the way through its unlock cycles.
+    Unlock1,
+    Unlock2,
+    /// An AMD erase is part of the way through its second set of unlock cycles.
+    EraseUnlock0,
+    EraseUnlock1,
+    EraseUnlock2,
+}
+
+/// The bits of the Intel status register.
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+/// The largest buffered write, which the query table gives as a power of two.
+const BUFFER_SIZE_LOG2: u8 = 6;
+
+/// The AMD unlock addresses of an 8-bit flash.
+const UNLOCK_ADDR0: u64 = 0x555;
+const UNLOCK_ADDR1: u64 = 0x2aa;
+/// Where an AMD flash takes the command to enter query mode.
+const AMD_QUERY_ADDR: u64 = 0x55;
+
+/// A parallel NOR flash which implements the Common Flash Interface, in 8-bit mode, with either the
+/// Intel or the AMD command set. Firmware can run in place from it, and store its settings by
+/// erasing blocks and programming them, which are written through to a file on the host so that
+/// they persist from one run to the next.
+///
+/// Programs and erases finish straight away, so the status register is always ready, and data
+/// polling always reads back the data which was programmed.
+#[derive(Debug)]
+pub struct Flash {
+    data: Vec<u8>,
+    file: File,
+    commands: CommandSet,
+    mode: Mode,
+    status: u8,
+    /// The number of writes left to buffer, and the data which has been buffered, in an Intel
+    /// buffered write.
+    buffer_remaining: u64,
+    buffer: Vec<(u64, u8)>,
+}
+
+impl Flash {
+    /// Opens a flash backed by a file, which is created, blank, if it doesn't exist. The file's
+    /// size has to be a power of two, of at least one block, since the query table gives it as one.
+    pub fn open(path: &Path, commands: CommandSet) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut len = file.metadata()?.len();
+        if len == 0 {
+            len = FLASH_DEFAULT_SIZE;
+            file.write_all_at(&vec![0xff; len as usize], 0)?;
+        }
+        if !len.is_power_of_two() || !(BLOCK_SIZE..=FLASH_MAX_SIZE).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("a flash image has to be a power of two from {} KiB to {} MiB", BLOCK_SIZE >> 10, FLASH_MAX_SIZE >> 20),
+            ));
+        }
+        let mut data = vec![0; len as usize];
+        file.read_exact_at(&mut data, 0)?;
+        Ok(Self::new(data, file, commands))
+    }
+
+    fn new(data: Vec<u8>, file: File, commands: CommandSet) -> Self {
+        Self { data, file, commands, mode: Mode::ReadArray, status: STATUS_READY, buffer_remaining: 0, buffer: vec![] }
+    }
+
+    /// Gets a byte of the query table, at an offset in 8-bit mode.
+    fn query(&self, offset: u64) -> u8 {
+        let mut table = [0u8; 0x50];
+        table[0x10..0x13].copy_from_slice(b"QRY");
+        table[0x13..0x15].copy_from_slice(&self.commands.id().to_le_bytes());
+        table[0x15] = self.commands.extended_table() as u8;
+        // 2.7 to 3.6 V, with no programming voltage.
+        table[0x1b] = 0x27;
+        table[0x1c] = 0x36;
+        // The typical times, as powers of two: 16 us to program, 1 s to erase a block, and 4 times
+        // as long at most.
+        table[0x1f] = 4;
+        table[0x21] = 10;
+        table[0x23] = 2;
+        table[0x25] = 2;
+        if self.commands == CommandSet::Intel {
+            table[0x20] = 7;
+            table[0x24] = 2;
+            table[0x2a] = BUFFER_SIZE_LOG2;
+        }
+        table[0x27] = self.data.len().trailing_zeros() as u8;
+        // The interface is 8 bits wide, and there's one region of equal blocks.
+        table[0x2c] = 1;
+        let blocks = (self.data.len() as u64 / BLOCK_SIZE - 1) as u16;
+        table[0x2d..0x2f].copy_from_slice(&blocks.to_le_bytes());
+        table[0x2f..0x31].copy_from_slice(&((BLOCK_SIZE >> 8) as u16).to_le_bytes());
+        // The extended table's version is 1.0, and everything it lists is left unsupported.
+        let extended = self.commands.extended_table();
+        table[extended..extended + 5].copy_from_slice(b"PRI10");
+        table.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    /// Programs data, which can only clear bits, and writes it through to the file.
+    fn program(&mut self, offset: u64, bytes: &[u8]) {
+        let start = offset as usize;
+        let Some(data) = self.data.get_mut(start..start + bytes.len()) else {
+            self.status |= STATUS_PROGRAM_ERROR;
+            return;
+        };
+        for (byte, new) in data.iter_mut().zip(bytes) {
+            *byte &= new;
+        }
+        self.write_through(start, bytes.len());
+    }
+
+    /// Erases the block which an offset is in, or the whole flash, setting every bit.
+    fn erase(&mut self, offset: Option<u64>) {
+        let (start, len) = match offset {
+            Some(offset) => ((offset - offset % BLOCK_SIZE) as usize, BLOCK_SIZE as usize),
+            None => (0, self.data.len()),
+        };
+        self.data[start..start + len].fill(0xff);
+        self.write_through(start, len);
+    }
+
+    fn write_through(&mut self, start: usize, len: usize) {
+        // The flash carries on with what's in memory if the file can't be written, but says so.
+        if let Err(e) = self.file.write_all_at(&self.data[start..start + len], start as u64) {
+            eprintln!("flash: {}", e);
+            self.status |= STATUS_PROGRAM_ERROR;
+        }
+    }
+
+    fn write_intel(&mut self, offset: u64, bytes: &[u8]) {
+        let command = bytes[0];
+        self.mode = match (self.mode, command) {
+            (Mode::Program, _) => {
+                self.program(offset, bytes);
+                Mode::ReadStatus
+            },
+            (Mode::EraseSetup, 0xd0) => {
+                self.erase(Some(offset));
+                Mode::ReadStatus
+            },
+            (Mode::EraseSetup, _) => {
+                self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                Mode::ReadStatus
+            },
+            (Mode::LockSetup, _) => Mode::ReadStatus,
+            (Mode::BufferCount, count) if count < 1 << BUFFER_SIZE_LOG2 => {
+                self.buffer_remaining = count as u64 + 1;
+                self.buffer.clear();
+                Mode::BufferData
+            },
+            (Mode::BufferCount, _) => {
+                self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                Mode::ReadStatus
+            },
+            (Mode::BufferData, _) => {
+                self.buffer.extend(bytes.iter().enumerate().map(|(i, &byte)| (offset + i as u64, byte)));
+                self.buffer_remaining -= 1;
+                match self.buffer_remaining {
+                    0 => Mode::BufferConfirm,
+                    _ => Mode::BufferData,
+                }
+            },
+            (Mode::BufferConfirm, 0xd0) => {
+                for (offset, byte) in std::mem::take(&mut self.buffer) {
+                    self.program(offset, &[byte]);
+                }
+                Mode::ReadStatus
+            },
+            (Mode::BufferConfirm, _) => {
+                self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                Mode::ReadStatus
+            },
+            (_, 0xff) => Mode::ReadArray,
+            (_, 0x90) => Mode::ReadId,
+            (_, 0x98) => Mode::Query,
+            (_, 0x70) => Mode::ReadStatus,
+            (mode, 0x50) => {
+                self.status = STATUS_READY;
+                mode
+            },
+            (_, 0x40 | 0x10) => Mode::Program,
+            (_, 0x20) => Mode::EraseSetup,
+            (_, 0xe8) => Mode::BufferCount,
+            (_, 0x60) => Mode::LockSetup,
+            (mode, _) => mode,
+        };
+    }
+
+    fn write_amd(&mut self, offset: u64, bytes: &[u8]) {
+        let (command, addr) = (bytes[0], offset & 0x7ff);
+        self.mode = match (self.mode, addr, command) {
+            (Mode::Program, _, _) => {
+                self.program(offset, bytes);
+                Mode::ReadArray
+            },
+            (_, _, 0xf0) => Mode::ReadArray,
+            (Mode::ReadArray | Mode::ReadId, AMD_QUERY_ADDR, 0x98) => Mode::Query,
+            (Mode::ReadArray | Mode::ReadId, UNLOCK_ADDR0, 0xaa) => Mode::Unlock1,
+            (Mode::Unlock1, UNLOCK_ADDR1, 0x55) => Mode::Unlock2,
+            (Mode::Unlock2, UNLOCK_ADDR0, 0x90) => Mode::ReadId,
+            (Mode::Unlock2, UNLOCK_ADDR0, 0xa0) => Mode::Program,
+            (Mode::Unlock2, UNLOCK_ADDR0, 0x80) => Mode::EraseUnlock0,
+            (Mode::EraseUnlock0, UNLOCK_ADDR0, 0xaa) => Mode::EraseUnlock1,
+            (Mode::EraseUnlock1, UNLOCK_ADDR1, 0x55) => Mode::EraseUnlock2,
+            (Mode::EraseUnlock2, _, 0x30) => {
+                self.erase(Some(offset));
+                Mode::ReadArray
+            },
+            (Mode::EraseUnlock2, UNLOCK_ADDR0, 0x10) => {
+                self.erase(None);
+                Mode::ReadArray
+            },
+            // Query mode is only left by a reset.
+            (Mode::Query, _, _) => Mode::Query,
+            // A command which breaks a sequence goes back to reading the array.
+            _ => Mode::ReadArray,
+        };
+    }
+}
+
+impl Device for Flash {
+    fn base(&self) -> u64 {
+        FLASH_BASE
+    }
+
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// In any mode but reading the array, a read gives a byte of the status, the ids or the query
+    /// table, in the low byte.
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        let value = match (self.mode, self.commands) {
+            (Mode::ReadStatus | Mode::EraseSetup | Mode::LockSetup | Mode::BufferCount | Mode::BufferData | Mode::BufferConfirm, CommandSet::Intel) => self.status,
+            (Mode::ReadId, CommandSet::Intel) => match offset % BLOCK_SIZE {
+                0 | 1 => self.commands.ids()[offset as usize % 2],
+                // The block isn't locked.
+                _ => 0,
+            },
+            (Mode::ReadId, CommandSet::Amd) => match offset & 0xff {
+                0 | 1 => self.commands.ids()[offset as usize & 1],
+                _ => 0,
+            },
+            (Mode::Query, _) => self.query(offset),
+            _ => {
+                let start = offset as usize;
+                return Ok(self.data.get(start..start + size as usize).map_or(0, from_le));
+            },
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, _dma: &mut Dma) -> Result<(), Trap> {
+        let bytes = value.to_le_bytes();
+        match self.commands {
+            CommandSet::Intel => self.write_intel(offset, &bytes[..size as usize]),
+            CommandSet::Amd => self.write_amd(offset, &bytes[..size as usize]),
+        }
+        Ok(())
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("flash@{:x}", FLASH_BASE))
+            .property_string("compatible", "cfi-flash")
+            .property_reg(&[(FLASH_BASE, self.data.len() as u64)])
+            .property_u32("bank-width", 1)
+            .end_node();
+    }
+}
+
+impl Snapshotable for Flash {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let section = snapshot.section("flash");
+        section.u8(self.mode as u8).u8(self.status).u64(self.buffer_remaining).u64(self.buffer.len() as u64);
+        for &(offset, byte) in &self.buffer {
+            section.u64(offset).u8(byte);
+        }
+        section.pages(self.data.len(), self.data.chunks(PAGE_SIZE).enumerate());
+    }
+
+    /// The restored contents are written through to the file, which follows the machine.
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("flash")?;
+        self.mode = Mode::try_from(section.u8()?).map_err(|_| section.corrupt())?;
+        self.status = section.u8()?;
+        self.buffer_remaining = section.u64()?;
+        let len = section.u64()?;
+        self.buffer = (0..len).map(|_| Ok((section.u64()?, section.u8()?))).collect::<Result<_, SnapshotError>>()?;
+        section.restore_pages(&mut self.data)?;
+        self.write_through(0, self.data.len());
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use crate::components::{
+        devices::{Device, Dma},
+        memory::{Size, DRAM},
+    };
+    use crate::replay::Journal;
+    use crate::snapshot::{SnapshotReader, SnapshotWriter, Snapshotable};
+
+    use super::{CommandSet, Flash, BLOCK_SIZE, FLASH_DEFAULT_SIZE, STATUS_READY};
+
+    fn image(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rv64-flash-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn write(flash: &mut Flash, offset: u64, value: u64) {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        flash.write(offset, Size::Byte, value, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+    }
+
+    fn read(flash: &Flash, offset: u64) -> u64 {
+        flash.read(offset, Size::Byte).unwrap()
+    }
+
+    fn query_string(flash: &Flash) -> Vec<u8> {
+        (0x10..0x13).map(|offset| read(flash, offset) as u8).collect()
+    }
+
+    #[test]
+    fn it_programs_and_erases_with_intel_commands() {
+        let path = image("intel");
+        let mut flash = Flash::open(&path, CommandSet::Intel).unwrap();
+        assert_eq!(flash.size(), FLASH_DEFAULT_SIZE);
+        assert_eq!(flash.read(0, Size::Word), Ok(0xffff_ffff));
+
+        write(&mut flash, 0, 0x98);
+        assert_eq!(query_string(&flash), b"QRY");
+        assert_eq!(read(&flash, 0x13), 1);
+        assert_eq!(read(&flash, 0x27), 25);
+        assert_eq!(read(&flash, 0x2e) << 8 | read(&flash, 0x2d), FLASH_DEFAULT_SIZE / BLOCK_SIZE - 1);
+        write(&mut flash, 0, 0x90);
+        assert_eq!((read(&flash, 0), read(&flash, 1)), (0x89, 0x18));
+
+        // A word is programmed, and the status is ready straight away.
+        write(&mut flash, BLOCK_SIZE, 0x40);
+        write(&mut flash, BLOCK_SIZE, 0x5a);
+        assert_eq!(read(&flash, BLOCK_SIZE), STATUS_READY as u64);
+        write(&mut flash, 0, 0xff);
+        assert_eq!(read(&flash, BLOCK_SIZE), 0x5a);
+
+        // A buffered write of two bytes.
+        for value in [0xe8, 1, b'o' as u64] {
+            write(&mut flash, BLOCK_SIZE + 1, value);
+        }
+        write(&mut flash, BLOCK_SIZE + 2, b'k' as u64);
+        write(&mut flash, BLOCK_SIZE, 0xd0);
+        write(&mut flash, 0, 0xff);
+        assert_eq!(flash.read(BLOCK_SIZE, Size::Word), Ok(0xff6b_6f5a));
+
+        // The file holds what was programmed, until the block is erased.
+        assert_eq!(&fs::read(&path).unwrap()[BLOCK_SIZE as usize..][..3], b"Zok");
+        write(&mut flash, BLOCK_SIZE + 10, 0x20);
+        write(&mut flash, BLOCK_SIZE + 10, 0xd0);
+        write(&mut flash, 0, 0xff);
+        assert_eq!(flash.read(BLOCK_SIZE, Size::Word), Ok(0xffff_ffff));
+        assert_eq!(fs::read(&path).unwrap()[BLOCK_SIZE as usize], 0xff);
+
+        // An erase which isn't confirmed is an error.
+        write(&mut flash, 0, 0x20);
+        write(&mut flash, 0, 0xff);
+        assert_eq!(read(&flash, 0), 0xb0);
+        write(&mut flash, 0, 0x50);
+        assert_eq!(read(&flash, 0), STATUS_READY as u64);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_programs_and_erases_with_amd_commands() {
+        let path = image("amd");
+        let mut flash = Flash::open(&path, CommandSet::Amd).unwrap();
+        let unlock = |flash: &mut Flash, command| {
+            write(flash, 0x555, 0xaa);
+            write(flash, 0x2aa, 0x55);
+            write(flash, 0x555, command);
+        };
+
+        write(&mut flash, 0x55, 0x98);
+        assert_eq!(query_string(&flash), b"QRY");
+        assert_eq!(read(&flash, 0x13), 2);
+        write(&mut flash, 0, 0xf0);
+        unlock(&mut flash, 0x90);
+        assert_eq!((read(&flash, 0), read(&flash, 1)), (0x01, 0x7e));
+        write(&mut flash, 0, 0xf0);
+
+        // Data polling reads back the programmed data as soon as it's written.
+        unlock(&mut flash, 0xa0);
+        write(&mut flash, 0x1234, 0x12);
+        assert_eq!(read(&flash, 0x1234), 0x12);
+        // Bits can't be set again without erasing.
+        unlock(&mut flash, 0xa0);
+        write(&mut flash, 0x1234, 0x21);
+        assert_eq!(read(&flash, 0x1234), 0x00);
+
+        // A sequence which is broken goes back to reading the array.
+        write(&mut flash, 0x555, 0xaa);
+        write(&mut flash, 0x123, 0x55);
+        assert_eq!(read(&flash, 0x1234), 0x00);
+
+        // The state survives a snapshot, and is written through to the file.
+        let mut snapshot = SnapshotWriter::new();
+        flash.save(&mut snapshot);
+        let mut data = vec![];
+        snapshot.write_to(&mut data).unwrap();
+        unlock(&mut flash, 0x80);
+        write(&mut flash, 0x555, 0xaa);
+        write(&mut flash, 0x2aa, 0x55);
+        write(&mut flash, 0x1000, 0x30);
+        assert_eq!(read(&flash, 0x1234), 0xff);
+        assert_eq!(fs::read(&path).unwrap()[0x1234], 0xff);
+        flash.restore(&mut SnapshotReader::read_from(&data[..]).unwrap()).unwrap();
+        assert_eq!(read(&flash, 0x1234), 0x00);
+        assert_eq!(read(&flash, 0x1235), 0xff);
+        assert_eq!(fs::read(&path).unwrap()[0x1234], 0x00);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_persists_across_runs() {
+        let path = image("persist");
+        let mut flash = Flash::open(&path, CommandSet::Intel).unwrap();
+        write(&mut flash, 0x40, 0x10);
+        write(&mut flash, 0x40, 0x42);
+        drop(flash);
+        let flash = Flash::open(&path, CommandSet::Intel).unwrap();
+        assert_eq!(read(&flash, 0x40), 0x42);
+
+        fs::write(&path, [0; 1000]).unwrap();
+        assert!(Flash::open(&path, CommandSet::Intel).is_err());
+        // Three blocks can't be described by the query table.
+        fs::write(&path, vec![0xff; 0xc_0000]).unwrap();
+        assert!(Flash::open(&path, CommandSet::Intel).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
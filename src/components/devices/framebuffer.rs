@@ -0,0 +1,278 @@
+use std::io::{self, Write};
+
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+use crate::{
+    components::{cpu::Trap, memory::{from_le, Size}},
+    fdt::FdtWriter,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable, PAGE_SIZE},
+};
+
+use super::{Device, Dma};
+
+/// The address of the framebuffer, which is clear of the devices on QEMU's virt machine.
+pub const FRAMEBUFFER_BASE: u64 = 0x2800_0000;
+
+/// The layout of a pixel, named as the device tree binding of `simple-framebuffer` names it, from
+/// the most significant bits of a little-endian value to the least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    R5G6B5,
+    R8G8B8,
+    X8R8G8B8,
+    A8R8G8B8,
+    X8B8G8R8,
+    A8B8G8R8,
+}
+
+impl PixelFormat {
+    const ALL: [Self; 6] = [Self::R5G6B5, Self::R8G8B8, Self::X8R8G8B8, Self::A8R8G8B8, Self::X8B8G8R8, Self::A8B8G8R8];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::R5G6B5 => "r5g6b5",
+            Self::R8G8B8 => "r8g8b8",
+            Self::X8R8G8B8 => "x8r8g8b8",
+            Self::A8R8G8B8 => "a8r8g8b8",
+            Self::X8B8G8R8 => "x8b8g8r8",
+            Self::A8B8G8R8 => "a8b8g8r8",
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::R5G6B5 => 2,
+            Self::R8G8B8 => 3,
+            _ => 4,
+        }
+    }
+
+    /// Converts a pixel to red, green and blue. Alpha is ignored, as there's nothing to blend
+    /// the framebuffer with.
+    fn rgb(self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            Self::R5G6B5 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                // Each channel is scaled up so that its maximum is 255.
+                let scale = |bits: u16, max: u16| (bits as u32 * 255 / max as u32) as u8;
+                [scale(value >> 11, 0x1f), scale(value >> 5 & 0x3f, 0x3f), scale(value & 0x1f, 0x1f)]
+            },
+            Self::R8G8B8 | Self::X8R8G8B8 | Self::A8R8G8B8 => [pixel[2], pixel[1], pixel[0]],
+            Self::X8B8G8R8 | Self::A8B8G8R8 => [pixel[0], pixel[1], pixel[2]],
+        }
+    }
+}
+
+/// A linear framebuffer, which the guest draws into and the host can take screenshots of. It is
+/// described as a `simple-framebuffer`, so that firmware and Linux's simplefb driver find it
+/// without any registers to set a mode up.
+#[derive(Debug)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Self {
+        assert!(width > 0 && height > 0, "a framebuffer needs pixels");
+        Self { width, height, format, pixels: vec![0; width * height * format.bytes_per_pixel()] }
+    }
+
+    /// The number of bytes between the start of one line and the next.
+    pub fn stride(&self) -> usize {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    /// Gets the pixels as red, green and blue bytes, line by line.
+    pub fn rgb(&self) -> Vec<u8> {
+        self.pixels.chunks(self.format.bytes_per_pixel()).flat_map(|pixel| self.format.rgb(pixel)).collect()
+    }
+
+    /// Writes a screenshot as a binary PPM image.
+    pub fn write_ppm(&self, mut out: impl Write) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.rgb())
+    }
+
+    /// Writes a screenshot as a PNG image, in 8-bit RGB.
+    pub fn write_png(&self, mut out: impl Write) -> io::Result<()> {
+        let mut header = vec![];
+        header.extend((self.width as u32).to_be_bytes());
+        header.extend((self.height as u32).to_be_bytes());
+        // A bit depth of 8, true colour, and the only compression, filter and interlace methods.
+        header.extend([8, 2, 0, 0, 0]);
+
+        // Each line starts with its filter type, which is none.
+        let rgb = self.rgb();
+        let mut lines = Vec::with_capacity(rgb.len() + self.height);
+        for line in rgb.chunks(self.width * 3) {
+            lines.push(0);
+            lines.extend_from_slice(line);
+        }
+
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+        write_png_chunk(&mut out, b"IHDR", &header)?;
+        write_png_chunk(&mut out, b"IDAT", &compress_to_vec_zlib(&lines, 6))?;
+        write_png_chunk(&mut out, b"IEND", &[])
+    }
+
+    /// Writes a screenshot as a PNG image if the path ends in `.png`, or a PPM image otherwise.
+    pub fn save_screenshot(&self, path: &str) -> io::Result<()> {
+        let out = io::BufWriter::new(std::fs::File::create(path)?);
+        match path.ends_with(".png") {
+            true => self.write_png(out),
+            false => self.write_ppm(out),
+        }
+    }
+
+    /// The size of the region the framebuffer takes up, which is a whole number of pages.
+    fn region_size(&self) -> u64 {
+        self.pixels.len().next_multiple_of(PAGE_SIZE) as u64
+    }
+}
+
+fn write_png_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc32(&[kind, data]).to_be_bytes())
+}
+
+/// Computes the CRC-32 which PNG checks each chunk with, over several slices in turn.
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => crc >> 1 ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+impl Device for Framebuffer {
+    fn base(&self) -> u64 {
+        FRAMEBUFFER_BASE
+    }
+
+    fn size(&self) -> u64 {
+        self.region_size()
+    }
+
+    /// The framebuffer is memory, which can be accessed at any size. The padding up to the end of
+    /// its last page reads as zero.
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        let start = offset as usize;
+        match self.pixels.get(start..start + size as usize) {
+            Some(bytes) => Ok(from_le(bytes)),
+            None => Ok(0),
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, _dma: &mut Dma) -> Result<(), Trap> {
+        let start = offset as usize;
+        if let Some(bytes) = self.pixels.get_mut(start..start + size as usize) {
+            bytes.copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        }
+        Ok(())
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("framebuffer@{:x}", FRAMEBUFFER_BASE))
+            .property_string("compatible", "simple-framebuffer")
+            .property_reg(&[(FRAMEBUFFER_BASE, self.region_size())])
+            .property_u32("width", self.width as u32)
+            .property_u32("height", self.height as u32)
+            .property_u32("stride", self.stride() as u32)
+            .property_string("format", self.format.name())
+            .end_node();
+    }
+}
+
+impl Snapshotable for Framebuffer {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("framebuffer").pages(self.pixels.len(), self.pixels.chunks(PAGE_SIZE).enumerate());
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("framebuffer")?;
+        section.restore_pages(&mut self.pixels)?;
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    use crate::components::{
+        devices::{Device, Dma},
+        memory::{Size, DRAM},
+    };
+    use crate::fdt::{self, FdtWriter};
+    use crate::replay::Journal;
+
+    use super::{crc32, Framebuffer, PixelFormat, FRAMEBUFFER_BASE};
+
+    #[test]
+    fn it_converts_pixel_formats() {
+        assert_eq!(PixelFormat::parse("a8r8g8b8"), Some(PixelFormat::A8R8G8B8));
+        assert_eq!(PixelFormat::parse("rgb"), None);
+        assert_eq!(PixelFormat::X8R8G8B8.rgb(&[0x30, 0x20, 0x10, 0xff]), [0x10, 0x20, 0x30]);
+        assert_eq!(PixelFormat::A8B8G8R8.rgb(&[0x10, 0x20, 0x30, 0xff]), [0x10, 0x20, 0x30]);
+        assert_eq!(PixelFormat::R5G6B5.rgb(&0xf800u16.to_le_bytes()), [255, 0, 0]);
+        assert_eq!(PixelFormat::R5G6B5.rgb(&0x07e0u16.to_le_bytes()), [0, 255, 0]);
+    }
+
+    #[test]
+    fn it_takes_screenshots() {
+        let mut fb = Framebuffer::new(2, 2, PixelFormat::X8R8G8B8);
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        // A red pixel, then a green one on the next line.
+        fb.write(0, Size::Word, 0xff0000, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+        fb.write(12, Size::Word, 0x00ff00, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+        assert_eq!(fb.read(0, Size::Byte), Ok(0));
+        assert_eq!(fb.read(2, Size::Byte), Ok(0xff));
+        assert_eq!(fb.read(0x800, Size::DoubleWord), Ok(0));
+
+        let mut ppm = vec![];
+        fb.write_ppm(&mut ppm).unwrap();
+        assert_eq!(ppm, [&b"P6\n2 2\n255\n"[..], &[255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 0]].concat());
+
+        let mut png = vec![];
+        fb.write_png(&mut png).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[29..33], crc32(&[&png[12..29]]).to_be_bytes());
+        let len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let lines = decompress_to_vec_zlib(&png[41..41 + len]).unwrap();
+        assert_eq!(lines, [0, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 0]);
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+
+    #[test]
+    fn it_describes_itself_as_a_simple_framebuffer() {
+        let fb = Framebuffer::new(640, 480, PixelFormat::R5G6B5);
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fb.describe(&mut fdt);
+        fdt.end_node();
+        let properties = fdt::test::properties(&fdt.finish());
+        let node = format!("/framebuffer@{:x}", FRAMEBUFFER_BASE);
+        assert_eq!(properties[&format!("{}/compatible", node)], b"simple-framebuffer\0");
+        assert_eq!(properties[&format!("{}/stride", node)], 1280u32.to_be_bytes());
+        assert_eq!(properties[&format!("{}/format", node)], b"r5g6b5\0");
+        assert_eq!(fb.size(), 640 * 480 * 2);
+    }
+}
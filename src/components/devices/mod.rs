@@ -0,0 +1,122 @@
+use std::{
+    any::Any,
+    fmt,
+    io::{self, Read},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use crate::{fdt::FdtWriter, replay::Journal, snapshot::Snapshotable};
+
+use super::{cpu::Trap, memory::{address::Addressable, Size, DRAM}};
+
+pub mod clint;
+pub mod flash;
+pub mod framebuffer;
+pub mod pci;
+pub mod plic;
+pub mod rtc;
+pub mod spi;
+pub mod uart;
+pub mod virtio;
+
+/// The number of instructions between polls of the devices for input from the host. Polls are
+/// keyed by the instruction count, so that a replay polls at the same points as the recording.
+pub const POLL_INTERVAL: u64 = 1024;
+
+/// A device whose registers are mapped into a region of the physical address space, outside of
+/// memory. Devices are `Any`, so that the host can find a particular kind of device on the bus.
+pub trait Device: Snapshotable + fmt::Debug + Any {
+    /// The address which the device's registers start at.
+    fn base(&self) -> u64;
+
+    /// The size of the region which the device's registers take up.
+    fn size(&self) -> u64;
+
+    /// Reads a register, at an offset from the base of the device.
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap>;
+
+    /// Writes a register, at an offset from the base of the device. A write which starts a
+    /// request, such as a notification of a virtqueue, is serviced straight away, with direct
+    /// access to memory.
+    fn write(&mut self, offset: u64, size: Size, value: u64, dma: &mut Dma) -> Result<(), Trap>;
+
+    /// Describes the device in the device tree, as a child of the root.
+    fn describe(&self, fdt: &mut FdtWriter);
+
+    /// Checks for input from the host, such as bytes typed into a console, every
+    /// `POLL_INTERVAL` instructions.
+    fn poll(&mut self, _dma: &mut Dma) {}
+
+    /// The PLIC sources whose lines the device is asserting, as a bit for each source.
+    fn interrupts(&self) -> u64 {
+        0
+    }
+}
+
+/// Passes the bytes of the emulator's standard input through a channel, for a device to take
+/// when it's polled. Standard input can't be read without blocking, so a thread reads it.
+pub fn stdin() -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            match byte {
+                Ok(byte) if tx.send(byte).is_ok() => {},
+                _ => break,
+            }
+        }
+    });
+    rx
+}
+
+/// A device's direct access to memory, for reading the requests which a driver left there and
+/// writing back the results. Inputs from the host, such as data read from a disk image, go
+/// through the journal, so that they can be replayed.
+pub struct Dma<'a> {
+    dram: &'a mut DRAM,
+    journal: &'a mut Journal,
+}
+
+impl<'a> Dma<'a> {
+    pub fn new(dram: &'a mut DRAM, journal: &'a mut Journal) -> Self {
+        Self { dram, journal }
+    }
+
+    pub fn journal(&mut self) -> &mut Journal {
+        self.journal
+    }
+
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Trap> {
+        self.dram.read_into(addr, buf)
+    }
+
+    pub fn read_vec(&self, addr: u64, len: usize) -> Result<Vec<u8>, Trap> {
+        let mut buf = vec![0; len];
+        self.read(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Trap> {
+        self.dram.write_from(addr, data)
+    }
+
+    pub fn read_u16(&self, addr: u64) -> Result<u16, Trap> {
+        self.dram.read_u16(addr)
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Result<u32, Trap> {
+        self.dram.read_u32(addr)
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Result<u64, Trap> {
+        self.dram.read_u64(addr)
+    }
+
+    pub fn write_u16(&mut self, addr: u64, value: u16) -> Result<(), Trap> {
+        self.dram.write_u16(addr, value)
+    }
+
+    pub fn write_u32(&mut self, addr: u64, value: u32) -> Result<(), Trap> {
+        self.dram.write_u32(addr, value)
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{
+    components::{cpu::Trap, devices::Dma, memory::Size},
+    snapshot::{Decoder, Encoder, SnapshotError},
+};
+
+use super::{PciFunction, BAR_COUNT};
+
+/// The size of the registers, in BAR 0.
+const BAR_SIZE: u32 = 0x10_0000;
+
+/// The registers, as offsets into BAR 0.
+mod reg {
+    pub const IDENTIFICATION: u64 = 0x00;
+    pub const LIVENESS: u64 = 0x04;
+    pub const FACTORIAL: u64 = 0x08;
+    pub const STATUS: u64 = 0x20;
+    pub const INTERRUPT_STATUS: u64 = 0x24;
+    pub const INTERRUPT_RAISE: u64 = 0x60;
+    pub const INTERRUPT_ACK: u64 = 0x64;
+    pub const DMA_SOURCE: u64 = 0x80;
+    pub const DMA_DESTINATION: u64 = 0x88;
+    pub const DMA_COUNT: u64 = 0x90;
+    pub const DMA_COMMAND: u64 = 0x98;
+}
+
+/// Version 1.0 of the device, in the top bytes, and its signature.
+const IDENTIFICATION: u32 = 0x0100_00ed;
+
+/// The device raises an interrupt when it has worked out a factorial.
+const STATUS_IRQ_FACTORIAL: u32 = 0x80;
+const IRQ_FACTORIAL: u32 = 0x1;
+
+const DMA_START: u64 = 1 << 0;
+/// The transfer goes from the device's buffer to memory, rather than the other way.
+const DMA_TO_MEMORY: u64 = 1 << 1;
+const DMA_IRQ: u64 = 1 << 2;
+const IRQ_DMA: u32 = 0x100;
+
+/// The address of the device's buffer, as the DMA registers see it.
+const BUFFER_BASE: u64 = 0x4_0000;
+const BUFFER_SIZE: usize = 0x1000;
+
+/// A test device modelled on QEMU's `edu`, for trying out PCI drivers: it checks that it's alive,
+/// works out factorials, raises interrupts when asked, and copies data between memory and a
+/// buffer of its own. Everything it does finishes straight away.
+#[derive(Debug)]
+pub struct Edu {
+    liveness: u32,
+    factorial: u32,
+    status: u32,
+    irq_status: u32,
+    dma_source: u64,
+    dma_destination: u64,
+    dma_count: u64,
+    dma_command: u64,
+    buffer: Vec<u8>,
+}
+
+impl Default for Edu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Edu {
+    pub fn new() -> Self {
+        Self {
+            liveness: 0,
+            factorial: 0,
+            status: 0,
+            irq_status: 0,
+            dma_source: 0,
+            dma_destination: 0,
+            dma_count: 0,
+            dma_command: 0,
+            buffer: vec![0; BUFFER_SIZE],
+        }
+    }
+
+    /// Finds the range of the buffer which a transfer covers, if it's all within the buffer.
+    fn buffer_range(&self, addr: u64) -> Option<std::ops::Range<usize>> {
+        let start = addr.checked_sub(BUFFER_BASE)? as usize;
+        let end = start.checked_add(self.dma_count as usize)?;
+        (end <= BUFFER_SIZE).then_some(start..end)
+    }
+
+    /// Carries out a transfer. Transfers which don't fit in the buffer, or which the device
+    /// can't make as it isn't a bus master, are dropped, as they are by QEMU.
+    fn transfer(&mut self, dma: Option<&mut Dma>) -> Result<(), Trap> {
+        let Some(dma) = dma else { return Ok(()) };
+        match self.dma_command & DMA_TO_MEMORY {
+            0 => if let Some(range) = self.buffer_range(self.dma_destination) {
+                dma.read(self.dma_source, &mut self.buffer[range])?;
+            },
+            _ => if let Some(range) = self.buffer_range(self.dma_source) {
+                dma.write(self.dma_destination, &self.buffer[range])?;
+            },
+        }
+        if self.dma_command & DMA_IRQ != 0 {
+            self.irq_status |= IRQ_DMA;
+        }
+        Ok(())
+    }
+}
+
+impl PciFunction for Edu {
+    /// QEMU's vendor id, and the id of its `edu` device.
+    fn vendor_id(&self) -> u16 {
+        0x1234
+    }
+
+    fn device_id(&self) -> u16 {
+        0x11e8
+    }
+
+    /// An unclassified device.
+    fn class(&self) -> u32 {
+        0x00_ff_00
+    }
+
+    fn revision(&self) -> u8 {
+        0x10
+    }
+
+    fn bars(&self) -> [u32; BAR_COUNT] {
+        [BAR_SIZE, 0, 0, 0, 0, 0]
+    }
+
+    /// The registers before the DMA registers are 32 bits wide, and those after can be 32 or
+    /// 64 bits wide.
+    fn read_bar(&self, _bar: usize, offset: u64, size: Size) -> Result<u64, Trap> {
+        let value = match (offset, size) {
+            (..reg::DMA_SOURCE, Size::Word) | (reg::DMA_SOURCE.., Size::Word | Size::DoubleWord) => match offset {
+                reg::IDENTIFICATION => IDENTIFICATION as u64,
+                reg::LIVENESS => !self.liveness as u64,
+                reg::FACTORIAL => self.factorial as u64,
+                reg::STATUS => self.status as u64,
+                reg::INTERRUPT_STATUS => self.irq_status as u64,
+                reg::DMA_SOURCE => self.dma_source,
+                reg::DMA_DESTINATION => self.dma_destination,
+                reg::DMA_COUNT => self.dma_count,
+                reg::DMA_COMMAND => self.dma_command,
+                _ => 0,
+            },
+            _ => return Err(Trap::LoadAccessFault),
+        };
+        Ok(value & size.mask())
+    }
+
+    fn write_bar(&mut self, _bar: usize, offset: u64, size: Size, value: u64, dma: Option<&mut Dma>) -> Result<(), Trap> {
+        match (offset, size) {
+            (..reg::DMA_SOURCE, Size::Word) | (reg::DMA_SOURCE.., Size::Word | Size::DoubleWord) => {},
+            _ => return Err(Trap::StoreAccessFault),
+        }
+        match offset {
+            reg::LIVENESS => self.liveness = value as u32,
+            reg::FACTORIAL => {
+                self.factorial = (1..=value as u32).fold(1u32, |product, n| product.wrapping_mul(n));
+                if self.status & STATUS_IRQ_FACTORIAL != 0 {
+                    self.irq_status |= IRQ_FACTORIAL;
+                }
+            },
+            reg::STATUS => self.status = value as u32 & STATUS_IRQ_FACTORIAL,
+            reg::INTERRUPT_RAISE => self.irq_status |= value as u32,
+            reg::INTERRUPT_ACK => self.irq_status &= !(value as u32),
+            reg::DMA_SOURCE => self.dma_source = value,
+            reg::DMA_DESTINATION => self.dma_destination = value,
+            reg::DMA_COUNT => self.dma_count = value,
+            reg::DMA_COMMAND if value & DMA_START != 0 => {
+                self.dma_command = value;
+                self.transfer(dma)?;
+                self.dma_command &= !DMA_START;
+            },
+            reg::DMA_COMMAND => self.dma_command = value,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn interrupt(&self) -> bool {
+        self.irq_status != 0
+    }
+
+    fn save(&self, encoder: &mut Encoder) {
+        encoder.u32(self.liveness)
+            .u32(self.factorial)
+            .u32(self.status)
+            .u32(self.irq_status)
+            .u64(self.dma_source)
+            .u64(self.dma_destination)
+            .u64(self.dma_count)
+            .u64(self.dma_command)
+            .bytes(&self.buffer);
+    }
+
+    fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        self.liveness = decoder.u32()?;
+        self.factorial = decoder.u32()?;
+        self.status = decoder.u32()?;
+        self.irq_status = decoder.u32()?;
+        self.dma_source = decoder.u64()?;
+        self.dma_destination = decoder.u64()?;
+        self.dma_count = decoder.u64()?;
+        self.dma_command = decoder.u64()?;
+        self.buffer = decoder.block()?;
+        if self.buffer.len() != BUFFER_SIZE {
+            return Err(decoder.corrupt());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::components::{
+        devices::{
+            pci::{reg as config, test::{bar_offset, enable, read_config, write_config}, PciHost, PCI_IRQ, PCI_MMIO_BASE},
+            Device, Dma,
+        },
+        memory::{address::Addressable, Size, DRAM},
+    };
+    use crate::replay::Journal;
+
+    use super::{reg, Edu, BUFFER_BASE, DMA_IRQ, DMA_START, DMA_TO_MEMORY, IRQ_DMA};
+
+    fn write(host: &mut PciHost, dram: &mut DRAM, offset: u64, size: Size, value: u64) {
+        let mut journal = Journal::new();
+        host.write(bar_offset(PCI_MMIO_BASE + offset), size, value, &mut Dma::new(dram, &mut journal)).unwrap();
+    }
+
+    fn read(host: &PciHost, offset: u64) -> u64 {
+        host.read(bar_offset(PCI_MMIO_BASE + offset), Size::Word).unwrap()
+    }
+
+    #[test]
+    fn it_sizes_and_assigns_bars() {
+        let mut host = PciHost::new();
+        let mut dram = DRAM::new(0x1000);
+        assert_eq!(host.attach(Box::new(Edu::new())), Some(1));
+        assert_eq!(read_config(&host, 1, config::VENDOR_ID, Size::Word), 0x11e8_1234);
+        assert_eq!(read_config(&host, 1, config::BAR0, Size::Word), PCI_MMIO_BASE);
+
+        // The driver sizes the BAR by writing ones to it, and then puts it back.
+        write_config(&mut host, &mut dram, 1, config::BAR0, Size::Word, 0xffff_ffff);
+        assert_eq!(read_config(&host, 1, config::BAR0, Size::Word), 0xfff0_0000);
+        write_config(&mut host, &mut dram, 1, config::BAR0, Size::Word, PCI_MMIO_BASE + 0x10_0000);
+        assert_eq!(read(&host, 0x10_0000), 0xffff_ffff);
+        enable(&mut host, &mut dram, 1);
+        assert_eq!(read(&host, 0x10_0000 + reg::IDENTIFICATION), 0x0100_00ed);
+    }
+
+    #[test]
+    fn it_computes_and_interrupts() {
+        let mut host = PciHost::new();
+        let mut dram = DRAM::new(0x1000);
+        host.attach(Box::new(Edu::new()));
+        enable(&mut host, &mut dram, 1);
+
+        write(&mut host, &mut dram, reg::LIVENESS, Size::Word, 0x1234_5678);
+        assert_eq!(read(&host, reg::LIVENESS), 0xedcb_a987);
+        write(&mut host, &mut dram, reg::FACTORIAL, Size::Word, 10);
+        assert_eq!(read(&host, reg::FACTORIAL), 3_628_800);
+        assert!(host.read(bar_offset(PCI_MMIO_BASE + reg::FACTORIAL), Size::Byte).is_err());
+
+        // Without MSIs, the interrupt is INTx, until it's acknowledged, and INTA of the second
+        // slot goes to the second source.
+        write(&mut host, &mut dram, reg::INTERRUPT_RAISE, Size::Word, 0x4);
+        assert!(host.interrupting());
+        assert_eq!(host.interrupts(), 1 << (PCI_IRQ + 1));
+        assert_eq!(read_config(&host, 1, config::STATUS, Size::HalfWord) & 0x8, 0x8);
+        write(&mut host, &mut dram, reg::INTERRUPT_ACK, Size::Word, 0x4);
+        assert!(!host.interrupting());
+
+        // With MSIs, the interrupt is a write of the data to the address.
+        write_config(&mut host, &mut dram, 1, config::MSI_ADDRESS, Size::Word, 0x8000_0100);
+        write_config(&mut host, &mut dram, 1, config::MSI_DATA, Size::HalfWord, 0x42);
+        write_config(&mut host, &mut dram, 1, config::MSI_CONTROL, Size::HalfWord, 1);
+        write(&mut host, &mut dram, reg::INTERRUPT_RAISE, Size::Word, 0x4);
+        assert!(!host.interrupting());
+        assert_eq!(dram.read_u32(0x8000_0100), Ok(0x42));
+    }
+
+    #[test]
+    fn it_copies_data_by_dma() {
+        let mut host = PciHost::new();
+        let mut dram = DRAM::new(0x1000);
+        host.attach(Box::new(Edu::new()));
+        dram.write_from(0x8000_0000, b"hello").unwrap();
+
+        let copy = |host: &mut PciHost, dram: &mut DRAM, source, destination, command| {
+            write(host, dram, reg::DMA_SOURCE, Size::DoubleWord, source);
+            write(host, dram, reg::DMA_DESTINATION, Size::DoubleWord, destination);
+            write(host, dram, reg::DMA_COUNT, Size::DoubleWord, 5);
+            write(host, dram, reg::DMA_COMMAND, Size::DoubleWord, DMA_START | command);
+        };
+        // Nothing is copied until the device is a bus master.
+        write_config(&mut host, &mut dram, 1, config::COMMAND, Size::HalfWord, 0x2);
+        copy(&mut host, &mut dram, 0x8000_0000, BUFFER_BASE, 0);
+        copy(&mut host, &mut dram, BUFFER_BASE, 0x8000_0010, DMA_TO_MEMORY);
+        assert_eq!(dram.read_u64(0x8000_0010), Ok(0));
+
+        enable(&mut host, &mut dram, 1);
+        copy(&mut host, &mut dram, 0x8000_0000, BUFFER_BASE, 0);
+        copy(&mut host, &mut dram, BUFFER_BASE, 0x8000_0010, DMA_TO_MEMORY | DMA_IRQ);
+        let mut data = [0; 5];
+        dram.read_into(0x8000_0010, &mut data).unwrap();
+        assert_eq!(&data, b"hello");
+        assert_eq!(read(&host, reg::DMA_COMMAND), DMA_TO_MEMORY | DMA_IRQ);
+        assert_eq!(read(&host, reg::INTERRUPT_STATUS), IRQ_DMA as u64);
+    }
+}
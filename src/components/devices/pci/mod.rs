@@ -0,0 +1,501 @@
+use std::fmt;
+
+use crate::{
+    components::{cpu::Trap, memory::{from_le, Size}},
+    fdt::{FdtWriter, PLIC_PHANDLE},
+    snapshot::{Decoder, Encoder, SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable},
+};
+
+use super::{Device, Dma};
+
+pub mod edu;
+
+pub use self::edu::Edu;
+
+/// The address of the configuration space, as on QEMU's virt machine, with 1 MiB for each bus.
+pub const ECAM_BASE: u64 = 0x3000_0000;
+const ECAM_SIZE: u64 = 0x1000_0000;
+/// The window which memory BARs are assigned from, which runs up to the start of DRAM.
+pub const PCI_MMIO_BASE: u64 = 0x4000_0000;
+const PCI_MMIO_SIZE: u64 = 0x4000_0000;
+/// The number of devices on the bus, each with a single function.
+pub const PCI_SLOTS: usize = 32;
+/// The PLIC source which INTA of the first slot is routed to, after which each slot has the next,
+/// wrapping around after four, as on QEMU's virt machine.
+pub const PCI_IRQ: u32 = 32;
+
+/// The size of the configuration space of a function which is implemented. The extended space
+/// after it reads as zero.
+const CONFIG_SIZE: usize = 256;
+
+/// The registers of the type 0 configuration header, and of the MSI capability after it.
+mod reg {
+    pub const VENDOR_ID: usize = 0x00;
+    pub const DEVICE_ID: usize = 0x02;
+    pub const COMMAND: usize = 0x04;
+    pub const STATUS: usize = 0x06;
+    pub const REVISION: usize = 0x08;
+    pub const CLASS: usize = 0x09;
+    pub const BAR0: usize = 0x10;
+    pub const SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+    pub const SUBSYSTEM_ID: usize = 0x2e;
+    pub const CAPABILITIES: usize = 0x34;
+    pub const INTERRUPT_LINE: usize = 0x3c;
+    pub const INTERRUPT_PIN: usize = 0x3d;
+    pub const MSI: usize = 0x40;
+    pub const MSI_CONTROL: usize = 0x42;
+    pub const MSI_ADDRESS: usize = 0x44;
+    pub const MSI_DATA: usize = 0x4c;
+}
+
+/// The function decodes accesses to its memory BARs.
+const COMMAND_MEMORY: u16 = 1 << 1;
+/// The function can access memory, to transfer data and to signal MSIs.
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// The function doesn't assert INTx.
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+/// The bits of the command register which the driver can set.
+const COMMAND_WRITABLE: u16 = COMMAND_MEMORY | COMMAND_BUS_MASTER | 1 << 6 | 1 << 8 | COMMAND_INTX_DISABLE;
+
+/// The function's INTx is asserted.
+const STATUS_INTERRUPT: u16 = 1 << 3;
+/// The function has a list of capabilities.
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const CAPABILITY_MSI: u8 = 0x05;
+const MSI_ENABLE: u16 = 1 << 0;
+/// The function can send MSIs to 64-bit addresses.
+const MSI_64_BIT: u16 = 1 << 7;
+
+/// The number of BARs in a type 0 header.
+pub const BAR_COUNT: usize = 6;
+
+/// A function of a PCI device, such as a network card, whose registers are in its memory BARs.
+pub trait PciFunction: fmt::Debug {
+    fn vendor_id(&self) -> u16;
+
+    fn device_id(&self) -> u16;
+
+    /// The class code, subclass and programming interface, from the most significant byte.
+    fn class(&self) -> u32;
+
+    fn revision(&self) -> u8 {
+        0
+    }
+
+    /// The sizes of the function's 32-bit memory BARs, which are powers of two of at least 16
+    /// bytes, or zero for the BARs it doesn't have.
+    fn bars(&self) -> [u32; BAR_COUNT] {
+        [0; BAR_COUNT]
+    }
+
+    /// Reads a register, at an offset into one of the BARs.
+    fn read_bar(&self, _bar: usize, _offset: u64, _size: Size) -> Result<u64, Trap> {
+        Ok(0)
+    }
+
+    /// Writes a register, at an offset into one of the BARs. The function can access memory only
+    /// if the driver has made it a bus master.
+    fn write_bar(&mut self, _bar: usize, _offset: u64, _size: Size, _value: u64, _dma: Option<&mut Dma>) -> Result<(), Trap> {
+        Ok(())
+    }
+
+    /// Checks for input from the host.
+    fn poll(&mut self, _dma: Option<&mut Dma>) {}
+
+    /// Indicates if the function is asserting its interrupt, which the host bridge passes on as
+    /// INTx while it's asserted, or as an MSI when it's first asserted.
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    /// Saves the function's own state, after its configuration space.
+    fn save(&self, _encoder: &mut Encoder) {}
+
+    fn restore(&mut self, _decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+/// The host bridge's own function, in the first slot, which the operating system finds but
+/// doesn't drive.
+#[derive(Debug)]
+pub struct HostBridge;
+
+impl PciFunction for HostBridge {
+    /// Red Hat's vendor id and QEMU's PCIe host bridge.
+    fn vendor_id(&self) -> u16 {
+        0x1b36
+    }
+
+    fn device_id(&self) -> u16 {
+        0x0008
+    }
+
+    fn class(&self) -> u32 {
+        0x06_00_00
+    }
+}
+
+/// A function in a slot of the bus, with its configuration space.
+#[derive(Debug)]
+struct Slot {
+    function: Box<dyn PciFunction>,
+    config: [u8; CONFIG_SIZE],
+    /// The bits of the configuration space which the driver can write.
+    writable: [u8; CONFIG_SIZE],
+    /// Whether the function was asserting its interrupt when it was last checked, so that an MSI
+    /// is only sent when it's first asserted.
+    asserted: bool,
+}
+
+impl Slot {
+    fn new(function: Box<dyn PciFunction>) -> Self {
+        let mut slot = Self { function, config: [0; CONFIG_SIZE], writable: [0; CONFIG_SIZE], asserted: false };
+        slot.set(reg::VENDOR_ID, Size::HalfWord, slot.function.vendor_id() as u64);
+        slot.set(reg::DEVICE_ID, Size::HalfWord, slot.function.device_id() as u64);
+        slot.set(reg::SUBSYSTEM_VENDOR_ID, Size::HalfWord, slot.function.vendor_id() as u64);
+        slot.set(reg::SUBSYSTEM_ID, Size::HalfWord, slot.function.device_id() as u64);
+        slot.set(reg::REVISION, Size::Byte, slot.function.revision() as u64);
+        slot.config[reg::CLASS..reg::CLASS + 3].copy_from_slice(&slot.function.class().to_le_bytes()[..3]);
+        slot.set(reg::STATUS, Size::HalfWord, STATUS_CAPABILITIES as u64);
+        slot.set(reg::CAPABILITIES, Size::Byte, reg::MSI as u64);
+        slot.set(reg::INTERRUPT_PIN, Size::Byte, 1);
+        slot.set(reg::MSI, Size::Byte, CAPABILITY_MSI as u64);
+        slot.set(reg::MSI_CONTROL, Size::HalfWord, MSI_64_BIT as u64);
+
+        slot.set_writable(reg::COMMAND, Size::HalfWord, COMMAND_WRITABLE as u64);
+        slot.set_writable(reg::INTERRUPT_LINE, Size::Byte, 0xff);
+        slot.set_writable(reg::MSI_CONTROL, Size::HalfWord, MSI_ENABLE as u64);
+        slot.set_writable(reg::MSI_ADDRESS, Size::DoubleWord, !0b11);
+        slot.set_writable(reg::MSI_DATA, Size::HalfWord, 0xffff);
+        // Writing ones to a BAR leaves the bits which are clear in its size, which is how the
+        // driver sizes it. The low bits say it's a 32-bit memory BAR.
+        for (i, size) in slot.function.bars().into_iter().enumerate().filter(|&(_, size)| size != 0) {
+            assert!(size.is_power_of_two() && size >= 16, "BAR {} of {:?} has an invalid size", i, slot.function);
+            slot.set_writable(reg::BAR0 + 4 * i, Size::Word, !(size - 1) as u64);
+        }
+        slot
+    }
+
+    fn get(&self, offset: usize, size: Size) -> u64 {
+        from_le(&self.config[offset..offset + size as usize])
+    }
+
+    fn set(&mut self, offset: usize, size: Size, value: u64) {
+        self.config[offset..offset + size as usize].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+    }
+
+    fn set_writable(&mut self, offset: usize, size: Size, mask: u64) {
+        self.writable[offset..offset + size as usize].copy_from_slice(&mask.to_le_bytes()[..size as usize]);
+    }
+
+    fn command(&self) -> u16 {
+        self.get(reg::COMMAND, Size::HalfWord) as u16
+    }
+
+    fn msi_enabled(&self) -> bool {
+        self.get(reg::MSI_CONTROL, Size::HalfWord) as u16 & MSI_ENABLE != 0
+    }
+
+    /// Indicates if the function is asserting INTx.
+    fn intx(&self) -> bool {
+        self.asserted && !self.msi_enabled()
+    }
+
+    /// Indicates if the function's INTx goes out to the interrupt controller, which it does
+    /// unless the driver disabled it.
+    fn intx_out(&self) -> bool {
+        self.intx() && self.command() & COMMAND_INTX_DISABLE == 0
+    }
+
+    /// Reads the configuration space, where the interrupt status is worked out when it's read.
+    fn read_config(&self, offset: usize, size: Size) -> u64 {
+        if offset + size as usize > CONFIG_SIZE {
+            return 0;
+        }
+        let value = self.get(offset, size);
+        let status = match self.intx() {
+            true => (STATUS_INTERRUPT as u64) << (8 * reg::STATUS),
+            false => 0,
+        };
+        match offset {
+            ..=reg::STATUS => value | status >> (8 * offset) & size.mask(),
+            _ => value,
+        }
+    }
+
+    /// Writes the configuration space, leaving the bits which aren't writable as they are.
+    fn write_config(&mut self, offset: usize, size: Size, value: u64) {
+        for (i, new) in value.to_le_bytes().into_iter().take(size as usize).enumerate() {
+            if let Some(byte) = self.config.get_mut(offset + i) {
+                let writable = self.writable[offset + i];
+                *byte = *byte & !writable | new & writable;
+            }
+        }
+    }
+
+    /// Finds the BAR which an address is in, and the offset into it, if the function decodes
+    /// its BARs.
+    fn decode(&self, addr: u64) -> Option<(usize, u64)> {
+        if self.command() & COMMAND_MEMORY == 0 {
+            return None;
+        }
+        self.function.bars().into_iter().enumerate().filter(|&(_, size)| size != 0).find_map(|(i, size)| {
+            let base = self.get(reg::BAR0 + 4 * i, Size::Word) & !0xf;
+            (base..base + size as u64).contains(&addr).then(|| (i, addr - base))
+        })
+    }
+
+    /// Lets the function access memory, if it's a bus master, and then passes its interrupt on.
+    fn service<T>(&mut self, dma: &mut Dma, f: impl FnOnce(&mut dyn PciFunction, Option<&mut Dma>) -> T) -> T {
+        let bus_master = self.command() & COMMAND_BUS_MASTER != 0;
+        let result = f(self.function.as_mut(), bus_master.then_some(&mut *dma));
+
+        let asserted = self.function.interrupt();
+        if asserted && !self.asserted && self.msi_enabled() && bus_master {
+            // There's no MSI controller to catch the write, so it goes to memory, where a test
+            // can look for it.
+            let _ = dma.write_u32(self.get(reg::MSI_ADDRESS, Size::DoubleWord), self.get(reg::MSI_DATA, Size::HalfWord) as u32);
+        }
+        self.asserted = asserted;
+        result
+    }
+}
+
+/// A PCIe host bridge with an ECAM configuration space and a window of memory for BARs, which is
+/// described as a `pci-host-ecam-generic`. It has a single bus, on which each device has a single
+/// function with an MSI capability.
+///
+/// The BARs are assigned when the functions are attached, as firmware would, but the driver has
+/// to enable them. INTx goes to the PLIC, as the device tree's interrupt map says. There's no MSI
+/// controller, so the device tree doesn't offer MSIs, and any which a driver enables anyway are
+/// plain writes to memory.
+#[derive(Debug)]
+pub struct PciHost {
+    slots: Vec<Option<Slot>>,
+    /// The next address in the window which is free for a BAR.
+    next_bar: u64,
+}
+
+impl Default for PciHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PciHost {
+    pub fn new() -> Self {
+        let mut host = Self { slots: (0..PCI_SLOTS).map(|_| None).collect(), next_bar: PCI_MMIO_BASE };
+        host.attach(Box::new(HostBridge));
+        host
+    }
+
+    /// Attaches a function in the first free slot, and assigns its BARs, returning the slot, or
+    /// `None` if there are no free slots or no room in the window for its BARs.
+    pub fn attach(&mut self, function: Box<dyn PciFunction>) -> Option<usize> {
+        let index = self.slots.iter().position(Option::is_none)?;
+        let mut slot = Slot::new(function);
+        let mut next = self.next_bar;
+        for (i, size) in slot.function.bars().into_iter().enumerate().filter(|&(_, size)| size != 0) {
+            let base = next.next_multiple_of(size as u64);
+            next = base + size as u64;
+            if next > PCI_MMIO_BASE + PCI_MMIO_SIZE {
+                return None;
+            }
+            slot.set(reg::BAR0 + 4 * i, Size::Word, base);
+        }
+        self.next_bar = next;
+        // The interrupt line is a note for the driver, of the source which INTx is routed to.
+        slot.set(reg::INTERRUPT_LINE, Size::Byte, (PCI_IRQ + index as u32 % 4) as u64);
+        self.slots[index] = Some(slot);
+        Some(index)
+    }
+
+    /// Indicates if a function is asserting INTx, which the driver hasn't disabled.
+    pub fn interrupting(&self) -> bool {
+        self.slots.iter().flatten().any(Slot::intx_out)
+    }
+
+    /// Finds the slot which an access to the configuration space is for. Only the first function
+    /// of each device on bus 0 is implemented.
+    fn config_slot(&self, offset: u64) -> Option<&Slot> {
+        let (bus, device, function) = (offset >> 20, (offset >> 15 & 0x1f) as usize, offset >> 12 & 0x7);
+        match (bus, function) {
+            (0, 0) => self.slots[device].as_ref(),
+            _ => None,
+        }
+    }
+
+    fn bar_slot(&mut self, addr: u64) -> Option<(&mut Slot, usize, u64)> {
+        self.slots.iter_mut().flatten().find_map(|slot| slot.decode(addr).map(|(bar, offset)| (slot, bar, offset)))
+    }
+}
+
+impl Device for PciHost {
+    fn base(&self) -> u64 {
+        ECAM_BASE
+    }
+
+    /// The window for BARs follows the configuration space.
+    fn size(&self) -> u64 {
+        ECAM_SIZE + PCI_MMIO_SIZE
+    }
+
+    /// Reads of functions which aren't there, and of addresses which no BAR decodes, are all
+    /// ones, as if the access was aborted.
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        if offset < ECAM_SIZE {
+            let register = (offset & 0xfff) as usize;
+            return Ok(self.config_slot(offset).map_or(size.mask(), |slot| slot.read_config(register, size)));
+        }
+        let addr = ECAM_BASE + offset;
+        match self.slots.iter().flatten().find_map(|slot| slot.decode(addr).map(|decoded| (slot, decoded))) {
+            Some((slot, (bar, offset))) => slot.function.read_bar(bar, offset, size),
+            None => Ok(size.mask()),
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, dma: &mut Dma) -> Result<(), Trap> {
+        if offset < ECAM_SIZE {
+            let (register, device) = ((offset & 0xfff) as usize, (offset >> 15 & 0x1f) as usize);
+            if self.config_slot(offset).is_some() {
+                let slot = self.slots[device].as_mut().unwrap();
+                slot.write_config(register, size, value);
+                // Enabling MSIs, or disabling bus mastering, changes how the interrupt goes out.
+                slot.service(dma, |_, _| {});
+            }
+            return Ok(());
+        }
+        match self.bar_slot(ECAM_BASE + offset) {
+            Some((slot, bar, offset)) => slot.service(dma, |function, dma| function.write_bar(bar, offset, size, value, dma)),
+            None => Ok(()),
+        }
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        let cells = |value: u64| [(value >> 32) as u32, value as u32];
+        // The window is 32-bit memory space, which is mapped one-to-one onto the bus.
+        let mut ranges = vec![0x0200_0000];
+        ranges.extend(cells(PCI_MMIO_BASE));
+        ranges.extend(cells(PCI_MMIO_BASE));
+        ranges.extend(cells(PCI_MMIO_SIZE));
+        // Each pin of each slot goes to a PLIC source, which repeats every four slots, and
+        // which the pin moves along from INTA's.
+        let mut interrupt_map = vec![];
+        for slot in 0..4 {
+            for pin in 1..=4 {
+                interrupt_map.extend([slot << 11, 0, 0, pin, PLIC_PHANDLE, PCI_IRQ + (slot + pin - 1) % 4]);
+            }
+        }
+        fdt.begin_node(&format!("pci@{:x}", ECAM_BASE))
+            .property_string("compatible", "pci-host-ecam-generic")
+            .property_string("device_type", "pci")
+            .property_u32("#address-cells", 3)
+            .property_u32("#size-cells", 2)
+            .property_u32("#interrupt-cells", 1)
+            .property_cells("interrupt-map-mask", &[0x1800, 0, 0, 7])
+            .property_cells("interrupt-map", &interrupt_map)
+            .property_cells("bus-range", &[0, 0xff])
+            .property_reg(&[(ECAM_BASE, ECAM_SIZE)])
+            .property_cells("ranges", &ranges)
+            .property_u32("linux,pci-domain", 0)
+            .property_null("dma-coherent")
+            .end_node();
+    }
+
+    fn poll(&mut self, dma: &mut Dma) {
+        for slot in self.slots.iter_mut().flatten() {
+            slot.service(dma, |function, dma| function.poll(dma));
+        }
+    }
+
+    fn interrupts(&self) -> u64 {
+        self.slots.iter().enumerate()
+            .filter(|(_, slot)| slot.as_ref().is_some_and(Slot::intx_out))
+            .fold(0, |lines, (index, _)| lines | 1 << (PCI_IRQ + index as u32 % 4))
+    }
+}
+
+impl Snapshotable for PciHost {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let section = snapshot.section(&format!("pci@{:x}", ECAM_BASE));
+        for slot in self.slots.iter().flatten() {
+            section.bytes(&slot.config).u8(slot.asserted as u8);
+            slot.function.save(section);
+        }
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section(&format!("pci@{:x}", ECAM_BASE))?;
+        for slot in self.slots.iter_mut().flatten() {
+            slot.config = section.block()?.try_into().map_err(|_| section.corrupt())?;
+            slot.asserted = section.u8()? != 0;
+            slot.function.restore(&mut section)?;
+        }
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::components::{devices::{Device, Dma}, memory::{Size, DRAM}};
+    use crate::fdt::{self, FdtWriter, PLIC_PHANDLE};
+    use crate::replay::Journal;
+
+    use super::{reg, PciHost, COMMAND_BUS_MASTER, COMMAND_MEMORY, ECAM_BASE, PCI_IRQ, PCI_MMIO_BASE};
+
+    /// Reads the configuration space of the function in a slot.
+    pub fn read_config(host: &PciHost, slot: usize, offset: usize, size: Size) -> u64 {
+        host.read((slot << 15 | offset) as u64, size).unwrap()
+    }
+
+    pub fn write_config(host: &mut PciHost, dram: &mut DRAM, slot: usize, offset: usize, size: Size, value: u64) {
+        let mut journal = Journal::new();
+        host.write((slot << 15 | offset) as u64, size, value, &mut Dma::new(dram, &mut journal)).unwrap();
+    }
+
+    /// Enables the BARs of a function and makes it a bus master, as its driver would.
+    pub fn enable(host: &mut PciHost, dram: &mut DRAM, slot: usize) {
+        write_config(host, dram, slot, reg::COMMAND, Size::HalfWord, (COMMAND_MEMORY | COMMAND_BUS_MASTER) as u64);
+    }
+
+    /// Converts an address in the window for BARs to an offset into the host bridge's region.
+    pub fn bar_offset(addr: u64) -> u64 {
+        addr - ECAM_BASE
+    }
+
+    #[test]
+    fn it_enumerates_functions() {
+        let host = PciHost::new();
+        assert_eq!(read_config(&host, 0, reg::VENDOR_ID, Size::Word), 0x0008_1b36);
+        assert_eq!(read_config(&host, 0, reg::CLASS + 1, Size::HalfWord), 0x0600);
+        assert_eq!(read_config(&host, 0, reg::CAPABILITIES, Size::Byte), reg::MSI as u64);
+        // Empty slots, other functions and other buses read as all ones.
+        assert_eq!(read_config(&host, 1, reg::VENDOR_ID, Size::HalfWord), 0xffff);
+        assert_eq!(host.read(1 << 12, Size::Word), Ok(0xffff_ffff));
+        assert_eq!(host.read(1 << 20, Size::Word), Ok(0xffff_ffff));
+        // The extended configuration space reads as zero.
+        assert_eq!(read_config(&host, 0, 0x100, Size::Word), 0);
+    }
+
+    #[test]
+    fn it_describes_the_host_bridge() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        PciHost::new().describe(&mut fdt);
+        fdt.end_node();
+        let properties = fdt::test::properties(&fdt.finish());
+        assert_eq!(properties["/pci@30000000/compatible"], b"pci-host-ecam-generic\0");
+        assert_eq!(properties["/pci@30000000/ranges"][..12], [2, 0, 0, 0, 0, 0, 0, 0, 0x40, 0, 0, 0]);
+        // INTB of the second slot goes to the third source after INTA of the first.
+        let map: Vec<u32> = properties["/pci@30000000/interrupt-map"]
+            .chunks(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+            .collect();
+        assert_eq!(map.len(), 16 * 6);
+        assert_eq!(map[30..36], [1 << 11, 0, 0, 2, PLIC_PHANDLE, PCI_IRQ + 2]);
+        // Nothing in the window is decoded until a BAR is enabled.
+        assert_eq!(PciHost::new().read(PCI_MMIO_BASE - ECAM_BASE, Size::Word), Ok(0xffff_ffff));
+    }
+}
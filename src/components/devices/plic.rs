@@ -0,0 +1,242 @@
+use std::cell::Cell;
+
+use crate::{
+    components::{cpu::Trap, memory::Size},
+    fdt::{FdtWriter, CPU_INTC_PHANDLE, PLIC_PHANDLE},
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable},
+};
+
+use super::{Device, Dma};
+
+/// The address of the controller's registers, as on QEMU's virt machine.
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+const PLIC_SIZE: u64 = 0x400_0000;
+/// The number of interrupt sources. Source 0 doesn't exist, so they're numbered from 1.
+pub const PLIC_SOURCES: usize = 64;
+/// The cause of the machine external interrupt, which the controller raises on the hart.
+const MACHINE_EXTERNAL: u32 = 11;
+
+/// The registers of the controller, as offsets from its base.
+mod reg {
+    /// The priority of each source, a word apiece.
+    pub const PRIORITY: u64 = 0x0000;
+    /// A bit for each source, which is set while it's waiting to be claimed, in two words.
+    pub const PENDING: u64 = 0x1000;
+    pub const PENDING_HIGH: u64 = 0x1004;
+    /// A bit for each source, which is set to let it interrupt the hart, in two words.
+    pub const ENABLE: u64 = 0x2000;
+    pub const ENABLE_HIGH: u64 = 0x2004;
+    /// The priority which a source has to be above to interrupt the hart.
+    pub const THRESHOLD: u64 = 0x20_0000;
+    /// Reading claims the most urgent pending source, and writing its number back completes it.
+    pub const CLAIM: u64 = 0x20_0004;
+}
+
+/// A platform-level interrupt controller, which collects the interrupt lines of the devices and
+/// raises the machine external interrupt on the hart when one which it enabled is pending.
+///
+/// There's a single context, for the hart's machine mode. Sources are level-triggered: one is
+/// pending while its device asserts its line, until it's claimed, and it can't be pending again
+/// until the claim is completed.
+#[derive(Debug)]
+pub struct Plic {
+    priorities: [u32; PLIC_SOURCES],
+    enabled: u64,
+    threshold: u32,
+    /// The lines which the devices are asserting, as a bit for each source.
+    lines: Cell<u64>,
+    /// The sources which have been claimed and not yet completed. Claiming is a read, so this
+    /// changes without mutable access.
+    claimed: Cell<u64>,
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priorities: [0; PLIC_SOURCES],
+            enabled: 0,
+            threshold: 0,
+            lines: Cell::new(0),
+            claimed: Cell::new(0),
+        }
+    }
+
+    /// Sets the interrupt lines which the devices are asserting, as a bit for each source.
+    pub fn set_lines(&self, lines: u64) {
+        // There's no source 0.
+        self.lines.set(lines & !1);
+    }
+
+    /// Indicates if the hart's external interrupt is raised.
+    pub fn interrupting(&self) -> bool {
+        self.next_claim().is_some()
+    }
+
+    fn pending(&self) -> u64 {
+        self.lines.get() & !self.claimed.get()
+    }
+
+    /// Finds the enabled pending source with the highest priority over the threshold, where the
+    /// lowest numbered source wins a tie.
+    fn next_claim(&self) -> Option<usize> {
+        let candidates = self.pending() & self.enabled;
+        if candidates == 0 {
+            return None;
+        }
+        (1..PLIC_SOURCES)
+            .filter(|&source| candidates & 1 << source != 0 && self.priorities[source] > self.threshold)
+            .max_by_key(|&source| (self.priorities[source], std::cmp::Reverse(source)))
+    }
+
+    fn claim(&self) -> u32 {
+        match self.next_claim() {
+            Some(source) => {
+                self.claimed.set(self.claimed.get() | 1 << source);
+                source as u32
+            },
+            None => 0,
+        }
+    }
+}
+
+impl Device for Plic {
+    fn base(&self) -> u64 {
+        PLIC_BASE
+    }
+
+    fn size(&self) -> u64 {
+        PLIC_SIZE
+    }
+
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        if size != Size::Word {
+            return Err(Trap::LoadAccessFault);
+        }
+        let value = match offset {
+            reg::PRIORITY..reg::PENDING => {
+                self.priorities.get(offset as usize / 4).copied().unwrap_or(0)
+            },
+            reg::PENDING => self.pending() as u32,
+            reg::PENDING_HIGH => (self.pending() >> 32) as u32,
+            reg::ENABLE => self.enabled as u32,
+            reg::ENABLE_HIGH => (self.enabled >> 32) as u32,
+            reg::THRESHOLD => self.threshold,
+            reg::CLAIM => self.claim(),
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, _dma: &mut Dma) -> Result<(), Trap> {
+        if size != Size::Word {
+            return Err(Trap::StoreAccessFault);
+        }
+        let value = value as u32;
+        match offset {
+            // Priorities run from 0, which never interrupts, to 7.
+            reg::PRIORITY..reg::PENDING => {
+                if let Some(priority) = self.priorities.get_mut(offset as usize / 4).filter(|_| offset >= 4) {
+                    *priority = value & 7;
+                }
+            },
+            reg::ENABLE => self.enabled = (self.enabled & !0xffff_ffff) | (value & !1) as u64,
+            reg::ENABLE_HIGH => self.enabled = (self.enabled & 0xffff_ffff) | (value as u64) << 32,
+            reg::THRESHOLD => self.threshold = value & 7,
+            reg::CLAIM if (value as usize) < PLIC_SOURCES => {
+                self.claimed.set(self.claimed.get() & !(1 << value));
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("plic@{:x}", PLIC_BASE))
+            .property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"])
+            .property_reg(&[(PLIC_BASE, PLIC_SIZE)])
+            .property_u32("#address-cells", 0)
+            .property_u32("#interrupt-cells", 1)
+            .property_null("interrupt-controller")
+            .property_cells("interrupts-extended", &[CPU_INTC_PHANDLE, MACHINE_EXTERNAL])
+            .property_u32("riscv,ndev", PLIC_SOURCES as u32 - 1)
+            .property_u32("phandle", PLIC_PHANDLE)
+            .end_node();
+    }
+}
+
+impl Snapshotable for Plic {
+    /// The lines aren't saved, as they're worked out from the devices again.
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let section = snapshot.section("plic");
+        for priority in self.priorities {
+            section.u32(priority);
+        }
+        section.u64(self.enabled)
+            .u32(self.threshold)
+            .u64(self.claimed.get());
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("plic")?;
+        for priority in self.priorities.iter_mut() {
+            *priority = section.u32()?;
+        }
+        self.enabled = section.u64()?;
+        self.threshold = section.u32()?;
+        self.claimed.set(section.u64()?);
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::components::{
+        devices::{Device, Dma},
+        memory::{Size, DRAM},
+    };
+    use crate::replay::Journal;
+
+    use super::{reg, Plic};
+
+    fn write(plic: &mut Plic, offset: u64, value: u64) {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        plic.write(offset, Size::Word, value, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+    }
+
+    #[test]
+    fn it_claims_the_most_urgent_source() {
+        let mut plic = Plic::new();
+        write(&mut plic, reg::PRIORITY + 4 * 10, 1);
+        write(&mut plic, reg::PRIORITY + 4 * 11, 1);
+        write(&mut plic, reg::PRIORITY + 4 * 33, 5);
+        write(&mut plic, reg::ENABLE, 1 << 10 | 1 << 11);
+
+        // A source interrupts once it's enabled and its priority is over the threshold.
+        plic.set_lines(1 << 11 | 1 << 33);
+        assert!(plic.interrupting());
+        write(&mut plic, reg::THRESHOLD, 1);
+        assert!(!plic.interrupting());
+        write(&mut plic, reg::THRESHOLD, 0);
+        assert_eq!(plic.read(reg::PENDING_HIGH, Size::Word), Ok(2));
+
+        write(&mut plic, reg::ENABLE_HIGH, 2);
+        assert_eq!(plic.read(reg::CLAIM, Size::Word), Ok(33));
+        plic.set_lines(1 << 10 | 1 << 11 | 1 << 33);
+        assert_eq!(plic.read(reg::CLAIM, Size::Word), Ok(10));
+        assert_eq!(plic.read(reg::CLAIM, Size::Word), Ok(11));
+        assert_eq!(plic.read(reg::CLAIM, Size::Word), Ok(0));
+        assert!(!plic.interrupting());
+
+        // A source which is still asserted is pending again when its claim completes.
+        write(&mut plic, reg::CLAIM, 33);
+        assert!(plic.interrupting());
+        assert_eq!(plic.read(reg::CLAIM, Size::Word), Ok(33));
+    }
+}
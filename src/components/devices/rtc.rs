@@ -0,0 +1,284 @@
+use std::cell::Cell;
+
+use crate::{
+    components::{cpu::Trap, memory::Size},
+    fdt::{FdtWriter, TIMEBASE_FREQUENCY},
+    replay::Source,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable},
+    syscall::realtime_nanos,
+};
+
+use super::{Device, Dma};
+
+/// The address of the clock's registers, as on QEMU's virt machine.
+pub const RTC_BASE: u64 = 0x10_1000;
+const RTC_SIZE: u64 = 0x1000;
+/// The interrupt of the alarm, as on QEMU's virt machine.
+pub const RTC_IRQ: u32 = 11;
+
+/// The nanoseconds which pass with each instruction, which is a tick of the timebase.
+const NANOS_PER_TICK: u64 = 1_000_000_000 / TIMEBASE_FREQUENCY as u64;
+
+/// The registers of the clock, as offsets from its base.
+mod reg {
+    pub const TIME_LOW: u64 = 0x00;
+    pub const TIME_HIGH: u64 = 0x04;
+    pub const ALARM_LOW: u64 = 0x08;
+    pub const ALARM_HIGH: u64 = 0x0c;
+    pub const IRQ_ENABLED: u64 = 0x10;
+    pub const CLEAR_ALARM: u64 = 0x14;
+    pub const ALARM_STATUS: u64 = 0x18;
+    pub const CLEAR_INTERRUPT: u64 = 0x1c;
+}
+
+/// Where the clock's time starts from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Epoch {
+    /// The host's wall clock when the machine first polls its devices, which is recorded in
+    /// the journal.
+    Host,
+    /// A fixed time, in nanoseconds since the Unix epoch, so that every run sees the same times.
+    Fixed(u64),
+}
+
+/// A goldfish real-time clock, which tells the guest the wall-clock time in nanoseconds since
+/// the Unix epoch, and raises an alarm at a time which it sets.
+///
+/// The time moves on with the instruction count, at the timebase frequency, rather than with the
+/// host's clock, so it keeps pace with the guest's timers and a replay sees the same times. It's
+/// brought up to date when the devices are polled, every `POLL_INTERVAL` instructions.
+#[derive(Debug)]
+pub struct Rtc {
+    /// The time at an instruction count of zero, or `None` until the host's clock is read.
+    base: Option<u64>,
+    /// The time as of the last poll.
+    now: u64,
+    /// The upper half of the time, which is latched when the lower half is read, so that the
+    /// halves are consistent.
+    time_high: Cell<u32>,
+    alarm: u64,
+    alarm_running: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Rtc {
+    pub fn new(epoch: Epoch) -> Self {
+        let base = match epoch {
+            Epoch::Host => None,
+            Epoch::Fixed(nanos) => Some(nanos),
+        };
+        Self {
+            base,
+            now: base.unwrap_or(0),
+            time_high: Cell::new(0),
+            alarm: 0,
+            alarm_running: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    /// Indicates if the alarm has gone off and the guest hasn't cleared its interrupt yet.
+    pub fn interrupting(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    /// Sets the alarm, which goes off straight away if its time has passed.
+    fn set_alarm(&mut self, alarm: u64) {
+        self.alarm = alarm;
+        self.alarm_running = true;
+        self.check_alarm();
+    }
+
+    fn check_alarm(&mut self) {
+        if self.alarm_running && self.alarm <= self.now {
+            self.alarm_running = false;
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Device for Rtc {
+    fn base(&self) -> u64 {
+        RTC_BASE
+    }
+
+    fn size(&self) -> u64 {
+        RTC_SIZE
+    }
+
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        if size != Size::Word {
+            return Err(Trap::LoadAccessFault);
+        }
+        let value = match offset {
+            reg::TIME_LOW => {
+                self.time_high.set((self.now >> 32) as u32);
+                self.now as u32
+            },
+            reg::TIME_HIGH => self.time_high.get(),
+            reg::ALARM_LOW => self.alarm as u32,
+            reg::ALARM_HIGH => (self.alarm >> 32) as u32,
+            reg::IRQ_ENABLED => self.irq_enabled as u32,
+            reg::ALARM_STATUS => self.alarm_running as u32,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, dma: &mut Dma) -> Result<(), Trap> {
+        if size != Size::Word {
+            return Err(Trap::StoreAccessFault);
+        }
+        let value = value as u32;
+        match offset {
+            // The guest sets the time by writing the upper half and then the lower half.
+            reg::TIME_HIGH => self.time_high.set(value),
+            reg::TIME_LOW => {
+                let time = (self.time_high.get() as u64) << 32 | value as u64;
+                let ticks = dma.journal().clock().wrapping_mul(NANOS_PER_TICK);
+                self.base = Some(time.wrapping_sub(ticks));
+                self.now = time;
+                self.check_alarm();
+            },
+            reg::ALARM_HIGH => self.alarm = (self.alarm & 0xffff_ffff) | (value as u64) << 32,
+            reg::ALARM_LOW => self.set_alarm((self.alarm & !0xffff_ffff) | value as u64),
+            reg::IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            reg::CLEAR_ALARM => self.alarm_running = false,
+            reg::CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("rtc@{:x}", RTC_BASE))
+            .property_string("compatible", "google,goldfish-rtc")
+            .property_reg(&[(RTC_BASE, RTC_SIZE)])
+            .property_u32("interrupts", RTC_IRQ)
+            .end_node();
+    }
+
+    fn interrupts(&self) -> u64 {
+        (self.interrupting() as u64) << RTC_IRQ
+    }
+
+    fn poll(&mut self, dma: &mut Dma) {
+        let ticks = dma.journal().clock().wrapping_mul(NANOS_PER_TICK);
+        let base = *self.base.get_or_insert_with(|| {
+            dma.journal().input_u64(Source::Time, realtime_nanos).wrapping_sub(ticks)
+        });
+        self.now = base.wrapping_add(ticks);
+        self.check_alarm();
+    }
+}
+
+impl Snapshotable for Rtc {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let section = snapshot.section("rtc");
+        match self.base {
+            Some(base) => section.u8(1).u64(base),
+            None => section.u8(0),
+        };
+        section.u64(self.now)
+            .u32(self.time_high.get())
+            .u64(self.alarm)
+            .u8(self.alarm_running as u8)
+            .u8(self.irq_enabled as u8)
+            .u8(self.irq_pending as u8);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("rtc")?;
+        self.base = match section.u8()? {
+            0 => None,
+            _ => Some(section.u64()?),
+        };
+        self.now = section.u64()?;
+        self.time_high.set(section.u32()?);
+        self.alarm = section.u64()?;
+        self.alarm_running = section.u8()? != 0;
+        self.irq_enabled = section.u8()? != 0;
+        self.irq_pending = section.u8()? != 0;
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::components::{
+        devices::{Device, Dma},
+        memory::{Size, DRAM},
+    };
+    use crate::replay::Journal;
+
+    use super::{reg, Epoch, Rtc, NANOS_PER_TICK};
+
+    const EPOCH: u64 = 1_700_000_000_000_000_000;
+
+    fn read_time(rtc: &Rtc) -> u64 {
+        let low = rtc.read(reg::TIME_LOW, Size::Word).unwrap();
+        rtc.read(reg::TIME_HIGH, Size::Word).unwrap() << 32 | low
+    }
+
+    #[test]
+    fn it_keeps_time_from_a_fixed_epoch() {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        let mut rtc = Rtc::new(Epoch::Fixed(EPOCH));
+        assert_eq!(read_time(&rtc), EPOCH);
+
+        journal.set_clock(1024);
+        rtc.poll(&mut Dma::new(&mut dram, &mut journal));
+        assert_eq!(read_time(&rtc), EPOCH + 1024 * NANOS_PER_TICK);
+
+        // The guest can set the time, which moves on from there.
+        let mut write = |rtc: &mut Rtc, journal: &mut Journal, offset, value: u64| {
+            rtc.write(offset, Size::Word, value, &mut Dma::new(&mut dram, journal)).unwrap();
+        };
+        write(&mut rtc, &mut journal, reg::TIME_HIGH, 1);
+        write(&mut rtc, &mut journal, reg::TIME_LOW, 0);
+        assert_eq!(read_time(&rtc), 1 << 32);
+        journal.set_clock(2048);
+        rtc.poll(&mut Dma::new(&mut DRAM::new(0x1000), &mut journal));
+        assert_eq!(read_time(&rtc), (1 << 32) + 1024 * NANOS_PER_TICK);
+    }
+
+    #[test]
+    fn it_raises_alarms() {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        let mut rtc = Rtc::new(Epoch::Fixed(EPOCH));
+        let alarm = EPOCH + 1500 * NANOS_PER_TICK;
+        for (offset, value) in [(reg::IRQ_ENABLED, 1), (reg::ALARM_HIGH, alarm >> 32), (reg::ALARM_LOW, alarm & 0xffff_ffff)] {
+            rtc.write(offset, Size::Word, value, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+        }
+        assert_eq!(rtc.read(reg::ALARM_STATUS, Size::Word), Ok(1));
+
+        journal.set_clock(1024);
+        rtc.poll(&mut Dma::new(&mut dram, &mut journal));
+        assert!(!rtc.interrupting());
+        journal.set_clock(2048);
+        rtc.poll(&mut Dma::new(&mut dram, &mut journal));
+        assert!(rtc.interrupting());
+        assert_eq!(rtc.read(reg::ALARM_STATUS, Size::Word), Ok(0));
+
+        rtc.write(reg::CLEAR_INTERRUPT, Size::Word, 1, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+        assert!(!rtc.interrupting());
+    }
+
+    #[test]
+    fn it_reads_the_host_clock_once() {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::tape(0);
+        let mut rtc = Rtc::new(Epoch::Host);
+        journal.set_clock(1024);
+        rtc.poll(&mut Dma::new(&mut dram, &mut journal));
+        let first = read_time(&rtc);
+        assert!(first > EPOCH);
+        journal.set_clock(2048);
+        rtc.poll(&mut Dma::new(&mut dram, &mut journal));
+        assert_eq!(read_time(&rtc), first + 1024 * NANOS_PER_TICK);
+    }
+}
@@ -0,0 +1,469 @@
+use std::{cell::RefCell, collections::VecDeque, fmt};
+
+use crate::{
+    components::{cpu::Trap, memory::Size},
+    fdt::{FdtWriter, SPI_CLOCK_PHANDLE},
+    snapshot::{Decoder, Encoder, SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable},
+};
+
+use super::{Device, Dma};
+
+pub mod nor;
+pub mod sd;
+
+pub use self::nor::SpiFlash;
+pub use self::sd::SdCard;
+
+/// The address of the controller's registers, as for the SD card's controller on the FU540.
+pub const SPI_BASE: u64 = 0x1005_0000;
+const SPI_SIZE: u64 = 0x1000;
+/// The interrupt of the controller's watermarks.
+pub const SPI_IRQ: u32 = 12;
+/// The number of chip selects, and so of devices on the bus.
+pub const SPI_CS_COUNT: usize = 4;
+/// The frequency of the clock which the serial clock is divided from.
+const CLOCK_FREQUENCY: u32 = 100_000_000;
+/// The number of frames which each FIFO holds.
+const FIFO_DEPTH: usize = 8;
+
+/// The registers of the controller, as offsets from its base.
+mod reg {
+    pub const SCKDIV: u64 = 0x00;
+    pub const SCKMODE: u64 = 0x04;
+    pub const CSID: u64 = 0x10;
+    pub const CSDEF: u64 = 0x14;
+    pub const CSMODE: u64 = 0x18;
+    pub const DELAY0: u64 = 0x28;
+    pub const DELAY1: u64 = 0x2c;
+    pub const FMT: u64 = 0x40;
+    pub const TXDATA: u64 = 0x48;
+    pub const RXDATA: u64 = 0x4c;
+    pub const TXMARK: u64 = 0x50;
+    pub const RXMARK: u64 = 0x54;
+    pub const FCTRL: u64 = 0x60;
+    pub const FFMT: u64 = 0x64;
+    pub const IE: u64 = 0x70;
+    pub const IP: u64 = 0x74;
+    /// The size of the block of registers.
+    pub const END: u64 = 0x80;
+}
+
+/// The chip select stays asserted after the first frame, until the mode or the device changes.
+const CSMODE_HOLD: u32 = 2;
+/// The chip select isn't driven.
+const CSMODE_OFF: u32 = 3;
+
+/// Frames are sent least significant bit first.
+const FMT_ENDIAN: u32 = 1 << 2;
+/// Frames are only sent, and nothing is received into the receive FIFO.
+const FMT_DIR: u32 = 1 << 3;
+
+/// A full transmit FIFO, or an empty receive FIFO, in the data registers.
+const FIFO_FLAG: u32 = 1 << 31;
+
+/// The transmit FIFO has fewer frames in it than its watermark.
+const IP_TXWM: u32 = 1 << 0;
+/// The receive FIFO has more frames in it than its watermark.
+const IP_RXWM: u32 = 1 << 1;
+
+/// A device on an SPI bus, such as an SD card or a flash, which is sent commands while its chip
+/// select is asserted.
+pub trait SpiSlave: fmt::Debug {
+    /// Describes the device in the device tree, as a child of the controller, at its chip select.
+    fn describe(&self, fdt: &mut FdtWriter, cs: usize);
+
+    /// The device's chip select was asserted, which starts a command.
+    fn select(&mut self) {}
+
+    /// The device's chip select was deasserted, which ends a command.
+    fn deselect(&mut self) {}
+
+    /// Shifts a byte out to the device, and returns the byte which it shifts back at the same
+    /// time, with direct access to memory for the journal.
+    fn transfer(&mut self, byte: u8, dma: &mut Dma) -> u8;
+
+    fn save(&self, _encoder: &mut Encoder) {}
+
+    fn restore(&mut self, _decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+/// A SiFive SPI controller, described as `sifive,spi0`, with devices on its chip selects. The
+/// driver shifts 8-bit frames through its FIFOs, and the memory-mapped flash interface isn't
+/// implemented.
+///
+/// Frames are exchanged with the selected device as soon as they're written, unless the receive
+/// FIFO is full, in which case they wait in the transmit FIFO until there's room. Single, dual and
+/// quad frames are all exchanged a byte at a time.
+#[derive(Debug)]
+pub struct SpiController {
+    slaves: Vec<Option<Box<dyn SpiSlave>>>,
+    /// The registers which hold what the driver wrote to them, by offset.
+    regs: [u32; reg::END as usize / 4],
+    tx: VecDeque<u8>,
+    /// The receive FIFO, which is emptied by reads of its data register.
+    rx: RefCell<VecDeque<u8>>,
+    /// The chip select which is asserted.
+    selected: Option<usize>,
+}
+
+impl Default for SpiController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpiController {
+    pub fn new() -> Self {
+        let mut controller = Self {
+            slaves: (0..SPI_CS_COUNT).map(|_| None).collect(),
+            regs: [0; reg::END as usize / 4],
+            tx: VecDeque::new(),
+            rx: RefCell::new(VecDeque::new()),
+            selected: None,
+        };
+        for (offset, value) in [
+            (reg::SCKDIV, 3),
+            (reg::CSDEF, (1 << SPI_CS_COUNT) - 1),
+            (reg::DELAY0, 0x0001_0001),
+            (reg::DELAY1, 0x0000_0001),
+            (reg::FMT, 8 << 16),
+            (reg::FFMT, 0x0003_0007),
+        ] {
+            controller.regs[offset as usize / 4] = value;
+        }
+        controller
+    }
+
+    /// Attaches a device on the first free chip select, returning the chip select, or `None` if
+    /// there are none free.
+    pub fn attach(&mut self, slave: Box<dyn SpiSlave>) -> Option<usize> {
+        let cs = self.slaves.iter().position(Option::is_none)?;
+        self.slaves[cs] = Some(slave);
+        Some(cs)
+    }
+
+    /// Indicates if a watermark interrupt which the driver enabled is pending.
+    pub fn interrupting(&self) -> bool {
+        self.pending() & self.reg(reg::IE) != 0
+    }
+
+    fn reg(&self, offset: u64) -> u32 {
+        self.regs[offset as usize / 4]
+    }
+
+    fn pending(&self) -> u32 {
+        let mut pending = 0;
+        if self.tx.len() < self.reg(reg::TXMARK) as usize {
+            pending |= IP_TXWM;
+        }
+        if self.rx.borrow().len() > self.reg(reg::RXMARK) as usize {
+            pending |= IP_RXWM;
+        }
+        pending
+    }
+
+    /// Asserts a chip select, deasserting the one which was asserted before, if it's different.
+    fn select(&mut self, cs: Option<usize>) {
+        if self.selected == cs {
+            return;
+        }
+        if let Some(slave) = self.selected.and_then(|cs| self.slaves[cs].as_mut()) {
+            slave.deselect();
+        }
+        if let Some(slave) = cs.and_then(|cs| self.slaves[cs].as_mut()) {
+            slave.select();
+        }
+        self.selected = cs;
+    }
+
+    /// Exchanges the frames in the transmit FIFO with the selected device, for as long as there's
+    /// room for what comes back.
+    fn pump(&mut self, dma: &mut Dma) {
+        let receive = self.reg(reg::FMT) & FMT_DIR == 0;
+        while !(receive && self.rx.borrow().len() >= FIFO_DEPTH) {
+            let Some(byte) = self.tx.pop_front() else { break };
+            let byte = self.exchange(byte, dma);
+            if receive {
+                self.rx.borrow_mut().push_back(byte);
+            }
+        }
+    }
+
+    /// Exchanges a frame with the device on the chip select, which is asserted for it. Nothing
+    /// drives the data line back if no device is selected, so it reads as all ones.
+    fn exchange(&mut self, byte: u8, dma: &mut Dma) -> u8 {
+        let lsb_first = self.reg(reg::FMT) & FMT_ENDIAN != 0;
+        let order = |byte: u8| if lsb_first { byte.reverse_bits() } else { byte };
+        let mode = self.reg(reg::CSMODE);
+        if mode == CSMODE_OFF {
+            return 0xff;
+        }
+        let cs = self.reg(reg::CSID) as usize;
+        self.select(Some(cs));
+        let received = self.slaves[cs].as_mut().map_or(0xff, |slave| slave.transfer(order(byte), dma));
+        // Otherwise, the chip select is asserted for each frame, and deasserted after it.
+        if mode != CSMODE_HOLD {
+            self.select(None);
+        }
+        order(received)
+    }
+
+    /// The bits of a register which the driver can write, or `None` if the register doesn't just
+    /// hold what's written to it.
+    fn writable(offset: u64) -> Option<u32> {
+        let mask = match offset {
+            reg::SCKDIV => 0xfff,
+            reg::SCKMODE => 0x3,
+            reg::CSID => SPI_CS_COUNT as u32 - 1,
+            reg::CSDEF => (1 << SPI_CS_COUNT) - 1,
+            reg::CSMODE => 0x3,
+            reg::DELAY0 | reg::DELAY1 => 0x00ff_00ff,
+            reg::FMT => 0x000f_000f,
+            reg::TXMARK | reg::RXMARK => 0x7,
+            reg::FCTRL => 0x1,
+            reg::FFMT => 0xffff_3fff,
+            reg::IE => IP_TXWM | IP_RXWM,
+            _ => return None,
+        };
+        Some(mask)
+    }
+}
+
+impl Device for SpiController {
+    fn base(&self) -> u64 {
+        SPI_BASE
+    }
+
+    fn size(&self) -> u64 {
+        SPI_SIZE
+    }
+
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        if size != Size::Word {
+            return Err(Trap::LoadAccessFault);
+        }
+        let value = match offset {
+            reg::TXDATA if self.tx.len() >= FIFO_DEPTH => FIFO_FLAG,
+            reg::TXDATA => 0,
+            reg::RXDATA => self.rx.borrow_mut().pop_front().map_or(FIFO_FLAG, u32::from),
+            reg::IP => self.pending(),
+            reg::END.. => 0,
+            offset => self.reg(offset & !3),
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, dma: &mut Dma) -> Result<(), Trap> {
+        if size != Size::Word {
+            return Err(Trap::StoreAccessFault);
+        }
+        let value = value as u32;
+        match offset {
+            reg::TXDATA if self.tx.len() < FIFO_DEPTH => self.tx.push_back(value as u8),
+            offset => {
+                if let Some(mask) = Self::writable(offset) {
+                    self.regs[offset as usize / 4] = value & mask;
+                }
+            },
+        }
+        // A chip select which is held is released when the driver changes the mode or the
+        // device, and frames which were waiting for room may now have it.
+        let held = self.reg(reg::CSMODE) == CSMODE_HOLD && self.selected == Some(self.reg(reg::CSID) as usize);
+        if !held {
+            self.select(None);
+        }
+        self.pump(dma);
+        Ok(())
+    }
+
+    fn interrupts(&self) -> u64 {
+        (self.interrupting() as u64) << SPI_IRQ
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node("spi-clock")
+            .property_string("compatible", "fixed-clock")
+            .property_u32("#clock-cells", 0)
+            .property_u32("clock-frequency", CLOCK_FREQUENCY)
+            .property_u32("phandle", SPI_CLOCK_PHANDLE)
+            .end_node();
+        fdt.begin_node(&format!("spi@{:x}", SPI_BASE))
+            .property_strings("compatible", &["sifive,fu540-c000-spi", "sifive,spi0"])
+            .property_reg(&[(SPI_BASE, SPI_SIZE)])
+            .property_u32("interrupts", SPI_IRQ)
+            .property_u32("clocks", SPI_CLOCK_PHANDLE)
+            .property_u32("#address-cells", 1)
+            .property_u32("#size-cells", 0);
+        for (cs, slave) in self.slaves.iter().enumerate() {
+            if let Some(slave) = slave {
+                slave.describe(fdt, cs);
+            }
+        }
+        fdt.end_node();
+    }
+
+    /// Frames which were left waiting for room in the receive FIFO are exchanged, as the time
+    /// which it took the driver to empty it passes.
+    fn poll(&mut self, dma: &mut Dma) {
+        self.pump(dma);
+    }
+}
+
+impl Snapshotable for SpiController {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let section = snapshot.section(&format!("spi@{:x}", SPI_BASE));
+        for &value in &self.regs {
+            section.u32(value);
+        }
+        section.bytes(&self.tx.iter().copied().collect::<Vec<_>>())
+            .bytes(&self.rx.borrow().iter().copied().collect::<Vec<_>>())
+            .u8(self.selected.map_or(0xff, |cs| cs as u8));
+        for slave in self.slaves.iter().flatten() {
+            slave.save(section);
+        }
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section(&format!("spi@{:x}", SPI_BASE))?;
+        for value in &mut self.regs {
+            *value = section.u32()?;
+        }
+        self.tx = section.block()?.into();
+        self.rx = RefCell::new(section.block()?.into());
+        self.selected = match section.u8()? {
+            0xff => None,
+            cs if (cs as usize) < SPI_CS_COUNT => Some(cs as usize),
+            _ => return Err(section.corrupt()),
+        };
+        for slave in self.slaves.iter_mut().flatten() {
+            slave.restore(&mut section)?;
+        }
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{fs, path::PathBuf};
+
+    use crate::components::{devices::{Device, Dma}, memory::{Size, DRAM}};
+    use crate::fdt::{self, FdtWriter};
+    use crate::replay::Journal;
+    use crate::snapshot::{SnapshotReader, SnapshotWriter, Snapshotable};
+
+    use super::{reg, SpiController, SpiFlash, SpiSlave, CSMODE_HOLD, FIFO_FLAG, IP_RXWM, SPI_BASE, SPI_IRQ};
+
+    /// Creates an image of a size, full of a byte.
+    pub fn image(name: &str, len: usize, byte: u8) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rv64-{}-{}", name, std::process::id()));
+        fs::write(&path, vec![byte; len]).unwrap();
+        path
+    }
+
+    /// Selects a device, sends it bytes, and deselects it, returning what it sent back.
+    pub fn exchange(slave: &mut dyn SpiSlave, bytes: &[u8]) -> Vec<u8> {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        slave.select();
+        let received = bytes.iter().map(|&byte| slave.transfer(byte, &mut Dma::new(&mut dram, &mut journal))).collect();
+        slave.deselect();
+        received
+    }
+
+    fn write(controller: &mut SpiController, offset: u64, value: u64) {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        controller.write(offset, Size::Word, value, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+    }
+
+    fn receive(controller: &SpiController, count: usize) -> Vec<u64> {
+        (0..count).map(|_| controller.read(reg::RXDATA, Size::Word).unwrap()).collect()
+    }
+
+    #[test]
+    fn it_exchanges_frames_with_the_selected_device() {
+        let path = image("spi-controller", 0x10_0000, 0xff);
+        let mut controller = SpiController::new();
+        assert_eq!(controller.attach(Box::new(SpiFlash::open(&path).unwrap())), Some(0));
+        assert_eq!(controller.read(reg::FMT, Size::Word), Ok(0x8_0000));
+
+        // The chip select is held across the command and its response.
+        write(&mut controller, reg::CSMODE, CSMODE_HOLD as u64);
+        for byte in [0x9f, 0, 0, 0] {
+            write(&mut controller, reg::TXDATA, byte);
+        }
+        write(&mut controller, reg::RXMARK, 2);
+        assert_eq!(controller.read(reg::IP, Size::Word), Ok(IP_RXWM as u64));
+        assert_eq!(controller.interrupts(), 0);
+        write(&mut controller, reg::IE, IP_RXWM as u64);
+        assert_eq!(controller.interrupts(), 1 << SPI_IRQ);
+        assert_eq!(receive(&controller, 5), [0xff, 0xef, 0x40, 0x14, FIFO_FLAG as u64]);
+
+        // Frames are sent least significant bit first if asked, and a frame which doesn't fit in
+        // the receive FIFO waits until there's room.
+        write(&mut controller, reg::CSMODE, 0);
+        write(&mut controller, reg::FMT, 8 << 16 | 1 << 2);
+        write(&mut controller, reg::CSMODE, CSMODE_HOLD as u64);
+        for byte in [0x9f_u8.reverse_bits(), 0, 0, 0, 0, 0, 0, 0, 0] {
+            write(&mut controller, reg::TXDATA, byte as u64);
+        }
+        assert_eq!(receive(&controller, 4), [0xff, 0xef_u8.reverse_bits() as u64, 0x40_u8.reverse_bits() as u64, 0x14_u8.reverse_bits() as u64]);
+        assert_eq!(receive(&controller, 5), [0, 0, 0, 0, FIFO_FLAG as u64]);
+        controller.poll(&mut Dma::new(&mut DRAM::new(0x1000), &mut Journal::new()));
+        assert_eq!(receive(&controller, 2), [0, FIFO_FLAG as u64]);
+
+        // Nothing answers on a chip select without a device.
+        write(&mut controller, reg::CSID, 1);
+        write(&mut controller, reg::TXDATA, 0x9f);
+        assert_eq!(receive(&controller, 1), [0xff]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_saves_its_devices() {
+        let path = image("spi-snapshot", 0x10_0000, 0xff);
+        let mut controller = SpiController::new();
+        controller.attach(Box::new(SpiFlash::open(&path).unwrap()));
+        write(&mut controller, reg::CSMODE, CSMODE_HOLD as u64);
+        write(&mut controller, reg::TXDATA, 0x06);
+        write(&mut controller, reg::CSMODE, 0);
+        write(&mut controller, reg::CSMODE, CSMODE_HOLD as u64);
+        for byte in [0x02, 0, 0, 0, 0x42] {
+            write(&mut controller, reg::TXDATA, byte);
+        }
+        let mut snapshot = SnapshotWriter::new();
+        controller.save(&mut snapshot);
+        let mut data = vec![];
+        snapshot.write_to(&mut data).unwrap();
+
+        // The program is part way through, and finishes when the restored controller releases
+        // the chip select.
+        assert_eq!(fs::read(&path).unwrap()[0], 0xff);
+        let mut restored = SpiController::new();
+        restored.attach(Box::new(SpiFlash::open(&path).unwrap()));
+        restored.restore(&mut SnapshotReader::read_from(&data[..]).unwrap()).unwrap();
+        write(&mut restored, reg::CSMODE, 0);
+        assert_eq!(fs::read(&path).unwrap()[0], 0x42);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_describes_its_devices() {
+        let path = image("spi-describe", 0x10_0000, 0xff);
+        let mut controller = SpiController::new();
+        controller.attach(Box::new(SpiFlash::open(&path).unwrap()));
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        controller.describe(&mut fdt);
+        fdt.end_node();
+        let properties = fdt::test::properties(&fdt.finish());
+        let node = format!("/spi@{:x}", SPI_BASE);
+        assert_eq!(properties[&format!("{}/compatible", node)], b"sifive,fu540-c000-spi\0sifive,spi0\0");
+        assert_eq!(properties[&format!("{}/clocks", node)], properties["/spi-clock/phandle"]);
+        assert_eq!(properties[&format!("{}/flash@0/compatible", node)], b"jedec,spi-nor\0");
+        assert_eq!(properties[&format!("{}/flash@0/reg", node)], 0u32.to_be_bytes());
+        let _ = fs::remove_file(&path);
+    }
+}
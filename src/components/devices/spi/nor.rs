@@ -0,0 +1,261 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use crate::{
+    components::devices::Dma,
+    fdt::FdtWriter,
+    snapshot::{Decoder, Encoder, SnapshotError, PAGE_SIZE},
+};
+
+use super::SpiSlave;
+
+/// The size of a new flash, which is that of a W25Q128.
+pub const SPI_FLASH_DEFAULT_SIZE: u64 = 0x100_0000;
+/// The sizes of the flashes in the family, which are powers of two.
+const SIZES: std::ops::RangeInclusive<u64> = 0x10_0000..=0x100_0000;
+/// The size of a page, which is the most that one command can program.
+const PAGE: usize = 256;
+
+/// The commands which the flash understands.
+mod op {
+    pub const WRITE_STATUS: u8 = 0x01;
+    pub const PAGE_PROGRAM: u8 = 0x02;
+    pub const READ: u8 = 0x03;
+    pub const WRITE_DISABLE: u8 = 0x04;
+    pub const READ_STATUS: u8 = 0x05;
+    pub const WRITE_ENABLE: u8 = 0x06;
+    pub const FAST_READ: u8 = 0x0b;
+    pub const READ_STATUS3: u8 = 0x15;
+    pub const SECTOR_ERASE: u8 = 0x20;
+    pub const READ_STATUS2: u8 = 0x35;
+    pub const DUAL_READ: u8 = 0x3b;
+    pub const BLOCK_ERASE_32K: u8 = 0x52;
+    pub const CHIP_ERASE: u8 = 0x60;
+    pub const QUAD_READ: u8 = 0x6b;
+    pub const READ_ID: u8 = 0x9f;
+    pub const CHIP_ERASE_ALT: u8 = 0xc7;
+    pub const BLOCK_ERASE: u8 = 0xd8;
+}
+
+/// Writes and erases are enabled, in the status register.
+const STATUS_WEL: u8 = 1 << 1;
+
+/// A JEDEC SPI NOR flash with 3-byte addresses, which identifies itself as a Winbond W25Q of its
+/// size, and is described as a `jedec,spi-nor`. Like the parallel flash, its contents are written
+/// through to a file on the host, and programs and erases finish straight away, so it's never
+/// busy.
+///
+/// Commands take effect when the chip select is deasserted, as on the real part, and dual and
+/// quad reads send the same bytes as a single read.
+#[derive(Debug)]
+pub struct SpiFlash {
+    data: Vec<u8>,
+    file: File,
+    status: u8,
+    /// The command which is being sent, and how many bytes of it have been sent.
+    opcode: u8,
+    position: usize,
+    address: u32,
+    /// The bytes of a page which are being programmed, with ones where nothing was sent.
+    page: Vec<u8>,
+}
+
+impl SpiFlash {
+    /// Opens a flash backed by a file, which is created, blank, if it doesn't exist. The file's
+    /// size has to be a power of two from 1 MiB to 16 MiB.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut len = file.metadata()?.len();
+        if len == 0 {
+            len = SPI_FLASH_DEFAULT_SIZE;
+            file.write_all_at(&vec![0xff; len as usize], 0)?;
+        }
+        if !len.is_power_of_two() || !SIZES.contains(&len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "an SPI flash image has to be a power of two from 1 to 16 MiB"));
+        }
+        let mut data = vec![0; len as usize];
+        file.read_exact_at(&mut data, 0)?;
+        Ok(Self { data, file, status: 0, opcode: 0, position: 0, address: 0, page: vec![0xff; PAGE] })
+    }
+
+    /// The manufacturer id, the memory type, and the capacity as a power of two.
+    fn id(&self) -> [u8; 3] {
+        [0xef, 0x40, self.data.len().trailing_zeros() as u8]
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let byte = self.data[self.address as usize];
+        self.address = (self.address + 1) % self.data.len() as u32;
+        byte
+    }
+
+    /// Erases the region of a size which the address is in, setting every bit.
+    fn erase(&mut self, size: usize) {
+        let start = self.address as usize / size * size;
+        self.data[start..start + size].fill(0xff);
+        self.write_through(start, size);
+    }
+
+    fn write_through(&mut self, start: usize, len: usize) {
+        // The flash carries on with what's in memory if the file can't be written, but says so.
+        if let Err(e) = self.file.write_all_at(&self.data[start..start + len], start as u64) {
+            eprintln!("spi flash: {}", e);
+        }
+    }
+
+    /// Carries out a command which takes effect at the end, if all of it was sent.
+    fn execute(&mut self) {
+        let addressed = self.position >= 4;
+        let enabled = self.status & STATUS_WEL != 0;
+        match self.opcode {
+            op::WRITE_ENABLE => self.status |= STATUS_WEL,
+            op::WRITE_DISABLE | op::WRITE_STATUS => self.status &= !STATUS_WEL,
+            op::PAGE_PROGRAM if enabled && self.position > 4 => {
+                let start = self.address as usize & !(PAGE - 1);
+                for (byte, new) in self.data[start..start + PAGE].iter_mut().zip(&self.page) {
+                    *byte &= new;
+                }
+                self.write_through(start, PAGE);
+            },
+            op::SECTOR_ERASE if enabled && addressed => self.erase(0x1000),
+            op::BLOCK_ERASE_32K if enabled && addressed => self.erase(0x8000),
+            op::BLOCK_ERASE if enabled && addressed => self.erase(0x1_0000),
+            op::CHIP_ERASE | op::CHIP_ERASE_ALT if enabled => self.erase(self.data.len()),
+            _ => return,
+        }
+        // A program or an erase disables writes again when it's done.
+        if self.opcode != op::WRITE_ENABLE {
+            self.status &= !STATUS_WEL;
+        }
+    }
+}
+
+impl SpiSlave for SpiFlash {
+    fn describe(&self, fdt: &mut FdtWriter, cs: usize) {
+        fdt.begin_node(&format!("flash@{:x}", cs))
+            .property_string("compatible", "jedec,spi-nor")
+            .property_u32("reg", cs as u32)
+            .property_u32("spi-max-frequency", 50_000_000)
+            .property_null("m25p,fast-read")
+            .end_node();
+    }
+
+    fn select(&mut self) {
+        self.position = 0;
+        self.address = 0;
+        self.page.fill(0xff);
+    }
+
+    fn deselect(&mut self) {
+        if self.position > 0 {
+            self.execute();
+        }
+        self.position = 0;
+    }
+
+    fn transfer(&mut self, byte: u8, _dma: &mut Dma) -> u8 {
+        let position = self.position;
+        self.position += 1;
+        if position == 0 {
+            self.opcode = byte;
+            return 0xff;
+        }
+        match self.opcode {
+            op::READ_ID => self.id().get(position - 1).copied().unwrap_or(0),
+            op::READ_STATUS => self.status,
+            op::READ_STATUS2 | op::READ_STATUS3 => 0,
+            // The address is sent after the command, most significant byte first.
+            op::READ | op::FAST_READ | op::DUAL_READ | op::QUAD_READ | op::PAGE_PROGRAM | op::SECTOR_ERASE | op::BLOCK_ERASE_32K | op::BLOCK_ERASE
+                if position <= 3 =>
+            {
+                self.address = (self.address << 8 | byte as u32) % self.data.len() as u32;
+                0xff
+            },
+            op::READ => self.read_byte(),
+            // The fast reads have a dummy byte after the address.
+            op::FAST_READ | op::DUAL_READ | op::QUAD_READ if position == 4 => 0xff,
+            op::FAST_READ | op::DUAL_READ | op::QUAD_READ => self.read_byte(),
+            // Data which runs past the end of the page wraps around to its start.
+            op::PAGE_PROGRAM => {
+                let offset = (self.address as usize + position - 4) % PAGE;
+                self.page[offset] = byte;
+                0xff
+            },
+            _ => 0xff,
+        }
+    }
+
+    fn save(&self, encoder: &mut Encoder) {
+        encoder.u8(self.status)
+            .u8(self.opcode)
+            .u64(self.position as u64)
+            .u32(self.address)
+            .bytes(&self.page)
+            .pages(self.data.len(), self.data.chunks(PAGE_SIZE).enumerate());
+    }
+
+    /// The restored contents are written through to the file, which follows the machine.
+    fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        self.status = decoder.u8()?;
+        self.opcode = decoder.u8()?;
+        self.position = decoder.u64()? as usize;
+        self.address = decoder.u32()?;
+        self.page = decoder.block()?;
+        if self.page.len() != PAGE || self.address as usize >= self.data.len() {
+            return Err(decoder.corrupt());
+        }
+        decoder.restore_pages(&mut self.data)?;
+        self.write_through(0, self.data.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::components::devices::spi::test::{exchange, image};
+
+    use super::{SpiFlash, SPI_FLASH_DEFAULT_SIZE};
+
+    #[test]
+    fn it_programs_and_erases() {
+        let path = image("spi-nor", 0x10_0000, 0xff);
+        let mut flash = SpiFlash::open(&path).unwrap();
+        assert_eq!(exchange(&mut flash, &[0x9f, 0, 0, 0]), [0xff, 0xef, 0x40, 0x14]);
+
+        // Programs need writes to be enabled, which a program disables again.
+        exchange(&mut flash, &[0x02, 0, 0x10, 0x00, 0x12]);
+        assert_eq!(exchange(&mut flash, &[0x03, 0, 0x10, 0x00, 0]), [0xff, 0xff, 0xff, 0xff, 0xff]);
+        exchange(&mut flash, &[0x06]);
+        assert_eq!(exchange(&mut flash, &[0x05, 0]), [0xff, 0x02]);
+        exchange(&mut flash, &[0x02, 0, 0x10, 0xff, 0x12, 0x34]);
+        assert_eq!(exchange(&mut flash, &[0x05, 0]), [0xff, 0x00]);
+        // The data wrapped around to the start of the page.
+        assert_eq!(exchange(&mut flash, &[0x0b, 0, 0x10, 0x00, 0, 0, 0]), [0xff, 0xff, 0xff, 0xff, 0xff, 0x34, 0xff]);
+        assert_eq!(exchange(&mut flash, &[0x03, 0, 0x10, 0xff, 0]), [0xff, 0xff, 0xff, 0xff, 0x12]);
+        assert_eq!(fs::read(&path).unwrap()[0x10ff], 0x12);
+
+        exchange(&mut flash, &[0x06]);
+        exchange(&mut flash, &[0x20, 0, 0x1f, 0xff]);
+        assert_eq!(exchange(&mut flash, &[0x03, 0, 0x10, 0xff, 0]), [0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(fs::read(&path).unwrap()[0x10ff], 0xff);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_creates_blank_images() {
+        let path = image("spi-nor-new", 0, 0);
+        let flash = SpiFlash::open(&path).unwrap();
+        assert_eq!(flash.id(), [0xef, 0x40, 0x18]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), SPI_FLASH_DEFAULT_SIZE);
+
+        fs::write(&path, [0xff; 0x3000]).unwrap();
+        assert!(SpiFlash::open(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
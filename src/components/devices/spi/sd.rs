@@ -0,0 +1,482 @@
+use std::{collections::VecDeque, io, path::Path};
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    components::devices::{
+        virtio::block::{Disk, DiskMode, SECTOR_SIZE},
+        Dma,
+    },
+    fdt::FdtWriter,
+    replay::Source,
+    snapshot::{Decoder, Encoder, SnapshotError},
+};
+
+use super::SpiSlave;
+
+/// The capacity of an SD card is a whole number of these, as the CSD gives it.
+const CAPACITY_UNIT: u64 = 512 * 1024;
+
+/// The commands which the card understands in SPI mode, with the application commands which
+/// follow `APP_CMD`.
+mod cmd {
+    pub const GO_IDLE_STATE: u8 = 0;
+    pub const SEND_OP_COND: u8 = 1;
+    pub const SEND_IF_COND: u8 = 8;
+    pub const SEND_CSD: u8 = 9;
+    pub const SEND_CID: u8 = 10;
+    pub const STOP_TRANSMISSION: u8 = 12;
+    pub const SEND_STATUS: u8 = 13;
+    pub const SET_BLOCKLEN: u8 = 16;
+    pub const READ_SINGLE_BLOCK: u8 = 17;
+    pub const READ_MULTIPLE_BLOCK: u8 = 18;
+    pub const WRITE_BLOCK: u8 = 24;
+    pub const WRITE_MULTIPLE_BLOCK: u8 = 25;
+    pub const APP_CMD: u8 = 55;
+    pub const READ_OCR: u8 = 58;
+    pub const CRC_ON_OFF: u8 = 59;
+
+    pub const SD_STATUS: u8 = 13;
+    pub const SET_WR_BLK_ERASE_COUNT: u8 = 23;
+    pub const SD_SEND_OP_COND: u8 = 41;
+    pub const SEND_SCR: u8 = 51;
+}
+
+/// The bits of the R1 response, which starts every response.
+const R1_IDLE: u8 = 1 << 0;
+const R1_ILLEGAL_COMMAND: u8 = 1 << 2;
+const R1_PARAMETER_ERROR: u8 = 1 << 6;
+
+/// The tokens which start a block of data, or stop a multiple block write.
+const TOKEN_START_BLOCK: u8 = 0xfe;
+const TOKEN_START_MULTIPLE: u8 = 0xfc;
+const TOKEN_STOP: u8 = 0xfd;
+/// The tokens which say that a read failed, because of an error or an address out of range.
+const TOKEN_ERROR: u8 = 0x01;
+const TOKEN_OUT_OF_RANGE: u8 = 0x08;
+/// The responses to a block which was written.
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_WRITE_ERROR: u8 = 0x0d;
+
+/// The operating conditions: powered up, high capacity, and 2.7 to 3.6 V.
+const OCR: u32 = 0x00ff_8000;
+const OCR_CCS: u32 = 1 << 30;
+const OCR_POWERED_UP: u32 = 1 << 31;
+
+/// The card identification: no particular manufacturer, a name, a revision, a serial number, and
+/// January 2024.
+const CID: [u8; 15] = [0x00, b'R', b'V', b'E', b'M', b'U', b'S', b'D', 0x10, 0, 0, 0, 1, 0x01, 0x81];
+
+/// The SD card configuration: version 2.0 of the specification, with 1 and 4-bit buses.
+const SCR: [u8; 8] = [0x02, 0x05, 0, 0, 0, 0, 0, 0];
+
+/// What the card does with the bytes which it's sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+enum State {
+    /// Waiting for a command.
+    Command,
+    /// Sending blocks one after another, until a command stops it.
+    Reading,
+    /// Waiting for the token which starts a block to write.
+    WriteToken,
+    /// Receiving a block to write, followed by its CRC.
+    WriteData,
+}
+
+/// A high-capacity SD card in SPI mode, backed by a disk image, which firmware can load a
+/// bootloader from and Linux can mount with its `mmc_spi` driver.
+///
+/// It answers the commands which the drivers send to start it up and to read and write blocks.
+/// CRCs are sent with the data, but not checked on what's received, and the card is never busy.
+/// Switching functions isn't supported, and the CSD says so.
+#[derive(Debug)]
+pub struct SdCard {
+    disk: Disk,
+    state: State,
+    /// The command which is being received.
+    command: Vec<u8>,
+    /// The bytes which the card has yet to send.
+    response: VecDeque<u8>,
+    /// The card hasn't finished starting up, which it does when it's asked for its operating
+    /// conditions.
+    idle: bool,
+    /// The last command was `APP_CMD`, so this one is an application command.
+    app_command: bool,
+    /// The next block to read or write.
+    sector: u64,
+    /// Whether blocks are written until a stop token.
+    multiple: bool,
+    /// The block which is being written.
+    data: Vec<u8>,
+}
+
+impl SdCard {
+    pub fn open(path: &Path, mode: DiskMode) -> io::Result<Self> {
+        let disk = Disk::open(path, mode)?;
+        if disk.capacity() * (SECTOR_SIZE as u64) < CAPACITY_UNIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "an SD card image has to be at least 512 KiB"));
+        }
+        Ok(Self {
+            disk,
+            state: State::Command,
+            command: vec![],
+            response: VecDeque::new(),
+            idle: true,
+            app_command: false,
+            sector: 0,
+            multiple: false,
+            data: vec![],
+        })
+    }
+
+    fn r1(&self, flags: u8) -> u8 {
+        flags | if self.idle { R1_IDLE } else { 0 }
+    }
+
+    /// The card-specific data, in version 2: the command classes without switching, 512-byte
+    /// blocks, and the capacity in units of 512 KiB, with the temporary write protection set if
+    /// the card is read-only.
+    fn csd(&self) -> [u8; 15] {
+        let size = (self.disk.capacity() * SECTOR_SIZE as u64 / CAPACITY_UNIT - 1) as u32;
+        let protect = match self.disk.mode() {
+            DiskMode::ReadOnly => 0x10,
+            _ => 0,
+        };
+        [0x40, 0x0e, 0x00, 0x32, 0x1b, 0x59, 0x00, (size >> 16) as u8 & 0x3f, (size >> 8) as u8, size as u8, 0x7f, 0x80, 0x0a, 0x40, protect]
+    }
+
+    /// Makes a block of data to send, which starts with a gap and a token and ends with its CRC.
+    fn block(data: &[u8]) -> Vec<u8> {
+        let mut block = vec![0xff, TOKEN_START_BLOCK];
+        block.extend_from_slice(data);
+        block.extend(crc16(data).to_be_bytes());
+        block
+    }
+
+    /// Makes a register into a block of data, with its CRC in its last byte.
+    fn register(data: &[u8]) -> Vec<u8> {
+        let mut register = data.to_vec();
+        register.push(crc7(data) << 1 | 1);
+        Self::block(&register)
+    }
+
+    /// Reads a sector as a block of data, or an error token.
+    fn read_block(&mut self, sector: u64, dma: &mut Dma) -> Vec<u8> {
+        if !self.disk.in_bounds(sector, SECTOR_SIZE) {
+            return vec![0xff, TOKEN_OUT_OF_RANGE];
+        }
+        // The image could change between a recording and its replay, so what was read is taken
+        // from the journal.
+        let data = dma.journal().input(Source::BlockCompletion, || self.disk.read_sectors(sector, SECTOR_SIZE).unwrap_or_default());
+        match data.len() {
+            SECTOR_SIZE => Self::block(&data),
+            _ => vec![0xff, TOKEN_ERROR],
+        }
+    }
+
+    /// Carries out a command, returning its response, which follows a byte's gap.
+    fn execute(&mut self, index: u8, argument: u32, dma: &mut Dma) -> Vec<u8> {
+        let app_command = std::mem::take(&mut self.app_command);
+        let mut response = match (app_command, index) {
+            (_, cmd::GO_IDLE_STATE) => {
+                self.idle = true;
+                self.state = State::Command;
+                vec![R1_IDLE]
+            },
+            (true, cmd::SD_SEND_OP_COND) | (false, cmd::SEND_OP_COND) => {
+                self.idle = false;
+                vec![self.r1(0)]
+            },
+            // The card accepts the voltage, and echoes the check pattern.
+            (false, cmd::SEND_IF_COND) => vec![self.r1(0), 0, 0, (argument >> 8) as u8 & 0xf, argument as u8],
+            (false, cmd::SEND_CSD) => [&[self.r1(0)], &Self::register(&self.csd())[..]].concat(),
+            (false, cmd::SEND_CID) => [&[self.r1(0)], &Self::register(&CID)[..]].concat(),
+            (false, cmd::STOP_TRANSMISSION) => {
+                self.state = State::Command;
+                // A stuff byte follows the command, before the response.
+                vec![0xff, self.r1(0)]
+            },
+            (false, cmd::SEND_STATUS) => vec![self.r1(0), 0],
+            (true, cmd::SD_STATUS) => [&[self.r1(0), 0], &Self::block(&[0; 64])[..]].concat(),
+            (false, cmd::SET_BLOCKLEN) if argument as usize == SECTOR_SIZE => vec![self.r1(0)],
+            (false, cmd::SET_BLOCKLEN) => vec![self.r1(R1_PARAMETER_ERROR)],
+            (false, cmd::READ_SINGLE_BLOCK) if self.disk.in_bounds(argument as u64, SECTOR_SIZE) => {
+                [&[self.r1(0)], &self.read_block(argument as u64, dma)[..]].concat()
+            },
+            // The blocks are read as they're sent.
+            (false, cmd::READ_MULTIPLE_BLOCK) if self.disk.in_bounds(argument as u64, SECTOR_SIZE) => {
+                self.sector = argument as u64;
+                self.state = State::Reading;
+                vec![self.r1(0)]
+            },
+            (false, cmd::WRITE_BLOCK | cmd::WRITE_MULTIPLE_BLOCK) if self.disk.in_bounds(argument as u64, SECTOR_SIZE) => {
+                self.sector = argument as u64;
+                self.multiple = index == cmd::WRITE_MULTIPLE_BLOCK;
+                self.state = State::WriteToken;
+                vec![self.r1(0)]
+            },
+            (false, cmd::READ_SINGLE_BLOCK | cmd::READ_MULTIPLE_BLOCK | cmd::WRITE_BLOCK | cmd::WRITE_MULTIPLE_BLOCK) => {
+                vec![self.r1(R1_PARAMETER_ERROR)]
+            },
+            (false, cmd::APP_CMD) => {
+                self.app_command = true;
+                vec![self.r1(0)]
+            },
+            (true, cmd::SET_WR_BLK_ERASE_COUNT) | (false, cmd::CRC_ON_OFF) => vec![self.r1(0)],
+            (true, cmd::SEND_SCR) => [&[self.r1(0)], &Self::block(&SCR)[..]].concat(),
+            (false, cmd::READ_OCR) => {
+                let ocr = match self.idle {
+                    true => OCR | OCR_CCS,
+                    false => OCR | OCR_CCS | OCR_POWERED_UP,
+                };
+                [&[self.r1(0)], &ocr.to_be_bytes()[..]].concat()
+            },
+            _ => vec![self.r1(R1_ILLEGAL_COMMAND)],
+        };
+        response.insert(0, 0xff);
+        response
+    }
+
+    /// Writes the block which was received, and returns the card's response to it.
+    fn write_block(&mut self) -> u8 {
+        let data = std::mem::take(&mut self.data);
+        if !self.disk.in_bounds(self.sector, SECTOR_SIZE) || self.disk.write_sectors(self.sector, &data[..SECTOR_SIZE]).is_err() {
+            self.state = State::Command;
+            return DATA_WRITE_ERROR;
+        }
+        self.sector += 1;
+        DATA_ACCEPTED
+    }
+}
+
+impl SpiSlave for SdCard {
+    fn describe(&self, fdt: &mut FdtWriter, cs: usize) {
+        fdt.begin_node(&format!("mmc@{:x}", cs))
+            .property_string("compatible", "mmc-spi-slot")
+            .property_u32("reg", cs as u32)
+            .property_u32("spi-max-frequency", 20_000_000)
+            .property_cells("voltage-ranges", &[3300, 3300])
+            .property_null("disable-wp")
+            .end_node();
+    }
+
+    /// A command which was cut short is dropped.
+    fn deselect(&mut self) {
+        self.command.clear();
+    }
+
+    fn transfer(&mut self, byte: u8, dma: &mut Dma) -> u8 {
+        if self.response.is_empty() && self.state == State::Reading {
+            let block = self.read_block(self.sector, dma);
+            self.sector += 1;
+            if block.len() < SECTOR_SIZE {
+                self.state = State::Command;
+            }
+            self.response.extend(block);
+        }
+        let out = self.response.pop_front().unwrap_or(0xff);
+
+        match self.state {
+            // A command can stop a multiple block read while it's being sent.
+            State::Command | State::Reading => {
+                // Commands start with a zero bit and then a one bit.
+                if !self.command.is_empty() || byte & 0xc0 == 0x40 {
+                    self.command.push(byte);
+                }
+                if self.command.len() == 6 {
+                    let command = std::mem::take(&mut self.command);
+                    let argument = u32::from_be_bytes(command[1..5].try_into().unwrap());
+                    self.response = self.execute(command[0] & 0x3f, argument, dma).into();
+                }
+            },
+            State::WriteToken => match byte {
+                TOKEN_START_BLOCK if !self.multiple => self.state = State::WriteData,
+                TOKEN_START_MULTIPLE if self.multiple => self.state = State::WriteData,
+                // The card is busy for a byte while it finishes.
+                TOKEN_STOP if self.multiple => {
+                    self.state = State::Command;
+                    self.response = [0xff, 0x00].into();
+                },
+                _ => {},
+            },
+            State::WriteData => {
+                self.data.push(byte);
+                if self.data.len() == SECTOR_SIZE + 2 {
+                    self.state = match self.multiple {
+                        true => State::WriteToken,
+                        false => State::Command,
+                    };
+                    let response = self.write_block();
+                    self.response = [response, 0x00].into();
+                }
+            },
+        }
+        out
+    }
+
+    fn save(&self, encoder: &mut Encoder) {
+        let response: Vec<u8> = self.response.iter().copied().collect();
+        encoder.u8(self.state as u8)
+            .bytes(&self.command)
+            .bytes(&response)
+            .u8(self.idle as u8)
+            .u8(self.app_command as u8)
+            .u64(self.sector)
+            .u8(self.multiple as u8)
+            .bytes(&self.data);
+        self.disk.save(encoder);
+    }
+
+    fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        self.state = State::try_from(decoder.u8()?).map_err(|_| decoder.corrupt())?;
+        self.command = decoder.block()?;
+        self.response = decoder.block()?.into();
+        self.idle = decoder.u8()? != 0;
+        self.app_command = decoder.u8()? != 0;
+        self.sector = decoder.u64()?;
+        self.multiple = decoder.u8()? != 0;
+        self.data = decoder.block()?;
+        if self.command.len() >= 6 || self.data.len() >= SECTOR_SIZE + 2 {
+            return Err(decoder.corrupt());
+        }
+        self.disk.restore(decoder)
+    }
+}
+
+/// Computes the 7-bit CRC which registers and commands end with.
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let feedback = (crc >> 6 ^ byte >> bit) & 1;
+            crc = (crc << 1) & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-16 of a block of data, as the CCITT polynomial with no initial value.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => crc << 1 ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::components::devices::{spi::test::{exchange, image}, virtio::block::DiskMode};
+
+    use super::{crc16, crc7, SdCard, SECTOR_SIZE};
+
+    /// Sends a command, and clocks out a number of bytes after it, returning them from the first
+    /// which isn't a gap.
+    fn command(card: &mut SdCard, index: u8, argument: u32, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0x40 | index];
+        bytes.extend(argument.to_be_bytes());
+        bytes.push(crc7(&bytes) << 1 | 1);
+        bytes.resize(6 + len, 0xff);
+        exchange(card, &bytes)[6..].iter().copied().skip_while(|&byte| byte == 0xff).collect()
+    }
+
+    /// Starts the card up, as the drivers do.
+    fn start(card: &mut SdCard) {
+        assert_eq!(command(card, 0, 0, 2), [0x01]);
+        assert_eq!(command(card, 8, 0x1aa, 6), [0x01, 0, 0, 0x01, 0xaa]);
+        assert_eq!(command(card, 58, 0, 6), [0x01, 0x40, 0xff, 0x80, 0x00]);
+        assert_eq!(command(card, 55, 0, 2), [0x01]);
+        assert_eq!(command(card, 41, 1 << 30, 2), [0x00]);
+        assert_eq!(command(card, 58, 0, 6), [0x00, 0xc0, 0xff, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn it_computes_crcs() {
+        assert_eq!(crc7(&[0x40, 0, 0, 0, 0]), 0x4a);
+        assert_eq!(crc7(&[0x48, 0, 0, 0x01, 0xaa]), 0x43);
+        assert_eq!(crc16(&[0xff; 512]), 0x7fa1);
+    }
+
+    #[test]
+    fn it_starts_up_and_reads_blocks() {
+        let path = image("sd-read", 0x10_0000, 0);
+        fs::write(&path, [vec![1; SECTOR_SIZE], vec![2; SECTOR_SIZE], vec![0; 0x10_0000 - 2 * SECTOR_SIZE]].concat()).unwrap();
+        let mut card = SdCard::open(&path, DiskMode::ReadOnly).unwrap();
+        start(&mut card);
+
+        // The CSD gives the size in units of 512 KiB, less one, and that the card is read-only.
+        let csd = command(&mut card, 9, 0, 23);
+        assert_eq!(&csd[..3], [0x00, 0xff, 0xfe]);
+        assert_eq!(csd[3 + 9], 1);
+        assert_eq!(csd[3 + 14], 0x10);
+        assert_eq!(csd[3 + 15], crc7(&csd[3..18]) << 1 | 1);
+
+        let block = command(&mut card, 17, 1, SECTOR_SIZE + 6);
+        assert_eq!(block[..3], [0x00, 0xff, 0xfe]);
+        assert_eq!(block[3..3 + SECTOR_SIZE], [2; SECTOR_SIZE]);
+        assert_eq!(block[3 + SECTOR_SIZE..5 + SECTOR_SIZE], crc16(&[2; SECTOR_SIZE]).to_be_bytes());
+
+        // A multiple block read carries on until it's stopped.
+        let mut bytes = vec![0x40 | 18, 0, 0, 0, 0, 0xff];
+        bytes.resize(2 * SECTOR_SIZE + 20, 0xff);
+        bytes.extend([0x40 | 12, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        let received = exchange(&mut card, &bytes);
+        let first = received.iter().position(|&byte| byte == 0xfe).unwrap();
+        assert_eq!(received[first + 1..first + 1 + SECTOR_SIZE], [1; SECTOR_SIZE]);
+        let second = first + 3 + SECTOR_SIZE + received[first + 3 + SECTOR_SIZE..].iter().position(|&byte| byte == 0xfe).unwrap();
+        assert_eq!(received[second + 1..second + 1 + SECTOR_SIZE], [2; SECTOR_SIZE]);
+        assert_eq!(received[received.len() - 3..], [0xff, 0xff, 0x00]);
+
+        // Blocks past the end are out of range, and writes to a read-only card fail.
+        assert_eq!(command(&mut card, 17, 0x800, 2), [0x40]);
+        assert_eq!(command(&mut card, 24, 0, 2), [0x00]);
+        let mut data = vec![0xff, 0xfe];
+        data.extend([3; SECTOR_SIZE + 2]);
+        data.extend([0xff, 0xff]);
+        assert_eq!(exchange(&mut card, &data)[SECTOR_SIZE + 4..], [0x0d, 0x00]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_writes_blocks() {
+        let path = image("sd-write", 0x10_0000, 0);
+        let mut card = SdCard::open(&path, DiskMode::ReadWrite).unwrap();
+        start(&mut card);
+
+        assert_eq!(command(&mut card, 24, 2, 2), [0x00]);
+        let mut data = vec![0xff, 0xfe];
+        data.extend([3; SECTOR_SIZE + 2]);
+        data.extend([0xff, 0xff]);
+        assert_eq!(exchange(&mut card, &data)[SECTOR_SIZE + 4..], [0x05, 0x00]);
+
+        // A multiple block write carries on until the stop token.
+        assert_eq!(command(&mut card, 25, 4, 2), [0x00]);
+        for byte in [4, 5] {
+            let mut data = vec![0xff, 0xfc];
+            data.extend([byte; SECTOR_SIZE + 2]);
+            data.extend([0xff, 0xff]);
+            assert_eq!(exchange(&mut card, &data)[SECTOR_SIZE + 4..], [0x05, 0x00]);
+        }
+        assert_eq!(exchange(&mut card, &[0xfd, 0xff, 0xff]), [0xff, 0xff, 0x00]);
+
+        let block = command(&mut card, 17, 5, SECTOR_SIZE + 6);
+        assert_eq!(block[3..3 + SECTOR_SIZE], [5; SECTOR_SIZE]);
+        let contents = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(contents[2 * SECTOR_SIZE..3 * SECTOR_SIZE], [3; SECTOR_SIZE]);
+        assert_eq!(contents[4 * SECTOR_SIZE..6 * SECTOR_SIZE], [[4; SECTOR_SIZE], [5; SECTOR_SIZE]].concat());
+        assert_eq!(contents[3 * SECTOR_SIZE..4 * SECTOR_SIZE], [0; SECTOR_SIZE]);
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    fmt,
+    io::Write,
+    sync::mpsc::Receiver,
+};
+
+use crate::{
+    components::{cpu::Trap, memory::Size},
+    fdt::FdtWriter,
+    replay::Source,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable},
+};
+
+use super::{Device, Dma};
+
+/// The address of the UART's registers, as on QEMU's virt machine.
+pub const UART_BASE: u64 = 0x1000_0000;
+const UART_SIZE: u64 = 0x100;
+/// The interrupt of the UART, as on QEMU's virt machine.
+pub const UART_IRQ: u32 = 10;
+/// The frequency of the clock which the baud rate is divided from.
+const CLOCK_FREQUENCY: u32 = 3_686_400;
+/// The number of bytes which the receive FIFO holds.
+const FIFO_DEPTH: usize = 16;
+
+/// The registers of the UART, as offsets from its base. The first two are the divisor latch
+/// instead while `LCR_DLAB` is set.
+mod reg {
+    /// The receive buffer when read, and the transmit holding register when written.
+    pub const DATA: u64 = 0;
+    pub const IER: u64 = 1;
+    /// The interrupt identification when read, and the FIFO control when written.
+    pub const IIR: u64 = 2;
+    pub const LCR: u64 = 3;
+    pub const MCR: u64 = 4;
+    pub const LSR: u64 = 5;
+    pub const MSR: u64 = 6;
+    pub const SCR: u64 = 7;
+}
+
+/// Interrupts when there's received data.
+const IER_RDI: u8 = 1 << 0;
+/// Interrupts when the transmit holding register is empty.
+const IER_THRI: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+/// The FIFOs are enabled.
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE_FIFO: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+/// The divisor latch is accessed in place of the data and interrupt enable registers.
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// Carrier detect, data set ready and clear to send, as a line with something on the other end.
+const MSR_CONNECTED: u8 = 0xb0;
+
+/// A 16550A UART, which passes bytes between the guest and the host, such as a console on the
+/// emulator's standard input and output. Linux drives it with its 8250 driver, as `ttyS0`.
+///
+/// Bytes are sent as soon as they're written, so the transmitter is always empty. Received bytes
+/// are taken from the host when the devices are polled, and go through the journal.
+pub struct Uart {
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write>,
+    rx: RefCell<VecDeque<u8>>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo_enabled: bool,
+    /// Whether the transmitter has emptied since the interrupt for it was last identified. The
+    /// interrupt is cleared by reading the IIR, so this changes without mutable access.
+    thr_empty: Cell<bool>,
+}
+
+impl fmt::Debug for Uart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Uart").field("rx", &self.rx).field("ier", &self.ier).finish()
+    }
+}
+
+impl Uart {
+    /// A UART which takes input from a channel, if there is one, and writes output to anything.
+    pub fn new(input: Option<Receiver<u8>>, output: Box<dyn Write>) -> Self {
+        Self {
+            input,
+            output,
+            rx: RefCell::new(VecDeque::new()),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            thr_empty: Cell::new(false),
+        }
+    }
+
+    /// Finds the interrupt which the UART is raising, in the form which the IIR identifies it.
+    /// Received data comes first.
+    fn interrupt(&self) -> Option<u8> {
+        if self.ier & IER_RDI != 0 && !self.rx.borrow().is_empty() {
+            Some(IIR_RDI)
+        } else if self.ier & IER_THRI != 0 && self.thr_empty.get() {
+            Some(IIR_THRI)
+        } else {
+            None
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        let ready = match self.rx.borrow().is_empty() {
+            true => 0,
+            false => LSR_DATA_READY,
+        };
+        ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+    }
+}
+
+impl Device for Uart {
+    fn base(&self) -> u64 {
+        UART_BASE
+    }
+
+    fn size(&self) -> u64 {
+        UART_SIZE
+    }
+
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        if size != Size::Byte {
+            return Err(Trap::LoadAccessFault);
+        }
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            reg::DATA if dlab => self.divisor as u8,
+            reg::DATA => self.rx.borrow_mut().pop_front().unwrap_or(0),
+            reg::IER if dlab => (self.divisor >> 8) as u8,
+            reg::IER => self.ier,
+            reg::IIR => {
+                let fifo = match self.fifo_enabled {
+                    true => IIR_FIFO,
+                    false => 0,
+                };
+                match self.interrupt() {
+                    Some(interrupt) => {
+                        // Identifying the transmitter's interrupt clears it.
+                        if interrupt == IIR_THRI {
+                            self.thr_empty.set(false);
+                        }
+                        fifo | interrupt
+                    },
+                    None => fifo | IIR_NO_INTERRUPT,
+                }
+            },
+            reg::LCR => self.lcr,
+            reg::MCR => self.mcr,
+            reg::LSR => self.line_status(),
+            reg::MSR => MSR_CONNECTED,
+            reg::SCR => self.scr,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, _dma: &mut Dma) -> Result<(), Trap> {
+        if size != Size::Byte {
+            return Err(Trap::StoreAccessFault);
+        }
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            reg::DATA if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            reg::DATA => {
+                // Output which the host can't take is dropped, as it would be by a serial line.
+                let _ = self.output.write_all(&[value]).and_then(|_| self.output.flush());
+                self.thr_empty.set(true);
+            },
+            reg::IER if dlab => self.divisor = (self.divisor & 0xff) | (value as u16) << 8,
+            reg::IER => {
+                // Enabling the transmitter's interrupt raises it straight away, as it's empty.
+                if value & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thr_empty.set(true);
+                }
+                self.ier = value & 0x0f;
+            },
+            reg::IIR => {
+                self.fifo_enabled = value & FCR_ENABLE_FIFO != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.borrow_mut().clear();
+                }
+            },
+            reg::LCR => self.lcr = value,
+            reg::MCR => self.mcr = value & 0x1f,
+            reg::SCR => self.scr = value,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("serial@{:x}", UART_BASE))
+            .property_string("compatible", "ns16550a")
+            .property_reg(&[(UART_BASE, UART_SIZE)])
+            .property_u32("interrupts", UART_IRQ)
+            .property_u32("clock-frequency", CLOCK_FREQUENCY)
+            .end_node();
+    }
+
+    fn poll(&mut self, dma: &mut Dma) {
+        let room = FIFO_DEPTH - self.rx.get_mut().len();
+        let Some(input) = self.input.as_ref().filter(|_| room > 0) else { return };
+        let data = dma.journal().poll(Source::UartRx, || {
+            let data: Vec<u8> = input.try_iter().take(room).collect();
+            (!data.is_empty()).then_some(data)
+        });
+        if let Some(data) = data {
+            self.rx.get_mut().extend(data);
+        }
+    }
+
+    fn interrupts(&self) -> u64 {
+        (self.interrupt().is_some() as u64) << UART_IRQ
+    }
+}
+
+impl Snapshotable for Uart {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let section = snapshot.section("uart");
+        let rx: Vec<u8> = self.rx.borrow().iter().copied().collect();
+        section.bytes(&rx)
+            .u8(self.ier)
+            .u8(self.lcr)
+            .u8(self.mcr)
+            .u8(self.scr)
+            .u32(self.divisor as u32)
+            .u8(self.fifo_enabled as u8)
+            .u8(self.thr_empty.get() as u8);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("uart")?;
+        *self.rx.get_mut() = section.block()?.into();
+        self.ier = section.u8()?;
+        self.lcr = section.u8()?;
+        self.mcr = section.u8()?;
+        self.scr = section.u8()?;
+        self.divisor = section.u32()? as u16;
+        self.fifo_enabled = section.u8()? != 0;
+        self.thr_empty.set(section.u8()? != 0);
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::{self, Write}, rc::Rc, sync::mpsc};
+
+    use crate::components::{
+        devices::{Device, Dma},
+        memory::{Size, DRAM},
+    };
+    use crate::replay::Journal;
+
+    use super::{reg, Uart, IER_RDI, IER_THRI, UART_IRQ};
+
+    /// Output which the test can look at after the UART has sent it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write(uart: &mut Uart, offset: u64, value: u64) {
+        let mut dram = DRAM::new(0x1000);
+        let mut journal = Journal::new();
+        uart.write(offset, Size::Byte, value, &mut Dma::new(&mut dram, &mut journal)).unwrap();
+    }
+
+    fn read(uart: &Uart, offset: u64) -> u64 {
+        uart.read(offset, Size::Byte).unwrap()
+    }
+
+    #[test]
+    fn it_passes_bytes_both_ways() {
+        let output = Shared::default();
+        let (input, rx) = mpsc::channel();
+        let mut uart = Uart::new(Some(rx), Box::new(output.clone()));
+
+        for byte in b"hi" {
+            write(&mut uart, reg::DATA, *byte as u64);
+        }
+        assert_eq!(*output.0.borrow(), b"hi");
+
+        assert_eq!(read(&uart, reg::LSR) & 1, 0);
+        input.send(b'x').unwrap();
+        input.send(b'y').unwrap();
+        uart.poll(&mut Dma::new(&mut DRAM::new(0x1000), &mut Journal::new()));
+        assert_eq!(read(&uart, reg::LSR) & 1, 1);
+        assert_eq!(read(&uart, reg::DATA), b'x' as u64);
+        assert_eq!(read(&uart, reg::DATA), b'y' as u64);
+        assert_eq!(read(&uart, reg::LSR), 0x60);
+
+        // The divisor latch takes the place of the first two registers.
+        write(&mut uart, reg::LCR, 0x83);
+        write(&mut uart, reg::DATA, 0x01);
+        write(&mut uart, reg::IER, 0x02);
+        assert_eq!(read(&uart, reg::IER), 0x02);
+        write(&mut uart, reg::LCR, 0x03);
+        assert_eq!(read(&uart, reg::IER), 0);
+        assert_eq!(*output.0.borrow(), b"hi");
+    }
+
+    #[test]
+    fn it_interrupts() {
+        let (input, rx) = mpsc::channel();
+        let mut uart = Uart::new(Some(rx), Box::new(io::sink()));
+        assert_eq!(read(&uart, reg::IIR), 0x01);
+
+        write(&mut uart, reg::IER, IER_RDI as u64);
+        input.send(b'x').unwrap();
+        uart.poll(&mut Dma::new(&mut DRAM::new(0x1000), &mut Journal::new()));
+        assert_eq!(uart.interrupts(), 1 << UART_IRQ);
+        assert_eq!(read(&uart, reg::IIR), 0x04);
+        read(&uart, reg::DATA);
+        assert_eq!(uart.interrupts(), 0);
+
+        // The transmitter is empty as soon as its interrupt is enabled, until that's identified.
+        write(&mut uart, reg::IER, (IER_RDI | IER_THRI) as u64);
+        assert_eq!(uart.interrupts(), 1 << UART_IRQ);
+        assert_eq!(read(&uart, reg::IIR), 0x02);
+        assert_eq!(uart.interrupts(), 0);
+        write(&mut uart, reg::DATA, b'a' as u64);
+        assert_eq!(uart.interrupts(), 1 << UART_IRQ);
+    }
+}
@@ -0,0 +1,344 @@
+use std::{collections::BTreeMap, fs::File, io, os::unix::fs::FileExt, path::Path};
+
+use crate::{
+    components::{cpu::Trap, devices::Dma},
+    replay::Source,
+    snapshot::{Decoder, Encoder, SnapshotError},
+};
+
+use super::{queue::Queue, VirtioDevice};
+
+/// The size of a sector, which requests are addressed in.
+pub const SECTOR_SIZE: usize = 512;
+
+const DEVICE_ID: u32 = 2;
+
+/// The device is read-only, and the driver mustn't write to it.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The device has a cache which the driver can flush.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The size of the serial number which a get-id request returns.
+const ID_SIZE: usize = 20;
+
+/// How writes from the guest reach the disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskMode {
+    /// Writes go to the image.
+    ReadWrite,
+    /// The device is read-only, and writes fail.
+    ReadOnly,
+    /// Writes go to an overlay in memory, which is dropped when the emulator exits, so that a
+    /// golden image is never changed.
+    CopyOnWrite,
+}
+
+/// A disk image on the host, which is read and written in sectors. It backs the virtio block
+/// device, and any other storage device, such as an SD card.
+#[derive(Debug)]
+pub struct Disk {
+    image: File,
+    /// The size of the disk, in sectors. A partial sector at the end of the image is left out.
+    capacity: u64,
+    mode: DiskMode,
+    /// The sectors which were written in copy-on-write mode.
+    overlay: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Disk {
+    pub fn open(path: &Path, mode: DiskMode) -> io::Result<Self> {
+        let image = File::options().read(true).write(mode == DiskMode::ReadWrite).open(path)?;
+        Self::new(image, mode)
+    }
+
+    pub fn new(image: File, mode: DiskMode) -> io::Result<Self> {
+        let capacity = image.metadata()?.len() / SECTOR_SIZE as u64;
+        Ok(Self { image, capacity, mode, overlay: BTreeMap::new() })
+    }
+
+    /// The size of the disk, in sectors.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn mode(&self) -> DiskMode {
+        self.mode
+    }
+
+    /// Reads whole sectors, preferring those in the overlay to the image.
+    pub fn read_sectors(&self, sector: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        for (i, buf) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+            let sector = sector + i as u64;
+            match self.overlay.get(&sector) {
+                Some(overlay) => buf.copy_from_slice(overlay),
+                None => self.image.read_exact_at(buf, sector * SECTOR_SIZE as u64)?,
+            }
+        }
+        Ok(data)
+    }
+
+    pub fn write_sectors(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => self.image.write_all_at(data, sector * SECTOR_SIZE as u64),
+            DiskMode::ReadOnly => Err(io::ErrorKind::PermissionDenied.into()),
+            DiskMode::CopyOnWrite => {
+                for (i, buf) in data.chunks(SECTOR_SIZE).enumerate() {
+                    self.overlay.insert(sector + i as u64, buf.to_vec());
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Checks that a transfer is of whole sectors, all of which are on the disk.
+    pub fn in_bounds(&self, sector: u64, len: usize) -> bool {
+        len.is_multiple_of(SECTOR_SIZE) && sector.checked_add((len / SECTOR_SIZE) as u64).is_some_and(|end| end <= self.capacity)
+    }
+
+    /// Makes sure that what was written has reached the image.
+    pub fn flush(&self) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => self.image.sync_data(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Saves the overlay, as the image itself isn't part of the machine.
+    pub fn save(&self, encoder: &mut Encoder) {
+        encoder.u64(self.overlay.len() as u64);
+        for (&sector, data) in &self.overlay {
+            encoder.u64(sector).bytes(data);
+        }
+    }
+
+    pub fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        self.overlay.clear();
+        for _ in 0..decoder.u64()? {
+            let (sector, data) = (decoder.u64()?, decoder.block()?);
+            if sector >= self.capacity || data.len() != SECTOR_SIZE {
+                return Err(decoder.corrupt());
+            }
+            self.overlay.insert(sector, data);
+        }
+        Ok(())
+    }
+}
+
+/// A virtio block device, backed by a disk image on the host. It has one request queue.
+#[derive(Debug)]
+pub struct Block {
+    disk: Disk,
+    id: Vec<u8>,
+}
+
+impl Block {
+    /// Opens a disk image, with the name of the file as the disk's serial number.
+    pub fn open(path: &Path, mode: DiskMode) -> io::Result<Self> {
+        let image = File::options().read(true).write(mode == DiskMode::ReadWrite).open(path)?;
+        let id = path.file_name().map_or(vec![], |name| name.as_encoded_bytes().to_vec());
+        Self::new(image, &id, mode)
+    }
+
+    pub fn new(image: File, id: &[u8], mode: DiskMode) -> io::Result<Self> {
+        let id = id[..id.len().min(ID_SIZE)].to_vec();
+        Ok(Self { disk: Disk::new(image, mode)?, id })
+    }
+
+    /// Services a request, which starts with a header of the type, a reserved word and the
+    /// sector, and returns what to write back to the driver: any data which was read, followed by
+    /// the status.
+    fn request(&mut self, header: &[u8], data: &[u8], writable: usize, dma: &mut Dma) -> Vec<u8> {
+        let Some(header) = header.get(..16) else { return vec![VIRTIO_BLK_S_IOERR] };
+        let kind = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..].try_into().unwrap());
+        // The status byte is the last writable byte.
+        let len = writable.saturating_sub(1);
+
+        let status = match kind {
+            VIRTIO_BLK_T_IN if self.disk.in_bounds(sector, len) => {
+                // The image could change between a recording and its replay, so what was read
+                // is taken from the journal.
+                return dma.journal().input(Source::BlockCompletion, || match self.disk.read_sectors(sector, len) {
+                    Ok(mut data) => {
+                        data.push(VIRTIO_BLK_S_OK);
+                        data
+                    },
+                    Err(_) => vec![VIRTIO_BLK_S_IOERR],
+                });
+            },
+            VIRTIO_BLK_T_OUT if self.disk.in_bounds(sector, data.len()) => match self.disk.write_sectors(sector, data) {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => VIRTIO_BLK_S_IOERR,
+            VIRTIO_BLK_T_FLUSH => match self.disk.flush() {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = self.id.clone();
+                id.resize(len.min(ID_SIZE), 0);
+                id.push(VIRTIO_BLK_S_OK);
+                return id;
+            },
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        vec![status]
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        match self.disk.mode() {
+            DiskMode::ReadOnly => VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO,
+            _ => VIRTIO_BLK_F_FLUSH,
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    /// The configuration is the capacity in sectors, and the rest of it is left at zero.
+    fn config(&self) -> Vec<u8> {
+        self.disk.capacity().to_le_bytes().to_vec()
+    }
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        let queue = &mut queues[0];
+        while let Some(mut chain) = queue.pop(dma)? {
+            let request = chain.read_all(dma)?;
+            let (header, data) = request.split_at(request.len().min(16));
+            let mut response = self.request(header, data, chain.writable_len(), dma);
+
+            // A response which was cut short leaves the status in the last byte.
+            let status = response.pop().unwrap();
+            response.resize(chain.writable_len().saturating_sub(1), 0);
+            response.push(status);
+            chain.write(dma, &response)?;
+            queue.push(dma, chain)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, encoder: &mut Encoder) {
+        self.disk.save(encoder);
+    }
+
+    fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        self.disk.restore(decoder)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::{Path, PathBuf}};
+
+    use crate::components::{
+        devices::{virtio::{queue::test::Driver, test::{set_up, write}, reg, VirtioMmio}, Device},
+        memory::{Size, DRAM},
+    };
+
+    use super::{Block, DiskMode, SECTOR_SIZE, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
+
+    /// Creates a disk image of two sectors, the first of 1s and the second of 2s.
+    fn image(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rv64-{}-{}", name, std::process::id()));
+        fs::write(&path, [[1; SECTOR_SIZE], [2; SECTOR_SIZE]].concat()).unwrap();
+        path
+    }
+
+    fn header(kind: u32, sector: u64) -> Vec<u8> {
+        [kind.to_le_bytes(), [0; 4]].concat().into_iter().chain(sector.to_le_bytes()).collect()
+    }
+
+    /// Makes a request and returns the status and the data which was read.
+    fn request(mmio: &mut VirtioMmio, dram: &mut DRAM, driver: &mut Driver, readable: &[&[u8]], len: u32) -> (u8, Vec<u8>) {
+        let writable = driver.offer(dram, readable, &[len, 1]);
+        write(mmio, dram, reg::QUEUE_NOTIFY, 0);
+        assert!(mmio.interrupting());
+        write(mmio, dram, reg::INTERRUPT_ACK, 1);
+        assert_eq!(driver.take_used(dram).map(|(_, written)| written), Some(len + 1));
+
+        let mut data = vec![0; len as usize];
+        dram.read_into(writable[0], &mut data).unwrap();
+        let mut status = [0];
+        dram.read_into(writable[1], &mut status).unwrap();
+        (status[0], data)
+    }
+
+    fn attach(path: &Path, mode: DiskMode, dram: &mut DRAM) -> VirtioMmio {
+        let mut mmio = VirtioMmio::new(0, Box::new(Block::open(path, mode).unwrap()));
+        set_up(&mut mmio, dram, 1);
+        mmio
+    }
+
+    #[test]
+    fn it_reads_and_writes_sectors() {
+        let path = image("disk");
+        let mut dram = DRAM::new(0x100000);
+        let mut mmio = attach(&path, DiskMode::ReadWrite, &mut dram);
+        let mut driver = Driver::new();
+        assert_eq!(mmio.read(reg::CONFIG, Size::DoubleWord), Ok(2));
+
+        let (status, data) = request(&mut mmio, &mut dram, &mut driver, &[&header(VIRTIO_BLK_T_IN, 1)], 512);
+        assert_eq!((status, data), (VIRTIO_BLK_S_OK, vec![2; 512]));
+
+        let writable = driver.offer(&mut dram, &[&header(VIRTIO_BLK_T_OUT, 0), &[3; 512]], &[1]);
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 0);
+        assert_eq!(driver.take_used(&dram), Some((3, 1)));
+        let mut status = [0xff];
+        dram.read_into(writable[0], &mut status).unwrap();
+        assert_eq!(status, [VIRTIO_BLK_S_OK]);
+
+        let (status, data) = request(&mut mmio, &mut dram, &mut driver, &[&header(VIRTIO_BLK_T_GET_ID, 0)], 20);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert!(data.starts_with(format!("rv64-disk-{}", std::process::id()).as_bytes()));
+
+        // Reads past the end of the disk fail.
+        let (status, _) = request(&mut mmio, &mut dram, &mut driver, &[&header(VIRTIO_BLK_T_IN, 1)], 1024);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        let contents = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(contents[..512], [3; 512]);
+    }
+
+    #[test]
+    fn it_keeps_the_image_unchanged() {
+        let path = image("golden");
+        let mut dram = DRAM::new(0x100000);
+        let mut driver = Driver::new();
+
+        let mut mmio = attach(&path, DiskMode::ReadOnly, &mut dram);
+        assert_eq!(mmio.read(reg::DEVICE_FEATURES, Size::Word), Ok(1 << 5 | 1 << 9));
+        let (status, _) = request(&mut mmio, &mut dram, &mut driver, &[&header(VIRTIO_BLK_T_OUT, 0), &[3; 512]], 0);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        // Copy-on-write writes are seen by later reads, but not by the image.
+        let mut driver = Driver::new();
+        let mut mmio = attach(&path, DiskMode::CopyOnWrite, &mut dram);
+        let (status, _) = request(&mut mmio, &mut dram, &mut driver, &[&header(VIRTIO_BLK_T_OUT, 1), &[3; 512]], 0);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        let (_, data) = request(&mut mmio, &mut dram, &mut driver, &[&header(VIRTIO_BLK_T_IN, 0)], 1024);
+        assert_eq!(data, [[1; 512], [3; 512]].concat());
+
+        let contents = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(contents[512..], [2; 512]);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Write},
+    sync::mpsc::Receiver,
+};
+
+use crate::{
+    components::{cpu::Trap, devices::{stdin, Dma}},
+    replay::Source,
+    snapshot::{Decoder, Encoder, SnapshotError},
+};
+
+use super::{queue::Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 3;
+
+/// The device has more than one port, and a control queue to tell the driver about them.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+/// The queues of the control channel, after the queues of the first port.
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+/// The events of control messages, some of which go from the driver to the device, and the rest
+/// the other way.
+mod event {
+    pub const DEVICE_READY: u16 = 0;
+    pub const DEVICE_ADD: u16 = 1;
+    pub const PORT_READY: u16 = 3;
+    pub const CONSOLE_PORT: u16 = 4;
+    pub const PORT_OPEN: u16 = 6;
+    pub const PORT_NAME: u16 = 7;
+}
+
+/// A port of a console, which passes bytes between the guest and the host.
+pub struct Port {
+    /// The name which the guest finds the port by, in `/dev/virtio-ports`. A port without a
+    /// name is a console, such as `hvc0`.
+    name: Option<String>,
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write>,
+}
+
+impl fmt::Debug for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Port").field("name", &self.name).finish()
+    }
+}
+
+impl Port {
+    /// A console on the emulator's standard input and output.
+    pub fn stdio() -> Self {
+        Self { name: None, input: Some(stdin()), output: Box::new(io::stdout()) }
+    }
+
+    /// A named port, which passes input from a channel, if there is one, and writes output
+    /// to a file or anything else.
+    pub fn named(name: &str, input: Option<Receiver<u8>>, output: Box<dyn Write>) -> Self {
+        Self { name: Some(name.to_string()), input, output }
+    }
+
+    /// Takes as much waiting input as fits in a buffer.
+    fn take_input(&self, len: usize) -> Option<Vec<u8>> {
+        let input = self.input.as_ref()?;
+        let data: Vec<u8> = input.try_iter().take(len).collect();
+        (!data.is_empty()).then_some(data)
+    }
+}
+
+/// A virtio console with several ports, such as a shell on the first and a channel for test
+/// results on another, which the driver is told about through the control queues.
+#[derive(Debug)]
+pub struct Console {
+    ports: Vec<Port>,
+    /// The control messages waiting for the driver to make buffers available to them.
+    control: VecDeque<Vec<u8>>,
+}
+
+impl Console {
+    pub fn new(ports: Vec<Port>) -> Self {
+        assert!(!ports.is_empty(), "a console needs a port");
+        Self { ports, control: VecDeque::new() }
+    }
+
+    /// Gets the receive and transmit queues of a port. The control queues come between those of
+    /// the first port and the rest.
+    fn queues(port: usize) -> (usize, usize) {
+        match port {
+            0 => (0, 1),
+            _ => (2 * port + 2, 2 * port + 3),
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut message = id.to_le_bytes().to_vec();
+        message.extend(event.to_le_bytes());
+        message.extend(value.to_le_bytes());
+        message.extend_from_slice(extra);
+        self.control.push_back(message);
+    }
+
+    /// Answers a control message from the driver.
+    fn control(&mut self, message: &[u8]) {
+        if message.len() < 8 {
+            return;
+        }
+        let id = u32::from_le_bytes(message[..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+        match event {
+            event::DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() as u32 {
+                    self.send_control(id, event::DEVICE_ADD, 1, &[]);
+                }
+            },
+            event::PORT_READY if value == 1 => {
+                let Some(port) = self.ports.get(id as usize) else { return };
+                match port.name.clone() {
+                    Some(name) => self.send_control(id, event::PORT_NAME, 1, name.as_bytes()),
+                    None => self.send_control(id, event::CONSOLE_PORT, 1, &[]),
+                }
+                self.send_control(id, event::PORT_OPEN, 1, &[]);
+            },
+            _ => {},
+        }
+    }
+
+    /// Passes control messages to the driver, as far as its buffers go.
+    fn flush_control(&mut self, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        while !self.control.is_empty() {
+            let Some(mut chain) = queues[CONTROL_RX].pop(dma)? else { break };
+            chain.write(dma, &self.control.pop_front().unwrap())?;
+            queues[CONTROL_RX].push(dma, chain)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn queue_count(&self) -> usize {
+        2 * self.ports.len() + 2
+    }
+
+    /// The configuration is the size of the console, which isn't known, and the number of ports.
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 12];
+        config[4..8].copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        if queue == CONTROL_TX {
+            while let Some(chain) = queues[CONTROL_TX].pop(dma)? {
+                self.control(&chain.read_all(dma)?);
+                queues[CONTROL_TX].push(dma, chain)?;
+            }
+        }
+        for (i, port) in self.ports.iter_mut().enumerate() {
+            let (_, tx) = Self::queues(i);
+            if queue != tx {
+                continue;
+            }
+            while let Some(chain) = queues[tx].pop(dma)? {
+                // Output which the host can't take is dropped, as it would be by a serial line.
+                let _ = port.output.write_all(&chain.read_all(dma)?).and_then(|_| port.output.flush());
+                queues[tx].push(dma, chain)?;
+            }
+        }
+        self.flush_control(queues, dma)
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        for (i, port) in self.ports.iter().enumerate() {
+            let (rx, _) = Self::queues(i);
+            if port.input.is_none() || !queues[rx].has_available(dma)? {
+                continue;
+            }
+            let mut chain = queues[rx].pop(dma)?.unwrap();
+            let len = chain.writable_len();
+            if let Some(data) = dma.journal().poll(Source::UartRx, || port.take_input(len)) {
+                chain.write(dma, &data)?;
+                queues[rx].push(dma, chain)?;
+            } else {
+                // Nothing was waiting, so the buffer is left for the next poll.
+                queues[rx].put_back();
+            }
+        }
+        self.flush_control(queues, dma)
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+    }
+
+    fn save(&self, encoder: &mut Encoder) {
+        encoder.u64(self.control.len() as u64);
+        for message in &self.control {
+            encoder.bytes(message);
+        }
+    }
+
+    fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        let len = decoder.u64()?;
+        self.control = (0..len).map(|_| decoder.block()).collect::<Result<_, _>>()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::{self, Write}, rc::Rc, sync::mpsc};
+
+    use crate::components::{
+        devices::{virtio::{queue::test::Driver, reg, test::{set_up, write}, VirtioMmio}, Device, Dma},
+        memory::{Size, DRAM},
+    };
+    use crate::replay::Journal;
+
+    use super::{event, Console, Port};
+
+    /// Output which the test can look at after the console has taken it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A driver for each queue, whose rings are laid out 64 KiB apart as `set_up` does.
+    fn drivers(count: usize) -> Vec<Driver> {
+        (0..count).map(|i| Driver::at(i as u64 * 0x10000)).collect()
+    }
+
+    fn message(id: u32, event: u16, value: u16) -> Vec<u8> {
+        [id.to_le_bytes().to_vec(), event.to_le_bytes().to_vec(), value.to_le_bytes().to_vec()].concat()
+    }
+
+    #[test]
+    fn it_tells_the_driver_about_its_ports() {
+        let results = Shared::default();
+        let (input, rx) = mpsc::channel();
+        let console = Console::new(vec![
+            Port::named("shell", Some(rx), Box::new(io::sink())),
+            Port::named("results", None, Box::new(results.clone())),
+        ]);
+        let mut dram = DRAM::new(0x100000);
+        let mut mmio = VirtioMmio::new(0, Box::new(console));
+        set_up(&mut mmio, &mut dram, 6);
+        assert_eq!(mmio.read(reg::CONFIG + 4, Size::Word), Ok(2));
+        let mut drivers = drivers(6);
+
+        // The device adds its ports once the driver is ready, and names them once they are.
+        let control_rx: Vec<u64> = (0..5).map(|_| drivers[2].offer(&mut dram, &[], &[64])[0]).collect();
+        drivers[3].offer(&mut dram, &[&message(0, event::DEVICE_READY, 1)], &[]);
+        drivers[3].offer(&mut dram, &[&message(1, event::PORT_READY, 1)], &[]);
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 3);
+        let mut sent = vec![];
+        while let Some((_, len)) = drivers[2].take_used(&dram) {
+            let mut buf = vec![0; len as usize];
+            dram.read_into(control_rx[sent.len()], &mut buf).unwrap();
+            sent.push(buf);
+        }
+        assert_eq!(sent, [
+            message(0, event::DEVICE_ADD, 1),
+            message(1, event::DEVICE_ADD, 1),
+            [message(1, event::PORT_NAME, 1), b"results".to_vec()].concat(),
+            message(1, event::PORT_OPEN, 1),
+        ]);
+
+        // Output on the second port goes to its own place.
+        drivers[5].offer(&mut dram, &[b"PASS\n"], &[]);
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 5);
+        assert_eq!(*results.0.borrow(), b"PASS\n");
+
+        // Input is passed on when the devices are polled.
+        let rx_buffer = drivers[0].offer(&mut dram, &[], &[16])[0];
+        let mut journal = Journal::new();
+        mmio.poll(&mut Dma::new(&mut dram, &mut journal));
+        assert_eq!(drivers[0].take_used(&dram), None);
+        input.send(b'l').unwrap();
+        input.send(b's').unwrap();
+        mmio.poll(&mut Dma::new(&mut dram, &mut journal));
+        assert_eq!(drivers[0].take_used(&dram), Some((0, 2)));
+        let mut buf = [0; 2];
+        dram.read_into(rx_buffer, &mut buf).unwrap();
+        assert_eq!(&buf, b"ls");
+    }
+}
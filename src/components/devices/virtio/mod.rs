@@ -0,0 +1,485 @@
+use std::fmt;
+
+use crate::{
+    components::{cpu::Trap, memory::{from_le, Size}},
+    fdt::FdtWriter,
+    snapshot::{Decoder, Encoder, SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable},
+};
+
+use self::queue::{Queue, MAX_SIZE};
+
+use super::{Device, Dma};
+
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod p9;
+pub mod queue;
+pub mod rng;
+
+pub use self::block::Block;
+pub use self::console::Console;
+pub use self::net::Net;
+pub use self::p9::P9;
+pub use self::rng::Rng;
+
+/// The address of the first virtio-mmio slot, as on QEMU's virt machine.
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+/// The size of each slot, which are laid out one after the other.
+pub const VIRTIO_SIZE: u64 = 0x1000;
+/// The number of slots for virtio devices.
+pub const VIRTIO_SLOTS: usize = 8;
+/// The interrupt of the first slot, after which each slot has the next.
+pub const VIRTIO_IRQ: u32 = 1;
+
+/// "virt", little-endian.
+const MAGIC: u32 = 0x7472_6976;
+/// The modern virtio-mmio layout, which legacy drivers don't understand.
+const VERSION: u32 = 2;
+/// "QEMU", which drivers which work around quirks of particular devices look for.
+const VENDOR_ID: u32 = 0x554d_4551;
+
+/// The device complies with version 1 of the virtio spec, rather than being a legacy device.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The registers of the transport, as offsets from its base.
+mod reg {
+    pub const MAGIC: u64 = 0x000;
+    pub const VERSION: u64 = 0x004;
+    pub const DEVICE_ID: u64 = 0x008;
+    pub const VENDOR_ID: u64 = 0x00c;
+    pub const DEVICE_FEATURES: u64 = 0x010;
+    pub const DEVICE_FEATURES_SEL: u64 = 0x014;
+    pub const DRIVER_FEATURES: u64 = 0x020;
+    pub const DRIVER_FEATURES_SEL: u64 = 0x024;
+    pub const QUEUE_SEL: u64 = 0x030;
+    pub const QUEUE_NUM_MAX: u64 = 0x034;
+    pub const QUEUE_NUM: u64 = 0x038;
+    pub const QUEUE_READY: u64 = 0x044;
+    pub const QUEUE_NOTIFY: u64 = 0x050;
+    pub const INTERRUPT_STATUS: u64 = 0x060;
+    pub const INTERRUPT_ACK: u64 = 0x064;
+    pub const STATUS: u64 = 0x070;
+    pub const QUEUE_DESC_LOW: u64 = 0x080;
+    pub const QUEUE_DESC_HIGH: u64 = 0x084;
+    pub const QUEUE_DRIVER_LOW: u64 = 0x090;
+    pub const QUEUE_DRIVER_HIGH: u64 = 0x094;
+    pub const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+    pub const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+    pub const CONFIG_GENERATION: u64 = 0x0fc;
+    pub const CONFIG: u64 = 0x100;
+}
+
+/// The device has returned buffers on one of its queues.
+const INTERRUPT_USED_BUFFER: u32 = 1;
+/// The device's configuration has changed, or it has hit an error.
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// The driver has set the device up, and is ready to drive it.
+const STATUS_DRIVER_OK: u32 = 4;
+/// The device hit an error which it can't recover from until the driver resets it, such as a
+/// descriptor which points outside of memory.
+const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+
+/// A virtio device, such as a disk, which is attached to the machine through a transport.
+pub trait VirtioDevice: fmt::Debug {
+    /// The kind of device, such as 2 for a block device.
+    fn device_id(&self) -> u32;
+
+    /// The feature bits which the device offers, besides `VIRTIO_F_VERSION_1`.
+    fn features(&self) -> u64;
+
+    fn queue_count(&self) -> usize;
+
+    /// The device-specific configuration space.
+    fn config(&self) -> Vec<u8>;
+
+    /// Services the buffers which the driver has made available on a queue, with access to all
+    /// of the device's queues so that it can answer on another one.
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap>;
+
+    /// Checks for input from the host, which is passed to the driver on one of the queues.
+    fn poll(&mut self, _queues: &mut [Queue], _dma: &mut Dma) -> Result<(), Trap> {
+        Ok(())
+    }
+
+    /// Returns the device to its initial state, when the driver resets it.
+    fn reset(&mut self) {}
+
+    /// Saves the device's own state, after the transport's.
+    fn save(&self, _encoder: &mut Encoder) {}
+
+    fn restore(&mut self, _decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+/// The virtio-mmio transport, version 2, which puts a virtio device in a slot of registers on
+/// the bus. Notifications are serviced as soon as the driver writes them.
+#[derive(Debug)]
+pub struct VirtioMmio {
+    base: u64,
+    irq: u32,
+    device: Box<dyn VirtioDevice>,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+    status: u32,
+}
+
+impl VirtioMmio {
+    /// Puts a device in one of the virtio-mmio slots.
+    pub fn new(slot: usize, device: Box<dyn VirtioDevice>) -> Self {
+        assert!(slot < VIRTIO_SLOTS, "there are only {} virtio slots", VIRTIO_SLOTS);
+        let queues = vec![Queue::default(); device.queue_count()];
+        Self {
+            base: VIRTIO_BASE + slot as u64 * VIRTIO_SIZE,
+            irq: VIRTIO_IRQ + slot as u32,
+            device,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            status: 0,
+        }
+    }
+
+    /// Indicates if the device is waiting for the driver to acknowledge an interrupt.
+    pub fn interrupting(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn queue(&self) -> Option<&Queue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    fn queue_mut(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queues.fill(Queue::default());
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device.reset();
+    }
+
+    fn notify(&mut self, queue: usize, dma: &mut Dma) {
+        if queue < self.queues.len() {
+            self.service(dma, |device, queues, dma| device.notify(queue, queues, dma));
+        }
+    }
+
+    /// Lets the device service its queues, and interrupts if it returned any buffers.
+    fn service(&mut self, dma: &mut Dma, f: impl FnOnce(&mut dyn VirtioDevice, &mut [Queue], &mut Dma) -> Result<(), Trap>) {
+        // A device which needs resetting does nothing until it is.
+        if self.status & STATUS_DEVICE_NEEDS_RESET != 0 {
+            return;
+        }
+        let before = self.queues.clone();
+        match f(self.device.as_mut(), &mut self.queues, dma) {
+            Ok(()) if self.queues != before => self.interrupt_status |= INTERRUPT_USED_BUFFER,
+            Ok(()) => {},
+            Err(_) => {
+                self.status |= STATUS_DEVICE_NEEDS_RESET;
+                self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+            },
+        }
+    }
+
+    /// Reads a 32-bit register.
+    fn read_register(&self, offset: u64) -> u32 {
+        let half = |value: u64, high: bool| if high { (value >> 32) as u32 } else { value as u32 };
+        match offset {
+            reg::MAGIC => MAGIC,
+            reg::VERSION => VERSION,
+            reg::DEVICE_ID => self.device.device_id(),
+            reg::VENDOR_ID => VENDOR_ID,
+            reg::DEVICE_FEATURES => match self.device_features_sel {
+                0 | 1 => half(self.features(), self.device_features_sel == 1),
+                _ => 0,
+            },
+            reg::QUEUE_NUM_MAX => self.queue().map_or(0, |_| MAX_SIZE as u32),
+            reg::QUEUE_NUM => self.queue().map_or(0, |q| q.size as u32),
+            reg::QUEUE_READY => self.queue().map_or(0, |q| q.ready as u32),
+            reg::INTERRUPT_STATUS => self.interrupt_status,
+            reg::STATUS => self.status,
+            reg::QUEUE_DESC_LOW | reg::QUEUE_DESC_HIGH => self.queue().map_or(0, |q| half(q.desc, offset == reg::QUEUE_DESC_HIGH)),
+            reg::QUEUE_DRIVER_LOW | reg::QUEUE_DRIVER_HIGH => self.queue().map_or(0, |q| half(q.driver, offset == reg::QUEUE_DRIVER_HIGH)),
+            reg::QUEUE_DEVICE_LOW | reg::QUEUE_DEVICE_HIGH => self.queue().map_or(0, |q| half(q.device, offset == reg::QUEUE_DEVICE_HIGH)),
+            // The configuration only changes when the device is reset.
+            reg::CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    /// Writes a 32-bit register.
+    fn write_register(&mut self, offset: u64, value: u32, dma: &mut Dma) {
+        let set_half = |field: &mut u64, high: bool| match high {
+            true => *field = (*field & 0xffff_ffff) | (value as u64) << 32,
+            false => *field = (*field & !0xffff_ffff) | value as u64,
+        };
+        match offset {
+            reg::DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            reg::DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            reg::DRIVER_FEATURES => match self.driver_features_sel {
+                0 | 1 => set_half(&mut self.driver_features, self.driver_features_sel == 1),
+                _ => {},
+            },
+            reg::QUEUE_SEL => self.queue_sel = value,
+            reg::QUEUE_NUM => if let Some(queue) = self.queue_mut() {
+                queue.size = (value as u16).min(MAX_SIZE);
+            },
+            reg::QUEUE_READY => if let Some(queue) = self.queue_mut() {
+                queue.ready = value & 1 != 0;
+            },
+            reg::QUEUE_NOTIFY => self.notify(value as usize, dma),
+            reg::INTERRUPT_ACK => self.interrupt_status &= !value,
+            reg::STATUS if value == 0 => self.reset(),
+            reg::STATUS => self.status = value,
+            reg::QUEUE_DESC_LOW | reg::QUEUE_DESC_HIGH => if let Some(queue) = self.queue_mut() {
+                set_half(&mut queue.desc, offset == reg::QUEUE_DESC_HIGH);
+            },
+            reg::QUEUE_DRIVER_LOW | reg::QUEUE_DRIVER_HIGH => if let Some(queue) = self.queue_mut() {
+                set_half(&mut queue.driver, offset == reg::QUEUE_DRIVER_HIGH);
+            },
+            reg::QUEUE_DEVICE_LOW | reg::QUEUE_DEVICE_HIGH => if let Some(queue) = self.queue_mut() {
+                set_half(&mut queue.device, offset == reg::QUEUE_DEVICE_HIGH);
+            },
+            _ => {},
+        }
+    }
+
+    fn section(&self) -> String {
+        format!("virtio@{:x}", self.base)
+    }
+}
+
+impl Device for VirtioMmio {
+    fn base(&self) -> u64 {
+        self.base
+    }
+
+    fn size(&self) -> u64 {
+        VIRTIO_SIZE
+    }
+
+    /// The registers are 32 bits wide, but the configuration space can be read a byte at a time.
+    fn read(&self, offset: u64, size: Size) -> Result<u64, Trap> {
+        if offset >= reg::CONFIG {
+            let config = self.device.config();
+            let start = (offset - reg::CONFIG) as usize;
+            return match config.get(start..start + size as usize) {
+                Some(bytes) => Ok(from_le(bytes)),
+                None => Ok(0),
+            };
+        }
+        match size {
+            Size::Word => Ok(self.read_register(offset) as u64),
+            _ => Err(Trap::LoadAccessFault),
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: Size, value: u64, dma: &mut Dma) -> Result<(), Trap> {
+        match size {
+            // The configuration spaces of the devices are read-only.
+            _ if offset >= reg::CONFIG => Ok(()),
+            Size::Word => {
+                self.write_register(offset, value as u32, dma);
+                Ok(())
+            },
+            _ => Err(Trap::StoreAccessFault),
+        }
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", self.base))
+            .property_string("compatible", "virtio,mmio")
+            .property_reg(&[(self.base, VIRTIO_SIZE)])
+            .property_u32("interrupts", self.irq)
+            .end_node();
+    }
+
+    fn poll(&mut self, dma: &mut Dma) {
+        // The driver has to have set the device up before anything can be passed to it.
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.service(dma, |device, queues, dma| device.poll(queues, dma));
+        }
+    }
+
+    fn interrupts(&self) -> u64 {
+        (self.interrupting() as u64) << self.irq
+    }
+}
+
+impl Snapshotable for VirtioMmio {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let section = snapshot.section(&self.section());
+        section.u32(self.device_features_sel)
+            .u32(self.driver_features_sel)
+            .u64(self.driver_features)
+            .u32(self.queue_sel)
+            .u32(self.interrupt_status)
+            .u32(self.status);
+        for queue in &self.queues {
+            queue.save(section);
+        }
+        self.device.save(section);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section(&self.section())?;
+        self.device_features_sel = section.u32()?;
+        self.driver_features_sel = section.u32()?;
+        self.driver_features = section.u64()?;
+        self.queue_sel = section.u32()?;
+        self.interrupt_status = section.u32()?;
+        self.status = section.u32()?;
+        for queue in self.queues.iter_mut() {
+            *queue = Queue::restore(&mut section)?;
+        }
+        self.device.restore(&mut section)?;
+        section.finish()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::components::{cpu::Trap, devices::{Device, Dma}, memory::{Size, DRAM}};
+    use crate::replay::Journal;
+    use crate::snapshot::{SnapshotReader, SnapshotWriter, Snapshotable};
+
+    use super::queue::{test::{Driver, DESC, DEVICE, DRIVER}, Queue};
+    use super::{reg, VirtioDevice, VirtioMmio, MAGIC, STATUS_DEVICE_NEEDS_RESET, VIRTIO_F_VERSION_1, VIRTIO_IRQ};
+
+    /// A device which answers every request with nothing.
+    #[derive(Debug)]
+    struct Null;
+
+    impl VirtioDevice for Null {
+        fn device_id(&self) -> u32 {
+            42
+        }
+
+        fn features(&self) -> u64 {
+            1
+        }
+
+        fn queue_count(&self) -> usize {
+            1
+        }
+
+        fn config(&self) -> Vec<u8> {
+            vec![1, 2, 3, 4]
+        }
+
+        fn notify(&mut self, _queue: usize, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+            while let Some(chain) = queues[0].pop(dma)? {
+                queues[0].push(dma, chain)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Writes a register of a transport.
+    pub fn write(mmio: &mut VirtioMmio, dram: &mut DRAM, offset: u64, value: u32) {
+        let mut journal = Journal::new();
+        mmio.write(offset, Size::Word, value as u64, &mut Dma::new(dram, &mut journal)).unwrap();
+    }
+
+    /// Brings a device up as a driver would, with its first queues at the rings which the test
+    /// driver lays out, and the other queues following them.
+    pub fn set_up(mmio: &mut VirtioMmio, dram: &mut DRAM, queues: u32) {
+        write(mmio, dram, reg::STATUS, 1 | 2);
+        write(mmio, dram, reg::DRIVER_FEATURES_SEL, 1);
+        write(mmio, dram, reg::DRIVER_FEATURES, 1);
+        write(mmio, dram, reg::STATUS, 1 | 2 | 8);
+        for queue in 0..queues {
+            let offset = queue as u64 * 0x10000;
+            write(mmio, dram, reg::QUEUE_SEL, queue);
+            write(mmio, dram, reg::QUEUE_NUM, 8);
+            write(mmio, dram, reg::QUEUE_DESC_LOW, (DESC + offset) as u32);
+            write(mmio, dram, reg::QUEUE_DRIVER_LOW, (DRIVER + offset) as u32);
+            write(mmio, dram, reg::QUEUE_DEVICE_LOW, (DEVICE + offset) as u32);
+            write(mmio, dram, reg::QUEUE_READY, 1);
+        }
+        write(mmio, dram, reg::STATUS, 1 | 2 | 4 | 8);
+    }
+
+    #[test]
+    fn it_negotiates_with_the_driver() {
+        let mut dram = DRAM::new(0x100000);
+        let mut mmio = VirtioMmio::new(1, Box::new(Null));
+        assert_eq!(mmio.base(), 0x1000_2000);
+        assert_eq!(mmio.read(reg::MAGIC, Size::Word), Ok(MAGIC as u64));
+        assert_eq!(mmio.read(reg::VERSION, Size::Word), Ok(2));
+        assert_eq!(mmio.read(reg::DEVICE_ID, Size::Word), Ok(42));
+        assert!(mmio.read(reg::MAGIC, Size::Byte).is_err());
+
+        assert_eq!(mmio.read(reg::DEVICE_FEATURES, Size::Word), Ok(1));
+        write(&mut mmio, &mut dram, reg::DEVICE_FEATURES_SEL, 1);
+        assert_eq!(mmio.read(reg::DEVICE_FEATURES, Size::Word), Ok(VIRTIO_F_VERSION_1 >> 32));
+        assert_eq!(mmio.read(reg::CONFIG + 1, Size::HalfWord), Ok(0x0302));
+        assert_eq!(mmio.read(reg::CONFIG + 4, Size::Byte), Ok(0));
+
+        set_up(&mut mmio, &mut dram, 1);
+        assert_eq!(mmio.read(reg::QUEUE_NUM, Size::Word), Ok(8));
+        assert_eq!(mmio.read(reg::QUEUE_DESC_LOW, Size::Word), Ok(DESC & 0xffff_ffff));
+        assert_eq!(mmio.read(reg::STATUS, Size::Word), Ok(15));
+
+        // Snapshots carry the queues.
+        let mut snapshot = SnapshotWriter::new();
+        mmio.save(&mut snapshot);
+        let mut restored = VirtioMmio::new(1, Box::new(Null));
+        let mut data = vec![];
+        snapshot.write_to(&mut data).unwrap();
+        restored.restore(&mut SnapshotReader::read_from(&data[..]).unwrap()).unwrap();
+        assert_eq!(restored.queues, mmio.queues);
+
+        // Writing zero to the status resets the device.
+        write(&mut mmio, &mut dram, reg::STATUS, 0);
+        assert_eq!(mmio.read(reg::QUEUE_NUM, Size::Word), Ok(0));
+        assert_eq!(mmio.read(reg::STATUS, Size::Word), Ok(0));
+        assert!(!mmio.interrupting());
+    }
+
+    #[test]
+    fn it_interrupts_when_buffers_are_used() {
+        let mut dram = DRAM::new(0x100000);
+        let mut mmio = VirtioMmio::new(0, Box::new(Null));
+        set_up(&mut mmio, &mut dram, 1);
+        let mut driver = Driver::new();
+
+        // Notifying a queue with nothing on it doesn't interrupt.
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 0);
+        assert!(!mmio.interrupting());
+        driver.offer(&mut dram, &[b"request"], &[]);
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 0);
+        assert_eq!(mmio.read(reg::INTERRUPT_STATUS, Size::Word), Ok(1));
+        assert_eq!(mmio.interrupts(), 1 << VIRTIO_IRQ);
+        assert_eq!(driver.take_used(&dram), Some((0, 0)));
+        write(&mut mmio, &mut dram, reg::INTERRUPT_ACK, 1);
+        assert!(!mmio.interrupting());
+        assert_eq!(mmio.interrupts(), 0);
+
+        // A descriptor table outside of memory needs the device to be reset.
+        write(&mut mmio, &mut dram, reg::QUEUE_DESC_LOW, 0);
+        driver.offer(&mut dram, &[b"request"], &[]);
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 0);
+        assert_eq!(mmio.read(reg::INTERRUPT_STATUS, Size::Word), Ok(2));
+        assert_eq!(mmio.read(reg::STATUS, Size::Word), Ok(15 | STATUS_DEVICE_NEEDS_RESET as u64));
+    }
+}
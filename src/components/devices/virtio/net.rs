@@ -0,0 +1,349 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs,
+    io::{self, ErrorKind, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    components::{cpu::Trap, devices::Dma},
+    replay::Source,
+    snapshot::{Decoder, Encoder, SnapshotError},
+};
+
+use super::{queue::Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 1;
+
+/// The device has a MAC address, in its configuration.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+/// The size of the header in front of every frame, which says nothing when no offloads are
+/// offered, except that each received frame takes one buffer.
+const HEADER_SIZE: usize = 12;
+
+/// The most frames which are held for the driver to make buffers available to, after which more
+/// are dropped, as a network card would.
+const MAX_PENDING: usize = 256;
+
+/// The largest Ethernet frame which a backend receives.
+const MAX_FRAME: usize = 65536;
+
+/// The MAC address of the first network device on QEMU.
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// Where the Ethernet frames which the guest sends go, and where the ones it receives come from.
+/// Backends take no privileges on the host, so they can run in CI.
+pub trait Backend: fmt::Debug {
+    fn send(&mut self, frame: &[u8]);
+
+    /// Takes a frame which is waiting to be received, if there is one.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+
+    /// Indicates if received frames come from outside the emulator, and so have to go through
+    /// the journal to be replayed.
+    fn is_external(&self) -> bool {
+        true
+    }
+}
+
+/// Sends every frame straight back to the guest.
+#[derive(Debug, Default)]
+pub struct Loopback {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Backend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        self.frames.push_back(frame.to_vec());
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+
+    /// The echoes are taken as soon as the frames are sent, so they are replayed by sending the
+    /// frames again.
+    fn is_external(&self) -> bool {
+        false
+    }
+}
+
+/// Captures the frames which the guest sends to a pcap file, which Wireshark and tcpdump read.
+/// Nothing is ever received.
+pub struct Pcap {
+    out: Box<dyn Write>,
+}
+
+impl fmt::Debug for Pcap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pcap").finish()
+    }
+}
+
+impl Pcap {
+    /// Writes the header of the capture: the version, the time zone and accuracy of the
+    /// timestamps, the most bytes of each frame which are kept, and the link type, Ethernet.
+    pub fn new(mut out: Box<dyn Write>) -> io::Result<Self> {
+        let mut header = 0xa1b2_c3d4u32.to_le_bytes().to_vec();
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        for word in [0, 0, MAX_FRAME as u32, 1] {
+            header.extend(word.to_le_bytes());
+        }
+        out.write_all(&header)?;
+        Ok(Self { out })
+    }
+}
+
+impl Backend for Pcap {
+    fn send(&mut self, frame: &[u8]) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let len = frame.len() as u32;
+        let record = [time.as_secs() as u32, time.subsec_micros(), len, len];
+        let record: Vec<u8> = record.iter().flat_map(|word| word.to_le_bytes()).collect();
+        if let Err(e) = self.out.write_all(&record).and_then(|_| self.out.write_all(frame)).and_then(|_| self.out.flush()) {
+            eprintln!("failed to capture a frame: {}", e);
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Passes frames over a Unix datagram socket, a frame to a datagram, so that two emulators on the
+/// same host can be connected by giving each the other's socket as its peer.
+#[derive(Debug)]
+pub struct Socket {
+    socket: UnixDatagram,
+    peer: PathBuf,
+}
+
+impl Socket {
+    /// Binds a socket at a path, replacing any socket which a previous run left there.
+    pub fn bind(path: &Path, peer: &Path) -> io::Result<Self> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {},
+        }
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, peer: peer.to_path_buf() })
+    }
+}
+
+impl Backend for Socket {
+    /// Frames are dropped while the peer isn't there, as they would be on an unplugged cable.
+    fn send(&mut self, frame: &[u8]) {
+        let _ = self.socket.send_to(frame, &self.peer);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let mut frame = vec![0; MAX_FRAME];
+        let len = self.socket.recv(&mut frame).ok()?;
+        frame.truncate(len);
+        Some(frame)
+    }
+}
+
+/// A virtio network device, with a receive queue and a transmit queue, which passes Ethernet
+/// frames to and from a backend.
+#[derive(Debug)]
+pub struct Net {
+    mac: [u8; 6],
+    backend: Box<dyn Backend>,
+    /// The frames which were received, waiting for the driver to make buffers available.
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Net {
+    pub fn new(mac: [u8; 6], backend: Box<dyn Backend>) -> Self {
+        Self { mac, backend, pending: VecDeque::new() }
+    }
+
+    /// Takes the frames which the backend has received.
+    fn receive(&mut self, dma: &mut Dma) {
+        loop {
+            let frame = match self.backend.is_external() {
+                true => dma.journal().poll(Source::Packet, || self.backend.recv()),
+                false => self.backend.recv(),
+            };
+            let Some(frame) = frame else { break };
+            if self.pending.len() < MAX_PENDING {
+                self.pending.push_back(frame);
+            }
+        }
+    }
+
+    /// Passes received frames to the driver, as far as its buffers go.
+    fn deliver(&mut self, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        while !self.pending.is_empty() {
+            let Some(mut chain) = queues[RX].pop(dma)? else { break };
+            let mut header = [0; HEADER_SIZE];
+            header[10..].copy_from_slice(&1u16.to_le_bytes());
+            chain.write(dma, &header)?;
+            chain.write(dma, &self.pending.pop_front().unwrap())?;
+            queues[RX].push(dma, chain)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.mac.to_vec()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        if queue == TX {
+            while let Some(chain) = queues[TX].pop(dma)? {
+                let packet = chain.read_all(dma)?;
+                if let Some(frame) = packet.get(HEADER_SIZE..) {
+                    self.backend.send(frame);
+                }
+                queues[TX].push(dma, chain)?;
+            }
+            // A loopback answers straight away.
+            self.receive(dma);
+        }
+        self.deliver(queues, dma)
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        self.receive(dma);
+        self.deliver(queues, dma)
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    fn save(&self, encoder: &mut Encoder) {
+        encoder.u64(self.pending.len() as u64);
+        for frame in &self.pending {
+            encoder.bytes(frame);
+        }
+    }
+
+    fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        let len = decoder.u64()?;
+        self.pending = (0..len).map(|_| decoder.block()).collect::<Result<_, _>>()?;
+        Ok(())
+    }
+}
+
+/// Parses a MAC address written as six hex bytes separated by colons.
+pub fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let bytes: Vec<u8> = value.split(':').map(|b| u8::from_str_radix(b, 16).ok()).collect::<Option<_>>()?;
+    bytes.try_into().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, fs, io::{self, Write}, rc::Rc};
+
+    use crate::components::{
+        devices::virtio::{queue::test::Driver, reg, test::{set_up, write}, VirtioMmio},
+        devices::Device,
+        memory::{Size, DRAM},
+    };
+
+    use super::{parse_mac, Backend, Loopback, Net, Pcap, Socket, DEFAULT_MAC, HEADER_SIZE};
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_echoes_frames() {
+        let mut dram = DRAM::new(0x100000);
+        let mut mmio = VirtioMmio::new(0, Box::new(Net::new(DEFAULT_MAC, Box::new(Loopback::default()))));
+        set_up(&mut mmio, &mut dram, 2);
+        assert_eq!(mmio.read(reg::CONFIG + 5, Size::Byte), Ok(0x56));
+        let (mut rx, mut tx) = (Driver::at(0), Driver::at(0x10000));
+
+        // The echo waits for a receive buffer.
+        let frame = [[0xff; 12].to_vec(), b"\x08\x00hello".to_vec()].concat();
+        tx.offer(&mut dram, &[&[0; HEADER_SIZE], &frame], &[]);
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 1);
+        assert_eq!(tx.take_used(&dram), Some((0, 0)));
+        assert_eq!(rx.take_used(&dram), None);
+
+        let buffer = rx.offer(&mut dram, &[], &[1514])[0];
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 0);
+        assert_eq!(rx.take_used(&dram), Some((0, (HEADER_SIZE + frame.len()) as u32)));
+        let mut packet = vec![0; HEADER_SIZE + frame.len()];
+        dram.read_into(buffer, &mut packet).unwrap();
+        // The frame takes one buffer.
+        assert_eq!(packet[10..12], [1, 0]);
+        assert_eq!(packet[HEADER_SIZE..], frame);
+    }
+
+    #[test]
+    fn it_captures_frames() {
+        let capture = Shared::default();
+        let mut pcap = Pcap::new(Box::new(capture.clone())).unwrap();
+        pcap.send(b"frame");
+        assert_eq!(pcap.recv(), None);
+
+        let capture = capture.0.borrow();
+        assert_eq!(capture[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(capture[4..8], [2, 0, 4, 0]);
+        assert_eq!(capture[20..24], [1, 0, 0, 0]);
+        assert_eq!(capture[32..40], [5, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(capture[40..], *b"frame");
+    }
+
+    #[test]
+    fn it_connects_through_sockets() {
+        let dir = std::env::temp_dir();
+        let (a, b) = (dir.join(format!("rv64-net-a-{}", std::process::id())), dir.join(format!("rv64-net-b-{}", std::process::id())));
+        let mut first = Socket::bind(&a, &b).unwrap();
+        // Frames to a peer which isn't there are dropped.
+        first.send(b"lost");
+        let mut second = Socket::bind(&b, &a).unwrap();
+
+        assert_eq!(second.recv(), None);
+        first.send(b"frame");
+        assert_eq!(second.recv(), Some(b"frame".to_vec()));
+        second.send(b"reply");
+        assert_eq!(first.recv(), Some(b"reply".to_vec()));
+        let _ = (fs::remove_file(&a), fs::remove_file(&b));
+    }
+
+    #[test]
+    fn it_parses_mac_addresses() {
+        assert_eq!(parse_mac("52:54:00:12:34:57"), Some([0x52, 0x54, 0, 0x12, 0x34, 0x57]));
+        assert_eq!(parse_mac("52:54:00:12:34"), None);
+        assert_eq!(parse_mac("52:54:00:12:34:zz"), None);
+    }
+}
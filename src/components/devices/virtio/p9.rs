@@ -0,0 +1,771 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, File, Metadata, OpenOptions},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    components::{cpu::Trap, devices::Dma},
+    replay::Source,
+    snapshot::{Decoder, Encoder, SnapshotError},
+    syscall::{errno::*, files::{host_errno, HostPath, Root}},
+};
+
+use super::{queue::Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 9;
+
+/// The device has a tag, in its configuration, which the guest mounts it by.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+/// The protocol which is spoken, the Linux dialect of 9P2000.
+const VERSION: &str = "9P2000.L";
+/// The largest message which is sent or received.
+const MAX_MSIZE: u32 = 512 * 1024;
+/// The size of the header of every message: the size, the type and the tag.
+const HEADER_SIZE: usize = 7;
+
+/// The types of the requests, each of whose replies is the next type.
+mod t {
+    pub const LERROR: u8 = 7;
+    pub const STATFS: u8 = 8;
+    pub const LOPEN: u8 = 12;
+    pub const LCREATE: u8 = 14;
+    pub const SYMLINK: u8 = 16;
+    pub const READLINK: u8 = 22;
+    pub const GETATTR: u8 = 24;
+    pub const SETATTR: u8 = 26;
+    pub const XATTRWALK: u8 = 30;
+    pub const READDIR: u8 = 40;
+    pub const FSYNC: u8 = 50;
+    pub const LOCK: u8 = 52;
+    pub const GETLOCK: u8 = 54;
+    pub const LINK: u8 = 70;
+    pub const MKDIR: u8 = 72;
+    pub const RENAMEAT: u8 = 74;
+    pub const UNLINKAT: u8 = 76;
+    pub const VERSION: u8 = 100;
+    pub const ATTACH: u8 = 104;
+    pub const FLUSH: u8 = 108;
+    pub const WALK: u8 = 110;
+    pub const READ: u8 = 116;
+    pub const WRITE: u8 = 118;
+    pub const CLUNK: u8 = 120;
+}
+
+const QID_DIR: u8 = 0x80;
+const QID_SYMLINK: u8 = 0x02;
+const QID_FILE: u8 = 0x00;
+
+/// The fields of a getattr reply which are filled in: everything but the birth time, the
+/// generation and the data version.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_SIZE: u32 = 0x8;
+
+const O_ACCMODE: u32 = 0o3;
+const O_RDONLY: u32 = 0o0;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// The types of directory entries, as `readdir` gives them.
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/// The magic number of a 9P file system, which `statfs` reports.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// Reads the fields of a request.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], i64> {
+        if self.data.len() < len {
+            return Err(EINVAL);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, i64> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, i64> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, i64> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, i64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a string, which is prefixed by its length as a u16.
+    fn string(&mut self) -> Result<&'a [u8], i64> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    /// Reads the name of a file within a directory, which can't be a path.
+    fn name(&mut self) -> Result<&'a OsStr, i64> {
+        let name = self.string()?;
+        match name {
+            b"" | b"." | b".." => Err(EINVAL),
+            _ if name.contains(&b'/') => Err(EINVAL),
+            _ => Ok(OsStr::from_bytes(name)),
+        }
+    }
+}
+
+/// Builds the body of a reply.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.buf.extend(value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend(value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend(value.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    fn string(&mut self, value: &[u8]) -> &mut Self {
+        self.u16(value.len() as u16).bytes(value)
+    }
+
+    /// Writes the unique id of a file: its type, a version which changes when it's modified, and
+    /// its inode number.
+    fn qid(&mut self, meta: &Metadata) -> &mut Self {
+        let kind = match meta.file_type() {
+            t if t.is_dir() => QID_DIR,
+            t if t.is_symlink() => QID_SYMLINK,
+            _ => QID_FILE,
+        };
+        self.u8(kind).u32(meta.mtime() as u32).u64(meta.ino())
+    }
+}
+
+/// A file which the guest has walked to, and perhaps opened.
+#[derive(Debug)]
+struct Fid {
+    /// The path from the root of the export.
+    path: PathBuf,
+    /// The file, and the flags it was opened with, once it's opened. Directories are opened
+    /// without a file.
+    open: Option<(Option<File>, u32)>,
+    /// The entries of a directory, which are listed when it's read from the start.
+    entries: Vec<Vec<u8>>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Self { path, open: None, entries: vec![] }
+    }
+
+    fn file(&self) -> Result<&File, i64> {
+        match &self.open {
+            Some((Some(file), _)) => Ok(file),
+            _ => Err(EBADF),
+        }
+    }
+}
+
+/// A virtio-9p device, which exports a directory on the host to the guest over 9P2000.L, so that
+/// it can be mounted with `mount -t 9p -o trans=virtio <tag> <dir>`.
+///
+/// Paths can't leave the directory through `..` or through a symbolic link, as links are never
+/// followed on the host. The guest follows them itself, by reading them and walking to their
+/// targets, so links within the directory still work.
+#[derive(Debug)]
+pub struct P9 {
+    root: Root,
+    tag: String,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl P9 {
+    pub fn new(root: PathBuf, tag: &str, read_only: bool) -> Self {
+        Self { root: Root::new(root), tag: tag.to_string(), read_only, msize: MAX_MSIZE, fids: HashMap::new() }
+    }
+
+    fn host_path(&self, path: &Path) -> Result<HostPath, i64> {
+        self.root.resolve(path).map_err(host_errno)
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid, i64> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, i64> {
+        self.root.metadata(path).map_err(host_errno)
+    }
+
+    fn writable(&self) -> Result<(), i64> {
+        match self.read_only {
+            true => Err(EROFS),
+            false => Ok(()),
+        }
+    }
+
+    fn open_options(flags: u32) -> Result<OpenOptions, i64> {
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_RDONLY => options.read(true),
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => return Err(EINVAL),
+        };
+        options.truncate(flags & O_TRUNC != 0).append(flags & O_APPEND != 0);
+        Ok(options)
+    }
+
+    /// Opens a fid, as a file unless it's a directory.
+    fn open(&self, path: &Path, flags: u32) -> Result<Option<File>, i64> {
+        if flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0 {
+            self.writable()?;
+        }
+        match self.metadata(path)?.is_dir() {
+            true => Ok(None),
+            false => self.root.open(path, &mut Self::open_options(flags)?).map(Some).map_err(host_errno),
+        }
+    }
+
+    /// Handles a request, returning the type and body of the reply.
+    fn handle(&mut self, kind: u8, body: &[u8]) -> Result<(u8, Vec<u8>), i64> {
+        let mut r = Reader { data: body };
+        let mut w = Writer::default();
+        match kind {
+            t::VERSION => {
+                self.msize = r.u32()?.min(MAX_MSIZE);
+                self.fids.clear();
+                let version = match r.string()? {
+                    b"9P2000.L" => VERSION,
+                    _ => "unknown",
+                };
+                w.u32(self.msize).string(version.as_bytes());
+            },
+            t::ATTACH => {
+                let fid = r.u32()?;
+                w.qid(&self.metadata(Path::new(""))?);
+                self.fids.insert(fid, Fid::new(PathBuf::new()));
+            },
+            t::WALK => {
+                let (fid, newfid, count) = (r.u32()?, r.u32()?, r.u16()?);
+                let mut path = self.fid(fid)?.path.clone();
+                let mut qids = Writer::default();
+                for i in 0..count {
+                    let name = r.string()?;
+                    match name {
+                        // The root is its own parent.
+                        b".." => { path.pop(); },
+                        b"." => {},
+                        _ if name.is_empty() || name.contains(&b'/') => return Err(ENOENT),
+                        _ => path.push(OsStr::from_bytes(name)),
+                    }
+                    match self.metadata(&path) {
+                        Ok(meta) => { qids.qid(&meta); },
+                        Err(e) if i == 0 => return Err(e),
+                        // A walk which stops part of the way returns how far it got, and doesn't
+                        // create the new fid.
+                        Err(_) => {
+                            w.u16(i).bytes(&qids.buf);
+                            return Ok((kind + 1, w.buf));
+                        },
+                    }
+                }
+                w.u16(count).bytes(&qids.buf);
+                self.fids.insert(newfid, Fid::new(path));
+            },
+            t::CLUNK => {
+                self.fids.remove(&r.u32()?).ok_or(EBADF)?;
+            },
+            t::FLUSH => {},
+            t::GETATTR => {
+                let meta = self.metadata(&self.fid(r.u32()?)?.path)?;
+                w.u64(GETATTR_BASIC).qid(&meta)
+                    .u32(meta.mode())
+                    .u32(meta.uid())
+                    .u32(meta.gid())
+                    .u64(meta.nlink())
+                    .u64(meta.rdev())
+                    .u64(meta.size())
+                    .u64(meta.blksize())
+                    .u64(meta.blocks())
+                    .u64(meta.atime() as u64).u64(meta.atime_nsec() as u64)
+                    .u64(meta.mtime() as u64).u64(meta.mtime_nsec() as u64)
+                    .u64(meta.ctime() as u64).u64(meta.ctime_nsec() as u64)
+                    .u64(0).u64(0)
+                    .u64(0).u64(0);
+            },
+            t::SETATTR => {
+                self.writable()?;
+                let path = self.fid(r.u32()?)?.path.clone();
+                let (valid, mode) = (r.u32()?, r.u32()?);
+                let (_uid, _gid, size) = (r.u32()?, r.u32()?, r.u64()?);
+                // Ownership and times are left as they are, as they would need privileges.
+                if valid & SETATTR_MODE != 0 {
+                    self.root.set_mode(&path, mode & 0o7777).map_err(host_errno)?;
+                }
+                if valid & SETATTR_SIZE != 0 {
+                    self.root.open(&path, OpenOptions::new().write(true)).and_then(|f| f.set_len(size)).map_err(host_errno)?;
+                }
+            },
+            t::STATFS => {
+                self.fid(r.u32()?)?;
+                w.u32(V9FS_MAGIC).u32(4096).u64(0).u64(0).u64(0).u64(0).u64(0).u64(0).u32(255);
+            },
+            t::LOPEN => {
+                let fid = r.u32()?;
+                let flags = r.u32()?;
+                let path = self.fid(fid)?.path.clone();
+                let file = self.open(&path, flags)?;
+                w.qid(&self.metadata(&path)?).u32(0);
+                self.fids.get_mut(&fid).unwrap().open = Some((file, flags));
+            },
+            t::LCREATE => {
+                self.writable()?;
+                let fid = r.u32()?;
+                let (name, flags, mode) = (r.name()?, r.u32()?, r.u32()?);
+                let path = self.fid(fid)?.path.join(name);
+                let file = self.root
+                    .open(&path, Self::open_options(flags)?.create_new(true).mode(mode & 0o7777))
+                    .map_err(host_errno)?;
+                w.qid(&file.metadata().map_err(host_errno)?).u32(0);
+                // The fid now refers to the new file.
+                let fid = self.fids.get_mut(&fid).unwrap();
+                *fid = Fid::new(path);
+                fid.open = Some((Some(file), flags));
+            },
+            t::READ => {
+                let file = self.fid(r.u32()?)?.file()?;
+                let (offset, count) = (r.u64()?, r.u32()?);
+                let mut data = vec![0; count.min(self.msize.saturating_sub(11)) as usize];
+                let len = file.read_at(&mut data, offset).map_err(host_errno)?;
+                w.u32(len as u32).bytes(&data[..len]);
+            },
+            t::WRITE => {
+                let file = self.fid(r.u32()?)?.file()?;
+                let (offset, count) = (r.u64()?, r.u32()?);
+                let data = r.bytes(count as usize)?;
+                let len = file.write_at(data, offset).map_err(host_errno)?;
+                w.u32(len as u32);
+            },
+            t::FSYNC => {
+                self.fid(r.u32()?)?.file()?.sync_all().map_err(host_errno)?;
+            },
+            t::READDIR => {
+                let fid = r.u32()?;
+                let (offset, count) = (r.u64()?, r.u32()?);
+                if offset == 0 {
+                    let entries = self.list(&self.fid(fid)?.path)?;
+                    self.fids.get_mut(&fid).unwrap().entries = entries;
+                }
+                let entries = &self.fid(fid)?.entries;
+                let mut data = vec![];
+                for entry in entries.iter().skip(offset as usize) {
+                    if data.len() + entry.len() > count.min(self.msize.saturating_sub(11)) as usize {
+                        break;
+                    }
+                    data.extend_from_slice(entry);
+                }
+                w.u32(data.len() as u32).bytes(&data);
+            },
+            t::READLINK => {
+                let path = self.host_path(&self.fid(r.u32()?)?.path)?;
+                let target = fs::read_link(&*path).map_err(host_errno)?;
+                w.string(target.as_os_str().as_bytes());
+            },
+            t::MKDIR => {
+                self.writable()?;
+                let path = self.fid(r.u32()?)?.path.join(r.name()?);
+                let mode = r.u32()?;
+                fs::DirBuilder::new().mode(mode & 0o7777).create(&*self.host_path(&path)?).map_err(host_errno)?;
+                w.qid(&self.metadata(&path)?);
+            },
+            t::SYMLINK => {
+                self.writable()?;
+                let path = self.fid(r.u32()?)?.path.join(r.name()?);
+                let target = OsStr::from_bytes(r.string()?);
+                symlink(target, &*self.host_path(&path)?).map_err(host_errno)?;
+                w.qid(&self.metadata(&path)?);
+            },
+            t::LINK => {
+                self.writable()?;
+                let dir = self.fid(r.u32()?)?.path.clone();
+                let target = self.fid(r.u32()?)?.path.clone();
+                let path = dir.join(r.name()?);
+                fs::hard_link(&*self.host_path(&target)?, &*self.host_path(&path)?).map_err(host_errno)?;
+            },
+            t::RENAMEAT => {
+                self.writable()?;
+                let from = self.fid(r.u32()?)?.path.join(r.name()?);
+                let to = self.fid(r.u32()?)?.path.join(r.name()?);
+                fs::rename(&*self.host_path(&from)?, &*self.host_path(&to)?).map_err(host_errno)?;
+            },
+            t::UNLINKAT => {
+                self.writable()?;
+                let path = self.host_path(&self.fid(r.u32()?)?.path.join(r.name()?))?;
+                match r.u32()? & AT_REMOVEDIR {
+                    0 => fs::remove_file(&*path),
+                    _ => fs::remove_dir(&*path),
+                }.map_err(host_errno)?;
+            },
+            // Locks are granted straight away, as only one guest uses the export.
+            t::LOCK => {
+                w.u8(0);
+            },
+            t::GETLOCK => {
+                let (_fid, _kind, start, length, proc_id) = (r.u32()?, r.u8()?, r.u64()?, r.u64()?, r.u32()?);
+                let client = r.string()?;
+                // The lock would be granted, as nothing holds it.
+                w.u8(2).u64(start).u64(length).u32(proc_id).string(client);
+            },
+            // Extended attributes aren't exported.
+            t::XATTRWALK => return Err(EOPNOTSUPP),
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok((kind + 1, w.buf))
+    }
+
+    /// Lists a directory as `readdir` entries, each with its offset, which is the index of the
+    /// next entry.
+    fn list(&self, path: &Path) -> Result<Vec<Vec<u8>>, i64> {
+        let mut names = vec![OsStr::new(".").to_os_string(), OsStr::new("..").to_os_string()];
+        let mut children: Vec<_> = self.root.read_dir(path).map_err(host_errno)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.file_name()))
+            .collect();
+        children.sort();
+        names.extend(children);
+
+        let mut entries = vec![];
+        for (i, name) in names.iter().enumerate() {
+            let entry_path = match name.as_bytes() {
+                b"." => path.to_path_buf(),
+                b".." => path.parent().unwrap_or(path).to_path_buf(),
+                _ => path.join(name),
+            };
+            let Ok(meta) = self.metadata(&entry_path) else { continue };
+            let kind = match meta.file_type() {
+                t if t.is_dir() => DT_DIR,
+                t if t.is_symlink() => DT_LNK,
+                t if t.is_file() => DT_REG,
+                _ => 0,
+            };
+            let mut w = Writer::default();
+            w.qid(&meta).u64(i as u64 + 1).u8(kind).string(name.as_bytes());
+            entries.push(w.buf);
+        }
+        Ok(entries)
+    }
+
+    /// Handles a message, returning the reply, which is an error reply if the request failed.
+    fn message(&mut self, request: &[u8]) -> Vec<u8> {
+        if request.len() < HEADER_SIZE {
+            return vec![];
+        }
+        let (kind, tag) = (request[4], &request[5..7]);
+        let (kind, body) = match self.handle(kind, &request[HEADER_SIZE..]) {
+            Ok(reply) => reply,
+            Err(errno) => (t::LERROR, (errno as u32).to_le_bytes().to_vec()),
+        };
+        let mut reply = ((HEADER_SIZE + body.len()) as u32).to_le_bytes().to_vec();
+        reply.push(kind);
+        reply.extend_from_slice(tag);
+        reply.extend(body);
+        reply
+    }
+}
+
+impl VirtioDevice for P9 {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config
+    }
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        while let Some(mut chain) = queues[0].pop(dma)? {
+            let request = chain.read_all(dma)?;
+            // Requests are always carried out, so that the fids stay the same, but what the host
+            // said is taken from the journal, as the directory could change between a recording
+            // and its replay.
+            let reply = self.message(&request);
+            let reply = dma.journal().input(Source::File, || reply);
+            chain.write(dma, &reply)?;
+            queues[0].push(dma, chain)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.fids.clear();
+    }
+
+    /// The fids are saved by their paths, and opened again when they are restored.
+    fn save(&self, encoder: &mut Encoder) {
+        encoder.u32(self.msize).u64(self.fids.len() as u64);
+        let mut fids: Vec<_> = self.fids.iter().collect();
+        fids.sort_by_key(|&(&id, _)| id);
+        for (&id, fid) in fids {
+            encoder.u32(id).bytes(fid.path.as_os_str().as_bytes());
+            match fid.open {
+                Some((_, flags)) => encoder.u8(1).u32(flags),
+                None => encoder.u8(0),
+            };
+        }
+    }
+
+    fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        self.msize = decoder.u32()?;
+        self.fids.clear();
+        for _ in 0..decoder.u64()? {
+            let id = decoder.u32()?;
+            let mut fid = Fid::new(PathBuf::from(OsStr::from_bytes(&decoder.block()?)));
+            if decoder.u8()? != 0 {
+                let flags = decoder.u32()? & !O_TRUNC;
+                // A file which has gone since the snapshot fails when it's next used.
+                fid.open = self.open(&fid.path, flags).ok().map(|file| (file, flags));
+            }
+            self.fids.insert(id, fid);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, os::unix::fs::symlink, path::PathBuf};
+
+    use crate::components::{
+        devices::virtio::{queue::test::Driver, reg, test::{set_up, write}, VirtioMmio},
+        devices::Device,
+        memory::{Size, DRAM},
+    };
+    use crate::snapshot::{SnapshotReader, SnapshotWriter, Snapshotable};
+    use crate::syscall::errno::{EBADF, ENOENT, EROFS};
+
+    use super::{t, P9, O_RDONLY, O_RDWR};
+
+    struct Client {
+        mmio: VirtioMmio,
+        dram: DRAM,
+        driver: Driver,
+    }
+
+    impl Client {
+        fn new(root: PathBuf, read_only: bool) -> Self {
+            let mut dram = DRAM::new(0x100000);
+            let mut mmio = VirtioMmio::new(0, Box::new(P9::new(root, "share", read_only)));
+            set_up(&mut mmio, &mut dram, 1);
+            Self { mmio, dram, driver: Driver::new() }
+        }
+
+        /// Sends a request, and returns the type and body of the reply.
+        fn call(&mut self, kind: u8, body: &[u8]) -> (u8, Vec<u8>) {
+            let mut request = ((7 + body.len()) as u32).to_le_bytes().to_vec();
+            request.push(kind);
+            request.extend([1, 0]);
+            request.extend_from_slice(body);
+            let reply = self.driver.offer(&mut self.dram, &[&request], &[4096])[0];
+            write(&mut self.mmio, &mut self.dram, reg::QUEUE_NOTIFY, 0);
+            let (_, len) = self.driver.take_used(&self.dram).unwrap();
+            let mut data = vec![0; len as usize];
+            self.dram.read_into(reply, &mut data).unwrap();
+            assert_eq!(u32::from_le_bytes(data[..4].try_into().unwrap()), len);
+            assert_eq!(data[5..7], [1, 0]);
+            (data[4], data[7..].to_vec())
+        }
+
+        fn error(&mut self, kind: u8, body: &[u8]) -> i64 {
+            let (reply, body) = self.call(kind, body);
+            assert_eq!(reply, t::LERROR);
+            u32::from_le_bytes(body.try_into().unwrap()) as i64
+        }
+
+        /// Attaches fid 0 to the root.
+        fn attach(&mut self) {
+            let (reply, body) = self.call(t::VERSION, &[&8192u32.to_le_bytes(), &string("9P2000.L")[..]].concat());
+            assert_eq!(reply, t::VERSION + 1);
+            assert_eq!(body, [&8192u32.to_le_bytes(), &string("9P2000.L")[..]].concat());
+            let attach = [&0u32.to_le_bytes()[..], &u32::MAX.to_le_bytes(), &string("root"), &string(""), &0u32.to_le_bytes()].concat();
+            assert_eq!(self.call(t::ATTACH, &attach).0, t::ATTACH + 1);
+        }
+
+        /// Walks from the root to a new fid.
+        fn walk(&mut self, fid: u32, names: &[&str]) -> (u8, Vec<u8>) {
+            let mut body = [0u32.to_le_bytes(), fid.to_le_bytes()].concat();
+            body.extend((names.len() as u16).to_le_bytes());
+            for name in names {
+                body.extend(string(name));
+            }
+            self.call(t::WALK, &body)
+        }
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        [&(value.len() as u16).to_le_bytes(), value.as_bytes()].concat()
+    }
+
+    fn export(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rv64-9p-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir/hello.txt"), "hello, world").unwrap();
+        root
+    }
+
+    #[test]
+    fn it_exports_directories() {
+        let root = export("read");
+        let mut client = Client::new(root.clone(), false);
+        assert_eq!(client.mmio.read(reg::CONFIG, Size::HalfWord), Ok(5));
+        assert_eq!(client.mmio.read(reg::CONFIG + 2, Size::Byte), Ok(b's' as u64));
+        client.attach();
+
+        // Walks can't leave the export.
+        let (reply, body) = client.walk(1, &["..", "dir", "hello.txt"]);
+        assert_eq!((reply, &body[..2]), (t::WALK + 1, &[3, 0][..]));
+        assert_eq!(client.error(t::WALK, &[&0u32.to_le_bytes()[..], &2u32.to_le_bytes(), &1u16.to_le_bytes(), &string("missing")].concat()), ENOENT);
+
+        let (reply, body) = client.call(t::GETATTR, &[&1u32.to_le_bytes()[..], &0x7ffu64.to_le_bytes()].concat());
+        assert_eq!(reply, t::GETATTR + 1);
+        // The size follows the mask, the qid, the mode, the ids, the links and the device.
+        assert_eq!(body[8 + 13 + 12 + 16..][..8], 12u64.to_le_bytes());
+
+        assert_eq!(client.call(t::LOPEN, &[1u32.to_le_bytes(), O_RDONLY.to_le_bytes()].concat()).0, t::LOPEN + 1);
+        let (reply, body) = client.call(t::READ, &[&1u32.to_le_bytes()[..], &7u64.to_le_bytes(), &100u32.to_le_bytes()].concat());
+        assert_eq!(reply, t::READ + 1);
+        assert_eq!(body, [&5u32.to_le_bytes()[..], b"world"].concat());
+
+        // The directory lists its entries, after . and ..
+        client.walk(2, &["dir"]);
+        client.call(t::LOPEN, &[2u32.to_le_bytes(), O_RDONLY.to_le_bytes()].concat());
+        let (_, body) = client.call(t::READDIR, &[&2u32.to_le_bytes()[..], &0u64.to_le_bytes(), &4096u32.to_le_bytes()].concat());
+        let entries = &body[4..];
+        assert!(entries.ends_with(&string("hello.txt")));
+        assert_eq!(entries[entries.len() - 12], 8);
+        // Reading on from the offset of the last entry gives nothing more.
+        let (_, body) = client.call(t::READDIR, &[&2u32.to_le_bytes()[..], &3u64.to_le_bytes(), &4096u32.to_le_bytes()].concat());
+        assert_eq!(body, 0u32.to_le_bytes());
+
+        // Open fids survive a snapshot.
+        let mut snapshot = SnapshotWriter::new();
+        client.mmio.save(&mut snapshot);
+        let mut data = vec![];
+        snapshot.write_to(&mut data).unwrap();
+        let mut restored = Client::new(root.clone(), false);
+        restored.mmio.restore(&mut SnapshotReader::read_from(&data[..]).unwrap()).unwrap();
+        restored.driver = client.driver;
+        restored.dram = client.dram;
+        let (_, body) = restored.call(t::READ, &[&1u32.to_le_bytes()[..], &0u64.to_le_bytes(), &5u32.to_le_bytes()].concat());
+        assert_eq!(body, [&5u32.to_le_bytes()[..], b"hello"].concat());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn it_writes_unless_read_only() {
+        let root = export("write");
+        let create = [&0u32.to_le_bytes()[..], &string("new.txt"), &O_RDWR.to_le_bytes(), &0o644u32.to_le_bytes(), &0u32.to_le_bytes()].concat();
+        let mut client = Client::new(root.clone(), true);
+        client.attach();
+        assert_eq!(client.error(t::LCREATE, &create), EROFS);
+        client.walk(1, &["dir", "hello.txt"]);
+        assert_eq!(client.error(t::LOPEN, &[1u32.to_le_bytes(), O_RDWR.to_le_bytes()].concat()), EROFS);
+
+        let mut client = Client::new(root.clone(), false);
+        client.attach();
+        // Creating a file moves the fid to it, so the root is kept in another.
+        client.walk(2, &[]);
+        assert_eq!(client.call(t::LCREATE, &create).0, t::LCREATE + 1);
+        let (reply, body) = client.call(t::WRITE, &[&0u32.to_le_bytes()[..], &0u64.to_le_bytes(), &3u32.to_le_bytes(), b"abc"].concat());
+        assert_eq!((reply, body), (t::WRITE + 1, 3u32.to_le_bytes().to_vec()));
+        assert_eq!(fs::read(root.join("new.txt")).unwrap(), b"abc");
+
+        let mkdir = [&2u32.to_le_bytes()[..], &string("sub"), &0o755u32.to_le_bytes(), &0u32.to_le_bytes()].concat();
+        assert_eq!(client.call(t::MKDIR, &mkdir).0, t::MKDIR + 1);
+        assert!(root.join("sub").is_dir());
+        let unlink = [&2u32.to_le_bytes()[..], &string("new.txt"), &0u32.to_le_bytes()].concat();
+        assert_eq!(client.call(t::UNLINKAT, &unlink).0, t::UNLINKAT + 1);
+        assert!(!root.join("new.txt").exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn it_does_not_follow_links_out_of_the_export() {
+        let root = export("links");
+        fs::write(root.with_extension("secret"), "outside").unwrap();
+        let mut client = Client::new(root.clone(), false);
+        client.attach();
+
+        // The guest makes a link to the host's root, and tries to walk through it.
+        let link = [&0u32.to_le_bytes()[..], &string("esc"), &string("/"), &0u32.to_le_bytes()].concat();
+        assert_eq!(client.call(t::SYMLINK, &link).0, t::SYMLINK + 1);
+        // The walk stops at the link, and doesn't create the new fid.
+        let (reply, body) = client.walk(1, &["esc", "tmp"]);
+        assert_eq!((reply, &body[..2]), (t::WALK + 1, &[1, 0][..]));
+        assert_eq!(client.error(t::GETATTR, &[&1u32.to_le_bytes()[..], &0x7ffu64.to_le_bytes()].concat()), EBADF);
+
+        // Nor can a link be opened, changed or truncated in place of its target.
+        symlink(root.with_extension("secret"), root.join("secret")).unwrap();
+        let (reply, body) = client.walk(2, &["secret"]);
+        assert_eq!((reply, body[2]), (t::WALK + 1, 0x02));
+        assert!(client.error(t::LOPEN, &[2u32.to_le_bytes(), O_RDWR.to_le_bytes()].concat()) > 0);
+        let setattr = [&2u32.to_le_bytes()[..], &9u32.to_le_bytes(), &0o777u32.to_le_bytes(), &0u32.to_le_bytes(), &0u32.to_le_bytes(), &0u64.to_le_bytes()].concat();
+        assert!(client.error(t::SETATTR, &setattr) > 0);
+        assert_eq!(fs::read_to_string(root.with_extension("secret")).unwrap(), "outside");
+
+        let _ = fs::remove_file(root.with_extension("secret"));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{
+    components::{cpu::Trap, devices::Dma},
+    snapshot::{Decoder, Encoder, SnapshotError},
+};
+
+/// The most descriptors which a queue can hold.
+pub const MAX_SIZE: u16 = 256;
+
+/// The descriptor continues in the one given by its `next` field.
+const DESC_F_NEXT: u16 = 1;
+/// The buffer is written by the device, rather than read.
+const DESC_F_WRITE: u16 = 2;
+
+/// A split virtqueue, made up of three rings in guest memory which the driver sets up:
+///
+/// - the descriptor table, of 16-byte buffer descriptors,
+/// - the driver (available) ring, of the heads of chains of descriptors which the driver has
+///   made available to the device,
+/// - the device (used) ring, of the chains which the device has finished with, and how much it
+///   wrote to each.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Queue {
+    pub size: u16,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    /// The index into the driver ring of the next chain to take.
+    last_avail: u16,
+    /// The index into the device ring of the next chain to return.
+    used: u16,
+}
+
+/// A chain of descriptors taken from a queue: the buffers which the device reads the request
+/// from, followed by those which it writes the response to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Chain {
+    head: u16,
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+    /// How many bytes the device has written to the writable buffers.
+    written: u32,
+}
+
+impl Queue {
+    /// Indicates if the driver has made any chains available which haven't been taken.
+    pub fn has_available(&self, dma: &Dma) -> Result<bool, Trap> {
+        if !self.ready || self.size == 0 {
+            return Ok(false);
+        }
+        Ok(dma.read_u16(self.driver + 2)? != self.last_avail)
+    }
+
+    /// Takes the next chain which the driver has made available, if there is one.
+    pub fn pop(&mut self, dma: &Dma) -> Result<Option<Chain>, Trap> {
+        if !self.has_available(dma)? {
+            return Ok(None);
+        }
+        let head = dma.read_u16(self.driver + 4 + 2 * (self.last_avail % self.size) as u64)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = Chain { head, readable: vec![], writable: vec![], written: 0 };
+        let mut index = head;
+        // A chain can't be longer than the table, which stops a looped chain hanging the device.
+        for _ in 0..self.size {
+            if index >= self.size {
+                return Err(Trap::LoadAccessFault);
+            }
+            let desc = self.desc + 16 * index as u64;
+            let (addr, len) = (dma.read_u64(desc)?, dma.read_u32(desc + 8)?);
+            let (flags, next) = (dma.read_u16(desc + 12)?, dma.read_u16(desc + 14)?);
+            match flags & DESC_F_WRITE {
+                0 => chain.readable.push((addr, len)),
+                _ => chain.writable.push((addr, len)),
+            }
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+            index = next;
+        }
+        Err(Trap::LoadAccessFault)
+    }
+
+    /// Puts back the chain which was taken last, for a device which found it had nothing to put
+    /// in it.
+    pub fn put_back(&mut self) {
+        self.last_avail = self.last_avail.wrapping_sub(1);
+    }
+
+    /// Returns a chain to the driver, along with how much was written to it.
+    pub fn push(&mut self, dma: &mut Dma, chain: Chain) -> Result<(), Trap> {
+        let elem = self.device + 4 + 8 * (self.used % self.size) as u64;
+        dma.write_u32(elem, chain.head as u32)?;
+        dma.write_u32(elem + 4, chain.written)?;
+        self.used = self.used.wrapping_add(1);
+        dma.write_u16(self.device + 2, self.used)
+    }
+
+    pub fn save(&self, encoder: &mut Encoder) {
+        encoder.u32(self.size as u32)
+            .u8(self.ready as u8)
+            .u64(self.desc)
+            .u64(self.driver)
+            .u64(self.device)
+            .u32(self.last_avail as u32)
+            .u32(self.used as u32);
+    }
+
+    pub fn restore(decoder: &mut Decoder) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            size: decoder.u32()? as u16,
+            ready: decoder.u8()? != 0,
+            desc: decoder.u64()?,
+            driver: decoder.u64()?,
+            device: decoder.u64()?,
+            last_avail: decoder.u32()? as u16,
+            used: decoder.u32()? as u16,
+        })
+    }
+}
+
+impl Chain {
+    /// Reads the whole of the readable buffers.
+    pub fn read_all(&self, dma: &Dma) -> Result<Vec<u8>, Trap> {
+        let mut data = vec![];
+        for &(addr, len) in &self.readable {
+            data.extend(dma.read_vec(addr, len as usize)?);
+        }
+        Ok(data)
+    }
+
+    /// The total size of the writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|&(_, len)| len as usize).sum()
+    }
+
+    /// Writes to the writable buffers, after what was written before. Anything which doesn't fit
+    /// is dropped.
+    pub fn write(&mut self, dma: &mut Dma, mut data: &[u8]) -> Result<(), Trap> {
+        let mut skip = self.written as usize;
+        for &(addr, len) in &self.writable {
+            let len = len as usize;
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let count = data.len().min(len - skip);
+            dma.write(addr + skip as u64, &data[..count])?;
+            self.written += count as u32;
+            data = &data[count..];
+            skip = 0;
+            if data.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::components::{bus::DRAM_BASE, devices::Dma, memory::DRAM};
+    use crate::replay::Journal;
+
+    use super::{Queue, DESC_F_NEXT, DESC_F_WRITE};
+
+    /// Where the rings of a test queue are, and where buffers go.
+    pub const DESC: u64 = DRAM_BASE + 0x1000;
+    pub const DRIVER: u64 = DRAM_BASE + 0x2000;
+    pub const DEVICE: u64 = DRAM_BASE + 0x3000;
+    pub const BUFFERS: u64 = DRAM_BASE + 0x4000;
+
+    /// Plays the part of a driver: lays out chains of buffers in memory and makes them available
+    /// on a queue of 8 descriptors.
+    pub struct Driver {
+        /// How far the rings and buffers are moved up from the first queue's.
+        offset: u64,
+        next_desc: u16,
+        next_buffer: u64,
+        avail: u16,
+        used: u16,
+    }
+
+    impl Default for Driver {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Driver {
+        pub fn new() -> Self {
+            Self::at(0)
+        }
+
+        /// Creates a driver for a queue whose rings are moved up by an offset.
+        pub fn at(offset: u64) -> Self {
+            Self { offset, next_desc: 0, next_buffer: BUFFERS + offset, avail: 0, used: 0 }
+        }
+
+        pub fn queue() -> Queue {
+            Queue { size: 8, ready: true, desc: DESC, driver: DRIVER, device: DEVICE, ..Default::default() }
+        }
+
+        /// Makes a chain available, of buffers holding the readable data, followed by writable
+        /// buffers of the given sizes. Returns the addresses of the writable buffers.
+        pub fn offer(&mut self, dram: &mut DRAM, readable: &[&[u8]], writable: &[u32]) -> Vec<u64> {
+            let head = self.next_desc % 8;
+            let count = readable.len() + writable.len();
+            let mut addrs = vec![];
+            for i in 0..count {
+                let (len, flags) = match readable.get(i) {
+                    Some(data) => {
+                        dram.write_from(self.next_buffer, data).unwrap();
+                        (data.len() as u32, 0)
+                    },
+                    None => {
+                        addrs.push(self.next_buffer);
+                        (writable[i - readable.len()], DESC_F_WRITE)
+                    },
+                };
+                let index = self.next_desc % 8;
+                self.next_desc += 1;
+                let last = i + 1 == count;
+                let desc = DESC + self.offset + 16 * index as u64;
+                let mut entry = vec![];
+                entry.extend(self.next_buffer.to_le_bytes());
+                entry.extend(len.to_le_bytes());
+                entry.extend((flags | if last { 0 } else { DESC_F_NEXT }).to_le_bytes());
+                entry.extend((self.next_desc % 8).to_le_bytes());
+                dram.write_from(desc, &entry).unwrap();
+                self.next_buffer += (len as u64).next_multiple_of(16);
+            }
+            let driver = DRIVER + self.offset;
+            dram.write_from(driver + 4 + 2 * (self.avail % 8) as u64, &head.to_le_bytes()).unwrap();
+            self.avail += 1;
+            dram.write_from(driver + 2, &self.avail.to_le_bytes()).unwrap();
+            addrs
+        }
+
+        /// Takes the next used chain, returning the head of the chain and how much was written.
+        pub fn take_used(&mut self, dram: &DRAM) -> Option<(u32, u32)> {
+            let mut idx = [0; 2];
+            let device = DEVICE + self.offset;
+            dram.read_into(device + 2, &mut idx).unwrap();
+            if u16::from_le_bytes(idx) == self.used {
+                return None;
+            }
+            let mut elem = [0; 8];
+            dram.read_into(device + 4 + 8 * (self.used % 8) as u64, &mut elem).unwrap();
+            self.used += 1;
+            Some((u32::from_le_bytes(elem[..4].try_into().unwrap()), u32::from_le_bytes(elem[4..].try_into().unwrap())))
+        }
+    }
+
+    #[test]
+    fn it_takes_and_returns_chains() {
+        let mut dram = DRAM::new(0x10000);
+        let mut journal = Journal::new();
+        let mut driver = Driver::new();
+        let mut queue = Driver::queue();
+        let writable = driver.offer(&mut dram, &[b"abc", b"de"], &[2, 4]);
+
+        let mut dma = Dma::new(&mut dram, &mut journal);
+        let mut chain = queue.pop(&dma).unwrap().unwrap();
+        assert_eq!(queue.pop(&dma), Ok(None));
+        assert_eq!(chain.read_all(&dma), Ok(b"abcde".to_vec()));
+        assert_eq!(chain.writable_len(), 6);
+        chain.write(&mut dma, b"xyz").unwrap();
+        chain.write(&mut dma, b"1234").unwrap();
+        queue.push(&mut dma, chain).unwrap();
+
+        assert_eq!(driver.take_used(&dram), Some((0, 6)));
+        assert_eq!(driver.take_used(&dram), None);
+        let mut buf = [0; 4];
+        dram.read_into(writable[0], &mut buf[..2]).unwrap();
+        assert_eq!(&buf[..2], b"xy");
+        dram.read_into(writable[1], &mut buf).unwrap();
+        assert_eq!(&buf, b"z123");
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{
+    components::{cpu::Trap, devices::Dma},
+    replay::Source,
+    snapshot::{Decoder, Encoder, SnapshotError},
+    util::host_random,
+};
+
+use super::{queue::Queue, VirtioDevice};
+
+const DEVICE_ID: u32 = 4;
+
+/// A virtio entropy device, which fills the buffers the driver gives it with random bytes, either
+/// from the host or from a seeded generator, so that runs can be reproduced without recording
+/// them.
+#[derive(Debug)]
+pub struct Rng {
+    /// The state of the SplitMix64 generator, or `None` if the bytes come from the host.
+    state: Option<u64>,
+}
+
+impl Rng {
+    /// Takes random bytes from the host, which go through the journal.
+    pub fn host() -> Self {
+        Self { state: None }
+    }
+
+    /// Generates the same bytes on every run with the same seed.
+    pub fn seeded(seed: u64) -> Self {
+        Self { state: Some(seed) }
+    }
+
+    fn next(state: &mut u64) -> u64 {
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn random(&mut self, len: usize, dma: &mut Dma) -> Vec<u8> {
+        match &mut self.state {
+            Some(state) => {
+                let mut data: Vec<u8> = (0..len.div_ceil(8)).flat_map(|_| Self::next(state).to_le_bytes()).collect();
+                data.truncate(len);
+                data
+            },
+            None => dma.journal().input(Source::Random, || host_random(len)),
+        }
+    }
+}
+
+impl VirtioDevice for Rng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Queue], dma: &mut Dma) -> Result<(), Trap> {
+        while let Some(mut chain) = queues[0].pop(dma)? {
+            let data = self.random(chain.writable_len(), dma);
+            chain.write(dma, &data)?;
+            queues[0].push(dma, chain)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, encoder: &mut Encoder) {
+        match self.state {
+            Some(state) => encoder.u8(1).u64(state),
+            None => encoder.u8(0),
+        };
+    }
+
+    fn restore(&mut self, decoder: &mut Decoder) -> Result<(), SnapshotError> {
+        let seeded = decoder.u8()? != 0;
+        if seeded != self.state.is_some() {
+            return Err(decoder.corrupt());
+        }
+        if seeded {
+            self.state = Some(decoder.u64()?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::components::{
+        devices::virtio::{queue::test::Driver, reg, test::{set_up, write}, VirtioMmio},
+        memory::DRAM,
+    };
+
+    use super::Rng;
+
+    /// Reads 20 random bytes from a device.
+    fn random(rng: Rng) -> Vec<u8> {
+        let mut dram = DRAM::new(0x100000);
+        let mut mmio = VirtioMmio::new(0, Box::new(rng));
+        set_up(&mut mmio, &mut dram, 1);
+        let mut driver = Driver::new();
+        let buffers = driver.offer(&mut dram, &[], &[12, 8]);
+        write(&mut mmio, &mut dram, reg::QUEUE_NOTIFY, 0);
+        assert_eq!(driver.take_used(&dram), Some((0, 20)));
+
+        let mut data = vec![0; 20];
+        dram.read_into(buffers[0], &mut data[..12]).unwrap();
+        dram.read_into(buffers[1], &mut data[12..]).unwrap();
+        data
+    }
+
+    #[test]
+    fn it_fills_buffers_with_random_bytes() {
+        let host = random(Rng::host());
+        assert_ne!(host, vec![0; 20]);
+        assert_ne!(random(Rng::host()), host);
+
+        // Seeded devices give the same bytes every time.
+        assert_eq!(random(Rng::seeded(1)), random(Rng::seeded(1)));
+        assert_ne!(random(Rng::seeded(1)), random(Rng::seeded(2)));
+    }
+}
@@ -16,6 +16,39 @@ pub trait Addressable {
     /// Returns the value stored at the given address.
     fn read(&self, addr: u64, size: Size) -> Result<u64, Trap>;
 
-    /// Attempts to store the given value at the given address.
-    fn write(&mut self, addr: u64, size: Size, data: Vec<u8>) -> Result<(), Trap>;
+    /// Attempts to store the low bytes of the given value, as many as the size, at the given
+    /// address.
+    fn write(&mut self, addr: u64, size: Size, value: u64) -> Result<(), Trap>;
+
+    fn read_u8(&self, addr: u64) -> Result<u8, Trap> {
+        self.read(addr, Size::Byte).map(|v| v as u8)
+    }
+
+    fn read_u16(&self, addr: u64) -> Result<u16, Trap> {
+        self.read(addr, Size::HalfWord).map(|v| v as u16)
+    }
+
+    fn read_u32(&self, addr: u64) -> Result<u32, Trap> {
+        self.read(addr, Size::Word).map(|v| v as u32)
+    }
+
+    fn read_u64(&self, addr: u64) -> Result<u64, Trap> {
+        self.read(addr, Size::DoubleWord)
+    }
+
+    fn write_u8(&mut self, addr: u64, value: u8) -> Result<(), Trap> {
+        self.write(addr, Size::Byte, value as u64)
+    }
+
+    fn write_u16(&mut self, addr: u64, value: u16) -> Result<(), Trap> {
+        self.write(addr, Size::HalfWord, value as u64)
+    }
+
+    fn write_u32(&mut self, addr: u64, value: u32) -> Result<(), Trap> {
+        self.write(addr, Size::Word, value as u64)
+    }
+
+    fn write_u64(&mut self, addr: u64, value: u64) -> Result<(), Trap> {
+        self.write(addr, Size::DoubleWord, value)
+    }
 }
\ No newline at end of file
@@ -1,63 +1,176 @@
 #![allow(dead_code)]
 
-use crate::components::{bus::DRAM_BASE, cpu::Trap};
+use std::{fs::File, io};
 
-use super::{address::Addressable, image::Imageable, Size};
+use crate::{components::{bus::DRAM_BASE, cpu::Trap}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable, PAGE_SIZE}};
 
+use super::{address::Addressable, from_le, image::Imageable, mapping::{Mapping, Sharing}, Size};
+
+/// A page of memory which has been written to.
+type Page = Box<[u8; PAGE_SIZE]>;
+
+/// Main memory, which is allocated a page at a time as it's written to, so that a machine with
+/// gigabytes of memory costs nothing until the program touches it. Pages which have never been
+/// written read as zero.
+///
+/// The start of memory can instead be backed by a file on the host, which is mapped in.
 #[derive(Debug)]
 pub struct DRAM {
-    dram: Vec<u8>,
+    pages: Vec<Option<Page>>,
+    mapping: Option<Mapping>,
+    size: usize,
     code_len: u64,
 }
 
 impl DRAM {
     pub fn new(size: usize) -> Self {
         Self {
-            dram: vec![0; size],
+            pages: std::iter::repeat_with(|| None).take(size.div_ceil(PAGE_SIZE)).collect(),
+            mapping: None,
+            size,
             code_len: 0,
         }
     }
 
-    fn write_bytes(&mut self, addr: u64, size: u8, data: Vec<u8>) -> Result<(), Trap> {
-        let index = (addr - DRAM_BASE) as usize;
-    
-        for (i, &byte) in data.iter().enumerate().take(size as usize) {
-            self.dram[index + i] = byte;
+    /// Backs memory with a file on the host, replacing what it held. A private mapping shows the
+    /// contents of the file, such as a firmware image, without copying it. A shared mapping
+    /// covers all of memory, so that another process can inspect it live by mapping the file.
+    pub fn map_file(&mut self, file: &File, sharing: Sharing) -> io::Result<()> {
+        self.mapping = Mapping::new(file, self.size, sharing)?;
+        self.pages.iter_mut().for_each(|page| *page = None);
+        Ok(())
+    }
+
+    /// The number of bytes at the start of memory which are mapped from a file.
+    fn mapped_len(&self) -> usize {
+        self.mapping.as_ref().map_or(0, |mapping| mapping.len())
+    }
+
+    /// The number of bytes of host memory which back the pages that have been written to.
+    pub fn allocated(&self) -> usize {
+        self.pages.iter().flatten().count() * PAGE_SIZE
+    }
+
+    /// Gets the page which holds an offset into memory, allocating it if it hasn't been written
+    /// to yet.
+    fn page_mut(&mut self, offset: usize) -> &mut [u8; PAGE_SIZE] {
+        self.pages[offset / PAGE_SIZE].get_or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+
+    /// Reads a value which may straddle two pages, or the end of the mapped file.
+    fn read_bytes(&self, offset: usize, size: usize) -> u64 {
+        let mut bytes = [0; 8];
+        for (i, byte) in bytes[..size].iter_mut().enumerate() {
+            let offset = offset + i;
+            *byte = match &self.mapping {
+                Some(mapping) if offset < mapping.len() => mapping[offset],
+                _ => self.pages[offset / PAGE_SIZE].as_ref().map_or(0, |page| page[offset % PAGE_SIZE]),
+            };
+        }
+        from_le(&bytes[..size])
+    }
+
+    /// Copies bytes into memory, starting at an offset from the start of DRAM.
+    fn copy_in(&mut self, mut offset: usize, mut data: &[u8]) {
+        if let Some(mapping) = &mut self.mapping {
+            if offset < mapping.len() {
+                let len = data.len().min(mapping.len() - offset);
+                mapping[offset..offset + len].copy_from_slice(&data[..len]);
+                data = &data[len..];
+                offset += len;
+            }
         }
+        while !data.is_empty() {
+            let start = offset % PAGE_SIZE;
+            let len = data.len().min(PAGE_SIZE - start);
+            self.page_mut(offset)[start..start + len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+            offset += len;
+        }
+    }
+
+    /// Copies memory out, starting at an offset from the start of DRAM.
+    fn copy_out(&self, mut offset: usize, mut buf: &mut [u8]) {
+        if let Some(mapping) = &self.mapping {
+            if offset < mapping.len() {
+                let len = buf.len().min(mapping.len() - offset);
+                buf[..len].copy_from_slice(&mapping[offset..offset + len]);
+                buf = &mut buf[len..];
+                offset += len;
+            }
+        }
+        while !buf.is_empty() {
+            let start = offset % PAGE_SIZE;
+            let len = buf.len().min(PAGE_SIZE - start);
+            match &self.pages[offset / PAGE_SIZE] {
+                Some(page) => buf[..len].copy_from_slice(&page[start..start + len]),
+                None => buf[..len].fill(0),
+            }
+            buf = &mut buf[len..];
+            offset += len;
+        }
+    }
+
+    /// Finds the offset into memory of a run of bytes, if all of it is in DRAM.
+    fn offset(&self, addr: u64, len: usize) -> Option<usize> {
+        let offset = addr.checked_sub(DRAM_BASE)? as usize;
+        (offset.checked_add(len)? <= self.size).then_some(offset)
+    }
 
+    /// Reads a run of bytes, such as a buffer which a device reads directly from memory.
+    pub fn read_into(&self, addr: u64, buf: &mut [u8]) -> Result<(), Trap> {
+        let offset = self.offset(addr, buf.len()).ok_or(Trap::LoadAccessFault)?;
+        self.copy_out(offset, buf);
         Ok(())
     }
 
-    fn read_bytes(&self, addr: u64, size: usize) -> Result<u64, Trap> {
-        let index = (addr - DRAM_BASE) as usize;
-        Ok((self.dram[index..index + size])
-            .iter()
-            .enumerate()
-            .map(|(i, &byte)| (byte as u64) << (i * 8))
-            .sum())
+    /// Writes a run of bytes, such as a buffer which a device writes directly to memory.
+    pub fn write_from(&mut self, addr: u64, data: &[u8]) -> Result<(), Trap> {
+        let offset = self.offset(addr, data.len()).ok_or(Trap::StoreAccessFault)?;
+        self.copy_in(offset, data);
+        Ok(())
     }
 }
 
 impl Addressable for DRAM {
+    /// Accesses within a page, or within the mapped file, are loaded directly from it.
     fn read(&self, addr: u64, size: Size) -> Result<u64, Trap> {
-        if self.contains(addr) && self.contains(addr + size.clone() as u64) {
-            self.read_bytes(addr, size as usize)
-        } else {
-            Err(Trap::LoadAccessFault)
+        if !self.contains(addr) || !self.contains(addr + size as u64 - 1) {
+            return Err(Trap::LoadAccessFault);
+        }
+        let (offset, len) = ((addr - DRAM_BASE) as usize, size as usize);
+        if let Some(mapping) = &self.mapping {
+            if offset + len <= mapping.len() {
+                return Ok(from_le(&mapping[offset..offset + len]));
+            }
+        }
+        let start = offset % PAGE_SIZE;
+        if start + len > PAGE_SIZE || offset < self.mapped_len() {
+            return Ok(self.read_bytes(offset, len));
+        }
+        match &self.pages[offset / PAGE_SIZE] {
+            Some(page) => Ok(from_le(&page[start..start + len])),
+            None => Ok(0),
         }
     }
 
-    fn write(&mut self, addr: u64, size: Size, data: Vec<u8>) -> Result<(), Trap> {
-        assert!(data.len() == size.clone() as usize);
-        if self.contains(addr) && self.contains(addr + size.clone() as u64) {
-            self.write_bytes(addr, size as u8, data)
+    fn write(&mut self, addr: u64, size: Size, value: u64) -> Result<(), Trap> {
+        if !self.contains(addr) || !self.contains(addr + size as u64 - 1) {
+            return Err(Trap::StoreAccessFault);
+        }
+        let (offset, len) = ((addr - DRAM_BASE) as usize, size as usize);
+        let bytes = &value.to_le_bytes()[..len];
+        let start = offset % PAGE_SIZE;
+        if start + len <= PAGE_SIZE && offset >= self.mapped_len() {
+            self.page_mut(offset)[start..start + len].copy_from_slice(bytes);
         } else {
-            Err(Trap::StoreAccessFault)
+            self.copy_in(offset, bytes);
         }
+        Ok(())
     }
 
     fn size(&self) -> u64 {
-        self.dram.len() as u64
+        self.size as u64
     }
 
     fn contains(&self, addr: u64) -> bool {
@@ -67,8 +180,9 @@ impl Addressable for DRAM {
 
 impl Imageable for DRAM {
     fn load_image(&mut self, image: Vec<u8>) {
+        assert!(image.len() <= self.size);
         self.code_len = image.len() as u64;
-        self.dram.splice(..image.len(), image.iter().cloned());
+        self.copy_in(0, &image);
     }
 
     fn clear_image(&mut self) {
@@ -76,15 +190,58 @@ impl Imageable for DRAM {
     }
 
     fn save_image(&self) -> Vec<u8> {
-        unimplemented!()
+        let mut image = vec![0; self.size];
+        let mapped = self.mapped_len();
+        for (index, page) in self.pages.iter().enumerate() {
+            if let Some(page) = page {
+                let start = index * PAGE_SIZE;
+                let len = PAGE_SIZE.min(self.size - start);
+                image[start..start + len].copy_from_slice(&page[..len]);
+            }
+        }
+        if let Some(mapping) = &self.mapping {
+            image[..mapped].copy_from_slice(mapping);
+        }
+        image
+    }
+}
+
+impl Snapshotable for DRAM {
+    /// Only the pages which have been written to, or which are mapped from a file, are saved.
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let mapped = self.mapping.iter().flat_map(|mapping| mapping.chunks(PAGE_SIZE).enumerate());
+        let pages = self.pages.iter().enumerate().filter_map(|(index, page)| {
+            let page = page.as_ref()?;
+            Some((index, &page[..PAGE_SIZE.min(self.size - index * PAGE_SIZE)]))
+        });
+        let pages = mapped.chain(pages);
+        snapshot.section("dram")
+            .u64(self.code_len)
+            .pages(self.size, pages);
+    }
+
+    /// Pages which weren't saved are freed, so a restored machine only holds the memory which the
+    /// snapshot did.
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("dram")?;
+        self.code_len = section.u64()?;
+        self.pages.iter_mut().for_each(|page| *page = None);
+        if let Some(mapping) = &mut self.mapping {
+            mapping.fill(0);
+        }
+        section.pages(self.size, |index, page| self.copy_in(index * PAGE_SIZE, page))?;
+        section.finish()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::fs::{self, File};
+
     use crate::components::memory::image::Imageable;
-    use crate::components::memory::{address::Addressable, Size};
+    use crate::components::memory::{address::Addressable, mapping::Sharing, Size};
     use crate::components::cpu::Trap;
+    use crate::snapshot::{SnapshotReader, SnapshotWriter, Snapshotable, PAGE_SIZE};
 
     use super::DRAM;
 
@@ -103,6 +260,10 @@ mod test {
 
         let result3 = dram.read(0x5000_3492, Size::HalfWord);
         assert!(result3.is_err_and(|e| e == Trap::LoadAccessFault));
+
+        // Accesses may end on the last byte, but not past it.
+        assert!(dram.read(0x8000_03f8, Size::DoubleWord).is_ok());
+        assert!(dram.read(0x8000_03fc, Size::DoubleWord).is_err());
         
         
     }
@@ -114,12 +275,82 @@ mod test {
 
         dram.load_image(vec![0x81, 0x23, 0x47, 0xa4, 0x7b, 0x00, 0x81, 0x20, 0x45]);
 
-        let result4 = dram.write(0x8000_1024, Size::Byte, vec![0xaa]);
+        let result4 = dram.write(0x8000_1024, Size::Byte, 0xaa);
         assert!(result4.is_err_and(|e| e == Trap::StoreAccessFault));
 
-        let result5 = dram.write(0x8000_0003, Size::Byte, vec![0xff]);
+        let result5 = dram.write(0x8000_0003, Size::Byte, 0xff);
         assert!(result5.is_ok());
         let read5 = dram.read(0x8000_0000, Size::Word);
         assert!(read5.is_ok_and(|v| v == 0xff_47_23_81));
     }
+
+    #[test]
+    pub fn it_allocates_pages_when_written() {
+        let mut dram = DRAM::new(1024 * 1024 * 1024);
+        assert_eq!(dram.allocated(), 0);
+
+        // Reads of untouched memory are zero, and don't allocate.
+        assert!(dram.read(0x8000_5000, Size::DoubleWord).is_ok_and(|v| v == 0));
+        assert_eq!(dram.allocated(), 0);
+
+        // A write which straddles two pages allocates both.
+        assert!(dram.write_u32(0x8000_1ffe, 0x44_33_22_11).is_ok());
+        assert!(dram.read(0x8000_1ffe, Size::Word).is_ok_and(|v| v == 0x44_33_22_11));
+        assert_eq!(dram.allocated(), 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    pub fn it_snapshots_only_touched_pages() {
+        let mut dram = DRAM::new(1024 * 1024 * 1024);
+        dram.load_image(vec![0xaa; 3]);
+        assert!(dram.write_u64(0xbfff_fff8, 0xbbbb_bbbb_bbbb_bbbb).is_ok());
+
+        let mut snapshot = SnapshotWriter::new();
+        dram.save(&mut snapshot);
+        let mut file = vec![];
+        snapshot.write_to(&mut file).unwrap();
+        assert!(file.len() < 4 * PAGE_SIZE);
+
+        let mut restored = DRAM::new(1024 * 1024 * 1024);
+        assert!(restored.write_u8(0x8010_0000, 0xcc).is_ok());
+        let mut snapshot = SnapshotReader::read_from(file.as_slice()).unwrap();
+        restored.restore(&mut snapshot).unwrap();
+
+        assert_eq!(restored.allocated(), 2 * PAGE_SIZE);
+        assert!(restored.read(0x8000_0000, Size::Word).is_ok_and(|v| v == 0x00_aa_aa_aa));
+        assert!(restored.read(0xbfff_fff8, Size::DoubleWord).is_ok_and(|v| v == 0xbbbb_bbbb_bbbb_bbbb));
+        assert!(restored.read(0x8010_0000, Size::Byte).is_ok_and(|v| v == 0));
+    }
+
+    #[test]
+    pub fn it_maps_files() {
+        let path = std::env::temp_dir().join(format!("rv64-dram-test-{}", std::process::id()));
+        fs::write(&path, [0x11, 0x22, 0x33, 0x44, 0x55]).unwrap();
+
+        // A private mapping shows the file, and the rest of memory is still there, but writes
+        // don't reach the file.
+        let mut dram = DRAM::new(4 * PAGE_SIZE);
+        dram.map_file(&File::open(&path).unwrap(), Sharing::Private).unwrap();
+        assert!(dram.read(0x8000_0002, Size::Word).is_ok_and(|v| v == 0x55_44_33));
+        assert!(dram.write_u64(0x8000_0ffc, 0xaaaa_bbbb_cccc_dddd).is_ok());
+        assert!(dram.read_u64(0x8000_0ffc).is_ok_and(|v| v == 0xaaaa_bbbb_cccc_dddd));
+        assert!(dram.write_u8(0x8000_0000, 0x99).is_ok());
+        assert_eq!(dram.allocated(), PAGE_SIZE);
+        assert_eq!(fs::read(&path).unwrap(), [0x11, 0x22, 0x33, 0x44, 0x55]);
+
+        // A shared mapping covers all of memory, and writes go through to the file.
+        let mut dram = DRAM::new(4 * PAGE_SIZE);
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+        dram.map_file(&file, Sharing::Shared).unwrap();
+        assert!(dram.write_u32(0x8000_3ffc, 0xdead_beef).is_ok());
+        assert!(dram.write_u8(0x8000_0000, 0x99).is_ok());
+        assert_eq!(dram.allocated(), 0);
+
+        let contents = fs::read(&path);
+        let _ = fs::remove_file(&path);
+        let contents = contents.unwrap();
+        assert_eq!(contents.len(), 4 * PAGE_SIZE);
+        assert_eq!(contents[..5], [0x99, 0x22, 0x33, 0x44, 0x55]);
+        assert_eq!(contents[4 * PAGE_SIZE - 4..], [0xef, 0xbe, 0xad, 0xde]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,72 @@
+use std::{fs::File, io, ops::{Deref, DerefMut}};
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::snapshot::PAGE_SIZE;
+
+/// How changes to memory which is backed by a host file reach the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharing {
+    /// Writes are copy-on-write, so the file is never changed.
+    Private,
+    /// Writes go through to the file, where other processes which map it see them straight away.
+    Shared,
+}
+
+/// A host file which is mapped over the start of a region of memory, so that large images are
+/// paged in by the host rather than copied in.
+#[derive(Debug)]
+pub struct Mapping {
+    map: MmapMut,
+    sharing: Sharing,
+}
+
+impl Mapping {
+    /// Maps a file over a region of the given size. A shared file is grown to the size of the
+    /// region, so that all of it is visible to other processes. A private mapping covers as much
+    /// of the region as the file does, up to a whole page, which is none of it if the file is
+    /// empty. The rest of the last page reads as zero.
+    pub fn new(file: &File, size: usize, sharing: Sharing) -> io::Result<Option<Self>> {
+        let file_len = file.metadata()?.len() as usize;
+        let len = match sharing {
+            Sharing::Private => file_len.next_multiple_of(PAGE_SIZE).min(size),
+            Sharing::Shared => {
+                if file_len < size {
+                    file.set_len(size as u64)?;
+                }
+                size
+            },
+        };
+        if len == 0 {
+            return Ok(None);
+        }
+
+        // Safety: the mapping is only sound while no other process truncates the file, which is
+        // the same contract as any other emulator which maps guest memory from a file.
+        let map = unsafe {
+            match sharing {
+                Sharing::Private => MmapOptions::new().len(len).map_copy(file)?,
+                Sharing::Shared => MmapOptions::new().len(len).map_mut(file)?,
+            }
+        };
+        Ok(Some(Self { map, sharing }))
+    }
+
+    pub fn sharing(&self) -> Sharing {
+        self.sharing
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl DerefMut for Mapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.map
+    }
+}
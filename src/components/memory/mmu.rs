@@ -1,13 +1,22 @@
 #![allow(dead_code)]
 
-use crate::components::{bus::{DRAM_BASE, ROM_BASE, ROM_END}, cpu::{PrivilegeMode, Trap, Xlen}, Bus};
+use crate::{components::{bus::{DRAM_BASE, ROM_BASE, ROM_END}, cpu::{PrivilegeMode, Trap, Xlen}, Bus}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable, PAGE_SIZE}};
 
 use super::{address::Addressable, image::Imageable, Size};
 
 pub struct MMU {
     bus: Bus,
     xlen: Xlen,
-    pmode: PrivilegeMode
+    pmode: PrivilegeMode,
+    /// The size of the address space of a Linux process which runs without a kernel. In place of
+    /// page tables, its user mode addresses are backed by DRAM from `DRAM_BASE` onwards.
+    user_space: Option<u64>,
+}
+
+impl Default for MMU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MMU {
@@ -16,18 +25,37 @@ impl MMU {
             bus: Bus::new(),
             xlen: Xlen::Bit64,
             pmode: PrivilegeMode::Machine,
+            user_space: None,
         }
     }
 
+    /// Retrieves a mutable reference to the bus, for access to physical memory and devices.
+    pub fn bus(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
     pub fn load_dram_image(&mut self, image: Vec<u8>) {
         self.bus.dram().load_image(image);
     }
 
     /// Updates the privilege mode of the MMU.
-    fn set_privilege_mode(&mut self, mode: PrivilegeMode) {
+    pub fn set_privilege_mode(&mut self, mode: PrivilegeMode) {
         self.pmode = mode;
     }
 
+    /// Backs user mode addresses with DRAM, for running a Linux process without a kernel. The
+    /// address space covers the size of DRAM, except for the first page, so that null pointers
+    /// fault.
+    pub fn map_user_space(&mut self) {
+        self.user_space = Some(self.bus.dram().size());
+    }
+
+    /// Retrieves the size of the address space of a Linux process, if user mode addresses are
+    /// backed by DRAM.
+    pub fn user_space(&self) -> Option<u64> {
+        self.user_space
+    }
+
     /// Gets the effective address from a given address. In the case that the MMU is running in
     /// 32-bit mode, then the upper 32 bits of the address are zeroed. 
     fn get_effective_address(&self, addr: u64) -> u64 {
@@ -44,7 +72,9 @@ impl MMU {
     /// the physical address is mapped to any device.
     fn validate_address(&mut self, vaddr: u64) -> Result<bool, Trap> {
         let eaddr = self.get_effective_address(vaddr);
-        let paddr = self.translate(eaddr);
+        let Some(paddr) = self.translate(eaddr) else {
+            return Ok(false);
+        };
         match paddr >= DRAM_BASE {
             true => Ok(true),
             false => match paddr {
@@ -56,9 +86,14 @@ impl MMU {
 
     /// Translates a virtual address into a physical address. If paging is disabled, or if the 
     /// privilege is machine mode, then the virtual address is the same as the physical address.    
-    fn translate(&self, vaddr: u64) -> u64 {
-        match self.pmode {
-            PrivilegeMode::Machine => vaddr,
+    /// Returns `None` if the address isn't mapped.
+    fn translate(&self, vaddr: u64) -> Option<u64> {
+        match (self.pmode, self.user_space) {
+            (PrivilegeMode::Machine, _) => Some(vaddr),
+            (PrivilegeMode::User, Some(size)) => match vaddr >= PAGE_SIZE as u64 && vaddr < size {
+                true => Some(DRAM_BASE + vaddr),
+                false => None,
+            },
             _ => unimplemented!(),
         }
     }
@@ -70,7 +105,7 @@ impl MMU {
     /// address is the same as the physical address. 
     pub fn load(&self, vaddr: u64, size: Size) -> Result<u64, Trap> {
         let eaddr = self.get_effective_address(vaddr);
-        let paddr = self.translate(eaddr);
+        let paddr = self.translate(eaddr).ok_or(Trap::LoadPageFault)?;
         self.bus.read(paddr, size)
     }
 
@@ -79,14 +114,132 @@ impl MMU {
     /// Translates the virtual address into a physical address before attempting to read from
     /// memory. If paging is disabled, or if the privilege is machine mode, then the virtual 
     /// address is the same as the physical address. 
-    pub fn store(&mut self, vaddr: u64, size: Size, data: Vec<u8>) -> Result<(), Trap> {
+    pub fn store(&mut self, vaddr: u64, size: Size, value: u64) -> Result<(), Trap> {
         let eaddr = self.get_effective_address(vaddr);
-        let paddr = self.translate(eaddr);
-        self.bus.write(paddr, size, data)
+        let paddr = self.translate(eaddr).ok_or(Trap::StorePageFault)?;
+        self.bus.write(paddr, size, value)
+    }
+
+    pub fn load_u8(&self, vaddr: u64) -> Result<u8, Trap> {
+        self.load(vaddr, Size::Byte).map(|v| v as u8)
+    }
+
+    pub fn load_u16(&self, vaddr: u64) -> Result<u16, Trap> {
+        self.load(vaddr, Size::HalfWord).map(|v| v as u16)
+    }
+
+    pub fn load_u32(&self, vaddr: u64) -> Result<u32, Trap> {
+        self.load(vaddr, Size::Word).map(|v| v as u32)
+    }
+
+    pub fn load_u64(&self, vaddr: u64) -> Result<u64, Trap> {
+        self.load(vaddr, Size::DoubleWord)
+    }
+
+    pub fn store_u8(&mut self, vaddr: u64, value: u8) -> Result<(), Trap> {
+        self.store(vaddr, Size::Byte, value as u64)
+    }
+
+    pub fn store_u16(&mut self, vaddr: u64, value: u16) -> Result<(), Trap> {
+        self.store(vaddr, Size::HalfWord, value as u64)
+    }
+
+    pub fn store_u32(&mut self, vaddr: u64, value: u32) -> Result<(), Trap> {
+        self.store(vaddr, Size::Word, value as u64)
+    }
+
+    pub fn store_u64(&mut self, vaddr: u64, value: u64) -> Result<(), Trap> {
+        self.store(vaddr, Size::DoubleWord, value)
+    }
+
+    /// Loads a run of bytes, such as a buffer passed to a system call.
+    pub fn load_bytes(&self, vaddr: u64, len: u64) -> Result<Vec<u8>, Trap> {
+        (0..len)
+            .map(|i| self.load_u8(vaddr.wrapping_add(i)))
+            .collect()
+    }
+
+    /// Loads a null-terminated string, without the null.
+    pub fn load_cstr(&self, vaddr: u64) -> Result<Vec<u8>, Trap> {
+        let mut bytes = vec![];
+        loop {
+            match self.load_u8(vaddr.wrapping_add(bytes.len() as u64))? {
+                0 => return Ok(bytes),
+                b => bytes.push(b),
+            }
+        }
+    }
+
+    /// Stores a run of bytes, such as the result of a system call.
+    pub fn store_bytes(&mut self, vaddr: u64, data: &[u8]) -> Result<(), Trap> {
+        for (i, &b) in data.iter().enumerate() {
+            self.store_u8(vaddr.wrapping_add(i as u64), b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Snapshotable for MMU {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("mmu")
+            .u8(self.xlen as u8)
+            .u8(self.pmode as u8);
+        self.bus.save(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("mmu")?;
+        self.xlen = Xlen::try_from(section.u8()?).map_err(|_| section.corrupt())?;
+        self.pmode = PrivilegeMode::try_from(section.u8()?).map_err(|_| section.corrupt())?;
+        section.finish()?;
+        self.bus.restore(snapshot)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::time::Instant;
+
+    use crate::components::{bus::DRAM_BASE, cpu::Trap, memory::Size};
+
+    use super::MMU;
+
+    #[test]
+    fn it_makes_typed_accesses() {
+        let mut mmu = MMU::new();
+        assert!(mmu.store_u64(DRAM_BASE, 0x0123_4567_89ab_cdef).is_ok());
+        assert_eq!(mmu.load_u8(DRAM_BASE), Ok(0xef));
+        assert_eq!(mmu.load_u16(DRAM_BASE + 2), Ok(0x89ab));
+        assert_eq!(mmu.load_u32(DRAM_BASE + 4), Ok(0x0123_4567));
+
+        // Only the low bytes of a value are stored.
+        assert!(mmu.store(DRAM_BASE, Size::HalfWord, 0xffff_0000_2211).is_ok());
+        assert_eq!(mmu.load_u64(DRAM_BASE), Ok(0x0123_4567_89ab_2211));
 
-}
\ No newline at end of file
+        // Accesses which straddle a page boundary take the slow path.
+        assert!(mmu.store_u32(DRAM_BASE + 0xffe, 0xdead_beef).is_ok());
+        assert_eq!(mmu.load_u32(DRAM_BASE + 0xffe), Ok(0xdead_beef));
+        assert_eq!(mmu.load_u16(DRAM_BASE + 0x1000), Ok(0xdead));
+
+        assert_eq!(mmu.store_u8(0x10, 1), Err(Trap::StoreAccessFault));
+        assert_eq!(mmu.load_u8(0x10), Err(Trap::LoadAccessFault));
+    }
+
+    /// Measures the time of a load and a store to DRAM. Run it with
+    /// `cargo test --release -- --ignored --nocapture bench_memory_accesses`.
+    #[test]
+    #[ignore]
+    fn bench_memory_accesses() {
+        const ACCESSES: u64 = 10_000_000;
+        let mut mmu = MMU::new();
+        let start = Instant::now();
+        let mut sum = 0u64;
+        for i in 0..ACCESSES {
+            let addr = DRAM_BASE + (i * 8) % 0x10_0000;
+            mmu.store_u64(addr, i).unwrap();
+            sum = sum.wrapping_add(mmu.load_u64(addr).unwrap());
+        }
+        assert_eq!(sum, ACCESSES * (ACCESSES - 1) / 2);
+        println!("{:?} per access", start.elapsed() / (2 * ACCESSES as u32));
+    }
+}
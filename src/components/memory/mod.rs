@@ -1,5 +1,6 @@
 pub mod address;
 pub mod image;
+pub mod mapping;
 pub mod mmu;
 pub mod registers;
 pub mod dram;
@@ -16,4 +17,23 @@ pub enum Size {
     HalfWord = 2,
     Word = 4,
     DoubleWord = 8,
+}
+
+impl Size {
+    /// The mask of the bits which an access of this size covers.
+    pub fn mask(self) -> u64 {
+        u64::MAX >> (64 - 8 * self as u64)
+    }
+}
+
+/// Reads a little-endian value from bytes, of which there are as many as the size of an access.
+#[inline]
+pub fn from_le(bytes: &[u8]) -> u64 {
+    match bytes.len() {
+        1 => bytes[0] as u64,
+        2 => u16::from_le_bytes(bytes.try_into().unwrap()) as u64,
+        4 => u32::from_le_bytes(bytes.try_into().unwrap()) as u64,
+        8 => u64::from_le_bytes(bytes.try_into().unwrap()),
+        len => panic!("invalid access of {} bytes", len),
+    }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Register {
     X0,  // zero
@@ -73,6 +74,36 @@ pub enum Register {
 
 }
 
+/// The ABI mnemonics of the integer registers, indexed by register number.
+pub const XREG_ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// The ABI mnemonics of the floating point registers, indexed by register number.
+pub const FREG_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+    "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+    "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+impl Register {
+    /// Gets the ABI mnemonic of the register, e.g. `sp` for X2 or `fa0` for F10.
+    pub fn abi_name(self) -> &'static str {
+        let num = self as usize;
+        match self {
+            Register::PC => "pc",
+            Register::ICOUNT => "icount",
+            Register::MISA => "misa",
+            _ if num < 32 => XREG_ABI_NAMES[num],
+            _ => FREG_ABI_NAMES[num - 32],
+        }
+    }
+}
+
 impl From<usize> for Register {
     fn from(val: usize) -> Register {
         assert!(val <= 31);
@@ -121,10 +152,16 @@ pub struct RegisterFile<T> {
     regs: Vec<T>
 }
 
+impl<T: Clone + Default> Default for RegisterFile<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Clone + Default> RegisterFile<T> {
     pub fn new() -> Self {
         RegisterFile { 
-            regs: vec![T::default(); 31] 
+            regs: vec![T::default(); 32] 
         }
     }
 
@@ -152,5 +189,14 @@ impl<T: Clone + Default> RegisterFile<T> {
 
 #[cfg(test)]
 mod test {
+    use super::Register;
 
+    #[test]
+    fn it_names_registers_by_abi() {
+        assert_eq!(Register::X0.abi_name(), "zero");
+        assert_eq!(Register::X8.abi_name(), "s0");
+        assert_eq!(Register::X31.abi_name(), "t6");
+        assert_eq!(Register::F10.abi_name(), "fa0");
+        assert_eq!(Register::from(2).abi_name(), "sp");
+    }
 }
\ No newline at end of file
@@ -1,37 +1,64 @@
-use crate::components::{bus::{ROM_BASE, ROM_END}, cpu::Trap};
+use std::{fs::File, io};
 
-use super::{address::Addressable, image::Imageable, Size};
+use crate::{components::{bus::{ROM_BASE, ROM_END}, cpu::Trap}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, Snapshotable, PAGE_SIZE}};
+
+use super::{address::Addressable, from_le, image::Imageable, mapping::{Mapping, Sharing}, Size};
 
 pub const SIZE: usize = 0xf000;
 
+/// Read-only memory, the start of which can be backed by a file on the host.
 #[derive(Debug)] 
 pub struct ROM {
-    rom: Vec<u8>    
+    rom: Vec<u8>,
+    mapping: Option<Mapping>,
+}
+
+impl Default for ROM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ROM {
     pub fn new() -> Self {
         Self {
-            rom: vec![0; SIZE]
+            rom: vec![0; SIZE],
+            mapping: None,
+        }
+    }
+
+    /// Backs the ROM with a file on the host, such as a firmware image, in place of what it held.
+    /// The file is always mapped copy-on-write, as loading the boot code or restoring a snapshot
+    /// replaces the ROM's image, which mustn't reach the file.
+    pub fn map_file(&mut self, file: &File) -> io::Result<()> {
+        self.mapping = Mapping::new(file, SIZE, Sharing::Private)?;
+        self.rom.fill(0);
+        Ok(())
+    }
+
+    /// Gets the bytes from an index to the end of the mapped file, or to the end of the ROM.
+    fn bytes(&self, index: usize) -> &[u8] {
+        match &self.mapping {
+            Some(mapping) if index < mapping.len() => &mapping[index..],
+            _ => &self.rom[index..],
         }
     }
 
     fn read_bytes(&self, addr: u64, size: usize) -> Result<u64, Trap> {
         let index = (addr - ROM_BASE) as usize;
-        Ok((self.rom[index..index + size])
-            .iter()
-            .enumerate()
-            .map(|(i, &byte)| (byte as u64) << (i * 8))
-            .sum())
+        let bytes = self.bytes(index);
+        if bytes.len() >= size {
+            return Ok(from_le(&bytes[..size]));
+        }
+        // The value straddles the end of the mapped file.
+        let bytes: Vec<u8> = (index..index + size).map(|i| self.bytes(i)[0]).collect();
+        Ok(from_le(&bytes))
     }
 }
 
 impl Addressable for ROM {
     fn contains(&self, addr: u64) -> bool {
-        match addr {
-            ROM_BASE..ROM_END => true,
-            _ => false
-        }
+        matches!(addr, ROM_BASE..ROM_END)
     }
     
     fn size(&self) -> u64 {
@@ -39,14 +66,14 @@ impl Addressable for ROM {
     }
 
     fn read(&self, addr: u64, size: Size) -> Result<u64, Trap> {
-        if self.contains(addr) && self.contains(addr + size.clone() as u64) {
+        if self.contains(addr) && self.contains(addr + size as u64 - 1) {
             self.read_bytes(addr, size as usize)
         } else {
             Err(Trap::LoadAccessFault)
         }
     }
 
-    fn write(&mut self, _addr: u64, _size: Size, _data: Vec<u8>) -> Result<(), Trap> {
+    fn write(&mut self, _addr: u64, _size: Size, _value: u64) -> Result<(), Trap> {
         Err(Trap::StoreAccessFault)
     }
 }
@@ -54,7 +81,11 @@ impl Addressable for ROM {
 impl Imageable for ROM {
     fn load_image(&mut self, image: Vec<u8>) {
         assert!(image.len() <= self.size() as usize);
-        self.rom.splice(..image.len(), image.iter().cloned());
+        let mapped = self.mapping.as_ref().map_or(0, |mapping| mapping.len()).min(image.len());
+        if let Some(mapping) = &mut self.mapping {
+            mapping[..mapped].copy_from_slice(&image[..mapped]);
+        }
+        self.rom[mapped..image.len()].copy_from_slice(&image[mapped..]);
     }
 
     fn clear_image(&mut self) {
@@ -62,12 +93,33 @@ impl Imageable for ROM {
     }
 
     fn save_image(&self) -> Vec<u8> {
-        unimplemented!()
+        let mut image = self.rom.clone();
+        if let Some(mapping) = &self.mapping {
+            image[..mapping.len()].copy_from_slice(mapping);
+        }
+        image
+    }
+}
+
+impl Snapshotable for ROM {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let image = self.save_image();
+        snapshot.section("rom").pages(image.len(), image.chunks(PAGE_SIZE).enumerate());
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut section = snapshot.section("rom")?;
+        let mut image = vec![0; SIZE];
+        section.restore_pages(&mut image)?;
+        self.load_image(image);
+        section.finish()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::fs::{self, File};
+
     use crate::components::memory::image::Imageable;
     use crate::components::memory::{address::Addressable, Size};
     use crate::components::cpu::Trap;
@@ -96,12 +148,34 @@ mod test {
 
         rom.load_image(vec![0x81, 0x23, 0x47, 0xa4, 0x7b, 0x00, 0x81, 0x20, 0x45]);
 
-        let result4 = rom.write(0x0000_f000, Size::Byte, vec![0xaa]);
+        let result4 = rom.write(0x0000_f000, Size::Byte, 0xaa);
         assert!(result4.is_err_and(|e| e == Trap::StoreAccessFault));
 
-        let result5 = rom.write(0x0000_1003, Size::Byte, vec![0xff]);
+        let result5 = rom.write(0x0000_1003, Size::Byte, 0xff);
         assert!(result5.is_err_and(|e| e == Trap::StoreAccessFault));
         let read5 = rom.read(0x0000_1000, Size::Word);
         assert!(read5.is_ok_and(|v| v == 0xa4_47_23_81));
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn it_maps_files() {
+        let path = std::env::temp_dir().join(format!("rv64-rom-test-{}", std::process::id()));
+        fs::write(&path, [0x97, 0x02, 0x00, 0x00]).unwrap();
+
+        let mut rom = ROM::new();
+        rom.load_image(vec![0xff; 0x2000]);
+        let mapped = rom.map_file(&File::open(&path).unwrap());
+        mapped.unwrap();
+
+        assert!(rom.read(0x0000_1000, Size::Word).is_ok_and(|v| v == 0x0000_0297));
+        // The rest of the page, and the ROM beyond it, read as zero.
+        assert!(rom.read(0x0000_1ffe, Size::Word).is_ok_and(|v| v == 0));
+        assert!(rom.read(0x0000_2004, Size::Word).is_ok_and(|v| v == 0));
+
+        // Loading an image over the file leaves the file as it was.
+        rom.load_image(vec![0x13; 8]);
+        assert!(rom.read(0x0000_1000, Size::Word).is_ok_and(|v| v == 0x1313_1313));
+        assert_eq!(fs::read(&path).unwrap(), [0x97, 0x02, 0x00, 0x00]);
+        let _ = fs::remove_file(&path);
+    }
+}
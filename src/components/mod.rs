@@ -1,5 +1,6 @@
 pub mod bus;
 pub mod cpu;
+pub mod devices;
 pub mod memory;
 
 pub use cpu::CPU;
@@ -0,0 +1,384 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::components::memory::Size;
+
+use super::{Debugger, StopReason};
+
+/// The register numbers which GDB uses for RISC-V: x0 to x31, then the pc.
+const PC_REGNUM: usize = 32;
+
+/// Waits for GDB to connect on a TCP port, then serves it until it detaches.
+pub fn listen(debugger: &mut Debugger, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for gdb on port {}", listener.local_addr()?.port());
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(stream)?.serve(debugger)
+}
+
+/// A minimal stub for GDB's remote serial protocol, with support for reverse execution:
+///
+/// ```text
+/// (gdb) set arch riscv:rv64
+/// (gdb) target remote :1234
+/// (gdb) reverse-stepi
+/// (gdb) reverse-continue
+/// ```
+pub struct GdbStub {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+    last_stop: String,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+            last_stop: "S05".to_string(),
+        })
+    }
+
+    /// Handles packets until GDB detaches, kills the program or disconnects.
+    pub fn serve(&mut self, debugger: &mut Debugger) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match self.handle(debugger, &packet) {
+                Some(reply) => reply,
+                None => {
+                    self.write_packet("OK")?;
+                    return Ok(());
+                },
+            };
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a packet, returning the reply, or `None` if the session is over.
+    fn handle(&mut self, debugger: &mut Debugger, packet: &str) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.last_stop.clone(),
+            Some(b'g') => {
+                let cpu = debugger.cpu();
+                let mut regs: String = (0..32).map(|r| hex_u64(cpu.read_xreg(r))).collect();
+                regs += &hex_u64(cpu.pc());
+                regs
+            },
+            Some(b'G') => {
+                let values: Vec<u64> = packet.as_bytes()[1..].chunks(16).filter_map(parse_hex_u64).collect();
+                debugger.modify(|cpu| {
+                    for (num, value) in values.iter().enumerate().take(PC_REGNUM + 1) {
+                        set_reg(cpu, num, *value);
+                    }
+                });
+                "OK".to_string()
+            },
+            Some(b'p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(num) if num < PC_REGNUM => hex_u64(debugger.cpu().read_xreg(num as u8)),
+                Ok(PC_REGNUM) => hex_u64(debugger.cpu().pc()),
+                _ => "E01".to_string(),
+            },
+            Some(b'P') => {
+                let parsed = packet[1..].split_once('=').and_then(|(num, value)| {
+                    Some((usize::from_str_radix(num, 16).ok()?, parse_hex_u64(value.as_bytes())?))
+                });
+                match parsed {
+                    Some((num, value)) if num <= PC_REGNUM => {
+                        debugger.modify(|cpu| set_reg(cpu, num, value));
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            Some(b'm') => {
+                let Some((addr, end)) = parse_addr_len(&packet[1..]) else { return Some("E01".to_string()) };
+                let bytes: Option<String> = (addr..end)
+                    .map(|a| debugger.cpu().mmu().load(a, Size::Byte).ok().map(|b| format!("{:02x}", b)))
+                    .collect();
+                bytes.unwrap_or_else(|| "E14".to_string())
+            },
+            Some(b'M') => {
+                let parsed = packet[1..].split_once(':').and_then(|(range, data)| {
+                    let (addr, end) = parse_addr_len(range)?;
+                    let data = parse_hex_bytes(data.as_bytes())?;
+                    (data.len() as u64 == end - addr).then_some((addr, data))
+                });
+                let Some((addr, data)) = parsed else { return Some("E01".to_string()) };
+                let stored = debugger.modify(|cpu| {
+                    data.iter().enumerate().all(|(i, b)| {
+                        cpu.mmu().store_u8(addr + i as u64, *b).is_ok()
+                    })
+                });
+                match stored {
+                    true => "OK".to_string(),
+                    false => "E14".to_string(),
+                }
+            },
+            Some(b'c') => {
+                let reason = debugger.cont(|| self.interrupt_pending());
+                self.stop(reason)
+            },
+            Some(b's') => {
+                let reason = debugger.step();
+                self.stop(reason)
+            },
+            Some(b'b') if packet == "bc" => {
+                let reason = debugger.reverse_continue();
+                self.stop(reason)
+            },
+            Some(b'b') if packet == "bs" => {
+                let reason = debugger.reverse_step();
+                self.stop(reason)
+            },
+            Some(b'Z') | Some(b'z') => {
+                let insert = packet.starts_with('Z');
+                let mut fields = packet[1..].split(',');
+                let kind = fields.next();
+                let addr = fields.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+                let end = fields.next().and_then(|l| addr?.checked_add(u64::from_str_radix(l, 16).ok()?));
+                match (kind, addr, end) {
+                    (Some("0"), Some(addr), _) if insert => {
+                        debugger.add_breakpoint(addr);
+                        "OK".to_string()
+                    },
+                    (Some("0"), Some(addr), _) => {
+                        debugger.remove_breakpoint(addr);
+                        "OK".to_string()
+                    },
+                    (Some("2"), Some(addr), Some(end)) if insert => {
+                        debugger.add_watchpoint(addr..end);
+                        "OK".to_string()
+                    },
+                    (Some("2"), Some(addr), Some(end)) => {
+                        debugger.remove_watchpoint(&(addr..end));
+                        "OK".to_string()
+                    },
+                    _ => String::new(),
+                }
+            },
+            Some(b'k') | Some(b'D') => return None,
+            Some(b'H') => "OK".to_string(),
+            Some(b'q') if packet.starts_with("qSupported") => {
+                "PacketSize=1000;ReverseStep+;ReverseContinue+".to_string()
+            },
+            Some(b'q') if packet == "qAttached" => "1".to_string(),
+            Some(b'q') if packet == "qC" => "QC1".to_string(),
+            Some(b'q') if packet == "qfThreadInfo" => "m1".to_string(),
+            Some(b'q') if packet == "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    /// Builds the stop reply for the reason that the machine stopped, and remembers it for `?`.
+    fn stop(&mut self, reason: StopReason) -> String {
+        self.last_stop = match reason {
+            StopReason::Step | StopReason::Breakpoint | StopReason::Trap(_) => "S05".to_string(),
+            StopReason::Watchpoint(addr) => format!("T05watch:{:x};", addr),
+            StopReason::StartOfHistory => "T05replaylog:begin;".to_string(),
+            StopReason::Interrupted => "S02".to_string(),
+            StopReason::Exited(code) => format!("W{:02x}", code & 0xff),
+        };
+        self.last_stop.clone()
+    }
+
+    /// Checks whether GDB has sent an interrupt (Ctrl-C) while the machine is running. It may
+    /// already have been read into the buffer along with the packet which resumed the machine.
+    fn interrupt_pending(&mut self) -> bool {
+        if let Some(&byte) = self.reader.buffer().first() {
+            let pending = byte == 0x03;
+            if pending {
+                self.reader.consume(1);
+            }
+            return pending;
+        }
+        let mut byte = [0];
+        let _ = self.stream.set_nonblocking(true);
+        let pending = matches!(self.stream.peek(&mut byte), Ok(1) if byte[0] == 0x03);
+        let _ = self.stream.set_nonblocking(false);
+        if pending {
+            let _ = self.reader.read_exact(&mut byte);
+        }
+        pending
+    }
+
+    /// Reads the next packet, acknowledging it. Returns `None` once GDB disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            if self.reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            // Acknowledgements and stray interrupts are skipped.
+            if byte[0] != b'$' {
+                continue;
+            }
+
+            let mut data = vec![];
+            loop {
+                self.reader.read_exact(&mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        write!(self.stream, "${}#{:02x}", data, checksum_of(data.as_bytes()))?;
+        self.stream.flush()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Formats a register in target byte order, which is little-endian.
+fn hex_u64(value: u64) -> String {
+    value.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_u64(hex: &[u8]) -> Option<u64> {
+    let hex = std::str::from_utf8(hex).ok().filter(|h| h.len() == 16)?;
+    u64::from_str_radix(hex, 16).ok().map(u64::swap_bytes)
+}
+
+/// Decodes pairs of hex digits into bytes, failing on anything else, including an odd digit.
+fn parse_hex_bytes(hex: &[u8]) -> Option<Vec<u8>> {
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks_exact(2).map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?)).collect()
+}
+
+/// Parses an `addr,length` pair into the start and end of the range, which mustn't overflow.
+fn parse_addr_len(text: &str) -> Option<(u64, u64)> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = u64::from_str_radix(addr, 16).ok()?;
+    Some((addr, addr.checked_add(u64::from_str_radix(len, 16).ok()?)?))
+}
+
+fn set_reg(cpu: &mut crate::components::CPU, num: usize, value: u64) {
+    match num {
+        PC_REGNUM => cpu.set_pc(value),
+        num => cpu.set_xreg(num as u8, value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use crate::{asm, components::CPU, debug::Debugger};
+
+    use super::{checksum_of, GdbStub};
+
+    fn send(stream: &mut TcpStream, packet: &str) -> String {
+        send_raw(stream, &format!("${}#{:02x}", packet, checksum_of(packet.as_bytes())))
+    }
+
+    /// Sends the bytes in one write, and reads the reply to the packet in them.
+    fn send_raw(stream: &mut TcpStream, bytes: &str) -> String {
+        stream.write_all(bytes.as_bytes()).unwrap();
+
+        let mut reply = vec![];
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn it_serves_gdb() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut cpu = CPU::new();
+            cpu.mmu().load_dram_image(asm!("
+                    li a0, 1
+                    li a0, 2
+                    sd a0, 64(zero)
+            "));
+            let mut debugger = Debugger::new(cpu, 100);
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new(stream).unwrap().serve(&mut debugger).unwrap();
+            debugger.cpu().clock()
+        });
+
+        let mut gdb = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(send(&mut gdb, "qSupported:swbreak+").contains("ReverseStep+"));
+        assert_eq!(send(&mut gdb, "?"), "S05");
+        assert_eq!(send(&mut gdb, "p20"), "0000008000000000");
+        assert_eq!(send(&mut gdb, "m80000000,4"), "13051000");
+        assert_eq!(send(&mut gdb, "mfffffffffffffffe,4"), "E01");
+        assert_eq!(send(&mut gdb, "M80000100,2:é1"), "E01");
+        assert_eq!(send(&mut gdb, "M80000100,2:0a0"), "E01");
+        assert_eq!(send(&mut gdb, "M80000100,2:0a0b"), "OK");
+        assert_eq!(send(&mut gdb, "m80000100,2"), "0a0b");
+
+        assert_eq!(send(&mut gdb, "Z0,80000008,4"), "OK");
+        assert_eq!(send(&mut gdb, "c"), "S05");
+        assert_eq!(send(&mut gdb, "pa"), "0200000000000000");
+        assert_eq!(send(&mut gdb, "bs"), "S05");
+        assert_eq!(send(&mut gdb, "pa"), "0100000000000000");
+        assert_eq!(send(&mut gdb, "bc"), "T05replaylog:begin;");
+        assert_eq!(send(&mut gdb, "p20"), "0000008000000000");
+
+        assert_eq!(send(&mut gdb, "Pa=0900000000000000"), "OK");
+        assert_eq!(&send(&mut gdb, "g")[160..176], "0900000000000000");
+        assert_eq!(send(&mut gdb, "D"), "OK");
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn it_sees_interrupts_sent_with_the_continue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut cpu = CPU::new();
+            cpu.mmu().load_dram_image(asm!("
+                loop:
+                    j loop
+            "));
+            let mut debugger = Debugger::new(cpu, 100);
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new(stream).unwrap().serve(&mut debugger).unwrap();
+        });
+
+        // The interrupt arrives in the same segment as the packet, so it's read into the buffer.
+        let mut gdb = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let packet = format!("$c#{:02x}\x03", checksum_of(b"c"));
+        assert_eq!(send_raw(&mut gdb, &packet), "S02");
+        assert_eq!(send(&mut gdb, "D"), "OK");
+        server.join().unwrap();
+    }
+}
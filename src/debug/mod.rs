@@ -0,0 +1,429 @@
+pub mod gdb;
+pub mod repl;
+
+use std::{collections::BTreeSet, fmt, ops::Range};
+
+use crate::{
+    components::{cpu::Trap, memory::registers::Register, CPU},
+    replay::Journal,
+    snapshot,
+};
+
+/// The number of instructions between the checkpoints which reverse execution restarts from.
+/// Going back by one instruction re-executes up to this many.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 1_000_000;
+
+/// The most checkpoints which are kept. Beyond that, every other one is dropped and the interval
+/// doubles, so a long run's history stays within bounded memory, at the cost of re-executing
+/// further to go back.
+const MAX_CHECKPOINTS: usize = 64;
+
+/// The number of instructions between checks for an interrupt from the user.
+const INTERRUPT_CHECK_INTERVAL: u64 = 4096;
+
+/// Why the machine stopped running under the debugger.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// A single step finished.
+    Step,
+    /// The pc reached a breakpoint.
+    Breakpoint,
+    /// An instruction stored to a watched address.
+    Watchpoint(u64),
+    /// An instruction raised a trap, and the pc is at the handler which took it. The machine only
+    /// stops for traps when they're caught.
+    Trap(Trap),
+    /// The program finished by jumping to address 0, with the exit code in `a0`.
+    Exited(u64),
+    /// Reverse execution reached the earliest point in the recorded history.
+    StartOfHistory,
+    /// The user interrupted the run.
+    Interrupted,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Step => write!(f, "stepped"),
+            Self::Breakpoint => write!(f, "breakpoint"),
+            Self::Watchpoint(addr) => write!(f, "watchpoint: store to {:#x}", addr),
+            Self::Trap(trap) => write!(f, "trap: {:?}", trap),
+            Self::Exited(code) => write!(f, "exited with code {}", code),
+            Self::StartOfHistory => write!(f, "reached the start of the recorded history"),
+            Self::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+/// Runs the machine under the control of a debugger, forwards or backwards.
+///
+/// Reverse execution works by taking a snapshot of the machine every so many instructions.
+/// Going back restores the nearest snapshot before the target instruction count and
+/// re-executes forwards to it, with the machine's nondeterministic inputs replayed from a tape
+/// so that the re-execution is exact.
+pub struct Debugger {
+    cpu: CPU,
+    /// The snapshots to restart from, and the clock at which each was taken, in order.
+    checkpoints: Vec<(u64, Vec<u8>)>,
+    /// The number of instructions between checkpoints, which doubles each time they're thinned.
+    interval: u64,
+    breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<Range<u64>>,
+    /// Whether the machine stops after an instruction raises a trap.
+    catch_traps: bool,
+}
+
+impl Debugger {
+    /// Takes control of the machine. History starts from the machine's current state.
+    pub fn new(cpu: CPU, interval: u64) -> Self {
+        let mut debugger = Self {
+            cpu,
+            checkpoints: vec![],
+            interval: interval.max(1),
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            catch_traps: false,
+        };
+        let clock = debugger.cpu.clock();
+        debugger.cpu.mmu().bus().set_journal(Journal::tape(clock));
+        debugger.checkpoint();
+        debugger
+    }
+
+    pub fn cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    /// Changes the state of the machine, such as writing a register. The history after this
+    /// point no longer applies, so it is forgotten.
+    pub fn modify<R>(&mut self, change: impl FnOnce(&mut CPU) -> R) -> R {
+        let result = change(&mut self.cpu);
+        let clock = self.cpu.clock();
+        self.checkpoints.retain(|(c, _)| *c < clock);
+        self.cpu.mmu().bus().journal().truncate();
+        self.checkpoint();
+        result
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u64> {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Stops the machine after any instruction which stores to the range of addresses.
+    pub fn add_watchpoint(&mut self, range: Range<u64>) {
+        self.watchpoints.push(range);
+    }
+
+    pub fn remove_watchpoint(&mut self, range: &Range<u64>) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != range);
+        self.watchpoints.len() != len
+    }
+
+    /// Stops the machine when an instruction raises a trap, once the guest's handler has taken it.
+    pub fn set_catch_traps(&mut self, catch: bool) {
+        self.catch_traps = catch;
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> StopReason {
+        match self.forward() {
+            Some(reason) => reason,
+            None => self.watch_hit().map_or(StopReason::Step, StopReason::Watchpoint),
+        }
+    }
+
+    /// Runs until a breakpoint or watchpoint is hit, or the program stops. `interrupted` is
+    /// polled every so often, and stops the run when it returns true.
+    pub fn cont(&mut self, mut interrupted: impl FnMut() -> bool) -> StopReason {
+        for count in 1.. {
+            if let Some(reason) = self.forward() {
+                return reason;
+            }
+            if let Some(addr) = self.watch_hit() {
+                return StopReason::Watchpoint(addr);
+            }
+            if self.breakpoints.contains(&self.cpu.pc()) {
+                return StopReason::Breakpoint;
+            }
+            if count % INTERRUPT_CHECK_INTERVAL == 0 && interrupted() {
+                return StopReason::Interrupted;
+            }
+        }
+        unreachable!()
+    }
+
+    /// Goes back by a single instruction.
+    pub fn reverse_step(&mut self) -> StopReason {
+        let clock = self.cpu.clock();
+        if clock <= self.checkpoints[0].0 {
+            return StopReason::StartOfHistory;
+        }
+        self.seek(clock - 1);
+        StopReason::Step
+    }
+
+    /// Runs backwards to the most recent point at which a breakpoint or watchpoint was hit, or
+    /// to the start of the recorded history.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        let end = self.cpu.clock();
+        let mut upper = end;
+
+        // Search each stretch between checkpoints for hits, starting from the most recent.
+        for index in (0..self.checkpoints.len()).rev() {
+            let start = self.checkpoints[index].0;
+            if start >= upper {
+                continue;
+            }
+            self.restore(index);
+
+            let mut hit = None;
+            loop {
+                let clock = self.cpu.clock();
+                // The instruction before a checkpoint is checked with the previous stretch.
+                let watch = match clock > start {
+                    true => self.watch_hit().map(StopReason::Watchpoint),
+                    false => None,
+                };
+                if let Some(reason) = watch {
+                    hit = Some((clock, reason));
+                } else if self.breakpoints.contains(&self.cpu.pc()) {
+                    hit = Some((clock, StopReason::Breakpoint));
+                }
+                if clock + 1 >= upper {
+                    break;
+                }
+                let _ = self.forward();
+            }
+
+            if let Some((clock, reason)) = hit {
+                self.seek(clock);
+                return reason;
+            }
+            upper = start + 1;
+        }
+
+        self.restore(0);
+        StopReason::StartOfHistory
+    }
+
+    /// Moves the machine to the point where the clock had a particular value, by restoring the
+    /// nearest checkpoint before it and executing forwards.
+    pub fn seek(&mut self, clock: u64) {
+        let index = self.checkpoints.partition_point(|(c, _)| *c <= clock).saturating_sub(1);
+        if self.cpu.clock() > clock || self.checkpoints[index].0 > self.cpu.clock() {
+            self.restore(index);
+        }
+        while self.cpu.clock() < clock {
+            if self.cpu.pc() == 0 {
+                break;
+            }
+            let _ = self.forward();
+        }
+    }
+
+    /// Executes one instruction, returning the reason if the machine stops there. A trap is
+    /// taken as it would be without the debugger, and only stops the machine if it's caught.
+    fn forward(&mut self) -> Option<StopReason> {
+        if self.cpu.pc() == 0 {
+            return Some(StopReason::Exited(self.cpu.read_xreg(Register::X10 as u8)));
+        }
+        let trap = self.cpu.tick();
+
+        let last = self.checkpoints.last().unwrap().0;
+        if self.cpu.clock() >= last + self.interval {
+            self.checkpoint();
+        }
+        trap.filter(|_| self.catch_traps).map(StopReason::Trap)
+    }
+
+    /// Finds the address of a store by the last instruction to a watched range.
+    fn watch_hit(&self) -> Option<u64> {
+        let stores = &self.cpu.last_commit().stores;
+        stores.iter()
+            .find(|s| {
+                let range = s.addr..s.addr + s.size as u64;
+                self.watchpoints.iter().any(|w| w.start < range.end && range.start < w.end)
+            })
+            .map(|s| s.addr)
+    }
+
+    fn checkpoint(&mut self) {
+        let mut data = vec![];
+        snapshot::save(&self.cpu, &mut data).unwrap();
+        self.checkpoints.push((self.cpu.clock(), data));
+
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            // The first checkpoint is kept, as the start of the history.
+            let mut index = 0;
+            self.checkpoints.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            self.interval *= 2;
+        }
+    }
+
+    fn restore(&mut self, index: usize) {
+        let (clock, data) = &self.checkpoints[index];
+        snapshot::restore(&mut self.cpu, data.as_slice()).expect("failed to restore a checkpoint");
+        self.cpu.mmu().bus().journal().rewind(*clock);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{asm, components::{cpu::Trap, CPU}};
+
+    use super::{Debugger, StopReason, MAX_CHECKPOINTS};
+
+    /// Counts `a0` up to 10, storing each value, then exits.
+    fn debugger(interval: u64) -> Debugger {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("
+                la t0, counter
+                li a0, 0
+                li a1, 10
+            loop:
+                addi a0, a0, 1
+                sd a0, 0(t0)
+                bne a0, a1, loop
+                li a0, 3
+                jr zero
+            counter:
+                .dword 0
+        "));
+        Debugger::new(cpu, interval)
+    }
+
+    #[test]
+    fn it_steps_backwards_and_forwards() {
+        let mut debugger = debugger(7);
+        for _ in 0..20 {
+            assert_eq!(debugger.step(), StopReason::Step);
+        }
+        let a0 = debugger.cpu().read_xreg(10);
+        let pc = debugger.cpu().pc();
+
+        for _ in 0..5 {
+            assert_eq!(debugger.reverse_step(), StopReason::Step);
+        }
+        assert_eq!(debugger.cpu().clock(), 15);
+        for _ in 0..5 {
+            debugger.step();
+        }
+        assert_eq!((debugger.cpu().read_xreg(10), debugger.cpu().pc()), (a0, pc));
+
+        debugger.seek(0);
+        assert_eq!(debugger.reverse_step(), StopReason::StartOfHistory);
+    }
+
+    #[test]
+    fn it_runs_backwards_to_the_last_write() {
+        let mut debugger = debugger(4);
+        assert_eq!(debugger.cont(|| false), StopReason::Exited(3));
+        let end = debugger.cpu().clock();
+
+        // The counter is written by the `sd` at 0x80000014 on each of the ten iterations.
+        let counter = 0x8000_0024;
+        debugger.add_watchpoint(counter..counter + 8);
+        assert_eq!(debugger.reverse_continue(), StopReason::Watchpoint(counter));
+        assert_eq!(debugger.cpu().read_xreg(10), 10);
+        assert_eq!(debugger.reverse_continue(), StopReason::Watchpoint(counter));
+        assert_eq!(debugger.cpu().read_xreg(10), 9);
+
+        debugger.add_breakpoint(0x8000_0010);
+        assert_eq!(debugger.reverse_continue(), StopReason::Breakpoint);
+        assert_eq!((debugger.cpu().pc(), debugger.cpu().read_xreg(10)), (0x8000_0010, 8));
+
+        assert_eq!(debugger.cont(|| false), StopReason::Watchpoint(counter));
+        assert_eq!(debugger.cpu().read_xreg(10), 9);
+
+        debugger.remove_breakpoint(0x8000_0010);
+        debugger.remove_watchpoint(&(counter..counter + 8));
+        assert_eq!(debugger.reverse_continue(), StopReason::StartOfHistory);
+        assert_eq!(debugger.cpu().clock(), 0);
+        assert_eq!(debugger.cont(|| false), StopReason::Exited(3));
+        assert_eq!(debugger.cpu().clock(), end);
+    }
+
+    #[test]
+    fn it_forgets_the_future_when_the_machine_is_changed() {
+        let mut debugger = debugger(4);
+        for _ in 0..10 {
+            debugger.step();
+        }
+        debugger.seek(5);
+        debugger.modify(|cpu| cpu.set_xreg(11, 3));
+        assert_eq!(debugger.cont(|| false), StopReason::Exited(3));
+        // The loop now stops after three iterations, rather than ten.
+        assert_eq!(debugger.cpu().clock(), 15);
+    }
+
+    #[test]
+    fn it_thins_the_checkpoints_of_a_long_run() {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("
+                li a0, 0
+                li a1, 1000
+            loop:
+                addi a0, a0, 1
+                bne a0, a1, loop
+                li a0, 3
+                jr zero
+        "));
+        let mut debugger = Debugger::new(cpu, 1);
+        assert_eq!(debugger.cont(|| false), StopReason::Exited(3));
+        assert!(debugger.checkpoints.len() <= MAX_CHECKPOINTS);
+        assert_eq!(debugger.checkpoints[0].0, 0);
+
+        // The whole history can still be gone back over.
+        debugger.seek(5);
+        assert_eq!(debugger.cpu().read_xreg(10), 2);
+        debugger.seek(1500);
+        assert_eq!(debugger.cpu().read_xreg(10), 749);
+    }
+
+    #[test]
+    fn it_takes_traps_and_stops_for_them_when_caught() {
+        let program = asm!("
+                la t0, handler
+                csrw mtvec, t0
+                ecall
+                li a0, 3
+                jr zero
+            handler:
+                li a1, 7
+                csrr t0, mepc
+                addi t0, t0, 4
+                csrw mepc, t0
+                mret
+        ");
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(program.clone());
+        let mut debugger = Debugger::new(cpu, 4);
+        assert_eq!(debugger.cont(|| false), StopReason::Exited(3));
+        assert_eq!(debugger.cpu().read_xreg(11), 7);
+
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(program);
+        let mut debugger = Debugger::new(cpu, 4);
+        debugger.set_catch_traps(true);
+        assert_eq!(debugger.cont(|| false), StopReason::Trap(Trap::EnvironmentCallFromMMode));
+        assert_eq!(debugger.cpu().pc(), 0x8000_0018);
+
+        // Going back over the trap returns to the ecall, and the handler runs again after it.
+        assert_eq!(debugger.reverse_step(), StopReason::Step);
+        assert_eq!(debugger.cpu().pc(), 0x8000_000c);
+        assert_eq!(debugger.step(), StopReason::Trap(Trap::EnvironmentCallFromMMode));
+        assert_eq!(debugger.cont(|| false), StopReason::Exited(3));
+    }
+}
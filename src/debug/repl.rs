@@ -0,0 +1,169 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    components::memory::{registers::XREG_ABI_NAMES, Size},
+    isa::{Disassembler, Instruction},
+    util::parse_addr,
+};
+
+use super::{Debugger, StopReason};
+
+const HELP: &str = "\
+commands:
+  s, step [n]                 execute n instructions (default 1)
+  c, continue                 run until a breakpoint, watchpoint or the end of the program
+  rs, reverse-step [n]        go back n instructions (default 1)
+  rc, reverse-continue        run backwards to the last breakpoint or watchpoint hit
+  b, break <addr>             stop when the pc reaches an address
+  d, delete <addr>            remove a breakpoint
+  w, watch <addr> [len]       stop after a store to len bytes at an address (default 8)
+  unwatch <addr> [len]        remove a watchpoint
+  catch [on|off]              stop when the program takes a trap (default on)
+  r, regs                     print the integer registers
+  x <addr> [count]            print count doublewords of memory (default 4)
+  where                       print the current instruction
+  q, quit                     stop debugging";
+
+/// Reads debugger commands from `input` until it runs out or the user quits.
+pub fn run(debugger: &mut Debugger, mut input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+    print_location(debugger, &mut out)?;
+    let mut line = String::new();
+    loop {
+        write!(out, "(rvdb) ")?;
+        out.flush()?;
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = args.split_first() else { continue };
+        let arg = |i: usize| args.get(i).and_then(|a| parse_addr(a));
+
+        match command {
+            "s" | "step" => {
+                let mut reason = StopReason::Step;
+                for _ in 0..arg(0).unwrap_or(1) {
+                    reason = debugger.step();
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                print_stop(debugger, reason, &mut out)?;
+            },
+            "c" | "continue" => {
+                let reason = debugger.cont(|| false);
+                print_stop(debugger, reason, &mut out)?;
+            },
+            "rs" | "reverse-step" => {
+                let mut reason = StopReason::Step;
+                for _ in 0..arg(0).unwrap_or(1) {
+                    reason = debugger.reverse_step();
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                print_stop(debugger, reason, &mut out)?;
+            },
+            "rc" | "reverse-continue" => {
+                let reason = debugger.reverse_continue();
+                print_stop(debugger, reason, &mut out)?;
+            },
+            "b" | "break" => match arg(0) {
+                Some(addr) => debugger.add_breakpoint(addr),
+                None => writeln!(out, "usage: break <addr>")?,
+            },
+            "d" | "delete" => match arg(0) {
+                Some(addr) if debugger.remove_breakpoint(addr) => {},
+                _ => writeln!(out, "no such breakpoint")?,
+            },
+            "w" | "watch" => match arg(0) {
+                Some(addr) => debugger.add_watchpoint(addr..addr + arg(1).unwrap_or(8)),
+                None => writeln!(out, "usage: watch <addr> [len]")?,
+            },
+            "unwatch" => match arg(0) {
+                Some(addr) if debugger.remove_watchpoint(&(addr..addr + arg(1).unwrap_or(8))) => {},
+                _ => writeln!(out, "no such watchpoint")?,
+            },
+            "catch" => match args.first().copied() {
+                None | Some("on") => debugger.set_catch_traps(true),
+                Some("off") => debugger.set_catch_traps(false),
+                Some(_) => writeln!(out, "usage: catch [on|off]")?,
+            },
+            "r" | "regs" => {
+                let cpu = debugger.cpu();
+                for num in 0..32u8 {
+                    let sep = if num % 4 == 3 { "\n" } else { "  " };
+                    write!(out, "{:>4} 0x{:016x}{}", XREG_ABI_NAMES[num as usize], cpu.read_xreg(num), sep)?;
+                }
+                writeln!(out, "  pc 0x{:016x}", cpu.pc())?;
+            },
+            "x" => match arg(0) {
+                Some(addr) => {
+                    for i in 0..arg(1).unwrap_or(4) {
+                        let addr = addr + i * 8;
+                        match debugger.cpu().mmu().load(addr, Size::DoubleWord) {
+                            Ok(value) => writeln!(out, "0x{:016x}: 0x{:016x}", addr, value)?,
+                            Err(_) => writeln!(out, "0x{:016x}: <unmapped>", addr)?,
+                        }
+                    }
+                },
+                None => writeln!(out, "usage: x <addr> [count]")?,
+            },
+            "where" => print_location(debugger, &mut out)?,
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(()),
+            _ => writeln!(out, "unknown command '{}', try 'help'", command)?,
+        }
+    }
+}
+
+fn print_stop(debugger: &mut Debugger, reason: StopReason, out: &mut impl Write) -> io::Result<()> {
+    if reason != StopReason::Step {
+        writeln!(out, "{}", reason)?;
+    }
+    print_location(debugger, out)
+}
+
+/// Prints the instruction count and the instruction which is about to execute.
+fn print_location(debugger: &mut Debugger, out: &mut impl Write) -> io::Result<()> {
+    let cpu = debugger.cpu();
+    let (clock, pc) = (cpu.clock(), cpu.pc());
+    let text = match cpu.mmu().load(pc, Size::Word) {
+        Ok(raw) => Disassembler::new().disassemble(&Instruction::decode(raw as u32), pc),
+        Err(_) => "<unmapped>".to_string(),
+    };
+    writeln!(out, "[{}] 0x{:016x}: {}", clock, pc, text)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{asm, components::CPU, debug::Debugger};
+
+    #[test]
+    fn it_runs_commands() {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("
+                li a0, 1
+                li a0, 2
+                li a0, 3
+                jr zero
+        "));
+        let mut debugger = Debugger::new(cpu, 100);
+
+        let mut out = vec![];
+        let commands = "step 2\nrs\nregs\nbreak 0x80000008\nc\nc\nbogus\nquit\nstep\n";
+        super::run(&mut debugger, commands.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("[0] 0x0000000080000000: li a0, 1"), "{}", out);
+        assert!(out.contains("[2] 0x0000000080000008: li a0, 3"), "{}", out);
+        assert!(out.contains("[1] 0x0000000080000004: li a0, 2"), "{}", out);
+        assert!(out.contains("  a0 0x0000000000000001"), "{}", out);
+        assert!(out.contains("breakpoint\n[2] 0x0000000080000008"), "{}", out);
+        assert!(out.contains("exited with code 3"), "{}", out);
+        assert!(out.contains("unknown command 'bogus'"), "{}", out);
+        // Nothing runs after quitting.
+        assert_eq!(debugger.cpu().clock(), 4);
+    }
+}
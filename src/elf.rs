@@ -0,0 +1,374 @@
+#![allow(dead_code)]
+
+/// The magic number found at the start of every ELF file.
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+/// ELF class for 64-bit objects.
+const ELFCLASS64: u8 = 2;
+/// ELF data encoding for little-endian objects.
+const ELFDATA2LSB: u8 = 1;
+/// Machine type for RISC-V.
+const EM_RISCV: u16 = 243;
+
+/// Object file type of an executable.
+pub const ET_EXEC: u16 = 2;
+/// Object file type of a shared object, which includes position independent executables.
+pub const ET_DYN: u16 = 3;
+
+/// Segment type of a segment which is loaded into memory.
+pub const PT_LOAD: u32 = 1;
+/// Segment type of the path of the program interpreter, for dynamically linked executables.
+pub const PT_INTERP: u32 = 3;
+/// Segment type of the program header table itself.
+pub const PT_PHDR: u32 = 6;
+
+/// Section type of a symbol table.
+const SHT_SYMTAB: u32 = 2;
+/// Section flag for sections which contain executable instructions.
+const SHF_EXECINSTR: u64 = 0x4;
+
+/// Symbol type of a section.
+const STT_SECTION: u8 = 3;
+/// Symbol type of a file name.
+const STT_FILE: u8 = 4;
+/// The size of an ELF64 program header.
+const PHDR_SIZE: u64 = 56;
+/// The size of an ELF64 section header.
+const SHDR_SIZE: u64 = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file does not start with the ELF magic number.
+    BadMagic,
+    /// The file is not a 64-bit little-endian RISC-V object.
+    Unsupported,
+    /// A header or table points outside of the file.
+    Truncated,
+    /// A header table's entries are smaller than the headers they hold.
+    BadEntrySize,
+}
+
+/// A section described by the section header table.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+}
+
+impl Section {
+    /// Indicates if the section contains executable instructions.
+    pub fn is_executable(&self) -> bool {
+        self.flags & SHF_EXECINSTR != 0
+    }
+}
+
+/// A segment described by the program header table.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// A named address taken from the symbol table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// A parsed 64-bit little-endian RISC-V ELF file.
+#[derive(Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub kind: u16,
+    pub entry: u64,
+    /// The offset of the program header table in the file.
+    pub phoff: u64,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+}
+
+impl<'a> Elf<'a> {
+    /// Parses the ELF header and section header table of the given file.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < 64 {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || read_u16(data, 18)? != EM_RISCV {
+            return Err(ElfError::Unsupported);
+        }
+
+        let mut elf = Self {
+            data,
+            kind: read_u16(data, 16)?,
+            entry: read_u64(data, 24)?,
+            phoff: read_u64(data, 32)?,
+            segments: vec![],
+            sections: vec![],
+        };
+        elf.segments = elf.parse_segments()?;
+        elf.sections = elf.parse_sections()?;
+        Ok(elf)
+    }
+
+    /// Indicates if the given file starts with the ELF magic number.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.len() >= 4 && data[0..4] == ELF_MAGIC
+    }
+
+    /// Gets the contents of a section in the file.
+    pub fn section_data(&self, section: &Section) -> Result<&'a [u8], ElfError> {
+        slice(self.data, section.offset, section.size)
+    }
+
+    /// Gets the contents of a segment in the file, which may be shorter than the segment is in
+    /// memory.
+    pub fn segment_data(&self, segment: &Segment) -> Result<&'a [u8], ElfError> {
+        slice(self.data, segment.offset, segment.filesz)
+    }
+
+    /// Finds the address at which the program header table is loaded, if it is loaded.
+    pub fn phdr_addr(&self) -> Option<u64> {
+        if let Some(phdr) = self.segments.iter().find(|s| s.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        self.segments
+            .iter()
+            .filter(|s| s.kind == PT_LOAD)
+            .find(|s| s.offset <= self.phoff && self.phoff < s.offset + s.filesz)
+            .map(|s| s.vaddr + self.phoff - s.offset)
+    }
+
+    /// Reads every named function, object and untyped symbol from the symbol table.
+    pub fn symbols(&self) -> Result<Vec<Symbol>, ElfError> {
+        let mut symbols = vec![];
+        let Some(symtab) = self.sections.iter().find(|s| s.kind == SHT_SYMTAB) else {
+            return Ok(symbols);
+        };
+        let strtab = self.sections.get(symtab.link as usize).ok_or(ElfError::Truncated)?;
+        let strings = self.section_data(strtab)?;
+
+        for entry in self.section_data(symtab)?.chunks_exact(24) {
+            let name = read_u32(entry, 0)? as usize;
+            let kind = entry[4] & 0xf;
+            let shndx = read_u16(entry, 6)?;
+            let addr = read_u64(entry, 8)?;
+            let size = read_u64(entry, 16)?;
+
+            if name == 0 || shndx == 0 || matches!(kind, STT_SECTION | STT_FILE) {
+                continue;
+            }
+            let name = read_str(strings, name)?;
+            // Skip the local labels which the assembler emits for relaxation.
+            if name.starts_with(".L") || name.starts_with('$') {
+                continue;
+            }
+            symbols.push(Symbol { name, addr, size });
+        }
+        Ok(symbols)
+    }
+
+    fn parse_segments(&self) -> Result<Vec<Segment>, ElfError> {
+        let phentsize = read_u16(self.data, 54)? as u64;
+        let phnum = read_u16(self.data, 56)? as u64;
+        if self.phoff == 0 {
+            return Ok(vec![]);
+        }
+        if phentsize < PHDR_SIZE {
+            return Err(ElfError::BadEntrySize);
+        }
+
+        let headers = slice(self.data, self.phoff, phentsize * phnum)?;
+        headers
+            .chunks_exact(phentsize as usize)
+            .map(|h| Ok(Segment {
+                kind: read_u32(h, 0)?,
+                flags: read_u32(h, 4)?,
+                offset: read_u64(h, 8)?,
+                vaddr: read_u64(h, 16)?,
+                filesz: read_u64(h, 32)?,
+                memsz: read_u64(h, 40)?,
+            }))
+            .collect()
+    }
+
+    fn parse_sections(&self) -> Result<Vec<Section>, ElfError> {
+        let shoff = read_u64(self.data, 40)?;
+        let shentsize = read_u16(self.data, 58)? as u64;
+        let shnum = read_u16(self.data, 60)? as u64;
+        let shstrndx = read_u16(self.data, 62)? as u64;
+        if shoff == 0 {
+            return Ok(vec![]);
+        }
+        if shentsize < SHDR_SIZE {
+            return Err(ElfError::BadEntrySize);
+        }
+
+        let headers = slice(self.data, shoff, shentsize * shnum)?;
+        let mut sections: Vec<Section> = headers
+            .chunks_exact(shentsize as usize)
+            .map(|h| Ok(Section {
+                name: String::new(),
+                kind: read_u32(h, 4)?,
+                flags: read_u64(h, 8)?,
+                addr: read_u64(h, 16)?,
+                offset: read_u64(h, 24)?,
+                size: read_u64(h, 32)?,
+                link: read_u32(h, 40)?,
+            }))
+            .collect::<Result<_, ElfError>>()?;
+
+        let names = match sections.get(shstrndx as usize) {
+            Some(shstrtab) => slice(self.data, shstrtab.offset, shstrtab.size)?,
+            None => &[],
+        };
+        for (i, section) in sections.iter_mut().enumerate() {
+            let name = read_u32(headers, i * shentsize as usize)? as usize;
+            section.name = read_str(names, name).unwrap_or_default();
+        }
+        Ok(sections)
+    }
+}
+
+/// Symbols sorted by address, used to resolve addresses into `symbol+offset` form.
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by(|a, b| a.addr.cmp(&b.addr).then(a.name.cmp(&b.name)));
+        symbols.dedup_by_key(|s| s.addr);
+        Self { symbols }
+    }
+
+    /// Reads the symbol table of an ELF file.
+    pub fn from_elf(elf: &Elf) -> Result<Self, ElfError> {
+        Ok(Self::new(elf.symbols()?))
+    }
+
+    /// Gets the symbol which starts exactly at the given address.
+    pub fn at(&self, addr: u64) -> Option<&Symbol> {
+        self.symbols
+            .binary_search_by_key(&addr, |s| s.addr)
+            .ok()
+            .map(|i| &self.symbols[i])
+    }
+
+    /// Finds the closest symbol at or below the given address, along with the offset of the
+    /// address from the start of that symbol. Sized symbols only match addresses inside them.
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let index = self.symbols.partition_point(|s| s.addr <= addr);
+        let symbol = self.symbols.get(index.checked_sub(1)?)?;
+        let offset = addr - symbol.addr;
+        match symbol.size == 0 || offset < symbol.size {
+            true => Some((symbol, offset)),
+            false => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+fn slice(data: &[u8], offset: u64, size: u64) -> Result<&[u8], ElfError> {
+    let start = offset as usize;
+    let end = start.checked_add(size as usize).ok_or(ElfError::Truncated)?;
+    data.get(start..end).ok_or(ElfError::Truncated)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = data.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Result<String, ElfError> {
+    let bytes = data.get(offset..).ok_or(ElfError::Truncated)?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or(ElfError::Truncated)?;
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Elf, ElfError, Symbol, SymbolTable};
+
+    fn symbol(name: &str, addr: u64, size: u64) -> Symbol {
+        Symbol { name: name.to_string(), addr, size }
+    }
+
+    #[test]
+    fn it_rejects_non_elf_files() {
+        let result = Elf::parse(&[0x13; 64]);
+        assert!(result.is_err_and(|e| e == ElfError::BadMagic));
+
+        let result = Elf::parse(&[0x7f, b'E', b'L', b'F']);
+        assert!(result.is_err_and(|e| e == ElfError::Truncated));
+    }
+
+    #[test]
+    fn it_rejects_short_header_entries() {
+        let mut data = vec![0; 64 + 64];
+        data[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+        data[4] = 2;
+        data[5] = 1;
+        data[18..20].copy_from_slice(&243u16.to_le_bytes());
+        // A section header table with one entry, whose entries are empty.
+        data[40..48].copy_from_slice(&64u64.to_le_bytes());
+        data[60..62].copy_from_slice(&1u16.to_le_bytes());
+        assert!(Elf::parse(&data).is_err_and(|e| e == ElfError::BadEntrySize));
+
+        data[58..60].copy_from_slice(&64u16.to_le_bytes());
+        assert_eq!(Elf::parse(&data).unwrap().sections.len(), 1);
+
+        // A program header table whose entries are too short for a program header.
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&8u16.to_le_bytes());
+        data[56..58].copy_from_slice(&1u16.to_le_bytes());
+        assert!(Elf::parse(&data).is_err_and(|e| e == ElfError::BadEntrySize));
+    }
+
+    #[test]
+    fn it_looks_up_symbols_by_address() {
+        let table = SymbolTable::new(vec![
+            symbol("main", 0x8000_0010, 0x20),
+            symbol("_start", 0x8000_0000, 0),
+            symbol("data", 0x8000_1000, 8),
+        ]);
+
+        assert_eq!(table.at(0x8000_0010).map(|s| s.name.as_str()), Some("main"));
+        assert!(table.at(0x8000_0014).is_none());
+
+        let (sym, offset) = table.lookup(0x8000_0018).unwrap();
+        assert_eq!((sym.name.as_str(), offset), ("main", 8));
+
+        let (sym, offset) = table.lookup(0x8000_000c).unwrap();
+        assert_eq!((sym.name.as_str(), offset), ("_start", 0xc));
+
+        assert!(table.lookup(0x8000_0030).is_none());
+        assert!(table.lookup(0x7fff_ffff).is_none());
+    }
+}
@@ -6,6 +6,12 @@ pub struct Emulator {
     cpu: CPU,
 }
 
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Emulator {
     pub fn new() -> Self {
         Self {
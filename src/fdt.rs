@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+
+use crate::components::{
+    cpu::ISA,
+    devices::uart::{Uart, UART_BASE},
+    Bus,
+};
+
+/// The magic number at the start of every flattened device tree.
+pub const MAGIC: u32 = 0xd00d_feed;
+const VERSION: u32 = 17;
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+/// The size of the header, after which the memory reservation map starts.
+const HEADER_SIZE: usize = 40;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const END: u32 = 9;
+
+/// The frequency of the timer which the harts report to the operating system.
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+/// The phandle of the interrupt controller of the first hart.
+pub const CPU_INTC_PHANDLE: u32 = 1;
+/// The phandle of the fixed clock which the SPI controller divides its serial clock from.
+pub const SPI_CLOCK_PHANDLE: u32 = 2;
+/// The phandle of the PLIC, which is the interrupt parent of the devices.
+pub const PLIC_PHANDLE: u32 = 3;
+
+/// The boot parameters which are passed to the kernel, in the `/chosen` node.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Chosen {
+    /// The kernel's command line.
+    pub bootargs: Option<String>,
+}
+
+/// Indicates if the data is a flattened device tree, as opposed to a device tree source file.
+pub fn is_fdt(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && data[..4] == MAGIC.to_be_bytes()
+}
+
+/// Builds a flattened device tree blob, in the format which firmware and kernels read, a node
+/// at a time:
+///
+/// - a header of ten big-endian u32s, giving the offsets and sizes of the other blocks,
+/// - an empty memory reservation map,
+/// - the structure block, of nodes and their properties,
+/// - the strings block, which holds each property name once.
+#[derive(Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    /// Appends bytes to the structure block, padded to a multiple of 4 bytes.
+    fn padded(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        self.structure.resize(self.structure.len().next_multiple_of(4), 0);
+    }
+
+    /// Starts a child of the current node. The root node has an empty name.
+    pub fn begin_node(&mut self, name: &str) -> &mut Self {
+        self.token(BEGIN_NODE);
+        self.padded(&[name.as_bytes(), &[0]].concat());
+        self.depth += 1;
+        self
+    }
+
+    pub fn end_node(&mut self) -> &mut Self {
+        assert!(self.depth > 0, "no node to end");
+        self.token(END_NODE);
+        self.depth -= 1;
+        self
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) -> &mut Self {
+        let offset = match self.string_offsets.get(name) {
+            Some(&offset) => offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.string_offsets.insert(name.to_string(), offset);
+                offset
+            },
+        };
+        self.token(PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.padded(value);
+        self
+    }
+
+    /// Adds a property with no value, which is true by being there.
+    pub fn property_null(&mut self, name: &str) -> &mut Self {
+        self.property(name, &[])
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) -> &mut Self {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    /// Adds a property of two cells, such as an address when `#address-cells` is 2.
+    pub fn property_u64(&mut self, name: &str, value: u64) -> &mut Self {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+        self.property(name, &cells.iter().flat_map(|cell| cell.to_be_bytes()).collect::<Vec<_>>())
+    }
+
+    /// Adds a `reg` property of 64-bit addresses and sizes, for nodes whose parent has 2 address
+    /// and size cells.
+    pub fn property_reg(&mut self, ranges: &[(u64, u64)]) -> &mut Self {
+        let value: Vec<u8> = ranges.iter()
+            .flat_map(|&(addr, size)| [addr.to_be_bytes(), size.to_be_bytes()])
+            .flatten()
+            .collect();
+        self.property("reg", &value)
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) -> &mut Self {
+        self.property_strings(name, &[value])
+    }
+
+    /// Adds a property of a list of strings, such as `compatible`.
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) -> &mut Self {
+        let value: Vec<u8> = values.iter().flat_map(|s| s.bytes().chain([0])).collect();
+        self.property(name, &value)
+    }
+
+    /// Ends the tree and lays out the blob.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(self.depth == 0, "{} nodes were not ended", self.depth);
+        self.token(END);
+
+        let reservations = HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+
+        let header = [
+            MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            VERSION,
+            LAST_COMPATIBLE_VERSION,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// Generates the device tree of the machine: its harts, and the memory and devices on the bus,
+/// along with the boot parameters.
+pub fn generate(bus: &Bus, chosen: &Chosen) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("")
+        .property_u32("#address-cells", 2)
+        .property_u32("#size-cells", 2)
+        .property_string("compatible", "riscv-virtio")
+        .property_string("model", "riscv-virtio,rv64-emulator")
+        .property_u32("interrupt-parent", PLIC_PHANDLE);
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = &chosen.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    // Without a UART, the kernel is told which console to use on the command line.
+    if bus.find::<Uart>().is_some() {
+        fdt.property_string("stdout-path", &format!("/serial@{:x}", UART_BASE));
+    }
+    fdt.end_node();
+
+    fdt.begin_node("cpus")
+        .property_u32("#address-cells", 1)
+        .property_u32("#size-cells", 0)
+        .property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+    fdt.begin_node("cpu@0")
+        .property_string("device_type", "cpu")
+        .property_u32("reg", 0)
+        .property_string("status", "okay")
+        .property_string("compatible", "riscv")
+        .property_string("riscv,isa", ISA);
+    fdt.begin_node("interrupt-controller")
+        .property_u32("#interrupt-cells", 1)
+        .property_null("interrupt-controller")
+        .property_string("compatible", "riscv,cpu-intc")
+        .property_u32("phandle", CPU_INTC_PHANDLE)
+        .end_node();
+    fdt.end_node().end_node();
+
+    bus.describe(&mut fdt);
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::HashMap;
+
+    use crate::components::{bus::DRAM_BASE, devices::uart::Uart, Bus};
+
+    use super::{generate, is_fdt, Chosen, FdtWriter, BEGIN_NODE, END, END_NODE, PROP};
+
+    /// Reads the properties of a device tree, keyed by their paths, such as `/cpus/cpu@0/reg`.
+    pub fn properties(blob: &[u8]) -> HashMap<String, Vec<u8>> {
+        let word = |offset: usize| u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap());
+        let (structure, strings) = (word(8) as usize, word(12) as usize);
+        let cstr = |offset: usize| {
+            let end = blob[offset..].iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(blob[offset..offset + end].to_vec()).unwrap()
+        };
+
+        let mut properties = HashMap::new();
+        let mut path: Vec<String> = vec![];
+        let mut pos = structure;
+        loop {
+            let token = word(pos);
+            pos += 4;
+            match token {
+                BEGIN_NODE => {
+                    let name = cstr(pos);
+                    pos += (name.len() + 1).next_multiple_of(4);
+                    path.push(name);
+                },
+                END_NODE => { path.pop(); },
+                PROP => {
+                    let (len, name) = (word(pos) as usize, cstr(strings + word(pos + 4) as usize));
+                    let value = blob[pos + 8..pos + 8 + len].to_vec();
+                    pos += 8 + len.next_multiple_of(4);
+                    properties.insert(format!("{}/{}", path.join("/"), name), value);
+                },
+                END => return properties,
+                _ => panic!("bad token {}", token),
+            }
+        }
+    }
+
+    #[test]
+    fn it_writes_device_trees() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("")
+            .property_string("compatible", "test")
+            .begin_node("node@1")
+            .property_u32("reg", 1)
+            .property_strings("compatible", &["a", "bc"])
+            .end_node()
+            .end_node();
+        let blob = fdt.finish();
+
+        assert!(is_fdt(&blob));
+        assert_eq!(u32::from_be_bytes(blob[4..8].try_into().unwrap()) as usize, blob.len());
+        let properties = properties(&blob);
+        assert_eq!(properties["/compatible"], b"test\0");
+        assert_eq!(properties["/node@1/reg"], [0, 0, 0, 1]);
+        assert_eq!(properties["/node@1/compatible"], b"a\0bc\0");
+        // Property names are stored once.
+        assert_eq!(blob.windows(10).filter(|w| w == b"compatible").count(), 1);
+    }
+
+    #[test]
+    fn it_describes_the_machine() {
+        let chosen = Chosen { bootargs: Some("console=hvc0".to_string()) };
+        let properties = properties(&generate(&Bus::new(), &chosen));
+        assert_eq!(properties["/cpus/cpu@0/riscv,isa"], b"rv64im\0");
+        let reg = [DRAM_BASE.to_be_bytes(), (1u64 << 30).to_be_bytes()].concat();
+        assert_eq!(properties["/memory@80000000/reg"], reg);
+        assert_eq!(properties["/memory@80000000/device_type"], b"memory\0");
+        assert_eq!(properties["/chosen/bootargs"], b"console=hvc0\0");
+        assert!(!properties.contains_key("/chosen/stdout-path"));
+        assert_eq!(properties["/clint@2000000/interrupts-extended"], [0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn it_points_the_console_at_the_uart() {
+        let mut bus = Bus::new();
+        bus.attach(Box::new(Uart::new(None, Box::new(std::io::sink()))));
+        let properties = properties(&generate(&bus, &Chosen::default()));
+        assert_eq!(properties["/chosen/stdout-path"], b"/serial@10000000\0");
+        assert_eq!(properties["/serial@10000000/interrupts"], 10u32.to_be_bytes());
+        assert_eq!(properties["/interrupt-parent"], properties["/plic@c000000/phandle"]);
+    }
+}
@@ -0,0 +1,725 @@
+use std::{collections::HashMap, fmt};
+
+use crate::components::memory::registers::XREG_ABI_NAMES;
+
+use super::{csr, decode::{BTypeParams, ITypeParams, JTypeParams, RTypeParams, STypeParams, UTypeParams}, Instruction::{self, *}};
+
+/// Assembles a snippet of RISC-V assembly which is loaded at the start of DRAM, panicking if the
+/// snippet does not assemble. Statements are separated by newlines or `;`.
+///
+/// ```ignore
+/// let program = asm!("addi a0, zero, 5; ecall");
+/// ```
+#[macro_export]
+macro_rules! asm {
+    ($source:expr) => {
+        $crate::isa::assemble::assemble($source, $crate::components::bus::DRAM_BASE)
+            .unwrap_or_else(|e| panic!("{}", e))
+    };
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// The line of the source which the error occurred on, starting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// A single statement of the source, after labels and comments have been removed.
+struct Statement<'a> {
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+/// Assembles the source into machine code which is to be loaded at the given base address.
+///
+/// Supports the RV64IM and Zicsr instructions, the common pseudo-instructions (`li`, `la`, `mv`, `call`,
+/// `ret`, `beqz`...), labels, and the `.byte`, `.half`, `.word` and `.dword` directives.
+pub fn assemble(source: &str, base: u64) -> Result<Vec<u8>, AsmError> {
+    let mut statements = vec![];
+    let mut labels = HashMap::new();
+    let mut addr = base;
+
+    // The first pass finds the address of each label. The size of every statement is known
+    // without resolving labels, so lowering with unresolved labels gives the correct layout.
+    let unresolved = Assembler { labels: None };
+    for (i, line) in source.lines().enumerate() {
+        let line_num = i + 1;
+        let code = line.split('#').next().unwrap_or_default();
+
+        for mut text in code.split(';') {
+            while let Some((label, rest)) = split_label(text) {
+                if labels.insert(label, addr).is_some() {
+                    return Err(error(line_num, format!("duplicate label `{}`", label)));
+                }
+                text = rest;
+            }
+
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+            let (mnemonic, operands) = match text.split_once(char::is_whitespace) {
+                Some((mnemonic, operands)) => (mnemonic, operands.split(',').map(str::trim).collect()),
+                None => (text, vec![]),
+            };
+            let statement = Statement { line: line_num, mnemonic, operands };
+
+            addr += unresolved.lower(&statement, addr)?.len() as u64;
+            statements.push(statement);
+        }
+    }
+
+    let resolved = Assembler { labels: Some(labels) };
+    let mut code = vec![];
+    for statement in statements.iter() {
+        let pc = base + code.len() as u64;
+        code.extend(resolved.lower(statement, pc)?);
+    }
+    Ok(code)
+}
+
+/// Splits a leading `label:` from a statement.
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (label, rest) = text.split_once(':')?;
+    let label = label.trim();
+    let valid = !label.is_empty() && label
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    valid.then_some((label, rest))
+}
+
+fn error(line: usize, message: String) -> AsmError {
+    AsmError { line, message }
+}
+
+struct Assembler<'a> {
+    /// The address of each label, or `None` while the labels are still being found.
+    labels: Option<HashMap<&'a str, u64>>,
+}
+
+impl Assembler<'_> {
+    /// Lowers a statement located at `pc` into machine code.
+    fn lower(&self, s: &Statement, pc: u64) -> Result<Vec<u8>, AsmError> {
+        let err = |message: String| error(s.line, message);
+
+        match s.mnemonic {
+            ".byte" => return self.data(s, 1),
+            ".half" => return self.data(s, 2),
+            ".word" => return self.data(s, 4),
+            ".dword" => return self.data(s, 8),
+            _ => {},
+        }
+
+        let insts = self.instructions(s, pc)?;
+        let mut code = Vec::with_capacity(insts.len() * 4);
+        for inst in insts {
+            let raw = inst
+                .encode()
+                .ok_or_else(|| err(format!("operand out of range in `{}`", inst)))?;
+            code.extend(raw.to_le_bytes());
+        }
+        Ok(code)
+    }
+
+    /// Lowers a data directive, where each operand is a value of the given size in bytes.
+    fn data(&self, s: &Statement, size: usize) -> Result<Vec<u8>, AsmError> {
+        let mut data = vec![];
+        for operand in s.operands.iter() {
+            let value = match parse_int(operand) {
+                Some(value) => value as u64,
+                None => self.label(s, operand)?,
+            };
+            data.extend(&value.to_le_bytes()[..size]);
+        }
+        Ok(data)
+    }
+
+    /// Lowers an instruction or pseudo-instruction into the instructions which implement it.
+    fn instructions(&self, s: &Statement, pc: u64) -> Result<Vec<Instruction>, AsmError> {
+        let ops = &s.operands;
+        let expect = |count: usize| match ops.len() == count {
+            true => Ok(()),
+            false => Err(error(s.line, format!(
+                "`{}` expects {} operands but was given {}", s.mnemonic, count, ops.len()
+            ))),
+        };
+        let reg = |i: usize| parse_reg(ops[i]).ok_or_else(|| {
+            error(s.line, format!("unknown register `{}`", ops[i]))
+        });
+        let imm = |i: usize| parse_imm(ops[i]).ok_or_else(|| {
+            error(s.line, format!("invalid immediate `{}`", ops[i]))
+        });
+        let mem = |i: usize| parse_mem(ops[i]).ok_or_else(|| {
+            error(s.line, format!("invalid memory operand `{}`", ops[i]))
+        });
+        let csr = |i: usize| parse_csr(ops[i]).ok_or_else(|| {
+            error(s.line, format!("unknown CSR `{}`", ops[i]))
+        });
+        // The 5-bit unsigned immediate of a CSR instruction, which takes the place of `rs1`.
+        let uimm = |i: usize| match parse_imm(ops[i]) {
+            Some(value @ 0..32) => Ok(value as u8),
+            _ => Err(error(s.line, format!("invalid immediate `{}`", ops[i]))),
+        };
+        // The pc-relative offset to a branch or jump target.
+        let offset = |i: usize| -> Result<i32, AsmError> {
+            if let Some(offset) = parse_offset(ops[i]) {
+                return Ok(offset);
+            }
+            if self.labels.is_none() {
+                return Ok(0);
+            }
+            let offset = self.label(s, ops[i])?.wrapping_sub(pc) as i64;
+            i32::try_from(offset).map_err(|_| error(s.line, format!("`{}` is out of range", ops[i])))
+        };
+
+        let r = |make: fn(RTypeParams) -> Instruction, rd, rs1, rs2| make(RTypeParams { rd, rs1, rs2 });
+        let i = |make: fn(ITypeParams) -> Instruction, rd, rs1, imm| make(ITypeParams { rd, rs1, imm });
+        let b = |make: fn(BTypeParams) -> Instruction, rs1, rs2, imm| make(BTypeParams { rs1, rs2, imm });
+
+        Ok(match s.mnemonic {
+            "add" | "sub" | "xor" | "or" | "and" | "sll" | "srl" | "sra" | "slt" | "sltu" |
+            "addw" | "subw" | "sllw" | "srlw" | "sraw" | "mul" | "mulh" | "mulhsu" | "mulhu" |
+            "div" | "divu" | "rem" | "remu" | "mulw" | "divw" | "divuw" | "remw" | "remuw" => {
+                expect(3)?;
+                let make = r_type(s.mnemonic).unwrap();
+                vec![r(make, reg(0)?, reg(1)?, reg(2)?)]
+            },
+            "addi" | "xori" | "ori" | "andi" | "slti" | "sltiu" | "addiw" |
+            "slli" | "srli" | "slliw" | "srliw" => {
+                expect(3)?;
+                vec![i(i_type(s.mnemonic).unwrap(), reg(0)?, reg(1)?, imm(2)?)]
+            },
+            "srai" | "sraiw" => {
+                expect(3)?;
+                let shamt = imm(2)?;
+                if !(0..64).contains(&shamt) {
+                    return Err(error(s.line, format!("invalid shift amount `{}`", ops[2])));
+                }
+                vec![i(i_type(s.mnemonic).unwrap(), reg(0)?, reg(1)?, shamt | 0x400)]
+            },
+            "lb" | "lh" | "lw" | "ld" | "lbu" | "lhu" | "lwu" => {
+                expect(2)?;
+                let (imm, rs1) = mem(1)?;
+                vec![i(i_type(s.mnemonic).unwrap(), reg(0)?, rs1, imm)]
+            },
+            "sb" | "sh" | "sw" | "sd" => {
+                expect(2)?;
+                let (imm, rs1) = mem(1)?;
+                let make = match s.mnemonic {
+                    "sb" => SB,
+                    "sh" => SH,
+                    "sw" => SW,
+                    _ => SD,
+                };
+                vec![make(STypeParams { rs1, rs2: reg(0)?, imm })]
+            },
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+                expect(3)?;
+                vec![b(b_type(s.mnemonic).unwrap(), reg(0)?, reg(1)?, offset(2)?)]
+            },
+            "bgt" | "ble" | "bgtu" | "bleu" => {
+                expect(3)?;
+                let make = match s.mnemonic {
+                    "bgt" => BLT,
+                    "ble" => BGE,
+                    "bgtu" => BLTU,
+                    _ => BGEU,
+                };
+                vec![b(make, reg(1)?, reg(0)?, offset(2)?)]
+            },
+            "beqz" | "bnez" | "bltz" | "bgez" => {
+                expect(2)?;
+                vec![b(b_type(&s.mnemonic[..3]).unwrap(), reg(0)?, 0, offset(1)?)]
+            },
+            "blez" => {
+                expect(2)?;
+                vec![b(BGE, 0, reg(0)?, offset(1)?)]
+            },
+            "bgtz" => {
+                expect(2)?;
+                vec![b(BLT, 0, reg(0)?, offset(1)?)]
+            },
+            "jal" if ops.len() == 1 => vec![JAL(JTypeParams { rd: 1, imm: offset(0)? })],
+            "jal" => {
+                expect(2)?;
+                vec![JAL(JTypeParams { rd: reg(0)?, imm: offset(1)? })]
+            },
+            "j" => {
+                expect(1)?;
+                vec![JAL(JTypeParams { rd: 0, imm: offset(0)? })]
+            },
+            "jalr" if ops.len() == 1 => match parse_reg(ops[0]) {
+                Some(rs1) => vec![i(JALR, 1, rs1, 0)],
+                None => {
+                    let (imm, rs1) = mem(0)?;
+                    vec![i(JALR, 1, rs1, imm)]
+                },
+            },
+            "jalr" if ops.len() == 3 => vec![i(JALR, reg(0)?, reg(1)?, imm(2)?)],
+            "jalr" => {
+                expect(2)?;
+                let (imm, rs1) = mem(1)?;
+                vec![i(JALR, reg(0)?, rs1, imm)]
+            },
+            "jr" => {
+                expect(1)?;
+                vec![i(JALR, 0, reg(0)?, 0)]
+            },
+            "ret" => {
+                expect(0)?;
+                vec![i(JALR, 0, 1, 0)]
+            },
+            "call" | "tail" => {
+                expect(1)?;
+                let (hi, lo) = split_offset(offset(0)?);
+                let (link, scratch) = match s.mnemonic {
+                    "call" => (1, 1),
+                    _ => (0, 6),
+                };
+                vec![AUIPC(UTypeParams { rd: scratch, imm: hi }), i(JALR, link, scratch, lo)]
+            },
+            "la" => {
+                expect(2)?;
+                let rd = reg(0)?;
+                let (hi, lo) = split_offset(offset(1)?);
+                vec![AUIPC(UTypeParams { rd, imm: hi }), i(ADDI, rd, rd, lo)]
+            },
+            "lui" | "auipc" => {
+                expect(2)?;
+                let make = match s.mnemonic {
+                    "lui" => LUI,
+                    _ => AUIPC,
+                };
+                vec![make(UTypeParams { rd: reg(0)?, imm: imm(1)? })]
+            },
+            "li" => {
+                expect(2)?;
+                let value = parse_imm64(ops[1]).ok_or_else(|| {
+                    error(s.line, format!("invalid immediate `{}`", ops[1]))
+                })?;
+                load_immediate(reg(0)?, value)
+            },
+            "nop" => {
+                expect(0)?;
+                vec![i(ADDI, 0, 0, 0)]
+            },
+            "mv" => {
+                expect(2)?;
+                vec![i(ADDI, reg(0)?, reg(1)?, 0)]
+            },
+            "not" => {
+                expect(2)?;
+                vec![i(XORI, reg(0)?, reg(1)?, -1)]
+            },
+            "neg" => {
+                expect(2)?;
+                vec![r(SUB, reg(0)?, 0, reg(1)?)]
+            },
+            "negw" => {
+                expect(2)?;
+                vec![r(SUBW, reg(0)?, 0, reg(1)?)]
+            },
+            "sext.w" => {
+                expect(2)?;
+                vec![i(ADDIW, reg(0)?, reg(1)?, 0)]
+            },
+            "seqz" => {
+                expect(2)?;
+                vec![i(SLTIU, reg(0)?, reg(1)?, 1)]
+            },
+            "snez" => {
+                expect(2)?;
+                vec![r(SLTU, reg(0)?, 0, reg(1)?)]
+            },
+            "sltz" => {
+                expect(2)?;
+                vec![r(SLT, reg(0)?, reg(1)?, 0)]
+            },
+            "sgtz" => {
+                expect(2)?;
+                vec![r(SLT, reg(0)?, 0, reg(1)?)]
+            },
+            "ecall" => {
+                expect(0)?;
+                vec![i(ECALL, 0, 0, 0)]
+            },
+            "ebreak" => {
+                expect(0)?;
+                vec![i(EBREAK, 0, 0, 1)]
+            },
+            "mret" => {
+                expect(0)?;
+                vec![i(MRET, 0, 0, 0x302)]
+            },
+            "wfi" => {
+                expect(0)?;
+                vec![i(WFI, 0, 0, 0x105)]
+            },
+            "csrrw" | "csrrs" | "csrrc" => {
+                expect(3)?;
+                vec![i(csr_type(s.mnemonic).unwrap(), reg(0)?, reg(2)?, csr(1)?)]
+            },
+            "csrrwi" | "csrrsi" | "csrrci" => {
+                expect(3)?;
+                vec![i(csr_type(s.mnemonic).unwrap(), reg(0)?, uimm(2)?, csr(1)?)]
+            },
+            "csrr" => {
+                expect(2)?;
+                vec![i(CSRRS, reg(0)?, 0, csr(1)?)]
+            },
+            "csrw" | "csrs" | "csrc" => {
+                expect(2)?;
+                let make = match s.mnemonic {
+                    "csrw" => CSRRW,
+                    "csrs" => CSRRS,
+                    _ => CSRRC,
+                };
+                vec![i(make, 0, reg(1)?, csr(0)?)]
+            },
+            "csrwi" | "csrsi" | "csrci" => {
+                expect(2)?;
+                let make = match s.mnemonic {
+                    "csrwi" => CSRRWI,
+                    "csrsi" => CSRRSI,
+                    _ => CSRRCI,
+                };
+                vec![i(make, 0, uimm(1)?, csr(0)?)]
+            },
+            _ => return Err(error(s.line, format!("unknown instruction `{}`", s.mnemonic))),
+        })
+    }
+
+    /// Gets the address of a label. Every label resolves to 0 while the labels are being found.
+    fn label(&self, s: &Statement, name: &str) -> Result<u64, AsmError> {
+        match &self.labels {
+            None => Ok(0),
+            Some(labels) => labels
+                .get(name)
+                .copied()
+                .ok_or_else(|| error(s.line, format!("undefined label `{}`", name))),
+        }
+    }
+}
+
+fn r_type(mnemonic: &str) -> Option<fn(RTypeParams) -> Instruction> {
+    Some(match mnemonic {
+        "add" => ADD,
+        "sub" => SUB,
+        "xor" => XOR,
+        "or" => OR,
+        "and" => AND,
+        "sll" => SLL,
+        "srl" => SRL,
+        "sra" => SRA,
+        "slt" => SLT,
+        "sltu" => SLTU,
+        "addw" => ADDW,
+        "subw" => SUBW,
+        "sllw" => SLLW,
+        "srlw" => SRLW,
+        "sraw" => SRAW,
+        "mul" => MUL,
+        "mulh" => MULH,
+        "mulhsu" => MULSU,
+        "mulhu" => MULU,
+        "div" => DIV,
+        "divu" => DIVU,
+        "rem" => REM,
+        "remu" => REMU,
+        "mulw" => MULW,
+        "divw" => DIVW,
+        "divuw" => DIVWU,
+        "remw" => REMW,
+        "remuw" => REMWU,
+        _ => return None,
+    })
+}
+
+fn i_type(mnemonic: &str) -> Option<fn(ITypeParams) -> Instruction> {
+    Some(match mnemonic {
+        "addi" => ADDI,
+        "xori" => XORI,
+        "ori" => ORI,
+        "andi" => ANDI,
+        "slti" => SLTI,
+        "sltiu" => SLTIU,
+        "addiw" => ADDIW,
+        "slli" => SLLI,
+        "srli" => SRLI,
+        "srai" => SRAI,
+        "slliw" => SLLIW,
+        "srliw" => SRLIW,
+        "sraiw" => SRAIW,
+        "lb" => LB,
+        "lh" => LH,
+        "lw" => LW,
+        "ld" => LD,
+        "lbu" => LBU,
+        "lhu" => LHU,
+        "lwu" => LWU,
+        _ => return None,
+    })
+}
+
+fn csr_type(mnemonic: &str) -> Option<fn(ITypeParams) -> Instruction> {
+    Some(match mnemonic {
+        "csrrw" => CSRRW,
+        "csrrs" => CSRRS,
+        "csrrc" => CSRRC,
+        "csrrwi" => CSRRWI,
+        "csrrsi" => CSRRSI,
+        "csrrci" => CSRRCI,
+        _ => return None,
+    })
+}
+
+fn b_type(mnemonic: &str) -> Option<fn(BTypeParams) -> Instruction> {
+    Some(match mnemonic {
+        "beq" => BEQ,
+        "bne" => BNE,
+        "blt" => BLT,
+        "bge" => BGE,
+        "bltu" => BLTU,
+        "bgeu" => BGEU,
+        _ => return None,
+    })
+}
+
+/// Splits a pc-relative offset into the upper 20 bits for an `auipc`, and the signed lower 12
+/// bits for the instruction which follows it.
+fn split_offset(offset: i32) -> (i32, i32) {
+    let hi = offset.wrapping_add(0x800) >> 12;
+    let lo = offset.wrapping_sub(hi << 12);
+    (hi & 0xfffff, lo)
+}
+
+/// Builds the shortest sequence of `lui`, `addi(w)` and `slli` which loads a 64-bit value, using
+/// the same algorithm as the GNU and LLVM assemblers.
+fn load_immediate(rd: u8, value: i64) -> Vec<Instruction> {
+    let lo12 = ((value << 52) >> 52) as i32;
+
+    if value == value as i32 as i64 {
+        let hi20 = (value.wrapping_add(0x800) >> 12) as i32 & 0xfffff;
+        return match (hi20, lo12) {
+            (0, _) => vec![ADDI(ITypeParams { rd, rs1: 0, imm: lo12 })],
+            (_, 0) => vec![LUI(UTypeParams { rd, imm: hi20 })],
+            _ => vec![
+                LUI(UTypeParams { rd, imm: hi20 }),
+                ADDIW(ITypeParams { rd, rs1: rd, imm: lo12 }),
+            ],
+        };
+    }
+
+    // Load the upper bits with the trailing zeros removed, then shift them into place.
+    let hi52 = value.wrapping_add(0x800) >> 12;
+    let shift = 12 + hi52.trailing_zeros();
+    let upper = ((hi52 >> (shift - 12)) << shift) >> shift;
+
+    let mut insts = load_immediate(rd, upper);
+    insts.push(SLLI(ITypeParams { rd, rs1: rd, imm: shift as i32 }));
+    if lo12 != 0 {
+        insts.push(ADDI(ITypeParams { rd, rs1: rd, imm: lo12 }));
+    }
+    insts
+}
+
+fn parse_reg(text: &str) -> Option<u8> {
+    if text == "fp" {
+        return Some(8);
+    }
+    if let Some(num) = text.strip_prefix('x').and_then(|n| n.parse::<u8>().ok()) {
+        return (num < 32).then_some(num);
+    }
+    XREG_ABI_NAMES.iter().position(|&name| name == text).map(|n| n as u8)
+}
+
+/// Parses a CSR by its name or its address, into the sign-extended immediate which holds it.
+fn parse_csr(text: &str) -> Option<i32> {
+    let addr = match csr::parse(text) {
+        Some(addr) => addr as i32,
+        None => parse_imm(text).filter(|addr| (0..0x1000).contains(addr))?,
+    };
+    Some((addr << 20) >> 20)
+}
+
+/// Parses an integer in decimal, or in hex or binary with a `0x` or `0b` prefix.
+fn parse_int(text: &str) -> Option<i128> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let digits = digits.replace('_', "");
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i128::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i128::from_str_radix(bin, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Parses a 32-bit immediate, which may be written as either a signed or an unsigned value.
+fn parse_imm(text: &str) -> Option<i32> {
+    let value = parse_int(text)?;
+    let value = i64::try_from(value).ok().filter(|v| (i32::MIN as i64..=u32::MAX as i64).contains(v))?;
+    Some(value as i32)
+}
+
+/// Parses a 64-bit immediate, which may be written as either a signed or an unsigned value.
+fn parse_imm64(text: &str) -> Option<i64> {
+    let value = parse_int(text)?;
+    match (i64::MIN as i128..=u64::MAX as i128).contains(&value) {
+        true => Some(value as i64),
+        false => None,
+    }
+}
+
+/// Parses a numeric branch or jump target, written as an offset like `8` or as `pc - 8`.
+fn parse_offset(text: &str) -> Option<i32> {
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    match compact.strip_prefix("pc") {
+        Some(rest) => parse_imm(rest.strip_prefix('+').unwrap_or(rest)),
+        None => parse_imm(&compact),
+    }
+}
+
+/// Parses a memory operand of the form `imm(reg)` or `(reg)`.
+fn parse_mem(text: &str) -> Option<(i32, u8)> {
+    let (imm, rest) = text.split_once('(')?;
+    let reg = parse_reg(rest.strip_suffix(')')?.trim())?;
+    let imm = match imm.trim() {
+        "" => 0,
+        imm => parse_imm(imm)?,
+    };
+    Some((imm, reg))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::isa::Instruction;
+
+    use super::{assemble, AsmError};
+
+    /// Assembles the source at address 0 and decodes the result back into instructions.
+    fn instructions(source: &str) -> Vec<String> {
+        assemble(source, 0)
+            .unwrap()
+            .chunks(4)
+            .map(|w| Instruction::decode(u32::from_le_bytes(w.try_into().unwrap())).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn it_assembles_instructions() {
+        assert_eq!(instructions("addi a0, zero, 5; ecall"), vec!["li a0, 5", "ecall"]);
+        assert_eq!(instructions("add x10, x11, x12"), vec!["add a0, a1, a2"]);
+        assert_eq!(instructions("sd ra, -8(sp)\nld s0, (sp)"), vec!["sd ra, -8(sp)", "ld s0, 0(sp)"]);
+        assert_eq!(instructions("srai a0, a1, 63"), vec!["srai a0, a1, 63"]);
+        assert_eq!(instructions("lui t0, 0x80000"), vec!["lui t0, 0x80000"]);
+        assert_eq!(instructions("jalr t0, 4(a0)"), vec!["jalr t0, 4(a0)"]);
+        assert_eq!(instructions("mret; wfi"), vec!["mret", "wfi"]);
+    }
+
+    #[test]
+    fn it_assembles_csr_instructions() {
+        assert_eq!(instructions("csrrw a0, mtvec, t0"), vec!["csrrw a0, mtvec, t0"]);
+        assert_eq!(instructions("csrrci a0, mstatus, 8"), vec!["csrrci a0, mstatus, 8"]);
+        assert_eq!(instructions("csrr a0, 0xf14"), vec!["csrr a0, mhartid"]);
+        assert_eq!(instructions("csrw mepc, a0; csrs mie, t0; csrc mip, t1"), vec![
+            "csrw mepc, a0",
+            "csrs mie, t0",
+            "csrc mip, t1",
+        ]);
+        assert_eq!(instructions("csrsi mstatus, 8; csrr a0, 0x7c0"), vec!["csrsi mstatus, 8", "csrr a0, 0x7c0"]);
+
+        let result = assemble("csrr a0, mfoo", 0);
+        assert!(result.is_err_and(|e| e.message == "unknown CSR `mfoo`"));
+        let result = assemble("csrwi mstatus, 32", 0);
+        assert!(result.is_err_and(|e| e.message == "invalid immediate `32`"));
+    }
+
+    #[test]
+    fn it_matches_the_reference_encoding() {
+        let code = assemble("addi a5, a0, 8; sd ra, -8(sp); srai a5, a5, 3", 0).unwrap();
+        assert_eq!(code, [
+            0x93, 0x07, 0x85, 0x00,
+            0x23, 0x3c, 0x11, 0xfe,
+            0x93, 0xd7, 0x37, 0x40,
+        ]);
+    }
+
+    #[test]
+    fn it_resolves_labels() {
+        let source = "
+            start:
+                li a0, 10
+            loop: addi a0, a0, -1   # count down
+                bnez a0, loop
+                call func
+                j start
+            func:
+                ret
+        ";
+        assert_eq!(instructions(source), vec![
+            "li a0, 10",
+            "addi a0, a0, -1",
+            "bnez a0, pc - 4",
+            "auipc ra, 0x0",
+            "jalr 12(ra)",
+            "j pc - 20",
+            "ret",
+        ]);
+    }
+
+    #[test]
+    fn it_reassembles_disassembly() {
+        let source = "beqz a5, pc - 16; j pc + 2048; bne a0, a1, 8";
+        assert_eq!(instructions(source), vec!["beqz a5, pc - 16", "j pc + 2048", "bne a0, a1, pc + 8"]);
+    }
+
+    #[test]
+    fn it_expands_load_immediate() {
+        assert_eq!(instructions("li a0, -1"), vec!["li a0, -1"]);
+        assert_eq!(instructions("li a0, 0x12345000"), vec!["lui a0, 0x12345"]);
+        assert_eq!(instructions("li a0, 0x12345678"), vec!["lui a0, 0x12345", "addiw a0, a0, 1656"]);
+        assert_eq!(instructions("li a0, 0x80000000"), vec!["li a0, 1", "slli a0, a0, 31"]);
+        assert_eq!(instructions("li a0, 0x123456789abcdef0").len(), 8);
+    }
+
+    #[test]
+    fn it_assembles_data() {
+        let code = assemble(".word 0x12345678; .dword end; .byte 1, 2\nend:", 0x1000).unwrap();
+        assert_eq!(code, [
+            0x78, 0x56, 0x34, 0x12,
+            0x0e, 0x10, 0, 0, 0, 0, 0, 0,
+            1, 2,
+        ]);
+    }
+
+    #[test]
+    fn it_reports_errors() {
+        let result = assemble("nop\nfoo a0", 0);
+        assert_eq!(result, Err(AsmError { line: 2, message: "unknown instruction `foo`".to_string() }));
+
+        let result = assemble("addi a0, a9, 1", 0);
+        assert!(result.is_err_and(|e| e.message == "unknown register `a9`"));
+
+        let result = assemble("addi a0, a0, 4096", 0);
+        assert!(result.is_err_and(|e| e.message.starts_with("operand out of range")));
+
+        let result = assemble("j nowhere", 0);
+        assert!(result.is_err_and(|e| e.message == "undefined label `nowhere`"));
+
+        let result = assemble("a: nop; a: nop", 0);
+        assert!(result.is_err_and(|e| e.message == "duplicate label `a`"));
+    }
+}
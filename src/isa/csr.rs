@@ -0,0 +1,62 @@
+//! The addresses of the control and status registers which the CPU implements, and their names
+//! in assembly.
+
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MCYCLE: u16 = 0xb00;
+pub const MINSTRET: u16 = 0xb02;
+pub const CYCLE: u16 = 0xc00;
+pub const TIME: u16 = 0xc01;
+pub const INSTRET: u16 = 0xc02;
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16 = 0xf12;
+pub const MIMPID: u16 = 0xf13;
+pub const MHARTID: u16 = 0xf14;
+
+/// The bits of `mstatus` which the CPU implements.
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+
+/// The interrupts of `mip` and `mie`, by their cause numbers.
+pub const MSI: u64 = 1 << 3;
+pub const MTI: u64 = 1 << 7;
+pub const MEI: u64 = 1 << 11;
+
+const NAMES: &[(u16, &str)] = &[
+    (MSTATUS, "mstatus"),
+    (MISA, "misa"),
+    (MIE, "mie"),
+    (MTVEC, "mtvec"),
+    (MSCRATCH, "mscratch"),
+    (MEPC, "mepc"),
+    (MCAUSE, "mcause"),
+    (MTVAL, "mtval"),
+    (MIP, "mip"),
+    (MCYCLE, "mcycle"),
+    (MINSTRET, "minstret"),
+    (CYCLE, "cycle"),
+    (TIME, "time"),
+    (INSTRET, "instret"),
+    (MVENDORID, "mvendorid"),
+    (MARCHID, "marchid"),
+    (MIMPID, "mimpid"),
+    (MHARTID, "mhartid"),
+];
+
+/// Gets the name of a CSR from its address, if the CPU implements it.
+pub fn name(addr: u16) -> Option<&'static str> {
+    NAMES.iter().find(|(a, _)| *a == addr).map(|(_, name)| *name)
+}
+
+/// Gets the address of a CSR from its name.
+pub fn parse(name: &str) -> Option<u16> {
+    NAMES.iter().find(|(_, n)| *n == name).map(|(addr, _)| *addr)
+}
@@ -23,9 +23,9 @@ lazy_static! {
         InstructionFormat::new_i_type(0b0010011, 0x4, None, XORI),
         InstructionFormat::new_i_type(0b0010011, 0x6, None, ORI),
         InstructionFormat::new_i_type(0b0010011, 0x7, None, ANDI),
-        InstructionFormat::new_i_type(0b0010011, 0x1, Some(|x| get_bits(x.imm as u32, 5, 11) == 0x00), SLLI),
-        InstructionFormat::new_i_type(0b0010011, 0x5, Some(|x| get_bits(x.imm as u32, 5, 11) == 0x00), SRLI),
-        InstructionFormat::new_i_type(0b0010011, 0x5, Some(|x| get_bits(x.imm as u32, 5, 11) == 0x20), SRAI),
+        InstructionFormat::new_i_type(0b0010011, 0x1, Some(|x| get_bits(x.imm as u32, 6, 11) == 0x00), SLLI),
+        InstructionFormat::new_i_type(0b0010011, 0x5, Some(|x| get_bits(x.imm as u32, 6, 11) == 0x00), SRLI),
+        InstructionFormat::new_i_type(0b0010011, 0x5, Some(|x| get_bits(x.imm as u32, 6, 11) == 0x10), SRAI),
         InstructionFormat::new_i_type(0b0010011, 0x2, None, SLTI),
         InstructionFormat::new_i_type(0b0010011, 0x3, None, SLTIU),
 
@@ -54,6 +54,16 @@ lazy_static! {
 
         InstructionFormat::new_i_type(0b1110011, 0x0, Some(|x| x.imm == 0x0), ECALL),
         InstructionFormat::new_i_type(0b1110011, 0x0, Some(|x| x.imm == 0x1), EBREAK),
+        InstructionFormat::new_i_type(0b1110011, 0x0, Some(|x| x.imm == 0x302), MRET),
+        InstructionFormat::new_i_type(0b1110011, 0x0, Some(|x| x.imm == 0x105), WFI),
+
+        // Zicsr Standard Extension
+        InstructionFormat::new_i_type(0b1110011, 0x1, None, CSRRW),
+        InstructionFormat::new_i_type(0b1110011, 0x2, None, CSRRS),
+        InstructionFormat::new_i_type(0b1110011, 0x3, None, CSRRC),
+        InstructionFormat::new_i_type(0b1110011, 0x5, None, CSRRWI),
+        InstructionFormat::new_i_type(0b1110011, 0x6, None, CSRRSI),
+        InstructionFormat::new_i_type(0b1110011, 0x7, None, CSRRCI),
 
         // RV64I Base Instruction Set
         InstructionFormat::new_i_type(0b0000011, 0x6, None, LWU),
@@ -82,6 +92,10 @@ lazy_static! {
         InstructionFormat::new_r_type(0b0110011, 0x6, 0x01, REM),
         InstructionFormat::new_r_type(0b0110011, 0x7, 0x01, REMU),
         InstructionFormat::new_r_type(0b0111011, 0x0, 0x01, MULW),
+        InstructionFormat::new_r_type(0b0111011, 0x4, 0x01, DIVW),
+        InstructionFormat::new_r_type(0b0111011, 0x5, 0x01, DIVWU),
+        InstructionFormat::new_r_type(0b0111011, 0x6, 0x01, REMW),
+        InstructionFormat::new_r_type(0b0111011, 0x7, 0x01, REMWU),
     ];
 }
 
@@ -177,7 +191,7 @@ impl STypeParams {
 pub struct BTypeParams {
     pub rs1: u8,
     pub rs2: u8,
-    pub imm: u32,
+    pub imm: i32,
 }
 
 impl BTypeParams {
@@ -190,7 +204,10 @@ impl BTypeParams {
         Self {
             rs1: get_bits::<u32>(inst, 15, 19) as u8,
             rs2: get_bits::<u32>(inst, 20, 24) as u8,
-            imm: (imm4 << 11) | (imm3 << 10) | (imm2 << 4) | imm1
+            imm: sign_extend_32(
+                (imm4 << 12) | (imm3 << 11) | (imm2 << 5) | (imm1 << 1),
+                13
+            )
         }
     }
 }
@@ -212,7 +229,7 @@ impl JTypeParams {
             rd: get_bits::<u32>(inst, 7, 11) as u8,
             imm: sign_extend_32(
                 (imm4 << 20) | (imm3 << 12) | (imm2 << 11) | (imm1 << 1),
-                 20
+                 21
             )
         }
     }
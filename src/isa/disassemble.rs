@@ -0,0 +1,312 @@
+use std::fmt;
+
+use crate::{components::memory::registers::XREG_ABI_NAMES, elf::SymbolTable};
+
+use super::{csr, decode::{BTypeParams, ITypeParams, JTypeParams, RTypeParams, STypeParams, UTypeParams}, Instruction};
+
+/// Renders decoded instructions as canonical RISC-V assembly, using ABI register names and the
+/// pseudo-instruction aliases which GNU objdump prints (`li`, `mv`, `ret`, `j`, `nop`, `beqz`...).
+///
+/// Branch and jump targets are resolved to absolute addresses, and to `symbol+offset` form when
+/// the symbol table contains the target.
+#[derive(Debug, Default)]
+pub struct Disassembler {
+    symbols: SymbolTable,
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_symbols(symbols: SymbolTable) -> Self {
+        Self { symbols }
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Disassembles an instruction which is located at the given address.
+    pub fn disassemble(&self, inst: &Instruction, pc: u64) -> String {
+        render(inst, &|offset| self.format_addr(pc.wrapping_add(offset as u64)))
+    }
+
+    /// Formats an address as hex, followed by the symbol which contains it if there is one.
+    pub fn format_addr(&self, addr: u64) -> String {
+        match self.symbols.lookup(addr) {
+            Some((symbol, 0)) => format!("{:#x} <{}>", addr, symbol.name),
+            Some((symbol, offset)) => format!("{:#x} <{}+{:#x}>", addr, symbol.name, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+/// Displays the instruction without knowledge of its address, so branch and jump targets are
+/// written relative to the program counter, e.g. `beqz a0, pc + 8`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = render(self, &|offset| match offset < 0 {
+            true => format!("pc - {}", offset.unsigned_abs()),
+            false => format!("pc + {}", offset),
+        });
+        f.write_str(&text)
+    }
+}
+
+impl Instruction {
+    /// Gets the assembler mnemonic of the instruction, ignoring any pseudo-instruction aliases.
+    pub fn mnemonic(&self) -> &'static str {
+        use Instruction::*;
+        match self {
+            UNDEF => "unknown",
+            ADD(_) => "add",
+            SUB(_) => "sub",
+            XOR(_) => "xor",
+            OR(_) => "or",
+            AND(_) => "and",
+            SLL(_) => "sll",
+            SRL(_) => "srl",
+            SRA(_) => "sra",
+            SLT(_) => "slt",
+            SLTU(_) => "sltu",
+            ADDW(_) => "addw",
+            SUBW(_) => "subw",
+            SLLW(_) => "sllw",
+            SRLW(_) => "srlw",
+            SRAW(_) => "sraw",
+            ADDI(_) => "addi",
+            XORI(_) => "xori",
+            ORI(_) => "ori",
+            ANDI(_) => "andi",
+            SLLI(_) => "slli",
+            SRLI(_) => "srli",
+            SRAI(_) => "srai",
+            SLTI(_) => "slti",
+            SLTIU(_) => "sltiu",
+            ADDIW(_) => "addiw",
+            SLLIW(_) => "slliw",
+            SRLIW(_) => "srliw",
+            SRAIW(_) => "sraiw",
+            LB(_) => "lb",
+            LH(_) => "lh",
+            LW(_) => "lw",
+            LD(_) => "ld",
+            LBU(_) => "lbu",
+            LHU(_) => "lhu",
+            LWU(_) => "lwu",
+            SB(_) => "sb",
+            SH(_) => "sh",
+            SW(_) => "sw",
+            SD(_) => "sd",
+            BEQ(_) => "beq",
+            BNE(_) => "bne",
+            BLT(_) => "blt",
+            BGE(_) => "bge",
+            BLTU(_) => "bltu",
+            BGEU(_) => "bgeu",
+            JAL(_) => "jal",
+            JALR(_) => "jalr",
+            LUI(_) => "lui",
+            AUIPC(_) => "auipc",
+            ECALL(_) => "ecall",
+            EBREAK(_) => "ebreak",
+            MRET(_) => "mret",
+            WFI(_) => "wfi",
+            CSRRW(_) => "csrrw",
+            CSRRS(_) => "csrrs",
+            CSRRC(_) => "csrrc",
+            CSRRWI(_) => "csrrwi",
+            CSRRSI(_) => "csrrsi",
+            CSRRCI(_) => "csrrci",
+            MUL(_) => "mul",
+            MULH(_) => "mulh",
+            MULSU(_) => "mulhsu",
+            MULU(_) => "mulhu",
+            DIV(_) => "div",
+            DIVU(_) => "divu",
+            REM(_) => "rem",
+            REMU(_) => "remu",
+            MULW(_) => "mulw",
+            DIVW(_) => "divw",
+            DIVWU(_) => "divuw",
+            REMW(_) => "remw",
+            REMWU(_) => "remuw",
+        }
+    }
+}
+
+/// Gets the ABI name of an integer register from its number.
+fn x(num: u8) -> &'static str {
+    XREG_ABI_NAMES[num as usize]
+}
+
+/// Renders an instruction, using `target` to format the pc-relative offsets of branches and jumps.
+fn render(inst: &Instruction, target: &dyn Fn(i64) -> String) -> String {
+    use Instruction::*;
+    let mn = inst.mnemonic();
+    match inst {
+        UNDEF => mn.to_string(),
+
+        SUB(RTypeParams { rd, rs1: 0, rs2 }) => format!("neg {}, {}", x(*rd), x(*rs2)),
+        SUBW(RTypeParams { rd, rs1: 0, rs2 }) => format!("negw {}, {}", x(*rd), x(*rs2)),
+        SLTU(RTypeParams { rd, rs1: 0, rs2 }) => format!("snez {}, {}", x(*rd), x(*rs2)),
+        SLT(RTypeParams { rd, rs1, rs2: 0 }) => format!("sltz {}, {}", x(*rd), x(*rs1)),
+        SLT(RTypeParams { rd, rs1: 0, rs2 }) => format!("sgtz {}, {}", x(*rd), x(*rs2)),
+        ADD(p) | SUB(p) | XOR(p) | OR(p) | AND(p) | SLL(p) | SRL(p) | SRA(p) | SLT(p) |
+        SLTU(p) | ADDW(p) | SUBW(p) | SLLW(p) | SRLW(p) | SRAW(p) | MUL(p) | MULH(p) |
+        MULSU(p) | MULU(p) | DIV(p) | DIVU(p) | REM(p) | REMU(p) | MULW(p) | DIVW(p) |
+        DIVWU(p) | REMW(p) | REMWU(p) => {
+            format!("{} {}, {}, {}", mn, x(p.rd), x(p.rs1), x(p.rs2))
+        },
+
+        ADDI(ITypeParams { rd: 0, rs1: 0, imm: 0 }) => "nop".to_string(),
+        ADDI(ITypeParams { rd, rs1: 0, imm }) => format!("li {}, {}", x(*rd), imm),
+        ADDI(ITypeParams { rd, rs1, imm: 0 }) => format!("mv {}, {}", x(*rd), x(*rs1)),
+        ADDIW(ITypeParams { rd, rs1, imm: 0 }) => format!("sext.w {}, {}", x(*rd), x(*rs1)),
+        XORI(ITypeParams { rd, rs1, imm: -1 }) => format!("not {}, {}", x(*rd), x(*rs1)),
+        SLTIU(ITypeParams { rd, rs1, imm: 1 }) => format!("seqz {}, {}", x(*rd), x(*rs1)),
+        ADDI(p) | XORI(p) | ORI(p) | ANDI(p) | SLTI(p) | SLTIU(p) | ADDIW(p) => {
+            format!("{} {}, {}, {}", mn, x(p.rd), x(p.rs1), p.imm)
+        },
+        SLLI(p) | SRLI(p) | SRAI(p) | SLLIW(p) | SRLIW(p) | SRAIW(p) => {
+            format!("{} {}, {}, {}", mn, x(p.rd), x(p.rs1), p.imm & 0x3f)
+        },
+
+        LB(p) | LH(p) | LW(p) | LD(p) | LBU(p) | LHU(p) | LWU(p) => {
+            format!("{} {}, {}({})", mn, x(p.rd), p.imm, x(p.rs1))
+        },
+        SB(STypeParams { rs1, rs2, imm }) |
+        SH(STypeParams { rs1, rs2, imm }) |
+        SW(STypeParams { rs1, rs2, imm }) |
+        SD(STypeParams { rs1, rs2, imm }) => {
+            format!("{} {}, {}({})", mn, x(*rs2), imm, x(*rs1))
+        },
+
+        BEQ(BTypeParams { rs1, rs2: 0, imm }) => branch_zero("beqz", *rs1, *imm, target),
+        BNE(BTypeParams { rs1, rs2: 0, imm }) => branch_zero("bnez", *rs1, *imm, target),
+        BGE(BTypeParams { rs1: 0, rs2, imm }) => branch_zero("blez", *rs2, *imm, target),
+        BGE(BTypeParams { rs1, rs2: 0, imm }) => branch_zero("bgez", *rs1, *imm, target),
+        BLT(BTypeParams { rs1, rs2: 0, imm }) => branch_zero("bltz", *rs1, *imm, target),
+        BLT(BTypeParams { rs1: 0, rs2, imm }) => branch_zero("bgtz", *rs2, *imm, target),
+        BEQ(p) | BNE(p) | BLT(p) | BGE(p) | BLTU(p) | BGEU(p) => {
+            format!("{} {}, {}, {}", mn, x(p.rs1), x(p.rs2), target(p.imm as i64))
+        },
+
+        JAL(JTypeParams { rd: 0, imm }) => format!("j {}", target(*imm as i64)),
+        JAL(JTypeParams { rd: 1, imm }) => format!("jal {}", target(*imm as i64)),
+        JAL(JTypeParams { rd, imm }) => format!("jal {}, {}", x(*rd), target(*imm as i64)),
+
+        JALR(ITypeParams { rd: 0, rs1: 1, imm: 0 }) => "ret".to_string(),
+        JALR(ITypeParams { rd: 0, rs1, imm: 0 }) => format!("jr {}", x(*rs1)),
+        JALR(ITypeParams { rd: 1, rs1, imm: 0 }) => format!("jalr {}", x(*rs1)),
+        JALR(ITypeParams { rd: 1, rs1, imm }) => format!("jalr {}({})", imm, x(*rs1)),
+        JALR(p) => format!("{} {}, {}({})", mn, x(p.rd), p.imm, x(p.rs1)),
+
+        LUI(UTypeParams { rd, imm }) |
+        AUIPC(UTypeParams { rd, imm }) => format!("{} {}, {:#x}", mn, x(*rd), imm & 0xfffff),
+
+        ECALL(_) | EBREAK(_) | MRET(_) | WFI(_) => mn.to_string(),
+
+        CSRRS(ITypeParams { rd, rs1: 0, imm }) => format!("csrr {}, {}", x(*rd), csr(*imm)),
+        CSRRW(ITypeParams { rd: 0, rs1, imm }) => format!("csrw {}, {}", csr(*imm), x(*rs1)),
+        CSRRS(ITypeParams { rd: 0, rs1, imm }) => format!("csrs {}, {}", csr(*imm), x(*rs1)),
+        CSRRC(ITypeParams { rd: 0, rs1, imm }) => format!("csrc {}, {}", csr(*imm), x(*rs1)),
+        CSRRWI(ITypeParams { rd: 0, rs1, imm }) => format!("csrwi {}, {}", csr(*imm), rs1),
+        CSRRSI(ITypeParams { rd: 0, rs1, imm }) => format!("csrsi {}, {}", csr(*imm), rs1),
+        CSRRCI(ITypeParams { rd: 0, rs1, imm }) => format!("csrci {}, {}", csr(*imm), rs1),
+        CSRRW(p) | CSRRS(p) | CSRRC(p) => {
+            format!("{} {}, {}, {}", mn, x(p.rd), csr(p.imm), x(p.rs1))
+        },
+        CSRRWI(p) | CSRRSI(p) | CSRRCI(p) => {
+            format!("{} {}, {}, {}", mn, x(p.rd), csr(p.imm), p.rs1)
+        },
+    }
+}
+
+/// Gets the name of a CSR from the immediate which holds its address, or the address in hex if
+/// the CPU doesn't implement it.
+fn csr(imm: i32) -> String {
+    let addr = (imm & 0xfff) as u16;
+    csr::name(addr).map_or_else(|| format!("{:#x}", addr), str::to_string)
+}
+
+fn branch_zero(mn: &str, rs: u8, offset: i32, target: &dyn Fn(i64) -> String) -> String {
+    format!("{} {}, {}", mn, x(rs), target(offset as i64))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{elf::{Symbol, SymbolTable}, isa::Instruction};
+
+    use super::Disassembler;
+
+    /// Decodes and displays each raw instruction.
+    fn display(raw: u32) -> String {
+        Instruction::decode(raw).to_string()
+    }
+
+    #[test]
+    fn it_disassembles_canonical_forms() {
+        assert_eq!(display(0x00850793), "addi a5, a0, 8");
+        assert_eq!(display(0x00c58533), "add a0, a1, a2");
+        assert_eq!(display(0x40b50533), "sub a0, a0, a1");
+        assert_eq!(display(0x00813083), "ld ra, 8(sp)");
+        assert_eq!(display(0xfe113c23), "sd ra, -8(sp)");
+        assert_eq!(display(0x4037d793), "srai a5, a5, 3");
+        assert_eq!(display(0x000802b7), "lui t0, 0x80");
+        assert_eq!(display(0x00000297), "auipc t0, 0x0");
+        assert_eq!(display(0x00000073), "ecall");
+        assert_eq!(display(0x00100073), "ebreak");
+        assert_eq!(display(0x02b50533), "mul a0, a0, a1");
+        assert_eq!(display(0x30200073), "mret");
+        assert_eq!(display(0x10500073), "wfi");
+        assert_eq!(display(0x30529573), "csrrw a0, mtvec, t0");
+        assert_eq!(display(0x7c0465f3), "csrrsi a1, 0x7c0, 8");
+        assert_eq!(display(0xffffffff), "unknown");
+    }
+
+    #[test]
+    fn it_disassembles_pseudo_instructions() {
+        assert_eq!(display(0x00000013), "nop");
+        assert_eq!(display(0x00500513), "li a0, 5");
+        assert_eq!(display(0x00050593), "mv a1, a0");
+        assert_eq!(display(0x0005051b), "sext.w a0, a0");
+        assert_eq!(display(0xfff54513), "not a0, a0");
+        assert_eq!(display(0x00153513), "seqz a0, a0");
+        assert_eq!(display(0x00a03533), "snez a0, a0");
+        assert_eq!(display(0x40a00533), "neg a0, a0");
+        assert_eq!(display(0x00008067), "ret");
+        assert_eq!(display(0x00028067), "jr t0");
+        assert_eq!(display(0x000280e7), "jalr t0");
+        assert_eq!(display(0x00c080e7), "jalr 12(ra)");
+        assert_eq!(display(0xfe0788e3), "beqz a5, pc - 16");
+        assert_eq!(display(0x00051463), "bnez a0, pc + 8");
+        assert_eq!(display(0xff9ff06f), "j pc - 8");
+        assert_eq!(display(0xff9ff0ef), "jal pc - 8");
+        assert_eq!(display(0x34202573), "csrr a0, mcause");
+        assert_eq!(display(0x34129073), "csrw mepc, t0");
+        assert_eq!(display(0x30046073), "csrsi mstatus, 8");
+    }
+
+    #[test]
+    fn it_resolves_targets_to_symbols() {
+        let symbols = SymbolTable::new(vec![
+            Symbol { name: "_start".to_string(), addr: 0x8000_0000, size: 0 },
+            Symbol { name: "loop".to_string(), addr: 0x8000_0010, size: 0x10 },
+        ]);
+        let disassembler = Disassembler::with_symbols(symbols);
+
+        let inst = Instruction::decode(0xfe0788e3);
+        assert_eq!(disassembler.disassemble(&inst, 0x8000_0020), "beqz a5, 0x80000010 <loop>");
+
+        let inst = Instruction::decode(0x00051463);
+        assert_eq!(disassembler.disassemble(&inst, 0x8000_0010), "bnez a0, 0x80000018 <loop+0x8>");
+
+        let inst = Instruction::decode(0xff9ff06f);
+        assert_eq!(disassembler.disassemble(&inst, 0x8000_0008), "j 0x80000000 <_start>");
+
+        let inst = Instruction::decode(0x00050593);
+        assert_eq!(disassembler.disassemble(&inst, 0x8000_0008), "mv a1, a0");
+    }
+}
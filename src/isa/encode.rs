@@ -0,0 +1,271 @@
+use super::{decode::{BTypeParams, ITypeParams, InstructionFormat, JTypeParams, RTypeParams, STypeParams, UTypeParams, INSTRUCTION_PATTERNS}, Instruction};
+
+/// The operands of an instruction, grouped by the format which the instruction is encoded with.
+enum Operands {
+    R(RTypeParams),
+    I(ITypeParams),
+    S(STypeParams),
+    B(BTypeParams),
+    U(UTypeParams),
+    J(JTypeParams),
+}
+
+impl Instruction {
+    /// Encodes the instruction into its binary form. This is the inverse of `Instruction::decode`,
+    /// and returns `None` for `UNDEF` or for operands which do not fit in their fields.
+    pub fn encode(&self) -> Option<u32> {
+        let operands = self.operands()?;
+
+        // The patterns are the single source of truth for opcodes, so find the pattern which
+        // builds this exact instruction from its operands.
+        for pattern in INSTRUCTION_PATTERNS.iter() {
+            match (pattern, &operands) {
+                (InstructionFormat::RType { opcode, funct3, funct7, make }, Operands::R(params))
+                    if make(*params) == *self => return params.encode(*opcode, *funct3, *funct7),
+                (InstructionFormat::IType { opcode, funct3, predicate, make }, Operands::I(params))
+                    if make(*params) == *self && predicate.is_none_or(|x| x(params)) => {
+                        return params.encode(*opcode, *funct3)
+                    },
+                (InstructionFormat::SType { opcode, funct3, make }, Operands::S(params))
+                    if make(*params) == *self => return params.encode(*opcode, *funct3),
+                (InstructionFormat::BType { opcode, funct3, make }, Operands::B(params))
+                    if make(*params) == *self => return params.encode(*opcode, *funct3),
+                (InstructionFormat::UType { opcode, make }, Operands::U(params))
+                    if make(*params) == *self => return params.encode(*opcode),
+                (InstructionFormat::JType { opcode, make }, Operands::J(params))
+                    if make(*params) == *self => return params.encode(*opcode),
+                _ => {},
+            }
+        }
+
+        None
+    }
+
+    fn operands(&self) -> Option<Operands> {
+        use Instruction::*;
+        Some(match self {
+            UNDEF => return None,
+
+            ADD(p) | SUB(p) | XOR(p) | OR(p) | AND(p) | SLL(p) | SRL(p) | SRA(p) | SLT(p) |
+            SLTU(p) | ADDW(p) | SUBW(p) | SLLW(p) | SRLW(p) | SRAW(p) | MUL(p) | MULH(p) |
+            MULSU(p) | MULU(p) | DIV(p) | DIVU(p) | REM(p) | REMU(p) | MULW(p) | DIVW(p) |
+            DIVWU(p) | REMW(p) | REMWU(p) => Operands::R(*p),
+
+            ADDI(p) | XORI(p) | ORI(p) | ANDI(p) | SLLI(p) | SRLI(p) | SRAI(p) | SLTI(p) |
+            SLTIU(p) | ADDIW(p) | SLLIW(p) | SRLIW(p) | SRAIW(p) | LB(p) | LH(p) | LW(p) |
+            LD(p) | LBU(p) | LHU(p) | LWU(p) | JALR(p) | ECALL(p) | EBREAK(p) | MRET(p) | WFI(p) |
+            CSRRW(p) | CSRRS(p) | CSRRC(p) | CSRRWI(p) | CSRRSI(p) | CSRRCI(p) => Operands::I(*p),
+
+            SB(p) | SH(p) | SW(p) | SD(p) => Operands::S(*p),
+
+            BEQ(p) | BNE(p) | BLT(p) | BGE(p) | BLTU(p) | BGEU(p) => Operands::B(*p),
+
+            LUI(p) | AUIPC(p) => Operands::U(*p),
+
+            JAL(p) => Operands::J(*p),
+        })
+    }
+}
+
+/// Indicates if a signed immediate can be represented with the given number of bits.
+fn fits_signed(imm: i32, bits: u32) -> bool {
+    let bound = 1i64 << (bits - 1);
+    (-bound..bound).contains(&(imm as i64))
+}
+
+/// Indicates if all of the register numbers are valid integer registers.
+fn valid_regs(regs: &[u8]) -> bool {
+    regs.iter().all(|&r| r < 32)
+}
+
+impl RTypeParams {
+    pub fn encode(&self, opcode: u32, funct3: u32, funct7: u32) -> Option<u32> {
+        if !valid_regs(&[self.rd, self.rs1, self.rs2]) {
+            return None;
+        }
+        Some(
+            (funct7 << 25) | ((self.rs2 as u32) << 20) | ((self.rs1 as u32) << 15) |
+            (funct3 << 12) | ((self.rd as u32) << 7) | opcode
+        )
+    }
+}
+
+impl ITypeParams {
+    pub fn encode(&self, opcode: u32, funct3: u32) -> Option<u32> {
+        if !valid_regs(&[self.rd, self.rs1]) || !fits_signed(self.imm, 12) {
+            return None;
+        }
+        Some(
+            ((self.imm as u32 & 0xfff) << 20) | ((self.rs1 as u32) << 15) | (funct3 << 12) |
+            ((self.rd as u32) << 7) | opcode
+        )
+    }
+}
+
+impl STypeParams {
+    pub fn encode(&self, opcode: u32, funct3: u32) -> Option<u32> {
+        if !valid_regs(&[self.rs1, self.rs2]) || !fits_signed(self.imm, 12) {
+            return None;
+        }
+        let imm = self.imm as u32;
+        Some(
+            (((imm >> 5) & 0x7f) << 25) | ((self.rs2 as u32) << 20) | ((self.rs1 as u32) << 15) |
+            (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+        )
+    }
+}
+
+impl BTypeParams {
+    pub fn encode(&self, opcode: u32, funct3: u32) -> Option<u32> {
+        if !valid_regs(&[self.rs1, self.rs2]) || !fits_signed(self.imm, 13) || self.imm & 1 != 0 {
+            return None;
+        }
+        let imm = self.imm as u32;
+        Some(
+            (((imm >> 12) & 0x1) << 31) | (((imm >> 5) & 0x3f) << 25) |
+            ((self.rs2 as u32) << 20) | ((self.rs1 as u32) << 15) | (funct3 << 12) |
+            (((imm >> 1) & 0xf) << 8) | (((imm >> 11) & 0x1) << 7) | opcode
+        )
+    }
+}
+
+impl UTypeParams {
+    pub fn encode(&self, opcode: u32) -> Option<u32> {
+        if !valid_regs(&[self.rd]) || !(0..1 << 20).contains(&self.imm) {
+            return None;
+        }
+        Some(((self.imm as u32) << 12) | ((self.rd as u32) << 7) | opcode)
+    }
+}
+
+impl JTypeParams {
+    pub fn encode(&self, opcode: u32) -> Option<u32> {
+        if !valid_regs(&[self.rd]) || !fits_signed(self.imm, 21) || self.imm & 1 != 0 {
+            return None;
+        }
+        let imm = self.imm as u32;
+        Some(
+            (((imm >> 20) & 0x1) << 31) | (((imm >> 1) & 0x3ff) << 21) |
+            (((imm >> 11) & 0x1) << 20) | (((imm >> 12) & 0xff) << 12) |
+            ((self.rd as u32) << 7) | opcode
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::isa::{decode::{BTypeParams, ITypeParams, InstructionFormat, JTypeParams, RTypeParams, STypeParams, UTypeParams, INSTRUCTION_PATTERNS}, Instruction};
+
+    /// A xorshift generator, so that the property tests are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 32) as u32
+        }
+
+        fn reg(&mut self) -> u8 {
+            (self.next() % 32) as u8
+        }
+
+        /// A random signed immediate of the given width, aligned to `align` bytes.
+        fn imm(&mut self, bits: u32, align: i32) -> i32 {
+            let imm = (self.next() as i32) >> (32 - bits);
+            imm & !(align - 1)
+        }
+    }
+
+    /// Builds a random instruction from a pattern, or `None` if the operands do not satisfy it.
+    fn random_instruction(pattern: &InstructionFormat, rng: &mut Rng) -> Option<Instruction> {
+        Some(match pattern {
+            InstructionFormat::RType { make, .. } => make(RTypeParams {
+                rs1: rng.reg(),
+                rs2: rng.reg(),
+                rd: rng.reg(),
+            }),
+            InstructionFormat::IType { predicate, make, .. } => {
+                let mut params = ITypeParams { rs1: rng.reg(), rd: rng.reg(), imm: rng.imm(12, 1) };
+                // Shifts and system instructions only accept a few immediates, so also try the
+                // immediate with its upper bits cleared, the funct6 / funct7 of an arithmetic
+                // shift, and the immediates of `mret` and `wfi`.
+                let imm = params.imm;
+                for imm in [imm, imm & 0x3f, imm & 0x1f, imm & 0x3f | 0x400, imm & 0x1f | 0x400, imm & 0x1, 0x302, 0x105] {
+                    params.imm = imm;
+                    if predicate.is_none_or(|x| x(&params)) {
+                        return Some(make(params));
+                    }
+                }
+                return None;
+            },
+            InstructionFormat::SType { make, .. } => make(STypeParams {
+                rs1: rng.reg(),
+                rs2: rng.reg(),
+                imm: rng.imm(12, 1),
+            }),
+            InstructionFormat::BType { make, .. } => make(BTypeParams {
+                rs1: rng.reg(),
+                rs2: rng.reg(),
+                imm: rng.imm(13, 2),
+            }),
+            InstructionFormat::UType { make, .. } => make(UTypeParams {
+                rd: rng.reg(),
+                imm: (rng.next() >> 12) as i32,
+            }),
+            InstructionFormat::JType { make, .. } => make(JTypeParams {
+                rd: rng.reg(),
+                imm: rng.imm(21, 2),
+            }),
+        })
+    }
+
+    #[test]
+    fn it_round_trips_every_instruction() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for pattern in INSTRUCTION_PATTERNS.iter() {
+            let mut checked = 0;
+            for _ in 0..1000 {
+                let Some(inst) = random_instruction(pattern, &mut rng) else { continue };
+                let raw = inst.encode().unwrap_or_else(|| panic!("failed to encode {:?}", inst));
+                assert_eq!(Instruction::decode(raw), inst, "raw instruction {:#010x}", raw);
+                checked += 1;
+            }
+            assert!(checked > 0);
+        }
+    }
+
+    #[test]
+    fn it_encodes_known_instructions() {
+        let inst = Instruction::ADDI(ITypeParams { rs1: 10, rd: 15, imm: 8 });
+        assert_eq!(inst.encode(), Some(0x00850793));
+
+        let inst = Instruction::SD(STypeParams { rs1: 2, rs2: 1, imm: -8 });
+        assert_eq!(inst.encode(), Some(0xfe113c23));
+
+        let inst = Instruction::BEQ(BTypeParams { rs1: 15, rs2: 0, imm: -16 });
+        assert_eq!(inst.encode(), Some(0xfe0788e3));
+
+        let inst = Instruction::JAL(JTypeParams { rd: 1, imm: -8 });
+        assert_eq!(inst.encode(), Some(0xff9ff0ef));
+
+        let inst = Instruction::SRAI(ITypeParams { rs1: 15, rd: 15, imm: 0x403 });
+        assert_eq!(inst.encode(), Some(0x4037d793));
+    }
+
+    #[test]
+    fn it_rejects_unencodable_instructions() {
+        assert_eq!(Instruction::UNDEF.encode(), None);
+
+        let inst = Instruction::ADDI(ITypeParams { rs1: 0, rd: 10, imm: 2048 });
+        assert_eq!(inst.encode(), None);
+
+        let inst = Instruction::BNE(BTypeParams { rs1: 10, rs2: 0, imm: 3 });
+        assert_eq!(inst.encode(), None);
+
+        let inst = Instruction::SLLI(ITypeParams { rs1: 10, rd: 10, imm: 0x400 });
+        assert_eq!(inst.encode(), None);
+    }
+}
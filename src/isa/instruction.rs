@@ -92,6 +92,19 @@ pub enum Instruction {
      */
     ECALL(ITypeParams),
     EBREAK(ITypeParams),
+    MRET(ITypeParams),
+    WFI(ITypeParams),
+
+    /**
+     * Control and status registers, whose address is the immediate. The immediate forms take
+     * their operand from the `rs1` field.
+     */
+    CSRRW(ITypeParams),
+    CSRRS(ITypeParams),
+    CSRRC(ITypeParams),
+    CSRRWI(ITypeParams),
+    CSRRSI(ITypeParams),
+    CSRRCI(ITypeParams),
 
     /**
      * Multiplication extension
@@ -166,8 +179,9 @@ impl Instruction {
 }
 
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
 mod tests {
-    use crate::isa::{decode::{BTypeParams, ITypeParams, RTypeParams, STypeParams}, Instruction};
+    use crate::isa::{decode::{BTypeParams, ITypeParams, JTypeParams, RTypeParams, STypeParams}, Instruction};
 
     #[test]
     pub fn it_decodes_add_and_sub_correctly() {
@@ -232,7 +246,25 @@ mod tests {
         let expected = Instruction::BEQ(BTypeParams {
             rs1: 7,
             rs2: 12,
-            imm: 1594,
+            imm: 3188,
+        });
+        assert_eq!(inst, expected);
+
+        let inst = Instruction::decode(0xfe0788e3);
+        let expected = Instruction::BEQ(BTypeParams {
+            rs1: 15,
+            rs2: 0,
+            imm: -16,
+        });
+        assert_eq!(inst, expected);
+    }
+
+    #[test]
+    pub fn it_decodes_jump_instrs_correctly() {
+        let inst = Instruction::decode(0xff9ff0ef);
+        let expected = Instruction::JAL(JTypeParams {
+            rd: 1,
+            imm: -8,
         });
         assert_eq!(inst, expected);
     }
@@ -1,4 +1,9 @@
+pub mod assemble;
+pub mod csr;
 pub mod decode;
+pub mod disassemble;
+pub mod encode;
 pub mod instruction;
 
-pub use instruction::Instruction;
\ No newline at end of file
+pub use disassemble::Disassembler;
+pub use instruction::Instruction;
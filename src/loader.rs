@@ -0,0 +1,153 @@
+use std::{fmt, ops::Range};
+
+use crate::{
+    components::{bus::{DRAM_BASE, ROM_BASE}, memory::{address::Addressable, image::Imageable, rom, MMU}, CPU},
+    elf::{Elf, ElfError, PT_LOAD},
+    fdt,
+    isa::assemble::assemble,
+    snapshot::PAGE_SIZE,
+};
+
+/// The size of the stack which is reserved at the top of memory, below which a program's heap
+/// must stop.
+pub const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The address which harts start at when the machine is reset, at the start of the ROM.
+pub const RESET_VECTOR: u64 = ROM_BASE;
+/// The size of the generated boot code, which the device tree follows.
+pub const BOOT_CODE_SIZE: u64 = 0x20;
+/// The address of the device tree in the ROM when the boot code is generated, as on Spike.
+pub const DTB_ADDR: u64 = ROM_BASE + BOOT_CODE_SIZE;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// The executable needs a dynamic linker, which isn't supported.
+    Dynamic,
+    /// The program, or its arguments and environment, don't fit in memory.
+    TooLarge,
+    /// The device tree isn't a flattened device tree blob.
+    DeviceTree,
+    /// The boot ROM and the device tree don't fit in the ROM.
+    RomTooLarge,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(e: ElfError) -> Self {
+        Self::Elf(e)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Elf(e) => write!(f, "invalid ELF file: {:?}", e),
+            Self::Dynamic => write!(f, "dynamically linked executables are not supported"),
+            Self::TooLarge => write!(f, "the program does not fit in memory"),
+            Self::DeviceTree => write!(f, "not a flattened device tree (.dtb) file"),
+            Self::RomTooLarge => write!(f, "the boot ROM and the device tree do not fit in the ROM"),
+        }
+    }
+}
+
+/// Loads a bare-metal program, either an ELF file at the addresses of its segments, starting at
+/// its entry point, or a flat binary image at the start of DRAM. Returns the memory between the
+/// end of the program and the stack, where its heap can go.
+///
+/// Flat images don't include the `.bss` section, so the heap of a program which has one should
+/// be placed using its ELF file.
+pub fn load_program(cpu: &mut CPU, data: Vec<u8>) -> Result<Range<u64>, LoadError> {
+    let stack = DRAM_BASE + cpu.mmu().bus().dram().size() - STACK_SIZE;
+    if !Elf::is_elf(&data) {
+        let end = DRAM_BASE + data.len() as u64;
+        cpu.mmu().load_dram_image(data);
+        return Ok(end.next_multiple_of(PAGE_SIZE as u64)..stack);
+    }
+
+    let elf = Elf::parse(&data)?;
+    let end = load_segments(cpu.mmu(), &elf, 0, DRAM_BASE..stack)?;
+    cpu.set_pc(elf.entry);
+    Ok(end..stack)
+}
+
+/// Copies the loadable segments of an ELF file into memory, moved up by `bias`, and returns the
+/// page-aligned end of the highest one. Every segment has to fit within `bounds`. Memory starts
+/// zeroed, so only the part of each segment which is in the file is copied.
+pub fn load_segments(mmu: &mut MMU, elf: &Elf, bias: u64, bounds: Range<u64>) -> Result<u64, LoadError> {
+    let mut end = bounds.start;
+    for segment in elf.segments.iter().filter(|s| s.kind == PT_LOAD) {
+        let start = segment.vaddr.wrapping_add(bias);
+        let segment_end = start.checked_add(segment.memsz).ok_or(LoadError::TooLarge)?;
+        if start < bounds.start || segment_end > bounds.end {
+            return Err(LoadError::TooLarge);
+        }
+        mmu.store_bytes(start, elf.segment_data(segment)?).map_err(|_| LoadError::TooLarge)?;
+        end = end.max(segment_end);
+    }
+    Ok(end.next_multiple_of(PAGE_SIZE as u64))
+}
+
+/// Generates the boot code of the ROM, which starts the program as firmware would, with the
+/// hart id in `a0` and the address of the device tree which follows the code in `a1`, and then
+/// jumps to the entry point. It's the same as the reset vector of QEMU's virt machine, except that
+/// there's only one hart, so its id isn't read from `mhartid`.
+pub fn boot_code(entry: u64) -> Vec<u8> {
+    let code = assemble(&format!("
+            auipc t0, 0
+            addi a1, t0, {}
+            li a0, 0
+            ld t0, 24(t0)
+            jr t0
+            .word 0
+            .dword {}
+    ", BOOT_CODE_SIZE, entry), RESET_VECTOR).expect("the boot code assembles");
+    assert_eq!(code.len() as u64, BOOT_CODE_SIZE);
+    code
+}
+
+/// Loads the ROM with boot code, followed by a device tree at the next 8-byte boundary, and
+/// resets the hart to the start of the ROM. Returns the address of the device tree.
+pub fn load_boot_rom(cpu: &mut CPU, code: &[u8], dtb: &[u8]) -> Result<u64, LoadError> {
+    if !fdt::is_fdt(dtb) {
+        return Err(LoadError::DeviceTree);
+    }
+    let offset = code.len().next_multiple_of(8);
+    if offset + dtb.len() > rom::SIZE {
+        return Err(LoadError::RomTooLarge);
+    }
+    let mut image = code.to_vec();
+    image.resize(offset, 0);
+    image.extend_from_slice(dtb);
+    cpu.mmu().bus().rom().load_image(image);
+
+    cpu.set_pc(RESET_VECTOR);
+    Ok(ROM_BASE + offset as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{asm, components::{bus::DRAM_BASE, CPU}, fdt::{self, Chosen}};
+
+    use super::{boot_code, load_boot_rom, LoadError, DTB_ADDR, RESET_VECTOR};
+
+    #[test]
+    fn it_boots_through_the_rom() {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("li a0, 42; jr a0").into_iter().chain(vec![0; 0x100]).collect());
+        let dtb = fdt::generate(cpu.mmu().bus(), &Chosen::default());
+        assert_eq!(load_boot_rom(&mut cpu, &boot_code(DRAM_BASE + 0x100), &dtb), Ok(DTB_ADDR));
+        assert_eq!(cpu.pc(), RESET_VECTOR);
+        assert_eq!(cpu.mmu().load_bytes(DTB_ADDR, dtb.len() as u64), Ok(dtb));
+
+        // The boot code passes the hart id and the device tree, and jumps to the entry point.
+        cpu.run_for(5);
+        assert_eq!(cpu.pc(), DRAM_BASE + 0x100);
+        assert_eq!(cpu.read_xreg(10), 0);
+        assert_eq!(cpu.read_xreg(11), DTB_ADDR);
+
+        // A custom ROM is followed by the device tree, on an 8-byte boundary.
+        let dtb = fdt::generate(cpu.mmu().bus(), &Chosen::default());
+        assert_eq!(load_boot_rom(&mut cpu, &[0x13; 12], &dtb), Ok(RESET_VECTOR + 16));
+        assert_eq!(load_boot_rom(&mut cpu, &[], b"/dts-v1/; / {};"), Err(LoadError::DeviceTree));
+    }
+}
@@ -1,20 +1,712 @@
-use components::CPU;
+use std::{fs::File, io::{self, BufReader, BufWriter, Write}, ops::Range, path::Path, process::exit};
+
+use components::{
+    bus::DRAM_BASE,
+    devices::{
+        flash::{CommandSet, Flash, FLASH_BASE},
+        framebuffer::{Framebuffer, PixelFormat},
+        pci::{Edu, PciHost},
+        rtc::{Epoch, Rtc},
+        spi::{SdCard, SpiController, SpiFlash},
+        stdin,
+        uart::Uart,
+        virtio::{
+            block::DiskMode,
+            console::Port,
+            net::{self, parse_mac, Loopback, Pcap, Socket, DEFAULT_MAC},
+            Block, Console, Net, P9, Rng, VirtioDevice,
+        },
+    },
+    memory::mapping::Sharing,
+    CPU,
+};
+use debug::Debugger;
+use elf::Elf;
+use fdt::Chosen;
+use replay::Journal;
+use syscall::{Linux, ProxyKernel, Semihosting};
+use util::parse_addr;
+use trace::{BinaryTraceReader, BinaryTracer, ReferenceTrace, SpikeTracer, TraceFilter, Tracer};
 
 pub mod util;
 pub mod components;
 pub mod isa;
 pub mod emulator;
+pub mod elf;
+pub mod objdump;
+pub mod trace;
+pub mod snapshot;
+pub mod replay;
+pub mod debug;
+pub mod syscall;
+pub mod loader;
+pub mod fdt;
+
+const USAGE: &str = "\
+usage: emulator [options] [<binary or ELF file>]
+       emulator --user [options] <executable> [<args>...]
+       emulator objdump [--raw] [--base <addr>] <file>
+       emulator trace <file>
+       emulator diff <reference log> <binary>
+
+options:
+  --user                       run a statically linked Linux executable, servicing its system
+                               calls on the host; arguments after it are passed to the program
+  --pk <dir>                   service ecalls from machine mode like the riscv-pk proxy kernel,
+                               opening files within a directory
+  --semihosting                service semihosting calls made with ebreak, on the host
+  --dram-file <path>           back DRAM with a host file, such as a firmware image, which is
+                               mapped rather than copied in; the program may then be left out
+  --rom-file <path>            back the ROM with a host file
+  --dtb <path>                 pass a device tree blob to the program, instead of generating one
+  --boot-rom <path>            boot from a mask ROM image, instead of the generated boot code
+  --append <args>              pass a kernel command line in the generated device tree
+  --shared-memory              write changes to DRAM through to its file, where other
+                               processes can see them, rather than copy-on-write
+  --disk <path>[,ro|,cow]      attach a virtio disk backed by an image, which is read-only, or
+                               whose changes are kept in memory, if asked
+  --console                    attach a virtio console on standard input and output
+  --console-port <name>=<path> add a named port to the console, whose output goes to a file
+  --uart                       attach a 16550 UART on standard input and output instead
+  --net <backend>[,mac=<mac>]  attach a virtio network device, whose frames are echoed back
+                               (loopback), captured to a file (pcap:<path>), or passed over a
+                               Unix datagram socket to a peer (socket:<path>:<peer path>)
+  --share <dir>[,ro][,tag=<tag>]
+                               share a host directory with the guest over virtio-9p, which it
+                               mounts by its tag, which is share by default
+  --rng                        attach a virtio entropy device, fed from the host's randomness
+  --rng-seed <seed>            feed the entropy device from a seeded generator instead
+  --rtc                        attach a goldfish real-time clock, set from the host's clock
+  --rtc-epoch <secs>           start the clock at a fixed time instead, in seconds since 1970
+  --framebuffer <w>x<h>[,<format>]
+                               attach a simple-framebuffer, whose pixels are x8r8g8b8 unless
+                               another format, such as r5g6b5, is given
+  --screenshot <path>          save the framebuffer as a PNG or PPM image when the program stops
+  --screenshot-at <addr>       save it each time the program reaches an address instead
+  --flash <path>[,amd][,boot]  attach a CFI NOR flash backed by an image, which is created if it
+                               doesn't exist, with the Intel command set or the AMD one; the boot
+                               code jumps to it, rather than to the program, if asked
+  --sd <path>[,ro|,cow]        attach an SD card backed by an image to a SiFive SPI controller
+  --spi-flash <path>           attach a JEDEC SPI NOR flash backed by an image to the controller,
+                               which is created if it doesn't exist
+  --pci <device>               attach a PCIe host bridge with a device on it, which can be an
+                               edu test device (edu)
+  --log-commits                print a Spike-compatible commit log to stdout
+  --trace-file <path>          write a compact binary trace to a file
+  --trace-pc <start>:<end>     only trace instructions within the address range
+  --trace-count <start>:<end>  only trace instructions within the range of the instruction count
+  --save-snapshot <path>       save a snapshot of the machine when the program stops
+  --snapshot-at <count>        save the snapshot after a number of instructions, and stop
+  --restore-snapshot <path>    start from a snapshot instead of loading a binary
+  --record <path>              record every nondeterministic input to a journal
+  --replay <path>              feed the inputs from a journal back in, to reproduce a run
+  --debug                      run under an interactive debugger which can step backwards
+  --gdb <port>                 wait for gdb to connect on a port, and run under its control
+  --checkpoint-interval <n>    the number of instructions between snapshots for reverse execution";
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("objdump") => objdump(&args[1..]),
+        Some("trace") => dump_trace(&args[1..]),
+        Some("diff") => diff(&args[1..]),
+        Some("-h" | "--help") => println!("{}", USAGE),
+        _ => run(&args),
+    }
+}
+
+/// The options for running a program.
+struct RunOptions {
+    /// The program to load, which is optional if DRAM is backed by a file.
+    image: Option<String>,
+    user: bool,
+    /// The arguments to pass to a Linux program, after the path of the executable.
+    user_args: Vec<String>,
+    pk: Option<String>,
+    semihosting: bool,
+    dram_file: Option<String>,
+    rom_file: Option<String>,
+    shared_memory: bool,
+    disks: Vec<String>,
+    console: bool,
+    console_ports: Vec<(String, String)>,
+    uart: bool,
+    nets: Vec<String>,
+    shares: Vec<String>,
+    rng: bool,
+    rng_seed: Option<u64>,
+    rtc: bool,
+    rtc_epoch: Option<u64>,
+    framebuffer: Option<(usize, usize, PixelFormat)>,
+    screenshot: Option<String>,
+    screenshot_at: Option<u64>,
+    pci: Vec<String>,
+    /// The flash's image, its command set, and whether to boot from it.
+    flash: Option<(String, CommandSet, bool)>,
+    sd: Option<String>,
+    spi_flash: Option<String>,
+    dtb: Option<String>,
+    boot_rom: Option<String>,
+    append: Option<String>,
+    log_commits: bool,
+    trace_file: Option<String>,
+    trace_pc: Option<Range<u64>>,
+    trace_count: Option<Range<u64>>,
+    save_snapshot: Option<String>,
+    snapshot_at: Option<u64>,
+    restore_snapshot: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    debug: bool,
+    gdb: Option<u16>,
+    checkpoint_interval: u64,
+}
+
+impl RunOptions {
+    fn parse(args: &[String]) -> Self {
+        let mut options = Self {
+            image: None,
+            user: false,
+            user_args: vec![],
+            pk: None,
+            semihosting: false,
+            dram_file: None,
+            rom_file: None,
+            shared_memory: false,
+            disks: vec![],
+            console: false,
+            console_ports: vec![],
+            uart: false,
+            nets: vec![],
+            shares: vec![],
+            rng: false,
+            rng_seed: None,
+            rtc: false,
+            rtc_epoch: None,
+            framebuffer: None,
+            screenshot: None,
+            screenshot_at: None,
+            pci: vec![],
+            flash: None,
+            sd: None,
+            spi_flash: None,
+            dtb: None,
+            boot_rom: None,
+            append: None,
+            log_commits: false,
+            trace_file: None,
+            trace_pc: None,
+            trace_count: None,
+            save_snapshot: None,
+            snapshot_at: None,
+            restore_snapshot: None,
+            record: None,
+            replay: None,
+            debug: false,
+            gdb: None,
+            checkpoint_interval: debug::DEFAULT_CHECKPOINT_INTERVAL,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if options.user && !options.user_args.is_empty() {
+                options.user_args.push(arg.clone());
+                continue;
+            }
+            let mut value = || args.next().cloned().unwrap_or_else(|| usage());
+            match arg.as_str() {
+                "--user" => options.user = true,
+                "--pk" => options.pk = Some(value()),
+                "--semihosting" => options.semihosting = true,
+                "--dram-file" => options.dram_file = Some(value()),
+                "--rom-file" => options.rom_file = Some(value()),
+                "--shared-memory" => options.shared_memory = true,
+                "--disk" => options.disks.push(value()),
+                "--console" => options.console = true,
+                "--console-port" => {
+                    let value = value();
+                    let (name, path) = value.split_once('=').unwrap_or_else(|| usage());
+                    options.console_ports.push((name.to_string(), path.to_string()));
+                },
+                "--uart" => options.uart = true,
+                "--net" => options.nets.push(value()),
+                "--share" => options.shares.push(value()),
+                "--rng" => options.rng = true,
+                "--rng-seed" => options.rng_seed = Some(parse_addr(&value()).unwrap_or_else(|| usage())),
+                "--rtc" => options.rtc = true,
+                "--rtc-epoch" => options.rtc_epoch = Some(parse_addr(&value()).unwrap_or_else(|| usage())),
+                "--framebuffer" => options.framebuffer = Some(parse_framebuffer(&value()).unwrap_or_else(|| usage())),
+                "--screenshot" => options.screenshot = Some(value()),
+                "--screenshot-at" => options.screenshot_at = Some(parse_addr(&value()).unwrap_or_else(|| usage())),
+                "--pci" => options.pci.push(value()),
+                "--flash" => options.flash = Some(parse_flash(&value()).unwrap_or_else(|| usage())),
+                "--sd" => options.sd = Some(value()),
+                "--spi-flash" => options.spi_flash = Some(value()),
+                "--dtb" => options.dtb = Some(value()),
+                "--boot-rom" => options.boot_rom = Some(value()),
+                "--append" => options.append = Some(value()),
+                "--log-commits" => options.log_commits = true,
+                "--trace-file" => options.trace_file = Some(value()),
+                "--trace-pc" => options.trace_pc = Some(parse_range(&value())),
+                "--trace-count" => options.trace_count = Some(parse_range(&value())),
+                "--save-snapshot" => options.save_snapshot = Some(value()),
+                "--snapshot-at" => options.snapshot_at = Some(parse_addr(&value()).unwrap_or_else(|| usage())),
+                "--restore-snapshot" => options.restore_snapshot = Some(value()),
+                "--record" => options.record = Some(value()),
+                "--replay" => options.replay = Some(value()),
+                "--debug" => options.debug = true,
+                "--gdb" => options.gdb = Some(value().parse().unwrap_or_else(|_| usage())),
+                "--checkpoint-interval" => options.checkpoint_interval = parse_addr(&value()).unwrap_or_else(|| usage()),
+                _ if arg.starts_with("--") => usage(),
+                _ => {
+                    options.image = Some(arg.clone());
+                    if options.user {
+                        options.user_args.push(arg.clone());
+                    }
+                },
+            }
+        }
+        // Firmware can boot from flash, or a boot ROM can load it from a device on the SPI bus,
+        // without a program.
+        let flash_boot = matches!(options.flash, Some((_, _, true)));
+        let spi_boot = options.boot_rom.is_some() && (options.sd.is_some() || options.spi_flash.is_some());
+        if options.image.is_none() && options.dram_file.is_none() && !flash_boot && !spi_boot {
+            options.image = Some("../emulator_test/binary".to_string());
+        }
+        options.check(flash_boot);
+        options
+    }
+
+    /// Exits with an error naming the options if any of them can't be used together.
+    fn check(&self, flash_boot: bool) {
+        let devices = !self.disks.is_empty() || self.console || !self.console_ports.is_empty()
+            || self.uart || !self.nets.is_empty() || !self.shares.is_empty() || self.rng
+            || self.rng_seed.is_some() || self.rtc || self.rtc_epoch.is_some()
+            || self.framebuffer.is_some() || !self.pci.is_empty() || self.flash.is_some()
+            || self.sd.is_some() || self.spi_flash.is_some();
+        let boot = self.dtb.is_some() || self.boot_rom.is_some() || self.append.is_some();
+        let checks = [
+            (self.snapshot_at.is_some() && self.save_snapshot.is_none(),
+                "--snapshot-at needs --save-snapshot"),
+            ((self.pk.is_some() || self.semihosting) && self.image.is_none(),
+                "--pk and --semihosting need a program"),
+            (self.user && self.user_args.is_empty(), "--user needs an executable"),
+            (self.user && self.restore_snapshot.is_some(), "--user conflicts with --restore-snapshot"),
+            (self.user && (self.pk.is_some() || self.semihosting),
+                "--user conflicts with --pk and --semihosting"),
+            (self.user && boot, "--user conflicts with --dtb, --boot-rom and --append"),
+            (self.user && devices, "--user can't attach devices"),
+            (self.restore_snapshot.is_some() && boot,
+                "--restore-snapshot conflicts with --dtb, --boot-rom and --append"),
+            (self.append.is_some() && self.dtb.is_some(), "--append conflicts with --dtb"),
+            (self.uart && (self.console || !self.console_ports.is_empty()),
+                "--uart conflicts with --console"),
+            (flash_boot && self.boot_rom.is_some(), "booting from --flash conflicts with --boot-rom"),
+            (self.screenshot.is_some() && self.framebuffer.is_none(),
+                "--screenshot needs --framebuffer"),
+            (self.screenshot_at.is_some() && self.screenshot.is_none(),
+                "--screenshot-at needs --screenshot"),
+            (self.record.is_some() && self.replay.is_some(), "--record conflicts with --replay"),
+            (self.debug && self.gdb.is_some(), "--debug conflicts with --gdb"),
+        ];
+        if let Some((_, message)) = checks.iter().find(|(failed, _)| *failed) {
+            eprintln!("{}", message);
+            exit(2);
+        }
+    }
+
+    /// Backs DRAM and the ROM with files, if asked to. A shared DRAM file is created if it doesn't
+    /// exist. The ROM's file is never shared, as the ROM's image is replaced when it's loaded.
+    fn map_memory(&self, cpu: &mut CPU) {
+        let sharing = match self.shared_memory {
+            true => Sharing::Shared,
+            false => Sharing::Private,
+        };
+        if let Some(path) = &self.dram_file {
+            let file = File::options()
+                .read(true)
+                .write(self.shared_memory)
+                .create(self.shared_memory)
+                .truncate(false)
+                .open(path)
+                .unwrap_or_else(|e| fail(path, e));
+            cpu.mmu().bus().dram().map_file(&file, sharing).unwrap_or_else(|e| fail(path, e));
+        }
+        if let Some(path) = &self.rom_file {
+            let file = File::open(path).unwrap_or_else(|e| fail(path, e));
+            cpu.mmu().bus().rom().map_file(&file).unwrap_or_else(|e| fail(path, e));
+        }
+    }
+
+    /// Attaches the devices which were asked for. They have to be attached before a snapshot is
+    /// restored, as the snapshot holds their state but not which devices there are.
+    fn attach_devices(&self, cpu: &mut CPU) {
+        let mut attach = |name: &str, device: Box<dyn VirtioDevice>| {
+            if cpu.mmu().bus().attach_virtio(device).is_none() {
+                fail(name, "there are no free virtio slots");
+            }
+        };
+        for disk in &self.disks {
+            let (path, mode) = parse_disk(disk);
+            attach(path, Box::new(Block::open(Path::new(path), mode).unwrap_or_else(|e| fail(path, e))));
+        }
+        if self.console || !self.console_ports.is_empty() {
+            let mut ports = vec![Port::stdio()];
+            for (name, path) in &self.console_ports {
+                let file = File::create(path).unwrap_or_else(|e| fail(path, e));
+                ports.push(Port::named(name, None, Box::new(file)));
+            }
+            attach("console", Box::new(Console::new(ports)));
+        }
+        for (i, net) in self.nets.iter().enumerate() {
+            let (backend, mac) = match net.split_once(",mac=") {
+                Some((backend, mac)) => (backend, parse_mac(mac).unwrap_or_else(|| usage())),
+                // Each device gets its own address, as on QEMU.
+                None => (net.as_str(), {
+                    let mut mac = DEFAULT_MAC;
+                    mac[5] += i as u8;
+                    mac
+                }),
+            };
+            let backend: Box<dyn net::Backend> = match backend.split_once(':') {
+                None if backend == "loopback" => Box::new(Loopback::default()),
+                Some(("pcap", path)) => {
+                    let file = File::create(path).unwrap_or_else(|e| fail(path, e));
+                    Box::new(Pcap::new(Box::new(file)).unwrap_or_else(|e| fail(path, e)))
+                },
+                Some(("socket", paths)) => {
+                    let (path, peer) = paths.split_once(':').unwrap_or_else(|| usage());
+                    Box::new(Socket::bind(Path::new(path), Path::new(peer)).unwrap_or_else(|e| fail(path, e)))
+                },
+                _ => usage(),
+            };
+            attach("net", Box::new(Net::new(mac, backend)));
+        }
+        for share in &self.shares {
+            let mut parts = share.split(',');
+            let path = parts.next().unwrap();
+            let (mut tag, mut read_only) = ("share", false);
+            for part in parts {
+                match part.split_once('=') {
+                    None if part == "ro" => read_only = true,
+                    Some(("tag", value)) if !value.is_empty() => tag = value,
+                    _ => usage(),
+                }
+            }
+            if !Path::new(path).is_dir() {
+                fail(path, "not a directory");
+            }
+            attach(path, Box::new(P9::new(path.into(), tag, read_only)));
+        }
+        match self.rng_seed {
+            Some(seed) => attach("rng", Box::new(Rng::seeded(seed))),
+            None if self.rng => attach("rng", Box::new(Rng::host())),
+            None => {},
+        }
+        if self.uart {
+            cpu.mmu().bus().attach(Box::new(Uart::new(Some(stdin()), Box::new(io::stdout()))));
+        }
+        let epoch = match self.rtc_epoch {
+            Some(secs) => Some(Epoch::Fixed(secs.saturating_mul(1_000_000_000))),
+            None => self.rtc.then_some(Epoch::Host),
+        };
+        if let Some(epoch) = epoch {
+            cpu.mmu().bus().attach(Box::new(Rtc::new(epoch)));
+        }
+        if let Some((width, height, format)) = self.framebuffer {
+            cpu.mmu().bus().attach(Box::new(Framebuffer::new(width, height, format)));
+        }
+        if !self.pci.is_empty() {
+            let mut host = PciHost::new();
+            for device in &self.pci {
+                let function = match device.as_str() {
+                    "edu" => Box::new(Edu::new()),
+                    _ => usage(),
+                };
+                if host.attach(function).is_none() {
+                    fail(device, "there are no free PCI slots");
+                }
+            }
+            cpu.mmu().bus().attach(Box::new(host));
+        }
+        if self.sd.is_some() || self.spi_flash.is_some() {
+            let mut controller = SpiController::new();
+            if let Some(sd) = &self.sd {
+                let (path, mode) = parse_disk(sd);
+                controller.attach(Box::new(SdCard::open(Path::new(path), mode).unwrap_or_else(|e| fail(path, e))));
+            }
+            if let Some(path) = &self.spi_flash {
+                controller.attach(Box::new(SpiFlash::open(Path::new(path)).unwrap_or_else(|e| fail(path, e))));
+            }
+            cpu.mmu().bus().attach(Box::new(controller));
+        }
+        if let Some((path, commands, _)) = &self.flash {
+            cpu.mmu().bus().attach(Box::new(Flash::open(Path::new(path), *commands).unwrap_or_else(|e| fail(path, e))));
+        }
+    }
+
+    /// Saves a screenshot of the framebuffer, if one was asked for.
+    fn save_screenshot(&self, cpu: &mut CPU) {
+        let (Some(path), Some(framebuffer)) = (&self.screenshot, cpu.mmu().bus().find::<Framebuffer>()) else { return };
+        framebuffer.save_screenshot(path).unwrap_or_else(|e| fail(path, e));
+    }
+
+    /// Loads the boot ROM, which starts the loaded program, or the firmware in flash if asked to,
+    /// and the device tree which was given or one generated from the machine. The hart starts at
+    /// the reset vector.
+    fn load_boot_rom(&self, cpu: &mut CPU, chosen: &Chosen) {
+        let read = |path: &String| std::fs::read(path).unwrap_or_else(|e| fail(path, e));
+        let code = match (&self.boot_rom, &self.flash) {
+            (Some(path), _) => read(path),
+            (None, Some((_, _, true))) => loader::boot_code(FLASH_BASE),
+            (None, _) => loader::boot_code(cpu.pc()),
+        };
+        let dtb = match &self.dtb {
+            Some(path) => read(path),
+            None => fdt::generate(cpu.mmu().bus(), chosen),
+        };
+        let path = self.boot_rom.as_deref().or(self.dtb.as_deref()).unwrap_or("boot ROM");
+        loader::load_boot_rom(cpu, &code, &dtb).unwrap_or_else(|e| fail(path, e));
+    }
+
+    /// Builds the tracer which was asked for, if any.
+    fn tracer(&self) -> Option<Box<dyn Tracer>> {
+        let tracer: Box<dyn Tracer> = match (self.log_commits, &self.trace_file) {
+            (false, None) => return None,
+            (true, None) => Box::new(SpikeTracer::new(BufWriter::new(io::stdout().lock()))),
+            (false, Some(path)) => {
+                let file = File::create(path).unwrap_or_else(|e| fail(path, e));
+                Box::new(BinaryTracer::new(BufWriter::new(file)))
+            },
+            (true, Some(_)) => usage(),
+        };
+
+        let mut filter = TraceFilter::new(tracer);
+        if let Some(range) = self.trace_pc.clone() {
+            filter = filter.pc_range(range);
+        }
+        if let Some(range) = self.trace_count.clone() {
+            filter = filter.count_range(range);
+        }
+        Some(Box::new(filter))
+    }
+
+    /// Opens the journal for recording or replaying, if either was asked for. A replay has to
+    /// start at the same point in the run as the recording did.
+    fn journal(&self, clock: u64) -> Option<Journal> {
+        if let Some(path) = &self.record {
+            let file = File::create(path).unwrap_or_else(|e| fail(path, e));
+            return Some(Journal::record(Box::new(BufWriter::new(file)), clock).unwrap_or_else(|e| fail(path, e)));
+        }
+
+        let path = self.replay.as_ref()?;
+        let file = File::open(path).unwrap_or_else(|e| fail(path, e));
+        let (journal, start) = Journal::replay(Box::new(BufReader::new(file))).unwrap_or_else(|e| fail(path, e));
+        if start != clock {
+            fail(path, format!("the recording starts at instruction {}, but the machine is at {}", start, clock));
+        }
+        Some(journal)
+    }
+}
+
+/// Loads a bare-metal program or a Linux executable, or restores a snapshot, and runs it.
+fn run(args: &[String]) {
+    let options = RunOptions::parse(args);
     let mut cpu = CPU::new();
+    options.map_memory(&mut cpu);
+    options.attach_devices(&mut cpu);
+
+    match &options.restore_snapshot {
+        Some(path) => {
+            let file = File::open(path).unwrap_or_else(|e| fail(path, e));
+            snapshot::restore(&mut cpu, BufReader::new(file)).unwrap_or_else(|e| fail(path, e));
+        },
+        None if options.user => {
+            let path = options.image.as_ref().unwrap();
+            let data = std::fs::read(path).unwrap_or_else(|e| fail(path, e));
+            let env: Vec<String> = std::env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+            let linux = Linux::load(&mut cpu, &data, &options.user_args, &env).unwrap_or_else(|e| fail(path, e));
+            cpu.set_syscall_handler(Box::new(linux));
+        },
+        // The program is already in memory, mapped from a file.
+        None if options.image.is_none() => {},
+        None => {
+            let path = options.image.as_ref().unwrap();
+            let image = std::fs::read(path).unwrap_or_else(|e| fail(path, e));
+            let heap = loader::load_program(&mut cpu, image).unwrap_or_else(|e| fail(path, e));
+
+            if options.semihosting {
+                let stack = heap.end..heap.end + loader::STACK_SIZE;
+                cpu.set_semihosting(Semihosting::new(path.clone(), heap.clone(), stack));
+            }
+            if let Some(root) = &options.pk {
+                cpu.set_syscall_handler(Box::new(ProxyKernel::new(root.into(), heap)));
+            }
+        },
+    }
+    if options.restore_snapshot.is_none() && !options.user {
+        let chosen = Chosen { bootargs: options.append.clone() };
+        options.load_boot_rom(&mut cpu, &chosen);
+    }
+
+    if let Some(tracer) = options.tracer() {
+        cpu.set_tracer(tracer);
+    }
+    if let Some(journal) = options.journal(cpu.clock()) {
+        cpu.mmu().bus().set_journal(journal);
+    }
+
+    if options.debug {
+        let mut debugger = Debugger::new(cpu, options.checkpoint_interval);
+        debug::repl::run(&mut debugger, io::stdin().lock(), io::stdout().lock()).unwrap_or_else(|e| fail("stdin", e));
+        return;
+    }
+    if let Some(port) = options.gdb {
+        let mut debugger = Debugger::new(cpu, options.checkpoint_interval);
+        debug::gdb::listen(&mut debugger, port).unwrap_or_else(|e| fail("gdb", e));
+        return;
+    }
 
-    let image = std::fs::read("../emulator_test/binary")
-        .expect("no file found");
+    match (options.snapshot_at, options.screenshot_at) {
+        // The program is run an instruction at a time, to catch it at the trigger.
+        (count, Some(trigger)) => for _ in 0..count.unwrap_or(u64::MAX) {
+            if cpu.run_for(1) {
+                break;
+            }
+            if cpu.pc() == trigger {
+                options.save_screenshot(&mut cpu);
+            }
+        },
+        (Some(count), None) => { cpu.run_for(count); },
+        (None, None) => cpu.run(),
+    }
+    if options.screenshot_at.is_none() {
+        options.save_screenshot(&mut cpu);
+    }
 
-    let _result = cpu
-        .mmu()
-        .load_dram_image(image);
-    
-    cpu.run();
+    if let Some(path) = &options.save_snapshot {
+        let file = File::create(path).unwrap_or_else(|e| fail(path, e));
+        snapshot::save(&cpu, BufWriter::new(file)).unwrap_or_else(|e| fail(path, e));
+    }
+    // Like `qemu-user`, exit with the program's exit code.
+    if (options.user || options.semihosting) && cpu.pc() == 0 {
+        exit(cpu.read_xreg(10) as i32);
+    }
 }
 
+/// Disassembles an ELF file or a raw binary image, in the style of `objdump -d`.
+fn objdump(args: &[String]) {
+    let mut raw = false;
+    let mut base = DRAM_BASE;
+    let mut path = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--raw" => raw = true,
+            "--base" => base = args.next().and_then(|v| parse_addr(v)).unwrap_or_else(|| usage()),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let path = path.unwrap_or_else(|| usage());
+    let data = std::fs::read(path).unwrap_or_else(|e| fail(path, e));
+
+    if raw || !Elf::is_elf(&data) {
+        print!("{}", objdump::disassemble_raw(&data, base));
+    } else {
+        match objdump::disassemble_elf(&data) {
+            Ok(listing) => print!("{}", listing),
+            Err(e) => fail(path, format!("invalid ELF file: {:?}", e)),
+        }
+    }
+}
+
+/// Prints a binary trace as a Spike commit log.
+fn dump_trace(args: &[String]) {
+    let [path] = args else { usage() };
+    let file = File::open(path).unwrap_or_else(|e| fail(path, e));
+    let reader = BinaryTraceReader::new(BufReader::new(file)).unwrap_or_else(|e| fail(path, e));
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    for commit in reader {
+        let commit = commit.unwrap_or_else(|e| fail(path, e));
+        let _ = writeln!(out, "{}", trace::spike::format_commit(0, &commit));
+    }
+}
+
+/// Runs a flat binary image in lock-step with a Spike or QEMU commit log, stopping at the first
+/// instruction whose effects differ.
+fn diff(args: &[String]) {
+    let [log, image] = args else { usage() };
+    let mut cpu = CPU::new();
+    let image = std::fs::read(image).unwrap_or_else(|e| fail(image, e));
+    cpu.mmu().load_dram_image(image);
+
+    let file = File::open(log).unwrap_or_else(|e| fail(log, e));
+    match trace::diff::lockstep(&mut cpu, ReferenceTrace::new(BufReader::new(file))) {
+        Ok(count) => println!("matched the reference for {} instructions", count),
+        Err(e) => fail(log, e),
+    }
+}
+
+/// Parses a half-open range written as `<start>:<end>`, where either bound may be left out.
+fn parse_range(value: &str) -> Range<u64> {
+    let (start, end) = value.split_once(':').unwrap_or_else(|| usage());
+    let start = match start {
+        "" => 0,
+        start => parse_addr(start).unwrap_or_else(|| usage()),
+    };
+    let end = match end {
+        "" => u64::MAX,
+        end => parse_addr(end).unwrap_or_else(|| usage()),
+    };
+    start..end
+}
+
+/// Parses the size of a framebuffer, written as `<width>x<height>`, and its pixel format.
+fn parse_framebuffer(value: &str) -> Option<(usize, usize, PixelFormat)> {
+    let (size, format) = match value.split_once(',') {
+        Some((size, format)) => (size, PixelFormat::parse(format)?),
+        None => (value, PixelFormat::X8R8G8B8),
+    };
+    let (width, height) = size.split_once('x')?;
+    let (width, height) = (width.parse().ok()?, height.parse().ok()?);
+    (width > 0 && height > 0).then_some((width, height, format))
+}
+
+/// Parses a disk image, written as `<path>[,ro|,cow]`, and how it's written to.
+fn parse_disk(value: &str) -> (&str, DiskMode) {
+    match value.rsplit_once(',') {
+        Some((path, "ro")) => (path, DiskMode::ReadOnly),
+        Some((path, "cow")) => (path, DiskMode::CopyOnWrite),
+        _ => (value, DiskMode::ReadWrite),
+    }
+}
+
+/// Parses a flash, written as `<path>[,amd][,boot]`.
+fn parse_flash(value: &str) -> Option<(String, CommandSet, bool)> {
+    let mut parts = value.split(',');
+    let path = parts.next().filter(|path| !path.is_empty())?;
+    let (mut commands, mut boot) = (CommandSet::Intel, false);
+    for part in parts {
+        match part {
+            "amd" => commands = CommandSet::Amd,
+            "boot" => boot = true,
+            _ => return None,
+        }
+    }
+    Some((path.to_string(), commands, boot))
+}
+
+fn fail(path: &str, error: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", path, error);
+    exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
@@ -0,0 +1,69 @@
+use std::fmt::Write;
+
+use crate::{elf::{Elf, ElfError, SymbolTable}, isa::{Disassembler, Instruction}};
+
+/// Disassembles every executable section of an ELF file, labelling the start of each symbol.
+pub fn disassemble_elf(data: &[u8]) -> Result<String, ElfError> {
+    let elf = Elf::parse(data)?;
+    let disassembler = Disassembler::with_symbols(SymbolTable::from_elf(&elf)?);
+
+    let mut listing = String::new();
+    for section in elf.sections.iter().filter(|s| s.is_executable()) {
+        let _ = writeln!(listing, "\nDisassembly of section {}:", section.name);
+        disassemble_into(&mut listing, &disassembler, elf.section_data(section)?, section.addr);
+    }
+    Ok(listing)
+}
+
+/// Disassembles a raw binary image which is loaded at the given base address.
+pub fn disassemble_raw(data: &[u8], base: u64) -> String {
+    let mut listing = String::new();
+    disassemble_into(&mut listing, &Disassembler::new(), data, base);
+    listing
+}
+
+fn disassemble_into(listing: &mut String, disassembler: &Disassembler, data: &[u8], base: u64) {
+    for (i, word) in data.chunks(4).enumerate() {
+        let addr = base.wrapping_add(i as u64 * 4);
+        if let Some(symbol) = disassembler.symbols().at(addr) {
+            let _ = writeln!(listing, "\n{:016x} <{}>:", addr, symbol.name);
+        }
+
+        let mut bytes = [0; 4];
+        bytes[..word.len()].copy_from_slice(word);
+        let raw = u32::from_le_bytes(bytes);
+        let text = match Instruction::decode(raw) {
+            Instruction::UNDEF => format!(".word {:#010x}", raw),
+            inst => disassembler.disassemble(&inst, addr),
+        };
+        let _ = writeln!(listing, "{:>12x}:\t{:08x}\t{}", addr, raw, text);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::disassemble_raw;
+
+    #[test]
+    fn it_disassembles_raw_images() {
+        let image: Vec<u8> = [0x00500513u32, 0x00008067, 0xffffffff]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+
+        let listing = disassemble_raw(&image, 0x8000_0000);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines, vec![
+            "    80000000:\t00500513\tli a0, 5",
+            "    80000004:\t00008067\tret",
+            "    80000008:\tffffffff\t.word 0xffffffff",
+        ]);
+    }
+
+    #[test]
+    fn it_wraps_around_the_address_space() {
+        let image: Vec<u8> = [0x00008067u32; 2].iter().flat_map(|w| w.to_le_bytes()).collect();
+        let listing = disassemble_raw(&image, u64::MAX - 3);
+        assert!(listing.lines().nth(1).unwrap().trim_start().starts_with("0:"));
+    }
+}
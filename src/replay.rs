@@ -0,0 +1,377 @@
+use std::{fmt, io::{self, ErrorKind, Read, Write}};
+
+use num_enum::TryFromPrimitive;
+
+use crate::util::{read_u8, read_varint, write_varint};
+
+/// The magic number and version at the start of every journal.
+const MAGIC: &[u8; 8] = b"RVJRNL01";
+
+/// The sources of nondeterministic input to the machine.
+///
+/// Interrupts aren't among them: the devices raise them from their own state, which only changes
+/// with the instructions and these inputs, so a replay delivers them at the same points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Source {
+    /// Bytes received by a UART or a console from the host.
+    UartRx = 0,
+    /// A read of the host's clock, such as the RTC's or `clock_gettime`.
+    Time = 1,
+    /// The completion of a request by a block device.
+    BlockCompletion = 2,
+    /// Random bytes from the host, such as for `getrandom`.
+    Random = 3,
+    /// Data read from a file or the terminal on the host by a system call, such as `read`.
+    File = 4,
+    /// A frame received by a network device from the host.
+    Packet = 5,
+}
+
+/// A nondeterministic input, and the point in the run at which the machine received it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The value of the CPU's clock, which counts instructions, when the input was received.
+    pub clock: u64,
+    pub source: Source,
+    pub data: Vec<u8>,
+}
+
+enum Mode {
+    /// Inputs are taken from the host and not recorded.
+    Live,
+    Record(Box<dyn Write>),
+    /// Inputs are taken from a journal, and the host is never asked for them.
+    Replay { input: Box<dyn Read>, next: Option<Event> },
+    /// Inputs are kept in memory, so that the machine can be rewound to an earlier point and
+    /// re-executed. Inputs up to `replay_until` are replayed, and later ones are recorded.
+    Tape { events: Vec<Event>, pos: usize, replay_until: u64 },
+}
+
+/// Records every nondeterministic input to the machine, or feeds recorded inputs back in, so that
+/// a run can be reproduced exactly. Inputs are keyed by the CPU's clock, so a replay must start
+/// from the same state as the recording, such as a freshly loaded program or a snapshot.
+///
+/// Anything which takes input from the host goes through the journal, in one of two ways:
+///
+/// - `input` for inputs which the machine asks for, such as reading the time. These are always
+///   recorded, and a replay must ask for them at the same point.
+/// - `poll` for inputs which may or may not be waiting, such as received bytes or interrupts.
+///   Only the polls which found something are recorded, and during a replay a poll finds
+///   something exactly when the recording did.
+///
+/// A journal is a header holding the clock when recording started, followed by each event as a
+/// varint clock delta from the previous event, the source as a byte, and the data as a varint
+/// length and the bytes.
+pub struct Journal {
+    mode: Mode,
+    clock: u64,
+    /// The clock of the previous event which was written.
+    last: u64,
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            Mode::Live => "live",
+            Mode::Record(_) => "record",
+            Mode::Replay { .. } => "replay",
+            Mode::Tape { .. } => "tape",
+        };
+        f.debug_struct("Journal").field("mode", &mode).field("clock", &self.clock).finish()
+    }
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Journal {
+    /// Creates a journal which passes the host's inputs through without recording them.
+    pub fn new() -> Self {
+        Self { mode: Mode::Live, clock: 0, last: 0 }
+    }
+
+    /// Starts recording inputs from the given clock.
+    pub fn record(mut out: Box<dyn Write>, clock: u64) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&clock.to_le_bytes())?;
+        Ok(Self { mode: Mode::Record(out), clock, last: clock })
+    }
+
+    /// Opens a journal for replay, returning it along with the clock which the machine must be
+    /// at when the replay starts.
+    pub fn replay(mut input: Box<dyn Read>) -> io::Result<(Self, u64)> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a journal"));
+        }
+        let mut clock = [0; 8];
+        input.read_exact(&mut clock)?;
+        let clock = u64::from_le_bytes(clock);
+
+        let mut journal = Self {
+            mode: Mode::Replay { input, next: None },
+            clock,
+            last: clock,
+        };
+        journal.advance()?;
+        Ok((journal, clock))
+    }
+
+    /// Starts keeping inputs in memory from the given clock, so that the machine can later be
+    /// rewound with `rewind` and re-executed with the same inputs.
+    pub fn tape(clock: u64) -> Self {
+        Self {
+            mode: Mode::Tape { events: vec![], pos: 0, replay_until: clock },
+            clock,
+            last: clock,
+        }
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        match &self.mode {
+            Mode::Replay { .. } => true,
+            Mode::Tape { replay_until, .. } => self.clock <= *replay_until,
+            _ => false,
+        }
+    }
+
+    /// Rewinds a tape to an earlier clock, after the machine has been restored to that point.
+    /// Everything up to the point which the machine had reached is replayed from then on.
+    pub fn rewind(&mut self, clock: u64) {
+        let Mode::Tape { events, pos, replay_until } = &mut self.mode else {
+            panic!("only a tape can be rewound");
+        };
+        *replay_until = (*replay_until).max(self.clock);
+        *pos = events.partition_point(|e| e.clock <= clock);
+        self.clock = clock;
+    }
+
+    /// Forgets the inputs on a tape after the current clock, once the machine has been changed
+    /// so that they would no longer happen. Inputs from then on are recorded afresh.
+    pub fn truncate(&mut self) {
+        if let Mode::Tape { events, pos, replay_until } = &mut self.mode {
+            events.truncate(*pos);
+            *replay_until = self.clock;
+        }
+    }
+
+    /// The CPU's clock, as of the last instruction.
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Moves the journal on to a new value of the CPU's clock. A replay which reaches this point
+    /// without having delivered an event has diverged from the recording.
+    pub fn set_clock(&mut self, clock: u64) {
+        self.clock = clock;
+        if let Some(event) = self.next_event() {
+            if event.clock < clock {
+                self.diverged(None);
+            }
+        }
+    }
+
+    /// Gets the next event to be replayed, if the journal is replaying.
+    fn next_event(&self) -> Option<&Event> {
+        match &self.mode {
+            Mode::Replay { next, .. } => next.as_ref(),
+            Mode::Tape { events, pos, replay_until } if self.clock <= *replay_until => events.get(*pos),
+            _ => None,
+        }
+    }
+
+    /// Takes an input which the machine asked for. The host is asked through `live` unless the
+    /// journal is replaying.
+    pub fn input(&mut self, source: Source, live: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        match self.poll(source, || Some(live())) {
+            Some(data) => data,
+            None => self.diverged(Some(source)),
+        }
+    }
+
+    /// Reads the host's clock, or any other input which fits in a u64.
+    pub fn input_u64(&mut self, source: Source, live: impl FnOnce() -> u64) -> u64 {
+        let data = self.input(source, || live().to_le_bytes().to_vec());
+        match data.try_into() {
+            Ok(bytes) => u64::from_le_bytes(bytes),
+            Err(_) => self.diverged(Some(source)),
+        }
+    }
+
+    /// Checks for an input which may be waiting. The host is asked through `live` unless the
+    /// journal is replaying.
+    pub fn poll(&mut self, source: Source, live: impl FnOnce() -> Option<Vec<u8>>) -> Option<Vec<u8>> {
+        match &mut self.mode {
+            Mode::Live => live(),
+            Mode::Record(out) => {
+                let data = live()?;
+                let mut record = vec![];
+                write_varint(&mut record, self.clock - self.last);
+                record.push(source as u8);
+                write_varint(&mut record, data.len() as u64);
+                record.extend_from_slice(&data);
+                if let Err(e) = out.write_all(&record) {
+                    eprintln!("stopped recording: {}", e);
+                    self.mode = Mode::Live;
+                }
+                self.last = self.clock;
+                Some(data)
+            },
+            Mode::Replay { next, .. } => {
+                match next {
+                    Some(event) if event.clock == self.clock && event.source == source => {},
+                    _ => return None,
+                }
+                let event = next.take().unwrap();
+                if let Err(e) = self.advance() {
+                    panic!("failed to read the journal: {}", e);
+                }
+                Some(event.data)
+            },
+            Mode::Tape { events, pos, replay_until } if self.clock > *replay_until => {
+                let data = live()?;
+                events.push(Event { clock: self.clock, source, data: data.clone() });
+                *pos = events.len();
+                Some(data)
+            },
+            Mode::Tape { events, pos, .. } => {
+                let event = events.get(*pos).filter(|e| e.clock == self.clock && e.source == source)?;
+                *pos += 1;
+                Some(event.data.clone())
+            },
+        }
+    }
+
+    /// Reads the next event of a replay.
+    fn advance(&mut self) -> io::Result<()> {
+        let Mode::Replay { input, next } = &mut self.mode else { return Ok(()) };
+
+        let delta = match read_varint(input) {
+            Ok(delta) => delta,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let source = Source::try_from(read_u8(input)?)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "unknown input source"))?;
+        let mut data = vec![0; read_varint(input)? as usize];
+        input.read_exact(&mut data)?;
+
+        self.last += delta;
+        *next = Some(Event { clock: self.last, source, data });
+        Ok(())
+    }
+
+    /// Stops the machine when a replay no longer matches the recording. This means that the
+    /// emulator itself behaved differently, which is a bug in its determinism.
+    fn diverged(&self, source: Option<Source>) -> ! {
+        let handling = match source {
+            Some(source) => format!(" while handling {:?} input", source),
+            None => String::new(),
+        };
+        let expected = match self.next_event() {
+            Some(event) => {
+                format!("the recording has {:?} input at instruction {}", event.source, event.clock)
+            },
+            None => "the recording has no more input".to_string(),
+        };
+        panic!("replay diverged at instruction {}{}: {}", self.clock, handling, expected);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::{self, Write}, rc::Rc};
+
+    use super::{Journal, Source};
+
+    /// A writer whose contents can still be read after it is given to the journal.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs a small "machine" which polls for a byte every instruction and reads the time every
+    /// tenth, returning what it saw.
+    fn run(journal: &mut Journal, start: u64, host: &mut impl FnMut(u64) -> Option<u8>) -> Vec<(u64, u64)> {
+        let mut seen = vec![];
+        for clock in start..start + 50 {
+            journal.set_clock(clock);
+            if let Some(data) = journal.poll(Source::UartRx, || host(clock).map(|b| vec![b])) {
+                seen.push((clock, data[0] as u64));
+            }
+            if clock % 10 == 0 {
+                seen.push((clock, journal.input_u64(Source::Time, || clock * 1000 + 7)));
+            }
+        }
+        seen
+    }
+
+    fn record(start: u64) -> (Vec<(u64, u64)>, Vec<u8>) {
+        let out = Shared::default();
+        let mut journal = Journal::record(Box::new(out.clone()), start).unwrap();
+        let seen = run(&mut journal, start, &mut |clock| (clock % 7 == 3).then_some(clock as u8));
+        let data = out.0.borrow().clone();
+        (seen, data)
+    }
+
+    #[test]
+    fn it_replays_recorded_inputs() {
+        let (recorded, data) = record(100);
+
+        let (mut journal, start) = Journal::replay(Box::new(io::Cursor::new(data))).unwrap();
+        assert_eq!(start, 100);
+        assert!(journal.is_replaying());
+        let replayed = run(&mut journal, start, &mut |_| panic!("the host was asked for input"));
+        assert_eq!(replayed, recorded);
+    }
+
+    #[test]
+    #[should_panic(expected = "replay diverged at instruction 101: the recording has Time input at instruction 100")]
+    fn it_stops_when_a_replay_diverges() {
+        let (_, data) = record(100);
+        let (mut journal, _) = Journal::replay(Box::new(io::Cursor::new(data))).unwrap();
+        // The time recorded at instruction 100 is never asked for.
+        for clock in 100..110 {
+            journal.set_clock(clock);
+        }
+    }
+
+    #[test]
+    fn it_replays_a_rewound_tape() {
+        // The tape starts after instruction 0, so the first input is taken at instruction 1.
+        let mut journal = Journal::tape(0);
+        let mut host = |clock: u64| (clock % 7 == 3).then_some(clock as u8);
+        let recorded = run(&mut journal, 1, &mut host);
+
+        // Rewinding into the middle replays the rest of the run, then goes back to the host.
+        journal.rewind(24);
+        let replayed = run(&mut journal, 25, &mut |clock| {
+            assert!(clock > 50, "the host was asked for input at {}", clock);
+            host(clock)
+        });
+        let expected: Vec<(u64, u64)> = recorded.iter().copied().filter(|(c, _)| *c >= 25).collect();
+        assert_eq!(replayed[..expected.len()], expected);
+        assert!(replayed.contains(&(52, 52)));
+    }
+
+    #[test]
+    fn it_passes_inputs_through_when_live() {
+        let mut journal = Journal::new();
+        let seen = run(&mut journal, 0, &mut |clock| (clock == 5).then_some(9));
+        assert!(seen.contains(&(5, 9)));
+        assert!(seen.contains(&(40, 40_007)));
+    }
+}
@@ -0,0 +1,369 @@
+use std::{borrow::Cow, collections::HashMap, fmt, io::{self, Read, Write}};
+
+use miniz_oxide::{deflate::compress_to_vec_zlib, inflate::decompress_to_vec_zlib};
+
+/// The magic number at the start of every snapshot.
+const MAGIC: &[u8; 8] = b"RVSNAPSH";
+
+/// The version of the snapshot format. Snapshots from other versions are rejected rather than
+/// misread, so this must be bumped whenever the layout of any section changes.
+///
+/// After the magic number and the version come named sections: the CPU's registers and CSRs, the
+/// MMU, the ROM, DRAM's allocated pages, the PLIC and the CLINT, and sections of their own for
+/// the devices on the bus.
+pub const VERSION: u32 = 1;
+
+/// The granularity at which memory is saved. Pages which are entirely zero are left out.
+pub const PAGE_SIZE: usize = 4096;
+
+const ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// Indicates if a page of memory holds any data. Comparing against a zero page is much faster
+/// than checking each byte, which matters when scanning gigabytes of memory.
+pub fn is_zero_page(page: &[u8]) -> bool {
+    page == &ZERO_PAGE[..page.len()]
+}
+
+/// The zlib compression level for memory, which favours speed over size.
+const COMPRESSION_LEVEL: u8 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    /// The snapshot was written by a different version of the format.
+    Version(u32),
+    /// A section which the machine needs is not in the snapshot.
+    MissingSection(String),
+    /// A section is in the snapshot, but no part of the machine restored it.
+    UnknownSection(String),
+    /// A section ended early, or holds values which don't fit the machine.
+    Corrupt(String),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::BadMagic => write!(f, "not a snapshot"),
+            Self::Version(v) => write!(f, "snapshot is version {}, expected version {}", v, VERSION),
+            Self::MissingSection(name) => write!(f, "snapshot has no '{}' section", name),
+            Self::UnknownSection(name) => write!(f, "snapshot has an unknown '{}' section", name),
+            Self::Corrupt(name) => write!(f, "the '{}' section of the snapshot is corrupt", name),
+        }
+    }
+}
+
+/// Models a part of the machine whose state can be saved to, and restored from, a snapshot.
+/// Each part stores its state in one or more named sections.
+pub trait Snapshotable {
+    /// Saves the state into sections of the snapshot.
+    fn save(&self, snapshot: &mut SnapshotWriter);
+
+    /// Restores the state from the sections which `save` wrote.
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError>;
+}
+
+/// Saves the state of the whole machine to a snapshot file.
+pub fn save(machine: &impl Snapshotable, out: impl Write) -> io::Result<()> {
+    let mut snapshot = SnapshotWriter::new();
+    machine.save(&mut snapshot);
+    snapshot.write_to(out)
+}
+
+/// Restores the state of the whole machine from a snapshot file. The machine is left partly
+/// restored if the snapshot is corrupt.
+pub fn restore(machine: &mut impl Snapshotable, input: impl Read) -> Result<(), SnapshotError> {
+    let mut snapshot = SnapshotReader::read_from(input)?;
+    machine.restore(&mut snapshot)?;
+    snapshot.finish()
+}
+
+/// Collects the sections of a snapshot, and writes them out as a file:
+///
+/// - the magic number `RVSNAPSH` and the version as a little-endian u32,
+/// - for each section, the length of its name as a byte, the name, the length of its contents
+///   as a little-endian u64 and the contents.
+#[derive(Default)]
+pub struct SnapshotWriter {
+    sections: Vec<(String, Encoder)>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new section, returning the encoder for its contents.
+    pub fn section(&mut self, name: &str) -> &mut Encoder {
+        assert!(self.sections.iter().all(|(n, _)| n != name), "duplicate section '{}'", name);
+        self.sections.push((name.to_string(), Encoder::default()));
+        &mut self.sections.last_mut().unwrap().1
+    }
+
+    pub fn write_to(&self, mut out: impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        for (name, section) in self.sections.iter() {
+            out.write_all(&[name.len() as u8])?;
+            out.write_all(name.as_bytes())?;
+            out.write_all(&(section.buf.len() as u64).to_le_bytes())?;
+            out.write_all(&section.buf)?;
+        }
+        out.flush()
+    }
+}
+
+/// Holds the sections of a snapshot which has been read from a file.
+pub struct SnapshotReader {
+    sections: HashMap<String, Vec<u8>>,
+}
+
+impl SnapshotReader {
+    pub fn read_from(mut input: impl Read) -> Result<Self, SnapshotError> {
+        let mut data = vec![];
+        input.read_to_end(&mut data)?;
+
+        let mut header = Decoder::new("header", &data);
+        if header.bytes(MAGIC.len()).map_err(|_| SnapshotError::BadMagic)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = header.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+
+        let mut sections = HashMap::new();
+        while !header.is_empty() {
+            let len = header.u8()? as usize;
+            let name = String::from_utf8_lossy(header.bytes(len)?).into_owned();
+            let len = header.u64()? as usize;
+            sections.insert(name, header.bytes(len)?.to_vec());
+        }
+        Ok(Self { sections })
+    }
+
+    /// Takes a section out of the snapshot, returning a decoder for its contents.
+    pub fn section(&mut self, name: &str) -> Result<Decoder<'static>, SnapshotError> {
+        let data = self.sections.remove(name)
+            .ok_or_else(|| SnapshotError::MissingSection(name.to_string()))?;
+        Ok(Decoder::owned(name, data))
+    }
+
+    /// Checks that every section has been restored, so that state isn't silently dropped.
+    pub fn finish(self) -> Result<(), SnapshotError> {
+        match self.sections.into_keys().next() {
+            Some(name) => Err(SnapshotError::UnknownSection(name)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Builds the contents of a section out of little-endian values.
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend(value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend(value.to_le_bytes());
+        self
+    }
+
+    /// Writes a length-prefixed block of bytes.
+    pub fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.u64(data.len() as u64);
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// Writes a region of memory, leaving out the pages which are entirely zero and compressing
+    /// the rest.
+    pub fn pages<'a>(&mut self, size: usize, pages: impl Iterator<Item = (usize, &'a [u8])>) -> &mut Self {
+        let mut indices = vec![];
+        let mut contents = vec![];
+        for (index, page) in pages.filter(|(_, page)| !is_zero_page(page)) {
+            indices.push(index as u64);
+            contents.extend_from_slice(page);
+            contents.resize(indices.len() * PAGE_SIZE, 0);
+        }
+
+        self.u64(size as u64).u64(indices.len() as u64);
+        for index in indices {
+            self.u64(index);
+        }
+        self.bytes(&compress_to_vec_zlib(&contents, COMPRESSION_LEVEL))
+    }
+}
+
+/// Reads the contents of a section back out.
+pub struct Decoder<'a> {
+    name: String,
+    data: Cow<'a, [u8]>,
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(name: &str, data: &'a [u8]) -> Self {
+        Self { name: name.to_string(), data: data.into(), pos: 0 }
+    }
+
+    fn owned(name: &str, data: Vec<u8>) -> Self {
+        Self { name: name.to_string(), data: data.into(), pos: 0 }
+    }
+
+    /// Creates the error for a section whose contents don't make sense.
+    pub fn corrupt(&self) -> SnapshotError {
+        SnapshotError::Corrupt(self.name.clone())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], SnapshotError> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len());
+        let end = end.ok_or_else(|| self.corrupt())?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a length-prefixed block of bytes.
+    pub fn block(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.u64()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    /// Reads a region of memory written by `Encoder::pages`, passing each saved page to `write`.
+    /// The region must be the same size as the memory it is restored into, and the caller is
+    /// responsible for zeroing the pages which were left out.
+    pub fn pages(&mut self, size: usize, mut write: impl FnMut(usize, &[u8])) -> Result<(), SnapshotError> {
+        if self.u64()? != size as u64 {
+            return Err(self.corrupt());
+        }
+        let count = self.u64()? as usize;
+        let indices = (0..count).map(|_| self.u64()).collect::<Result<Vec<_>, _>>()?;
+        let contents = decompress_to_vec_zlib(&self.block()?).map_err(|_| self.corrupt())?;
+        if contents.len() != count * PAGE_SIZE {
+            return Err(self.corrupt());
+        }
+
+        for (index, page) in indices.into_iter().zip(contents.chunks(PAGE_SIZE)) {
+            let index = index as usize;
+            if index >= size.div_ceil(PAGE_SIZE) {
+                return Err(self.corrupt());
+            }
+            let len = PAGE_SIZE.min(size - index * PAGE_SIZE);
+            write(index, &page[..len]);
+        }
+        Ok(())
+    }
+
+    /// Restores a region of memory written by `Encoder::pages`, zeroing the pages which were left
+    /// out. Pages which are already zero aren't touched, so that restoring into a fresh machine
+    /// doesn't commit memory the program never used.
+    pub fn restore_pages(&mut self, memory: &mut [u8]) -> Result<(), SnapshotError> {
+        let mut saved = vec![false; memory.len().div_ceil(PAGE_SIZE)];
+        self.pages(memory.len(), |index, page| {
+            memory[index * PAGE_SIZE..][..page.len()].copy_from_slice(page);
+            saved[index] = true;
+        })?;
+
+        for (page, _) in memory.chunks_mut(PAGE_SIZE).zip(saved).filter(|(_, saved)| !saved) {
+            if !is_zero_page(page) {
+                page.fill(0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the whole section was read.
+    pub fn finish(self) -> Result<(), SnapshotError> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(self.corrupt()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SnapshotError, SnapshotReader, SnapshotWriter, PAGE_SIZE, VERSION};
+
+    #[test]
+    fn it_round_trips_sections() {
+        let mut memory = vec![0u8; 4 * PAGE_SIZE + 100];
+        memory[5] = 0xaa;
+        memory[4 * PAGE_SIZE + 99] = 0xbb;
+
+        let mut writer = SnapshotWriter::new();
+        writer.section("regs").u8(3).u32(0x1234_5678).u64(u64::MAX);
+        writer.section("mem").pages(memory.len(), memory.chunks(PAGE_SIZE).enumerate());
+        let mut file = vec![];
+        writer.write_to(&mut file).unwrap();
+        // Only the two pages which hold data are saved, and they compress well.
+        assert!(file.len() < 200);
+
+        let mut reader = SnapshotReader::read_from(file.as_slice()).unwrap();
+        let mut regs = reader.section("regs").unwrap();
+        assert_eq!((regs.u8().unwrap(), regs.u32().unwrap(), regs.u64().unwrap()), (3, 0x1234_5678, u64::MAX));
+        regs.finish().unwrap();
+
+        // Pages which were zero when the snapshot was taken are cleared.
+        let mut restored = vec![0x55u8; memory.len()];
+        reader.section("mem").unwrap().restore_pages(&mut restored).unwrap();
+        assert_eq!(restored, memory);
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn it_rejects_bad_snapshots() {
+        let mut file = vec![];
+        SnapshotWriter::new().write_to(&mut file).unwrap();
+        file[8] = VERSION as u8 + 1;
+        assert!(matches!(SnapshotReader::read_from(file.as_slice()), Err(SnapshotError::Version(v)) if v == VERSION + 1));
+        assert!(matches!(SnapshotReader::read_from(&b"RVTRACE1"[..]), Err(SnapshotError::BadMagic)));
+
+        let mut writer = SnapshotWriter::new();
+        writer.section("regs").u32(1);
+        let mut file = vec![];
+        writer.write_to(&mut file).unwrap();
+
+        let mut reader = SnapshotReader::read_from(file.as_slice()).unwrap();
+        assert!(matches!(reader.section("mem"), Err(SnapshotError::MissingSection(_))));
+        assert!(reader.section("regs").unwrap().u64().is_err());
+
+        let reader = SnapshotReader::read_from(file.as_slice()).unwrap();
+        assert!(matches!(reader.finish(), Err(SnapshotError::UnknownSection(_))));
+    }
+}
@@ -0,0 +1,369 @@
+use std::{ffi::{OsStr, OsString}, fs::{self, File, Metadata, OpenOptions, Permissions, ReadDir}, io::{self, Read, Seek, SeekFrom, Write}, ops::Deref, os::{fd::AsRawFd, unix::{ffi::OsStringExt, fs::{MetadataExt, OpenOptionsExt, PermissionsExt}}}, path::{Component, Path, PathBuf}};
+
+use crate::{components::CPU, replay::Source};
+
+use super::{errno::*, store};
+
+/// The most bytes which a single read or write transfers.
+pub const MAX_IO: u64 = 1024 * 1024;
+
+/// Used in place of a directory descriptor, for paths relative to the working directory.
+pub const AT_FDCWD: u64 = -100i64 as u64;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const O_ACCMODE: u64 = 0o3;
+pub const O_RDONLY: u64 = 0o0;
+pub const O_WRONLY: u64 = 0o1;
+pub const O_RDWR: u64 = 0o2;
+pub const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+
+/// An open file of the program.
+#[derive(Debug)]
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// The files which a program has open on the host, indexed by their file descriptors. The first
+/// three are the host's standard input, output and error.
+///
+/// Paths can be confined to a directory on the host, in which case absolute paths are taken to
+/// be within it, and paths which lead out of it with `..` or through a symbolic link are refused.
+#[derive(Debug)]
+pub struct Files {
+    fds: Vec<Option<Fd>>,
+    root: Option<Root>,
+}
+
+impl Files {
+    pub fn new(root: Option<PathBuf>) -> Self {
+        Self {
+            fds: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            root: root.map(Root::new),
+        }
+    }
+
+    fn fd(&mut self, fd: u64) -> Result<&mut Fd, i64> {
+        self.fds.get_mut(fd as usize).and_then(Option::as_mut).ok_or(EBADF)
+    }
+
+    /// Gets an open file, as opposed to the standard streams.
+    pub fn file(&mut self, fd: u64) -> Result<&mut File, i64> {
+        match self.fd(fd)? {
+            Fd::File(file) => Ok(file),
+            _ => Err(EBADF),
+        }
+    }
+
+    /// Indicates if a file descriptor is one of the standard streams, which are terminals.
+    pub fn is_terminal(&mut self, fd: u64) -> Result<bool, i64> {
+        Ok(!matches!(self.fd(fd)?, Fd::File(_)))
+    }
+
+    pub fn read(&mut self, cpu: &mut CPU, fd: u64, buf: u64, count: u64) -> Result<u64, i64> {
+        let fd = self.fd(fd)?;
+        let data = host_input(cpu, || {
+            let mut data = vec![0; count.min(MAX_IO) as usize];
+            let len = match fd {
+                Fd::Stdin => io::stdin().read(&mut data),
+                Fd::File(file) => file.read(&mut data),
+                _ => return Err(EBADF),
+            };
+            data.truncate(len.map_err(host_errno)?);
+            Ok(data)
+        })?;
+        store(cpu, buf, &data)?;
+        Ok(data.len() as u64)
+    }
+
+    pub fn write(&mut self, cpu: &mut CPU, fd: u64, buf: u64, count: u64) -> Result<u64, i64> {
+        let data = cpu.mmu().load_bytes(buf, count.min(MAX_IO)).map_err(|_| EFAULT)?;
+        let written = match self.fd(fd)? {
+            Fd::Stdout => io::stdout().write_all(&data).and_then(|_| io::stdout().flush()).map(|_| data.len()),
+            Fd::Stderr => io::stderr().write_all(&data).map(|_| data.len()),
+            Fd::File(file) => file.write(&data),
+            Fd::Stdin => return Err(EBADF),
+        };
+        written.map(|len| len as u64).map_err(host_errno)
+    }
+
+    /// Opens a file, returning the lowest free file descriptor.
+    pub fn openat(&mut self, cpu: &mut CPU, dirfd: u64, path: u64, flags: u64, mode: u64) -> Result<u64, i64> {
+        let path = self.resolve(cpu, dirfd, path)?;
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_RDONLY => options.read(true),
+            O_WRONLY => options.write(true),
+            _ => options.read(true).write(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .mode(mode as u32);
+        match (flags & O_CREAT != 0, flags & O_EXCL != 0) {
+            (true, true) => options.create_new(true),
+            (true, false) => options.create(true),
+            _ => &mut options,
+        };
+        let file = match &self.root {
+            Some(root) => root.open(&path, &mut options),
+            None => options.open(path),
+        };
+
+        let fd = Some(Fd::File(file.map_err(host_errno)?));
+        match self.fds.iter().position(Option::is_none) {
+            Some(index) => {
+                self.fds[index] = fd;
+                Ok(index as u64)
+            },
+            None => {
+                self.fds.push(fd);
+                Ok(self.fds.len() as u64 - 1)
+            },
+        }
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<u64, i64> {
+        match self.fds.get_mut(fd as usize).and_then(Option::take) {
+            Some(_) => Ok(0),
+            None => Err(EBADF),
+        }
+    }
+
+    pub fn lseek(&mut self, fd: u64, offset: u64, whence: u64) -> Result<u64, i64> {
+        let Fd::File(file) = self.fd(fd)? else { return Err(ESPIPE) };
+        let pos = match whence {
+            0 => SeekFrom::Start(offset),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL),
+        };
+        file.seek(pos).map_err(host_errno)
+    }
+
+    /// Stores a `struct stat` for an open file.
+    pub fn fstat(&mut self, cpu: &mut CPU, fd: u64, buf: u64) -> Result<u64, i64> {
+        let stat = match self.fd(fd)? {
+            Fd::File(file) => stat(Some(&file.metadata().map_err(host_errno)?)),
+            _ => stat(None),
+        };
+        store(cpu, buf, &stat)
+    }
+
+    /// Stores a `struct stat` for a path, or for an open file if the path is empty and
+    /// `AT_EMPTY_PATH` is given.
+    pub fn fstatat(&mut self, cpu: &mut CPU, dirfd: u64, path: u64, buf: u64, flags: u64) -> Result<u64, i64> {
+        if flags & AT_EMPTY_PATH != 0 && cpu.mmu().load_cstr(path).map_err(|_| EFAULT)?.is_empty() {
+            return self.fstat(cpu, dirfd, buf);
+        }
+        let path = self.resolve(cpu, dirfd, path)?;
+        let meta = match (&self.root, flags & AT_SYMLINK_NOFOLLOW) {
+            // Links aren't followed within the root, so only the link itself can be described.
+            (Some(root), 0) => root.metadata(&path).and_then(|meta| match meta.is_symlink() {
+                true => Err(io::Error::from_raw_os_error(libc::ELOOP)),
+                false => Ok(meta),
+            }),
+            (Some(root), _) => root.metadata(&path),
+            (None, 0) => fs::metadata(path),
+            (None, _) => fs::symlink_metadata(path),
+        };
+        store(cpu, buf, &stat(Some(&meta.map_err(host_errno)?)))
+    }
+
+    /// Reads a path from the program's memory. It's taken to be within the root, if there is
+    /// one, and only paths relative to the working directory are supported.
+    fn resolve(&self, cpu: &mut CPU, dirfd: u64, addr: u64) -> Result<PathBuf, i64> {
+        let bytes = cpu.mmu().load_cstr(addr).map_err(|_| EFAULT)?;
+        let path = PathBuf::from(OsString::from_vec(bytes));
+        if path.is_relative() && dirfd != AT_FDCWD {
+            return Err(EBADF);
+        }
+        Ok(path)
+    }
+}
+
+/// A directory on the host which paths are confined to. Each directory on the way to a file is
+/// opened in turn, from the one before it, with `O_NOFOLLOW`, so neither `..` nor a symbolic link
+/// can lead out of it, as QEMU's passthrough file system does since CVE-2016-9602.
+///
+/// The last component is named within its open directory through `/proc/self/fd`, so the usual
+/// file system functions can act on it. Those which follow links in the last component mustn't
+/// be used on it, and [`Root::open`], [`Root::read_dir`] and [`Root::set_mode`] are there instead.
+#[derive(Debug, Clone)]
+pub struct Root {
+    path: PathBuf,
+}
+
+/// A path to a file under a root, which keeps the directory it's in open.
+#[derive(Debug)]
+pub struct HostPath {
+    _dir: File,
+    path: PathBuf,
+}
+
+impl Deref for HostPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Root {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Finds a path under the root on the host. Absolute paths are taken to be within the root,
+    /// and `..` is refused, as are links on the way to the last component.
+    pub fn resolve(&self, path: &Path) -> io::Result<HostPath> {
+        let mut names = vec![];
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::ParentDir => return Err(io::Error::from_raw_os_error(libc::EACCES)),
+                _ => {},
+            }
+        }
+        let last = names.pop().unwrap_or(OsStr::new("."));
+
+        let mut dir = OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_DIRECTORY).open(&self.path)?;
+        for name in names {
+            dir = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW)
+                .open(fd_path(&dir).join(name))?;
+        }
+        let path = fd_path(&dir).join(last);
+        Ok(HostPath { _dir: dir, path })
+    }
+
+    /// Opens a file under the root, which mustn't be a link.
+    pub fn open(&self, path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+        options.custom_flags(libc::O_NOFOLLOW).open(&*self.resolve(path)?)
+    }
+
+    /// Gets the metadata of a file under the root, or of the link if it's a link.
+    pub fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(&*self.resolve(path)?)
+    }
+
+    /// Lists a directory under the root, which mustn't be a link.
+    pub fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        let dir = self.open(path, OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY))?;
+        fs::read_dir(fd_path(&dir))
+    }
+
+    /// Changes the permissions of a file under the root. Links don't have permissions of their
+    /// own, so they're refused, as Linux does.
+    pub fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let file = OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_NOFOLLOW).open(&*self.resolve(path)?)?;
+        if file.metadata()?.is_symlink() {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        fs::set_permissions(fd_path(&file), Permissions::from_mode(mode))
+    }
+}
+
+/// Names an open file through `/proc`, which leads to the file itself whatever its path is now.
+fn fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+/// Takes input from the host through the journal, so that a replay sees the same data. The
+/// result of the read is recorded along with the data, as a 0 followed by the data, or a 1
+/// followed by the error number.
+pub fn host_input(cpu: &mut CPU, live: impl FnOnce() -> Result<Vec<u8>, i64>) -> Result<Vec<u8>, i64> {
+    let data = cpu.mmu().bus().journal().input(Source::File, || match live() {
+        Ok(data) => [&[0], data.as_slice()].concat(),
+        Err(errno) => [&[1], errno.to_le_bytes().as_slice()].concat(),
+    });
+    match data.split_first() {
+        Some((0, data)) => Ok(data.to_vec()),
+        Some((_, errno)) => Err(errno.try_into().map_or(EIO, i64::from_le_bytes)),
+        None => Err(EIO),
+    }
+}
+
+/// Converts a host error into a Linux error number. The host is Linux as well, so the numbers
+/// are the same.
+pub fn host_errno(e: io::Error) -> i64 {
+    e.raw_os_error().map_or(EINVAL, i64::from)
+}
+
+/// Encodes a `struct stat` for a host file, or for a terminal if there is no file.
+fn stat(meta: Option<&Metadata>) -> [u8; 128] {
+    let mut stat = [0; 128];
+    let mut put = |offset: usize, size: usize, value: u64| {
+        stat[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    };
+    match meta {
+        Some(meta) => {
+            put(0, 8, meta.dev());
+            put(8, 8, meta.ino());
+            put(16, 4, meta.mode() as u64);
+            put(20, 4, meta.nlink());
+            put(24, 4, meta.uid() as u64);
+            put(28, 4, meta.gid() as u64);
+            put(32, 8, meta.rdev());
+            put(48, 8, meta.size());
+            put(56, 4, meta.blksize());
+            put(64, 8, meta.blocks());
+            put(72, 8, meta.atime() as u64);
+            put(80, 8, meta.atime_nsec() as u64);
+            put(88, 8, meta.mtime() as u64);
+            put(96, 8, meta.mtime_nsec() as u64);
+            put(104, 8, meta.ctime() as u64);
+            put(112, 8, meta.ctime_nsec() as u64);
+        },
+        None => {
+            // A character device which can be read and written by its owner.
+            put(16, 4, 0o20620);
+            put(20, 4, 1);
+            put(32, 8, 0x8800);
+            put(56, 4, 1024);
+        },
+    }
+    stat
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::{self, OpenOptions}, os::unix::fs::symlink, path::Path};
+
+    use super::Root;
+
+    #[test]
+    fn it_keeps_paths_within_the_root() {
+        let dir = std::env::temp_dir().join(format!("rv64-root-test-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(dir.join("secret"), "outside").unwrap();
+        fs::write(root.join("sub/file"), "inside").unwrap();
+        symlink(&dir, root.join("escape")).unwrap();
+        symlink("../secret", root.join("sub/secret")).unwrap();
+        let root = Root::new(root);
+
+        let read = |path: &str| fs::read_to_string(&*root.resolve(Path::new(path))?);
+        assert_eq!(read("/sub/file").unwrap(), "inside");
+        assert_eq!(read("sub/./file").unwrap(), "inside");
+        assert!(read("sub/../../secret").is_err());
+        // Neither a link on the way nor the last component is followed out of the root.
+        assert!(read("escape/secret").is_err());
+        assert!(root.open(Path::new("sub/secret"), OpenOptions::new().read(true)).is_err());
+        assert!(root.metadata(Path::new("sub/secret")).unwrap().is_symlink());
+        assert!(root.read_dir(Path::new("escape")).is_err());
+        assert!(root.set_mode(Path::new("sub/secret"), 0o777).is_err());
+        assert_eq!(fs::metadata(dir.join("secret")).unwrap().len(), 7);
+
+        assert_eq!(root.read_dir(Path::new("")).unwrap().count(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
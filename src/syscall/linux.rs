@@ -0,0 +1,473 @@
+use std::{os::unix::fs::FileExt, time::Instant};
+
+use crate::{
+    components::{cpu::{PrivilegeMode, Trap}, memory::{Size, MMU}, CPU},
+    elf::{Elf, ElfError, ET_DYN, ET_EXEC, PT_INTERP},
+    loader::{load_segments, LoadError, STACK_SIZE},
+    replay::Source,
+    snapshot::PAGE_SIZE,
+    util::host_random,
+};
+
+use super::{args, errno::*, files::{host_input, host_errno, Files, MAX_IO}, realtime_nanos, store, timespec, Outcome, SyscallHandler};
+
+const PAGE: u64 = PAGE_SIZE as u64;
+/// The address which position independent executables are loaded at.
+const PIE_BASE: u64 = 0x10_0000;
+/// The process ID which the program sees.
+const PID: u64 = 1;
+/// The extensions which the hart supports, one bit per letter, as reported by `AT_HWCAP`.
+const HWCAP: u64 = 1 << (b'I' - b'A') | 1 << (b'M' - b'A');
+
+/// The auxiliary vector entries which are passed to a new process.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+
+/// The system call numbers of riscv64 Linux.
+mod nr {
+    pub const IOCTL: u64 = 29;
+    pub const OPENAT: u64 = 56;
+    pub const CLOSE: u64 = 57;
+    pub const LSEEK: u64 = 62;
+    pub const READ: u64 = 63;
+    pub const WRITE: u64 = 64;
+    pub const READV: u64 = 65;
+    pub const WRITEV: u64 = 66;
+    pub const NEWFSTATAT: u64 = 79;
+    pub const FSTAT: u64 = 80;
+    pub const EXIT: u64 = 93;
+    pub const EXIT_GROUP: u64 = 94;
+    pub const SET_TID_ADDRESS: u64 = 96;
+    pub const SET_ROBUST_LIST: u64 = 99;
+    pub const CLOCK_GETTIME: u64 = 113;
+    pub const SIGALTSTACK: u64 = 132;
+    pub const RT_SIGACTION: u64 = 134;
+    pub const RT_SIGPROCMASK: u64 = 135;
+    pub const UNAME: u64 = 160;
+    pub const GETTIMEOFDAY: u64 = 169;
+    pub const GETPID: u64 = 172;
+    pub const GETPPID: u64 = 173;
+    pub const GETUID: u64 = 174;
+    pub const GETEUID: u64 = 175;
+    pub const GETGID: u64 = 176;
+    pub const GETEGID: u64 = 177;
+    pub const GETTID: u64 = 178;
+    pub const BRK: u64 = 214;
+    pub const MUNMAP: u64 = 215;
+    pub const MMAP: u64 = 222;
+    pub const MPROTECT: u64 = 226;
+    pub const MADVISE: u64 = 233;
+    pub const GETRANDOM: u64 = 278;
+}
+
+/// Runs a statically linked Linux program without a kernel, in the style of `qemu-user`. The
+/// program runs in user mode, and its system calls are serviced on the host.
+///
+/// The process's address space is backed by DRAM, from the first page up to the size of DRAM.
+/// The program is loaded at the addresses it was linked at, the heap grows up from the end of it,
+/// the stack sits at the top of the address space, and memory mappings grow down from below the
+/// stack.
+///
+/// Inputs from the host, such as the time and data read from files, go through the journal, so
+/// that a run can be replayed. The open files and the bounds of the heap aren't saved in
+/// snapshots.
+#[derive(Debug)]
+pub struct Linux {
+    files: Files,
+    brk_start: u64,
+    brk: u64,
+    /// The highest which the heap has reached, above which memory has never been used.
+    brk_high: u64,
+    /// The top of the memory mappings, just below the stack.
+    mmap_base: u64,
+    /// The lowest address which is mapped, which the next mapping goes below.
+    mmap_top: u64,
+    /// The lowest which `mmap_top` has reached, below which memory has never been used.
+    mmap_low: u64,
+    /// When the process started, for the monotonic clock.
+    start: Instant,
+}
+
+impl Linux {
+    /// Loads a statically linked executable into the machine as a new process, with its
+    /// arguments and environment on the stack, and switches the CPU to user mode at its entry
+    /// point.
+    pub fn load(cpu: &mut CPU, data: &[u8], args: &[String], env: &[String]) -> Result<Self, LoadError> {
+        let elf = Elf::parse(data)?;
+        let bias = match elf.kind {
+            ET_EXEC => 0,
+            ET_DYN => PIE_BASE,
+            _ => return Err(LoadError::Elf(ElfError::Unsupported)),
+        };
+        if elf.segments.iter().any(|s| s.kind == PT_INTERP) {
+            return Err(LoadError::Dynamic);
+        }
+
+        cpu.mmu().map_user_space();
+        cpu.set_privilege_mode(PrivilegeMode::User);
+        let mmu = cpu.mmu();
+        let size = mmu.user_space().unwrap();
+        let stack = size - STACK_SIZE;
+
+        let end = load_segments(mmu, &elf, bias, PAGE..stack)?;
+
+        // The C library seeds its stack protector from the bytes at `AT_RANDOM`. They are fixed,
+        // so that runs are reproducible.
+        let random = size - 16;
+        mmu.store_bytes(random, b"rv64-emulator-rn").map_err(|_| LoadError::TooLarge)?;
+        let phnum = elf.segments.len() as u64;
+        let auxv = [
+            (AT_PHDR, elf.phdr_addr().map_or(0, |addr| addr.wrapping_add(bias))),
+            (AT_PHENT, 56),
+            (AT_PHNUM, phnum),
+            (AT_PAGESZ, PAGE),
+            (AT_BASE, 0),
+            (AT_ENTRY, elf.entry.wrapping_add(bias)),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, HWCAP),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+        ];
+        let sp = initial_stack(mmu, random, args, env, &auxv).map_err(|_| LoadError::TooLarge)?;
+        if sp < stack {
+            return Err(LoadError::TooLarge);
+        }
+
+        cpu.set_xreg(2, sp);
+        cpu.set_pc(elf.entry.wrapping_add(bias));
+
+        Ok(Self {
+            files: Files::new(None),
+            brk_start: end,
+            brk: end,
+            brk_high: end,
+            mmap_base: stack,
+            mmap_top: stack,
+            mmap_low: stack,
+            start: Instant::now(),
+        })
+    }
+
+    /// Services a system call, returning its result or an error number.
+    fn syscall(&mut self, cpu: &mut CPU, num: u64, args: [u64; 6]) -> Result<u64, i64> {
+        let [a0, a1, a2, a3, a4, a5] = args;
+        match num {
+            nr::READ => self.files.read(cpu, a0, a1, a2),
+            nr::WRITE => self.files.write(cpu, a0, a1, a2),
+            nr::READV | nr::WRITEV => {
+                let mut total = 0;
+                for i in 0..a2 {
+                    let iov = a1.wrapping_add(i * 16);
+                    let base = cpu.mmu().load(iov, Size::DoubleWord).map_err(|_| EFAULT)?;
+                    let len = cpu.mmu().load(iov + 8, Size::DoubleWord).map_err(|_| EFAULT)?;
+                    let done = match num {
+                        nr::READV => self.files.read(cpu, a0, base, len)?,
+                        _ => self.files.write(cpu, a0, base, len)?,
+                    };
+                    total += done;
+                    if done < len {
+                        break;
+                    }
+                }
+                Ok(total)
+            },
+            nr::OPENAT => self.files.openat(cpu, a0, a1, a2, a3),
+            nr::CLOSE => self.files.close(a0),
+            nr::LSEEK => self.files.lseek(a0, a1, a2),
+            nr::FSTAT => self.files.fstat(cpu, a0, a1),
+            nr::NEWFSTATAT => self.files.fstatat(cpu, a0, a1, a2, a3),
+            nr::BRK => Ok(self.brk(cpu, a0)),
+            nr::MMAP => self.mmap(cpu, a0, a1, a3, a4, a5),
+            // Only the most recent mapping is given back, to be reused by the next one.
+            nr::MUNMAP => {
+                if a0 == self.mmap_top {
+                    self.mmap_top = a0.saturating_add(a1.next_multiple_of(PAGE)).min(self.mmap_base);
+                }
+                Ok(0)
+            },
+            nr::CLOCK_GETTIME => {
+                let nanos = cpu.mmu().bus().journal().input_u64(Source::Time, || match a0 {
+                    CLOCK_REALTIME | CLOCK_REALTIME_COARSE => realtime_nanos(),
+                    _ => self.start.elapsed().as_nanos() as u64,
+                });
+                store(cpu, a1, &timespec(nanos / 1_000_000_000, nanos % 1_000_000_000))
+            },
+            nr::GETTIMEOFDAY => {
+                let nanos = cpu.mmu().bus().journal().input_u64(Source::Time, realtime_nanos);
+                match a0 {
+                    0 => Ok(0),
+                    tv => store(cpu, tv, &timespec(nanos / 1_000_000_000, nanos % 1_000_000_000 / 1000)),
+                }
+            },
+            nr::GETRANDOM => {
+                let len = a1.min(MAX_IO);
+                let data = cpu.mmu().bus().journal().input(Source::Random, || host_random(len as usize));
+                store(cpu, a0, &data)?;
+                Ok(data.len() as u64)
+            },
+            nr::UNAME => {
+                let fields = ["Linux", "emulator", "6.1.0", "#1", "riscv64", ""];
+                let mut utsname = vec![0; 65 * fields.len()];
+                for (i, field) in fields.iter().enumerate() {
+                    utsname[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                store(cpu, a0, &utsname)
+            },
+            // No terminal control is emulated, so the C library treats every file as a file.
+            nr::IOCTL => Err(ENOTTY),
+            nr::SET_TID_ADDRESS | nr::GETPID | nr::GETTID => Ok(PID),
+            nr::GETPPID | nr::GETUID | nr::GETEUID | nr::GETGID | nr::GETEGID => Ok(0),
+            // There are no signals or threads, and all memory is accessible, so these succeed
+            // without doing anything.
+            nr::SET_ROBUST_LIST | nr::RT_SIGACTION | nr::RT_SIGPROCMASK | nr::SIGALTSTACK
+                | nr::MPROTECT | nr::MADVISE => Ok(0),
+            _ => Err(ENOSYS),
+        }
+    }
+
+    /// Moves the end of the heap, returning where it ends. The heap can't shrink below where it
+    /// started or grow into the memory mappings, and is left alone if asked to.
+    fn brk(&mut self, cpu: &mut CPU, addr: u64) -> u64 {
+        if addr < self.brk_start || addr > self.mmap_low {
+            return self.brk;
+        }
+        // Memory which the heap gave back has to be zeroed when it grows over it again.
+        if addr > self.brk && self.brk < self.brk_high {
+            let end = addr.min(self.brk_high);
+            if zero(cpu, self.brk, end - self.brk).is_err() {
+                return self.brk;
+            }
+        }
+        self.brk = addr;
+        self.brk_high = self.brk_high.max(addr);
+        self.brk
+    }
+
+    fn mmap(&mut self, cpu: &mut CPU, addr: u64, len: u64, flags: u64, fd: u64, offset: u64) -> Result<u64, i64> {
+        if len == 0 || !addr.is_multiple_of(PAGE) && flags & MAP_FIXED != 0 {
+            return Err(EINVAL);
+        }
+        let len = len.checked_next_multiple_of(PAGE).ok_or(ENOMEM)?;
+
+        let start = if flags & MAP_FIXED != 0 {
+            zero(cpu, addr, len).map_err(|_| ENOMEM)?;
+            addr
+        } else {
+            let start = self.mmap_top.checked_sub(len).filter(|&s| s >= self.brk).ok_or(ENOMEM)?;
+            // Only the memory which was mapped before needs to be zeroed.
+            let used = start.max(self.mmap_low);
+            if used < self.mmap_top {
+                zero(cpu, used, self.mmap_top - used).map_err(|_| ENOMEM)?;
+            }
+            self.mmap_top = start;
+            self.mmap_low = self.mmap_low.min(start);
+            start
+        };
+
+        // Files are mapped privately, by copying them in.
+        if flags & MAP_ANONYMOUS == 0 {
+            let file = self.files.file(fd)?;
+            let data = host_input(cpu, || {
+                let mut data = vec![0; len as usize];
+                let read = file.read_at(&mut data, offset).map_err(host_errno)?;
+                data.truncate(read);
+                Ok(data)
+            })?;
+            store(cpu, start, &data)?;
+        }
+        Ok(start)
+    }
+}
+
+impl SyscallHandler for Linux {
+    fn ecall(&mut self, cpu: &mut CPU, trap: &Trap) -> Outcome {
+        if *trap != Trap::EnvironmentCallFromUMode {
+            return Outcome::Unhandled;
+        }
+        match args(cpu) {
+            (nr::EXIT | nr::EXIT_GROUP, [code, ..]) => Outcome::Exit(code),
+            (num, args) => self.syscall(cpu, num, args).into(),
+        }
+    }
+}
+
+/// Lays out the initial stack of a process below `top`, and returns the stack pointer. From the
+/// stack pointer up, it holds `argc`, the `argv` pointers, a null, the `envp` pointers, a null,
+/// and the auxiliary vector ending with `AT_NULL`. The strings go at the top.
+pub fn initial_stack(mmu: &mut MMU, top: u64, args: &[String], env: &[String], auxv: &[(u64, u64)]) -> Result<u64, Trap> {
+    let mut sp = top;
+    let mut push_strings = |strings: &[String]| -> Result<Vec<u64>, Trap> {
+        strings.iter()
+            .map(|s| {
+                sp -= s.len() as u64 + 1;
+                mmu.store_bytes(sp, s.as_bytes())?;
+                mmu.store_u8(sp + s.len() as u64, 0)?;
+                Ok(sp)
+            })
+            .collect()
+    };
+    let argv = push_strings(args)?;
+    let envp = push_strings(env)?;
+
+    let mut words = vec![args.len() as u64];
+    words.extend(argv);
+    words.push(0);
+    words.extend(envp);
+    words.push(0);
+    for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        words.extend([key, value]);
+    }
+
+    // The ABI requires the stack pointer to be 16-byte aligned.
+    let sp = (sp - words.len() as u64 * 8) & !15;
+    for (i, word) in words.iter().enumerate() {
+        mmu.store_u64(sp + i as u64 * 8, *word)?;
+    }
+    Ok(sp)
+}
+
+fn zero(cpu: &mut CPU, addr: u64, len: u64) -> Result<u64, i64> {
+    store(cpu, addr, &vec![0; len as usize])
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        components::{bus::DRAM_BASE, memory::{Size, MMU}, CPU},
+        isa::assemble::assemble,
+    };
+
+    use super::{initial_stack, Linux, AT_PAGESZ};
+
+    /// Wraps code in a statically linked executable with a single segment, which is loaded at
+    /// 0x10000 with the code straight after the headers.
+    fn executable(source: &str) -> Vec<u8> {
+        let entry = 0x10000 + 64 + 56;
+        let code = assemble(source, entry).unwrap();
+        let len = (entry - 0x10000) + code.len() as u64;
+
+        let mut elf = vec![0; 64];
+        elf[0..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        elf[16..20].copy_from_slice(&[2, 0, 243, 0]);
+        elf[24..32].copy_from_slice(&entry.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[54..58].copy_from_slice(&[56, 0, 1, 0]);
+
+        let mut phdr = [0; 56];
+        phdr[0..8].copy_from_slice(&[1, 0, 0, 0, 5, 0, 0, 0]);
+        phdr[16..24].copy_from_slice(&0x10000u64.to_le_bytes());
+        phdr[32..40].copy_from_slice(&len.to_le_bytes());
+        phdr[40..48].copy_from_slice(&len.to_le_bytes());
+        elf.extend(phdr);
+        elf.extend(code);
+        elf
+    }
+
+    #[test]
+    fn it_runs_a_linux_program() {
+        let path = std::env::temp_dir().join(format!("rv64-linux-test-{}", std::process::id()));
+        let args = ["prog", path.to_str().unwrap(), "hello from riscv"].map(String::from);
+
+        // Writes its second argument to the file named by its first, grows the heap, and exits
+        // with argc plus the length of the message plus the growth of the heap.
+        let program = executable("
+                ld s0, 0(sp)
+                ld s1, 16(sp)
+                ld s2, 24(sp)
+                mv a0, s2
+                call strlen
+                mv s3, a0
+
+                li a0, -100
+                mv a1, s1
+                li a2, 0x241
+                li a3, 0x1a4
+                li a7, 56
+                ecall
+                mv s4, a0
+                mv a1, s2
+                mv a2, s3
+                li a7, 64
+                ecall
+                mv a0, s4
+                li a7, 57
+                ecall
+
+                li a0, 0
+                li a7, 214
+                ecall
+                mv s5, a0
+                addi a0, a0, 64
+                li a7, 214
+                ecall
+                sub s5, a0, s5
+
+                add a0, s0, s3
+                add a0, a0, s5
+                li a7, 94
+                ecall
+            strlen:
+                mv t0, a0
+            next:
+                lbu t1, 0(t0)
+                beqz t1, done
+                addi t0, t0, 1
+                j next
+            done:
+                sub a0, t0, a0
+                ret
+        ");
+
+        let mut cpu = CPU::new();
+        let linux = Linux::load(&mut cpu, &program, &args, &["HOME=/".to_string()]).unwrap();
+        cpu.set_syscall_handler(Box::new(linux));
+        assert!(cpu.run_for(10_000));
+
+        let written = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(written.unwrap(), "hello from riscv");
+        assert_eq!(cpu.read_xreg(10), 3 + 16 + 64);
+    }
+
+    #[test]
+    fn it_lays_out_the_initial_stack() {
+        let mut mmu = MMU::new();
+        let top = DRAM_BASE + 0x1000;
+        let args = ["ls", "-l"].map(String::from);
+        let env = ["PATH=/bin".to_string()];
+        let sp = initial_stack(&mut mmu, top, &args, &env, &[(AT_PAGESZ, 4096)]).unwrap();
+        assert_eq!(sp % 16, 0);
+
+        let word = |i: u64| mmu.load(sp + i * 8, Size::DoubleWord).unwrap();
+        let string = |addr: u64| String::from_utf8(mmu.load_cstr(addr).unwrap()).unwrap();
+        assert_eq!(word(0), 2);
+        assert_eq!((string(word(1)), string(word(2)), word(3)), ("ls".into(), "-l".into(), 0));
+        assert_eq!((string(word(4)), word(5)), ("PATH=/bin".into(), 0));
+        assert_eq!((word(6), word(7), word(8), word(9)), (AT_PAGESZ, 4096, 0, 0));
+        assert!(word(1) > sp + 10 * 8 && word(1) < top);
+    }
+}
@@ -0,0 +1,90 @@
+pub mod files;
+pub mod linux;
+pub mod pk;
+pub mod semihosting;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::components::{cpu::Trap, memory::registers::Register, CPU};
+
+pub use self::linux::Linux;
+pub use self::pk::ProxyKernel;
+pub use self::semihosting::Semihosting;
+
+/// Linux error numbers, which system calls return negated in `a0`.
+pub mod errno {
+    pub const ENOENT: i64 = 2;
+    pub const EIO: i64 = 5;
+    pub const EBADF: i64 = 9;
+    pub const ENOMEM: i64 = 12;
+    pub const EACCES: i64 = 13;
+    pub const EFAULT: i64 = 14;
+    pub const EINVAL: i64 = 22;
+    pub const ENOTTY: i64 = 25;
+    pub const ESPIPE: i64 = 29;
+    pub const EROFS: i64 = 30;
+    pub const ENOSYS: i64 = 38;
+    pub const EOPNOTSUPP: i64 = 95;
+}
+
+/// What the CPU does once an environment call has been serviced.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The call returned a value, which is written to `a0`.
+    Return(u64),
+    /// The program exited with a code. The CPU stops as if it had jumped to address 0.
+    Exit(u64),
+    /// The call isn't serviced by the host, so the trap is raised as usual.
+    Unhandled,
+}
+
+impl From<Result<u64, i64>> for Outcome {
+    /// Returns a value, or an error number negated as Linux does.
+    fn from(result: Result<u64, i64>) -> Self {
+        match result {
+            Ok(value) => Self::Return(value),
+            Err(errno) => Self::Return(-errno as u64),
+        }
+    }
+}
+
+/// Services environment calls on the host, in place of an operating system running on the
+/// machine.
+pub trait SyscallHandler {
+    /// Handles an `ecall`. The trap which it would otherwise raise is passed in, which tells the
+    /// privilege mode it was made from.
+    fn ecall(&mut self, cpu: &mut CPU, trap: &Trap) -> Outcome;
+}
+
+/// Reads the system call number and its six arguments, following the Linux calling convention.
+pub fn args(cpu: &CPU) -> (u64, [u64; 6]) {
+    let arg = |reg: Register| cpu.read_xreg(reg as u8);
+    let num = arg(Register::X17);
+    (num, [
+        arg(Register::X10),
+        arg(Register::X11),
+        arg(Register::X12),
+        arg(Register::X13),
+        arg(Register::X14),
+        arg(Register::X15),
+    ])
+}
+
+/// Stores the result of a system call into the program's memory, and returns 0.
+pub fn store(cpu: &mut CPU, addr: u64, data: &[u8]) -> Result<u64, i64> {
+    cpu.mmu().store_bytes(addr, data).map_err(|_| errno::EFAULT)?;
+    Ok(0)
+}
+
+/// Reads the host's wall clock, in nanoseconds since the Unix epoch.
+pub fn realtime_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Encodes a `struct timespec`, or a `struct timeval`, which has the same layout.
+pub fn timespec(secs: u64, fraction: u64) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&secs.to_le_bytes());
+    bytes[8..].copy_from_slice(&fraction.to_le_bytes());
+    bytes
+}
@@ -0,0 +1,177 @@
+use std::{ops::Range, path::PathBuf};
+
+use crate::{components::{cpu::Trap, CPU}, replay::Source};
+
+use super::{args, errno::*, files::{Files, AT_FDCWD}, realtime_nanos, store, timespec, Outcome, SyscallHandler};
+
+/// The system call numbers of the riscv-pk proxy kernel, which follow Linux, along with the
+/// older calls which newlib still makes.
+mod nr {
+    pub const OPENAT: u64 = 56;
+    pub const CLOSE: u64 = 57;
+    pub const LSEEK: u64 = 62;
+    pub const READ: u64 = 63;
+    pub const WRITE: u64 = 64;
+    pub const FSTATAT: u64 = 79;
+    pub const FSTAT: u64 = 80;
+    pub const EXIT: u64 = 93;
+    pub const EXIT_GROUP: u64 = 94;
+    pub const CLOCK_GETTIME: u64 = 113;
+    pub const GETTIMEOFDAY: u64 = 169;
+    pub const BRK: u64 = 214;
+    pub const OPEN: u64 = 1024;
+    pub const STAT: u64 = 1038;
+    pub const LSTAT: u64 = 1039;
+}
+
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+
+/// Services the system calls of bare-metal programs built against newlib, following the ABI of
+/// the riscv-pk proxy kernel, so that programs which use `printf` and `fopen` run without any
+/// device drivers. Calls are made with `ecall` from machine mode, with the number in `a7`.
+///
+/// Files are opened within a directory on the host, which the program can't leave.
+#[derive(Debug)]
+pub struct ProxyKernel {
+    files: Files,
+    /// The memory which the heap can grow into.
+    heap: Range<u64>,
+    brk: u64,
+}
+
+impl ProxyKernel {
+    pub fn new(root: PathBuf, heap: Range<u64>) -> Self {
+        Self {
+            files: Files::new(Some(root)),
+            brk: heap.start,
+            heap,
+        }
+    }
+
+    /// Services a system call, returning its result or an error number.
+    fn syscall(&mut self, cpu: &mut CPU, num: u64, args: [u64; 6]) -> Result<u64, i64> {
+        let [a0, a1, a2, a3, ..] = args;
+        match num {
+            nr::READ => self.files.read(cpu, a0, a1, a2),
+            nr::WRITE => self.files.write(cpu, a0, a1, a2),
+            nr::OPENAT => self.files.openat(cpu, a0, a1, a2, a3),
+            nr::OPEN => self.files.openat(cpu, AT_FDCWD, a0, a1, a2),
+            nr::CLOSE => self.files.close(a0),
+            nr::LSEEK => self.files.lseek(a0, a1, a2),
+            nr::FSTAT => self.files.fstat(cpu, a0, a1),
+            nr::FSTATAT => self.files.fstatat(cpu, a0, a1, a2, a3),
+            nr::STAT => self.files.fstatat(cpu, AT_FDCWD, a0, a1, 0),
+            nr::LSTAT => self.files.fstatat(cpu, AT_FDCWD, a0, a1, AT_SYMLINK_NOFOLLOW),
+            // The heap is left alone if asked to move outside of its memory.
+            nr::BRK => {
+                if self.heap.contains(&a0) || a0 == self.heap.end {
+                    self.brk = a0;
+                }
+                Ok(self.brk)
+            },
+            nr::GETTIMEOFDAY | nr::CLOCK_GETTIME => {
+                let nanos = cpu.mmu().bus().journal().input_u64(Source::Time, realtime_nanos);
+                let (secs, nanos) = (nanos / 1_000_000_000, nanos % 1_000_000_000);
+                match num {
+                    nr::GETTIMEOFDAY => store(cpu, a0, &timespec(secs, nanos / 1000)),
+                    _ => store(cpu, a1, &timespec(secs, nanos)),
+                }
+            },
+            _ => Err(ENOSYS),
+        }
+    }
+}
+
+impl SyscallHandler for ProxyKernel {
+    fn ecall(&mut self, cpu: &mut CPU, trap: &Trap) -> Outcome {
+        if *trap != Trap::EnvironmentCallFromMMode {
+            return Outcome::Unhandled;
+        }
+        match args(cpu) {
+            (nr::EXIT | nr::EXIT_GROUP, [code, ..]) => Outcome::Exit(code),
+            (num, args) => self.syscall(cpu, num, args).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{asm, components::{bus::DRAM_BASE, CPU}};
+
+    use super::ProxyKernel;
+
+    #[test]
+    fn it_services_newlib_system_calls() {
+        let root = std::env::temp_dir().join(format!("rv64-pk-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("in.txt"), "hello pk").unwrap();
+
+        // Copies in.txt to out.txt through a buffer on the heap, tries to open a file outside of
+        // the directory, and exits with the number of bytes copied.
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("
+                li a0, 0
+                li a7, 214
+                ecall
+                mv s0, a0
+                addi a0, s0, 64
+                ecall
+
+                la a0, input
+                li a1, 0
+                li a2, 0
+                li a7, 1024
+                ecall
+                mv s1, a0
+                mv a1, s0
+                li a2, 64
+                li a7, 63
+                ecall
+                mv s2, a0
+                mv a0, s1
+                li a7, 57
+                ecall
+
+                li a0, -100
+                la a1, output
+                li a2, 0x241
+                li a3, 0x1a4
+                li a7, 56
+                ecall
+                mv a1, s0
+                mv a2, s2
+                li a7, 64
+                ecall
+
+                la a0, escape
+                li a1, 0
+                li a2, 0
+                li a7, 1024
+                ecall
+                mv s4, a0
+
+                mv a0, s2
+                li a7, 93
+                ecall
+            input:
+                .byte 0x69, 0x6e, 0x2e, 0x74, 0x78, 0x74, 0
+            output:
+                .byte 0x2f, 0x6f, 0x75, 0x74, 0x2e, 0x74, 0x78, 0x74, 0
+            escape:
+                .byte 0x2e, 0x2e, 0x2f, 0x78, 0
+        "));
+        let heap = DRAM_BASE + 0x1000..DRAM_BASE + 0x2000;
+        cpu.set_syscall_handler(Box::new(ProxyKernel::new(root.clone(), heap)));
+        assert!(cpu.run_for(1000));
+
+        let copied = fs::read_to_string(root.join("out.txt"));
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(copied.unwrap(), "hello pk");
+        assert_eq!(cpu.read_xreg(10), 8);
+        // The heap started at the given address.
+        assert_eq!(cpu.read_xreg(8), DRAM_BASE + 0x1000);
+        assert_eq!(cpu.read_xreg(20), -13i64 as u64);
+    }
+}
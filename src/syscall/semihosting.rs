@@ -0,0 +1,304 @@
+use std::{fs, io::Read, ops::Range, path::PathBuf, time::Instant};
+
+use crate::{components::{memory::Size, CPU}, replay::Source};
+
+use super::{args, errno::*, files::{host_errno, host_input, Files, AT_FDCWD, O_APPEND, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY}, realtime_nanos, store, Outcome};
+
+/// The instruction before the `ebreak` of a semihosting call, `slli x0, x0, 0x1f`.
+pub const ENTRY_NOP: u32 = 0x01f01013;
+/// The instruction after the `ebreak` of a semihosting call, `srai x0, x0, 7`.
+pub const EXIT_NOP: u32 = 0x40705013;
+
+/// The semihosting operation numbers.
+mod op {
+    pub const OPEN: u64 = 0x01;
+    pub const CLOSE: u64 = 0x02;
+    pub const WRITEC: u64 = 0x03;
+    pub const WRITE0: u64 = 0x04;
+    pub const WRITE: u64 = 0x05;
+    pub const READ: u64 = 0x06;
+    pub const READC: u64 = 0x07;
+    pub const ISERROR: u64 = 0x08;
+    pub const ISTTY: u64 = 0x09;
+    pub const SEEK: u64 = 0x0a;
+    pub const FLEN: u64 = 0x0c;
+    pub const REMOVE: u64 = 0x0e;
+    pub const RENAME: u64 = 0x0f;
+    pub const CLOCK: u64 = 0x10;
+    pub const TIME: u64 = 0x11;
+    pub const ERRNO: u64 = 0x13;
+    pub const GET_CMDLINE: u64 = 0x15;
+    pub const HEAPINFO: u64 = 0x16;
+    pub const EXIT: u64 = 0x18;
+    pub const EXIT_EXTENDED: u64 = 0x20;
+    pub const ELAPSED: u64 = 0x30;
+    pub const TICKFREQ: u64 = 0x31;
+}
+
+/// The reason given to `SYS_EXIT` when the program finished normally.
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+/// The frequency of the ticks counted by `SYS_ELAPSED`, which are nanoseconds.
+const TICK_FREQ: u64 = 1_000_000_000;
+
+/// Services ARM-style semihosting calls on the host, as used by embedded toolchains. A call is an
+/// `ebreak` between the `slli x0, x0, 0x1f` and `srai x0, x0, 7` hints, with the operation in
+/// `a0` and a pointer to a block of arguments, each 8 bytes long, in `a1`. The result is
+/// returned in `a0`.
+///
+/// Files are opened on the host, and the special file `:tt` is the host's terminal.
+#[derive(Debug)]
+pub struct Semihosting {
+    files: Files,
+    /// The error number of the last call which failed, for `SYS_ERRNO`.
+    errno: i64,
+    cmdline: String,
+    heap: Range<u64>,
+    stack: Range<u64>,
+    start: Instant,
+}
+
+impl Semihosting {
+    /// Creates a semihosting host for a program with the given command line, and heap and stack
+    /// memory which it reports to `SYS_HEAPINFO`.
+    pub fn new(cmdline: String, heap: Range<u64>, stack: Range<u64>) -> Self {
+        Self {
+            files: Files::new(None),
+            errno: 0,
+            cmdline,
+            heap,
+            stack,
+            start: Instant::now(),
+        }
+    }
+
+    /// Services the call which the CPU is stopped at.
+    pub fn call(&mut self, cpu: &mut CPU) -> Outcome {
+        let (_, [op, block, ..]) = args(cpu);
+        match op {
+            op::EXIT | op::EXIT_EXTENDED => {
+                let reason = block_arg(cpu, block, 0).unwrap_or(0);
+                let code = block_arg(cpu, block, 1).unwrap_or(1);
+                Outcome::Exit(match reason {
+                    ADP_STOPPED_APPLICATION_EXIT => code,
+                    _ => 1,
+                })
+            },
+            _ => match self.operation(cpu, op, block) {
+                Ok(value) => Outcome::Return(value),
+                Err(errno) => {
+                    self.errno = errno;
+                    Outcome::Return(u64::MAX)
+                },
+            },
+        }
+    }
+
+    fn operation(&mut self, cpu: &mut CPU, op: u64, block: u64) -> Result<u64, i64> {
+        let arg = |cpu: &mut CPU, index| block_arg(cpu, block, index);
+        match op {
+            op::OPEN => {
+                let (name, mode) = (arg(cpu, 0)?, arg(cpu, 1)?);
+                // Mode is an index into "r", "rb", "r+", "r+b", "w", "wb", "w+", "w+b", "a"...
+                let write = mode & 2 != 0;
+                if cpu.mmu().load_cstr(name).map_err(|_| EFAULT)? == b":tt" {
+                    return Ok(match mode / 4 {
+                        0 => 0,
+                        1 => 1,
+                        _ => 2,
+                    });
+                }
+                let flags = match (mode / 4, write) {
+                    (0, false) => O_RDONLY,
+                    (0, true) => O_RDWR,
+                    (1, false) => O_WRONLY | O_CREAT | O_TRUNC,
+                    (1, true) => O_RDWR | O_CREAT | O_TRUNC,
+                    (2, false) => O_WRONLY | O_CREAT | O_APPEND,
+                    _ => O_RDWR | O_CREAT | O_APPEND,
+                };
+                self.files.openat(cpu, AT_FDCWD, name, flags, 0o644)
+            },
+            op::CLOSE => self.files.close(arg(cpu, 0)?),
+            op::WRITEC => self.files.write(cpu, 1, block, 1).map(|_| 0),
+            op::WRITE0 => {
+                let len = cpu.mmu().load_cstr(block).map_err(|_| EFAULT)?.len();
+                self.files.write(cpu, 1, block, len as u64).map(|_| 0)
+            },
+            // Reads and writes return the number of bytes which were not transferred.
+            op::WRITE => {
+                let (handle, buf, len) = (arg(cpu, 0)?, arg(cpu, 1)?, arg(cpu, 2)?);
+                let written = self.files.write(cpu, handle, buf, len)?;
+                Ok(len - written)
+            },
+            op::READ => {
+                let (handle, buf, len) = (arg(cpu, 0)?, arg(cpu, 1)?, arg(cpu, 2)?);
+                let read = self.files.read(cpu, handle, buf, len)?;
+                Ok(len - read)
+            },
+            op::READC => {
+                let data = host_input(cpu, || {
+                    let mut byte = [0];
+                    std::io::stdin().read_exact(&mut byte).map_err(host_errno)?;
+                    Ok(byte.to_vec())
+                })?;
+                data.first().map(|&b| b as u64).ok_or(EIO)
+            },
+            op::ISERROR => Ok(((arg(cpu, 0)? as i64) < 0) as u64),
+            op::ISTTY => self.files.is_terminal(arg(cpu, 0)?).map(u64::from),
+            op::SEEK => self.files.lseek(arg(cpu, 0)?, arg(cpu, 1)?, 0).map(|_| 0),
+            op::FLEN => {
+                let file = self.files.file(arg(cpu, 0)?)?;
+                file.metadata().map(|m| m.len()).map_err(host_errno)
+            },
+            op::REMOVE => {
+                let name = arg(cpu, 0)?;
+                let path = load_path(cpu, name)?;
+                fs::remove_file(path).map(|_| 0).map_err(host_errno)
+            },
+            op::RENAME => {
+                let (from, to) = (arg(cpu, 0)?, arg(cpu, 2)?);
+                let (from, to) = (load_path(cpu, from)?, load_path(cpu, to)?);
+                fs::rename(from, to).map(|_| 0).map_err(host_errno)
+            },
+            op::CLOCK => Ok(self.elapsed(cpu) / 10_000_000),
+            op::TIME => Ok(cpu.mmu().bus().journal().input_u64(Source::Time, realtime_nanos) / 1_000_000_000),
+            op::ELAPSED => {
+                let ticks = self.elapsed(cpu);
+                store(cpu, block, &ticks.to_le_bytes())
+            },
+            op::TICKFREQ => Ok(TICK_FREQ),
+            op::ERRNO => Ok(self.errno as u64),
+            op::GET_CMDLINE => {
+                let (buf, len) = (arg(cpu, 0)?, arg(cpu, 1)?);
+                let cmdline = self.cmdline.as_bytes();
+                if cmdline.len() as u64 >= len {
+                    return Err(EINVAL);
+                }
+                store(cpu, buf, &[cmdline, &[0]].concat())?;
+                store(cpu, block + 8, &(cmdline.len() as u64).to_le_bytes())
+            },
+            op::HEAPINFO => {
+                let info = [self.heap.start, self.heap.end, self.stack.end, self.stack.start];
+                let buf = arg(cpu, 0)?;
+                store(cpu, buf, &info.map(u64::to_le_bytes).concat())
+            },
+            _ => Err(ENOSYS),
+        }
+    }
+
+    /// Reads the nanoseconds since the program started.
+    fn elapsed(&self, cpu: &mut CPU) -> u64 {
+        cpu.mmu().bus().journal().input_u64(Source::Time, || self.start.elapsed().as_nanos() as u64)
+    }
+}
+
+/// Reads the argument at an index of the block.
+fn block_arg(cpu: &mut CPU, block: u64, index: u64) -> Result<u64, i64> {
+    cpu.mmu().load(block.wrapping_add(index * 8), Size::DoubleWord).map_err(|_| EFAULT)
+}
+
+fn load_path(cpu: &mut CPU, addr: u64) -> Result<PathBuf, i64> {
+    let bytes = cpu.mmu().load_cstr(addr).map_err(|_| EFAULT)?;
+    Ok(PathBuf::from(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{asm, components::{bus::DRAM_BASE, cpu::Trap, CPU}};
+
+    use super::Semihosting;
+
+    /// Encodes a path as the operands of a `.byte` directive, with its terminating null.
+    fn bytes(text: &str) -> String {
+        text.bytes().chain([0]).map(|b| b.to_string()).collect::<Vec<_>>().join(", ")
+    }
+
+    fn semihosting() -> Semihosting {
+        Semihosting::new("program".to_string(), DRAM_BASE + 0x1000..DRAM_BASE + 0x2000, DRAM_BASE + 0x2000..DRAM_BASE + 0x3000)
+    }
+
+    #[test]
+    fn it_services_semihosting_calls() {
+        let path = std::env::temp_dir().join(format!("rv64-semihosting-test-{}", std::process::id()));
+
+        // Opens a file for writing, writes to it, finds its length, and exits with code 7.
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!(&format!("
+                li a0, 0x01
+                la a1, open
+                call semihost
+                la a1, write
+                sd a0, 0(a1)
+                li a0, 0x05
+                call semihost
+                mv s0, a0
+                la a1, write
+                li a0, 0x0c
+                call semihost
+                mv s1, a0
+                la a1, write
+                li a0, 0x02
+                call semihost
+                mv s2, a0
+                li a0, 0x18
+                la a1, exit
+                call semihost
+                j end
+            semihost:
+                slli zero, zero, 0x1f
+                ebreak
+                srai zero, zero, 7
+                ret
+            end:
+                j end
+            open:
+                .dword name, 4, 0
+            write:
+                .dword 0, data, 5
+            exit:
+                .dword 0x20026, 7
+            data:
+                .byte 104, 101, 108, 108, 111
+            name:
+                .byte {}
+        ", bytes(path.to_str().unwrap()))));
+        cpu.set_semihosting(semihosting());
+        assert!(cpu.run_for(1000));
+
+        let written = fs::read_to_string(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(written.unwrap(), "hello");
+        assert_eq!(cpu.read_xreg(10), 7);
+        // Nothing was left unwritten, the file is 5 bytes long, and it closed.
+        assert_eq!(cpu.read_xreg(8), 0);
+        assert_eq!(cpu.read_xreg(9), 5);
+        assert_eq!(cpu.read_xreg(18), 0);
+    }
+
+    #[test]
+    fn it_only_services_calls_when_enabled() {
+        let program = asm!("
+                li a0, 0x31
+                slli zero, zero, 0x1f
+                ebreak
+                srai zero, zero, 7
+        ");
+
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(program.clone());
+        cpu.run_for(2);
+        assert_eq!(cpu.step(), Err(Trap::Breakpoint));
+
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("ebreak"));
+        cpu.set_semihosting(semihosting());
+        assert_eq!(cpu.step(), Err(Trap::Breakpoint));
+
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(program);
+        cpu.set_semihosting(semihosting());
+        cpu.run_for(3);
+        assert_eq!(cpu.read_xreg(10), 1_000_000_000);
+    }
+}
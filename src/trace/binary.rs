@@ -0,0 +1,188 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::{components::memory::Size, util::{read_u8, read_varint, write_varint}};
+
+use super::{Commit, MemAccess, Tracer};
+
+/// The magic number and version at the start of every binary trace.
+const MAGIC: &[u8; 8] = b"RVTRACE1";
+
+/// Writes commits in a compact binary format for long runs. Each record is:
+///
+/// - a header byte holding the privilege level and the number of register writes, loads and
+///   stores (2 bits each),
+/// - the difference between the pc and the fall-through pc of the previous instruction, as a
+///   zigzag LEB128 varint, which is a single zero byte for straight-line code,
+/// - the raw instruction as 4 little-endian bytes,
+/// - each register write as the register number followed by a varint value,
+/// - each load and store as a varint address, a byte holding the size and a varint value.
+pub struct BinaryTracer<W: Write> {
+    out: W,
+    next_pc: u64,
+    started: bool,
+}
+
+impl<W: Write> BinaryTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, next_pc: 0, started: false }
+    }
+}
+
+impl<W: Write> Tracer for BinaryTracer<W> {
+    fn commit(&mut self, commit: &Commit) -> io::Result<()> {
+        if !self.started {
+            self.out.write_all(MAGIC)?;
+            self.started = true;
+        }
+        if commit.reg_writes.len() > 3 || commit.loads.len() > 3 || commit.stores.len() > 3 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "too many effects in one commit"));
+        }
+
+        let mut record = Vec::with_capacity(32);
+        record.push(
+            (commit.privilege & 0x3) |
+            ((commit.reg_writes.len() as u8) << 2) |
+            ((commit.loads.len() as u8) << 4) |
+            ((commit.stores.len() as u8) << 6)
+        );
+        let delta = commit.pc.wrapping_sub(self.next_pc) as i64;
+        write_varint(&mut record, ((delta << 1) ^ (delta >> 63)) as u64);
+        record.extend(commit.raw.to_le_bytes());
+
+        for (reg, value) in commit.reg_writes.iter() {
+            record.push(*reg);
+            write_varint(&mut record, *value);
+        }
+        for access in commit.loads.iter().chain(commit.stores.iter()) {
+            write_varint(&mut record, access.addr);
+            record.push(access.size as u8);
+            write_varint(&mut record, access.value);
+        }
+
+        self.next_pc = commit.pc.wrapping_add(4);
+        self.out.write_all(&record)
+    }
+}
+
+/// Reads the commits back out of a binary trace.
+pub struct BinaryTraceReader<R: Read> {
+    input: R,
+    next_pc: u64,
+}
+
+impl<R: Read> BinaryTraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a binary trace"));
+        }
+        Ok(Self { input, next_pc: 0 })
+    }
+
+    fn read_commit(&mut self, header: u8) -> io::Result<Commit> {
+        let zigzag = read_varint(&mut self.input)?;
+        let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+        let pc = self.next_pc.wrapping_add(delta as u64);
+        let mut raw = [0; 4];
+        self.input.read_exact(&mut raw)?;
+
+        let mut commit = Commit {
+            privilege: header & 0x3,
+            pc,
+            raw: u32::from_le_bytes(raw),
+            ..Default::default()
+        };
+        for _ in 0..(header >> 2) & 0x3 {
+            let reg = read_u8(&mut self.input)?;
+            commit.reg_writes.push((reg, read_varint(&mut self.input)?));
+        }
+        for _ in 0..(header >> 4) & 0x3 {
+            commit.loads.push(self.read_access()?);
+        }
+        for _ in 0..(header >> 6) & 0x3 {
+            commit.stores.push(self.read_access()?);
+        }
+
+        self.next_pc = pc.wrapping_add(4);
+        Ok(commit)
+    }
+
+    fn read_access(&mut self) -> io::Result<MemAccess> {
+        let addr = read_varint(&mut self.input)?;
+        let size = match read_u8(&mut self.input)? {
+            1 => Size::Byte,
+            2 => Size::HalfWord,
+            4 => Size::Word,
+            8 => Size::DoubleWord,
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "invalid access size")),
+        };
+        let value = read_varint(&mut self.input)?;
+        Ok(MemAccess { addr, size, value })
+    }
+}
+
+impl<R: Read> Iterator for BinaryTraceReader<R> {
+    type Item = io::Result<Commit>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A clean end of file is only allowed between records.
+        let header = match read_u8(&mut self.input) {
+            Ok(header) => header,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(self.read_commit(header))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{components::memory::Size, trace::{Commit, MemAccess, Tracer}};
+
+    use super::{BinaryTraceReader, BinaryTracer};
+
+    #[test]
+    fn it_round_trips_commits() {
+        let commits = vec![
+            Commit {
+                privilege: 3,
+                pc: 0x8000_0000,
+                raw: 0x00000297,
+                reg_writes: vec![(5, 0x8000_0000)],
+                ..Default::default()
+            },
+            Commit {
+                privilege: 3,
+                pc: 0x8000_0004,
+                raw: 0xfe113c23,
+                stores: vec![MemAccess { addr: 0x8000_0ff8, size: Size::DoubleWord, value: u64::MAX }],
+                ..Default::default()
+            },
+            Commit {
+                privilege: 0,
+                pc: 0x7fff_fff0,
+                raw: 0x0182b283,
+                reg_writes: vec![(5, 0x1234)],
+                loads: vec![MemAccess { addr: 0x8000_0018, size: Size::Byte, value: 0x34 }],
+                ..Default::default()
+            },
+        ];
+
+        let mut tracer = BinaryTracer::new(vec![]);
+        for commit in commits.iter() {
+            tracer.commit(commit).unwrap();
+        }
+        // Straight-line code with a single register write costs a handful of bytes per commit.
+        assert!(tracer.out.len() < 8 + 3 * 24);
+
+        let reader = BinaryTraceReader::new(tracer.out.as_slice()).unwrap();
+        let read: Vec<Commit> = reader.map(|c| c.unwrap()).collect();
+        assert_eq!(read, commits);
+    }
+
+    #[test]
+    fn it_rejects_other_files() {
+        assert!(BinaryTraceReader::new(&b"core   0: 3"[..]).is_err());
+    }
+}
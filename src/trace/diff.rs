@@ -0,0 +1,325 @@
+use std::{fmt, io::{self, BufRead}};
+
+use crate::{
+    components::{cpu::Trap, memory::registers::XREG_ABI_NAMES, CPU},
+    isa::{Disassembler, Instruction},
+};
+
+use super::{reference::{Entry, ReferenceTrace}, Commit, MemAccess};
+
+/// What differed between the emulator and the reference.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The emulator executed an instruction at a different address.
+    Pc,
+    /// The same address held a different instruction.
+    Instruction,
+    /// The instruction wrote different values, or different registers.
+    RegisterWrites,
+    /// The instruction stored to different addresses, or stored different values.
+    Stores,
+    /// The emulator raised a trap where the reference retired an instruction, or raised a
+    /// different exception.
+    Trap(Trap),
+    /// The reference raised an exception where the emulator retired the instruction.
+    Retired,
+}
+
+/// The first point at which the emulator and the reference disagree.
+#[derive(Debug)]
+pub struct Divergence {
+    /// The number of instructions which matched before the divergence.
+    pub index: u64,
+    pub mismatch: Mismatch,
+    pub expected: Entry,
+    /// The effects of the instruction which the emulator retired, unless it raised a trap.
+    pub actual: Option<Commit>,
+}
+
+#[derive(Debug)]
+pub enum DiffError {
+    Io(io::Error),
+    /// The reference never executes the instruction which the emulator starts from.
+    NoSync(u64),
+    Diverged(Box<Divergence>),
+}
+
+impl From<io::Error> for DiffError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read the reference trace: {}", e),
+            Self::NoSync(pc) => write!(f, "the reference trace never reaches {:#x}", pc),
+            Self::Diverged(divergence) => write!(f, "{}", divergence),
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "diverged from the reference after {} instructions", self.index)?;
+        match &self.expected {
+            Entry::Commit(expected) => writeln!(f, "  expected: {}", describe(expected))?,
+            Entry::Exception { cause, epc } => {
+                writeln!(f, "  expected: 0x{:016x} raises the exception with cause {}", epc, cause)?
+            },
+        }
+        match &self.actual {
+            Some(actual) => write!(f, "  actual:   {}", describe(actual))?,
+            None => write!(f, "  actual:   no instruction retired")?,
+        }
+
+        let actual = self.actual.as_ref();
+        match (&self.mismatch, &self.expected) {
+            (Mismatch::RegisterWrites, Entry::Commit(expected)) => {
+                write!(f, "\n  expected registers: {}", registers(&expected.reg_writes))?;
+                write!(f, "\n  actual registers:   {}", registers(&actual.unwrap().reg_writes))
+            },
+            (Mismatch::Stores, Entry::Commit(expected)) => {
+                write!(f, "\n  expected stores: {}", stores(&expected.stores))?;
+                write!(f, "\n  actual stores:   {}", stores(&actual.unwrap().stores))
+            },
+            (Mismatch::Trap(trap), _) => write!(f, "\n  the emulator raised {:?}", trap),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn describe(commit: &Commit) -> String {
+    let inst = Instruction::decode(commit.raw);
+    let text = Disassembler::new().disassemble(&inst, commit.pc);
+    format!("0x{:016x} (0x{:08x}) {}", commit.pc, commit.raw, text)
+}
+
+fn registers(writes: &[(u8, u64)]) -> String {
+    if writes.is_empty() {
+        return "none".to_string();
+    }
+    let writes: Vec<String> = writes.iter()
+        .map(|(reg, value)| format!("{} = {:#x}", XREG_ABI_NAMES[*reg as usize], value))
+        .collect();
+    writes.join(", ")
+}
+
+fn stores(stores: &[MemAccess]) -> String {
+    if stores.is_empty() {
+        return "none".to_string();
+    }
+    let stores: Vec<String> = stores.iter()
+        .map(|s| format!("{} bytes of {:#x} at {:#x}", s.size as usize, s.value, s.addr))
+        .collect();
+    stores.join(", ")
+}
+
+/// Runs the CPU in lock-step with a trace from a reference simulator, comparing the pc, the
+/// register writes and the stores of every retired instruction. Returns the number of
+/// instructions which matched once the reference trace runs out.
+///
+/// Reference commits before the emulator's starting pc are skipped, so that a trace which
+/// begins in Spike's boot ROM lines up with a program loaded straight into DRAM. Exceptions are
+/// taken as they would be without the reference, and must match those which it logs.
+pub fn lockstep<R: BufRead>(cpu: &mut CPU, mut reference: ReferenceTrace<R>) -> Result<u64, DiffError> {
+    let start = cpu.pc();
+    let mut next = loop {
+        match reference.next().transpose()? {
+            Some(Entry::Commit(commit)) if commit.pc == start => break Some(Entry::Commit(commit)),
+            Some(_) => continue,
+            None => return Err(DiffError::NoSync(start)),
+        }
+    };
+    // The format is known once a commit has been read.
+    let format = reference.format().unwrap();
+
+    let mut index = 0;
+    while let Some(mut expected) = next.take() {
+        if let Entry::Commit(commit) = &mut expected {
+            // Writes to x0 have no effect, and the emulator doesn't record them.
+            commit.reg_writes.retain(|(reg, _)| *reg != 0);
+        }
+        let previous: Vec<u64> = (0..32).map(|r| cpu.read_xreg(r)).collect();
+
+        let diverged = |mismatch, actual| {
+            let divergence = Divergence { index, mismatch, expected: expected.clone(), actual };
+            DiffError::Diverged(Box::new(divergence))
+        };
+        if cpu.pc() != expected.pc() {
+            return Err(diverged(Mismatch::Pc, None));
+        }
+        let trap = cpu.tick();
+
+        let expected = match (&expected, trap) {
+            (Entry::Exception { cause, .. }, Some(trap)) if trap.cause() == *cause => None,
+            (Entry::Exception { .. }, None) => {
+                return Err(diverged(Mismatch::Retired, Some(cpu.last_commit().clone())));
+            },
+            // The instruction which raised the exception is logged as if it retired.
+            (Entry::Commit(expected), Some(_)) if !format.logs_exceptions() && expected.reg_writes.is_empty() &&
+                expected.stores.is_empty() && cpu.last_commit().pc == expected.pc => {
+                match cpu.last_commit().raw == expected.raw {
+                    true => None,
+                    false => return Err(diverged(Mismatch::Instruction, Some(cpu.last_commit().clone()))),
+                }
+            },
+            (_, Some(trap)) => return Err(diverged(Mismatch::Trap(trap), None)),
+            (Entry::Commit(expected), None) => Some(expected),
+        };
+
+        if let Some(expected) = expected {
+            let mut actual = cpu.last_commit().clone();
+            if !format.logs_unchanged_registers() {
+                actual.reg_writes.retain(|(reg, value)| previous[*reg as usize] != *value);
+            }
+            let stores_match = match format.has_store_values() {
+                true => actual.stores == expected.stores,
+                false => actual.stores.iter().map(|s| s.addr).eq(expected.stores.iter().map(|s| s.addr)),
+            };
+
+            let mismatch = if actual.raw != expected.raw {
+                Some(Mismatch::Instruction)
+            } else if actual.reg_writes != expected.reg_writes {
+                Some(Mismatch::RegisterWrites)
+            } else if !stores_match {
+                Some(Mismatch::Stores)
+            } else {
+                None
+            };
+            if let Some(mismatch) = mismatch {
+                return Err(diverged(mismatch, Some(actual)));
+            }
+            index += 1;
+        }
+
+        next = reference.next().transpose()?;
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{asm, components::{cpu::Trap, CPU}, trace::reference::ReferenceTrace};
+
+    use super::{lockstep, DiffError, Mismatch};
+
+    /// A Spike log for the program below, starting in the boot ROM.
+    const SPIKE_LOG: &str = "\
+core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000
+core   0: 3 0x0000000000001004 (0x02028593) x11 0x0000000000001020
+core   0: 3 0x0000000080000000 (0x00600513) x10 0x0000000000000006
+core   0: 3 0x0000000080000004 (0x00300593) x11 0x0000000000000003
+core   0: 3 0x0000000080000008 (0x00b57633) x12 0x0000000000000002
+core   0: 3 0x000000008000000c (0x00001297) x5  0x000000008000100c
+core   0: 3 0x0000000080000010 (0x00c2b023) mem 0x000000008000100c 0x0000000000000002
+";
+
+    fn program() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.mmu().load_dram_image(asm!("
+            li a0, 6
+            li a1, 3
+            and a2, a0, a1
+            auipc t0, 1
+            sd a2, 0(t0)
+        "));
+        cpu
+    }
+
+    fn diff(log: &str) -> Result<u64, DiffError> {
+        let trace = ReferenceTrace::new(Cursor::new(log));
+        lockstep(&mut program(), trace)
+    }
+
+    #[test]
+    fn it_runs_in_lockstep_with_spike() {
+        assert_eq!(diff(SPIKE_LOG).unwrap(), 5);
+    }
+
+    #[test]
+    fn it_reports_the_first_divergence() {
+        let log = SPIKE_LOG.replace("x12 0x0000000000000002", "x12 0x0000000000000012");
+        let Err(DiffError::Diverged(divergence)) = diff(&log) else { panic!("no divergence") };
+        assert_eq!(divergence.index, 2);
+        assert_eq!(divergence.mismatch, Mismatch::RegisterWrites);
+
+        let report = divergence.to_string();
+        assert!(report.contains("and a2, a0, a1"), "{}", report);
+        assert!(report.contains("expected registers: a2 = 0x12"), "{}", report);
+        assert!(report.contains("actual registers:   a2 = 0x2"), "{}", report);
+
+        let log = SPIKE_LOG.replace("0x000000008000100c 0x0000000000000002", "0x000000008000100c 0x00000002");
+        let Err(DiffError::Diverged(divergence)) = diff(&log) else { panic!("no divergence") };
+        assert_eq!(divergence.mismatch, Mismatch::Stores);
+
+        let log = SPIKE_LOG.replace("0x0000000080000004 (0x00300593)", "0x0000000080000010 (0x00300593)");
+        let Err(DiffError::Diverged(divergence)) = diff(&log) else { panic!("no divergence") };
+        assert_eq!((divergence.index, divergence.mismatch), (1, Mismatch::Pc));
+    }
+
+    #[test]
+    fn it_compares_only_changed_registers_with_qemu() {
+        let log = "\
+0, 0x80000000, 0x600513, \"li a0,6\", a0 -> 0x0000000000000006
+0, 0x80000004, 0x300593, \"li a1,3\", a1 -> 0x0000000000000003
+0, 0x80000008, 0xb57633, \"and a2,a0,a1\", a2 -> 0x0000000000000002
+";
+        let trace = ReferenceTrace::new(Cursor::new(log));
+        assert_eq!(lockstep(&mut program(), trace).unwrap(), 3);
+
+        let trace = ReferenceTrace::new(Cursor::new("0, 0x1000, 0x297, \"auipc t0,0\"\n"));
+        assert!(matches!(lockstep(&mut program(), trace), Err(DiffError::NoSync(_))));
+    }
+
+    #[test]
+    fn it_takes_the_exceptions_which_the_reference_logs() {
+        let ecall = || {
+            let mut cpu = CPU::new();
+            cpu.mmu().load_dram_image(asm!("
+                    la t0, handler
+                    csrw mtvec, t0
+                    ecall
+                handler:
+                    li a0, 1
+                    jr zero
+            "));
+            cpu
+        };
+        let log = "\
+core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+core   0: 3 0x0000000080000004 (0x01028293) x5  0x0000000080000010
+core   0: 3 0x0000000080000008 (0x30529073) c773_mtvec 0x0000000080000010
+core   0: exception trap_machine_ecall, epc 0x000000008000000c
+core   0:           tval 0x0000000000000000
+core   0: 3 0x0000000080000010 (0x00100513) x10 0x0000000000000001
+core   0: 3 0x0000000080000014 (0x00000067)
+";
+        assert_eq!(lockstep(&mut ecall(), ReferenceTrace::new(Cursor::new(log))).unwrap(), 5);
+
+        // A different exception, or none, is a divergence.
+        let log = log.replace("trap_machine_ecall", "trap_illegal_instruction");
+        let Err(DiffError::Diverged(divergence)) = lockstep(&mut ecall(), ReferenceTrace::new(Cursor::new(log)))
+            else { panic!("no divergence") };
+        assert_eq!(divergence.mismatch, Mismatch::Trap(Trap::EnvironmentCallFromMMode));
+        let log = SPIKE_LOG.replace(
+            "core   0: 3 0x0000000080000008 (0x00b57633) x12 0x0000000000000002",
+            "core   0: exception trap_illegal_instruction, epc 0x0000000080000008",
+        );
+        let Err(DiffError::Diverged(divergence)) = diff(&log) else { panic!("no divergence") };
+        assert_eq!((divergence.index, divergence.mismatch), (2, Mismatch::Retired));
+
+        // QEMU logs the ecall as if it retired.
+        let log = "\
+0, 0x80000000, 0x297, \"auipc t0,0\", t0 -> 0x0000000080000000
+0, 0x80000004, 0x1028293, \"addi t0,t0,16\", t0 -> 0x0000000080000010
+0, 0x80000008, 0x30529073, \"csrw mtvec,t0\"
+0, 0x8000000c, 0x73, \"ecall\"
+0, 0x80000010, 0x100513, \"li a0,1\", a0 -> 0x0000000000000001
+";
+        assert_eq!(lockstep(&mut ecall(), ReferenceTrace::new(Cursor::new(log))).unwrap(), 4);
+    }
+}
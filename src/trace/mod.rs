@@ -0,0 +1,137 @@
+pub mod binary;
+pub mod diff;
+pub mod reference;
+pub mod spike;
+
+use std::{io, ops::Range};
+
+use crate::components::memory::Size;
+
+pub use self::binary::{BinaryTraceReader, BinaryTracer};
+pub use self::reference::{ReferenceFormat, ReferenceTrace};
+pub use self::spike::SpikeTracer;
+
+/// A single access to memory made by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: u64,
+    pub size: Size,
+    pub value: u64,
+}
+
+/// The architectural effects of one retired instruction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Commit {
+    /// The privilege level which the instruction executed in, encoded as in the spec (3 = M).
+    pub privilege: u8,
+    pub pc: u64,
+    pub raw: u32,
+    /// The integer registers written by the instruction, as `(register number, value)`.
+    pub reg_writes: Vec<(u8, u64)>,
+    pub loads: Vec<MemAccess>,
+    pub stores: Vec<MemAccess>,
+}
+
+impl Commit {
+    /// Resets the record for the next instruction, keeping the allocated buffers.
+    pub fn begin(&mut self, privilege: u8, pc: u64, raw: u32) {
+        self.privilege = privilege;
+        self.pc = pc;
+        self.raw = raw;
+        self.reg_writes.clear();
+        self.loads.clear();
+        self.stores.clear();
+    }
+}
+
+/// Receives every instruction which the CPU retires.
+pub trait Tracer {
+    fn commit(&mut self, commit: &Commit) -> io::Result<()>;
+}
+
+/// Only passes on the commits which fall within a range of addresses and/or a window of the
+/// instruction count, to keep traces of long runs down to the interesting part.
+pub struct TraceFilter<T: Tracer> {
+    inner: T,
+    pc_range: Option<Range<u64>>,
+    count_range: Option<Range<u64>>,
+    /// The number of instructions which have been seen so far, whether or not they were traced.
+    count: u64,
+}
+
+impl<T: Tracer> TraceFilter<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            pc_range: None,
+            count_range: None,
+            count: 0,
+        }
+    }
+
+    /// Only traces instructions whose address is within the range.
+    pub fn pc_range(mut self, range: Range<u64>) -> Self {
+        self.pc_range = Some(range);
+        self
+    }
+
+    /// Only traces the instructions whose index in the run, starting from 0, is within the range.
+    pub fn count_range(mut self, range: Range<u64>) -> Self {
+        self.count_range = Some(range);
+        self
+    }
+}
+
+impl<T: Tracer> Tracer for TraceFilter<T> {
+    fn commit(&mut self, commit: &Commit) -> io::Result<()> {
+        let index = self.count;
+        self.count += 1;
+
+        let in_pc_range = self.pc_range.as_ref().is_none_or(|r| r.contains(&commit.pc));
+        let in_count_range = self.count_range.as_ref().is_none_or(|r| r.contains(&index));
+        match in_pc_range && in_count_range {
+            true => self.inner.commit(commit),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<T: Tracer + ?Sized> Tracer for Box<T> {
+    fn commit(&mut self, commit: &Commit) -> io::Result<()> {
+        (**self).commit(commit)
+    }
+}
+
+/// Collects commits in memory, which is mostly useful for tests.
+impl Tracer for Vec<Commit> {
+    fn commit(&mut self, commit: &Commit) -> io::Result<()> {
+        self.push(commit.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Commit, TraceFilter, Tracer};
+
+    fn commit(pc: u64) -> Commit {
+        Commit { privilege: 3, pc, raw: 0x13, ..Default::default() }
+    }
+
+    #[test]
+    fn it_filters_by_pc_and_count() {
+        let mut filter = TraceFilter::new(vec![]).pc_range(0x100..0x108);
+        for pc in [0xfc, 0x100, 0x104, 0x108, 0x100] {
+            filter.commit(&commit(pc)).unwrap();
+        }
+        let pcs: Vec<u64> = filter.inner.iter().map(|c| c.pc).collect();
+        assert_eq!(pcs, vec![0x100, 0x104, 0x100]);
+
+        let mut filter = TraceFilter::new(vec![]).count_range(1..3);
+        for pc in [0x0, 0x4, 0x8, 0xc] {
+            filter.commit(&commit(pc)).unwrap();
+        }
+        let pcs: Vec<u64> = filter.inner.iter().map(|c| c.pc).collect();
+        assert_eq!(pcs, vec![0x4, 0x8]);
+    }
+}
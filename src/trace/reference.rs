@@ -0,0 +1,307 @@
+use std::io::{self, BufRead, ErrorKind};
+
+use crate::components::memory::{registers::XREG_ABI_NAMES, Size};
+
+use super::{Commit, MemAccess};
+
+/// The simulator which produced a reference trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferenceFormat {
+    /// Spike's `--log-commits` output.
+    Spike,
+    /// The output of QEMU's execlog plugin, e.g. `-plugin libexeclog.so,reg=* -d plugin`.
+    Qemu,
+}
+
+impl ReferenceFormat {
+    /// Guesses the format of a trace from one of its lines.
+    pub fn detect(line: &str) -> Option<Self> {
+        if line.starts_with("core") {
+            Some(Self::Spike)
+        } else if parse_qemu(line).is_some() {
+            Some(Self::Qemu)
+        } else {
+            None
+        }
+    }
+
+    /// Indicates if the format records the values written by stores. QEMU only records the
+    /// addresses which were accessed.
+    pub fn has_store_values(&self) -> bool {
+        matches!(self, Self::Spike)
+    }
+
+    /// Indicates if the format records register writes which leave the value unchanged. QEMU
+    /// only logs the registers whose values changed.
+    pub fn logs_unchanged_registers(&self) -> bool {
+        matches!(self, Self::Spike)
+    }
+
+    /// Indicates if the format records the exceptions which instructions raise. QEMU logs an
+    /// instruction which raises one as if it retired, with no effects.
+    pub fn logs_exceptions(&self) -> bool {
+        matches!(self, Self::Spike)
+    }
+}
+
+/// An event in a reference trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// An instruction retired.
+    Commit(Commit),
+    /// The instruction at `epc` raised an exception instead of retiring, with the code which
+    /// `mcause` is set to.
+    Exception { cause: u64, epc: u64 },
+}
+
+impl Entry {
+    /// The address of the instruction which the entry is about.
+    pub fn pc(&self) -> u64 {
+        match self {
+            Self::Commit(commit) => commit.pc,
+            Self::Exception { epc, .. } => *epc,
+        }
+    }
+}
+
+/// Reads the commits and exceptions out of a reference trace, skipping any lines which
+/// describe neither, such as Spike's symbol annotations.
+pub struct ReferenceTrace<R: BufRead> {
+    input: R,
+    format: Option<ReferenceFormat>,
+    line: String,
+}
+
+impl<R: BufRead> ReferenceTrace<R> {
+    /// Creates a reader, detecting the format from the first commit in the trace.
+    pub fn new(input: R) -> Self {
+        Self { input, format: None, line: String::new() }
+    }
+
+    /// Gets the format of the trace, once the first commit has been read.
+    pub fn format(&self) -> Option<ReferenceFormat> {
+        self.format
+    }
+}
+
+impl<R: BufRead> Iterator for ReferenceTrace<R> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+            match self.input.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {},
+                Err(e) => return Some(Err(e)),
+            }
+            let line = self.line.trim();
+
+            if self.format.is_none() {
+                self.format = ReferenceFormat::detect(line);
+            }
+            let entry = match self.format {
+                Some(ReferenceFormat::Spike) => parse_spike(line).map(Entry::Commit)
+                    .or_else(|| parse_spike_exception(line)),
+                Some(ReferenceFormat::Qemu) => parse_qemu(line).map(Entry::Commit),
+                None => None,
+            };
+            if entry.is_some() {
+                return entry.map(Ok);
+            }
+            if self.format.is_none() && !line.is_empty() && !line.starts_with('#') {
+                let message = format!("unrecognised reference trace: {}", line);
+                return Some(Err(io::Error::new(ErrorKind::InvalidData, message)));
+            }
+        }
+    }
+}
+
+/// Parses a line of a Spike commit log, such as
+/// `core   0: 3 0x0000000080000010 (0x00b13023) mem 0x0000000080000f00 0x0000000080000020`.
+pub fn parse_spike(line: &str) -> Option<Commit> {
+    let (_, rest) = line.strip_prefix("core")?.split_once(':')?;
+    let mut tokens = rest.split_whitespace().peekable();
+
+    let privilege = tokens.next()?.parse().ok().filter(|p| *p <= 3)?;
+    let pc = parse_hex(tokens.next()?)?;
+    let raw = parse_hex(tokens.next()?.strip_prefix('(')?.strip_suffix(')')?)? as u32;
+    let mut commit = Commit { privilege, pc, raw, ..Default::default() };
+
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let addr = parse_hex(tokens.next()?)?;
+            // Stores are followed by the value, sized by its number of digits. Loads are not.
+            match tokens.peek().filter(|t| t.starts_with("0x")) {
+                Some(value) => {
+                    let size = size_of_digits(value.len() - 2)?;
+                    let value = parse_hex(tokens.next()?)?;
+                    commit.stores.push(MemAccess { addr, size, value });
+                },
+                None => commit.loads.push(MemAccess { addr, size: Size::DoubleWord, value: 0 }),
+            }
+        } else if let Some(reg) = token.strip_prefix('x').and_then(|r| r.parse::<u8>().ok()) {
+            let value = parse_hex(tokens.next()?)?;
+            commit.reg_writes.push((reg, value));
+        } else {
+            // Floating point, vector and CSR writes are not compared, so skip their values.
+            tokens.next();
+        }
+    }
+    Some(commit)
+}
+
+/// Parses a line of a Spike commit log which reports an exception, such as
+/// `core   0: exception trap_illegal_instruction, epc 0x0000000080000000`. The line which may
+/// follow it with the trap value isn't needed, as the emulator's `mtval` is always 0.
+pub fn parse_spike_exception(line: &str) -> Option<Entry> {
+    let (_, rest) = line.strip_prefix("core")?.split_once(':')?;
+    let (name, epc) = rest.trim().strip_prefix("exception ")?.split_once(", epc ")?;
+    let cause = match name.strip_prefix("trap #") {
+        Some(cause) => cause.parse().ok()?,
+        None => match name.strip_prefix("trap_")? {
+            "instruction_address_misaligned" => 0,
+            "instruction_access_fault" => 1,
+            "illegal_instruction" => 2,
+            "breakpoint" => 3,
+            "load_address_misaligned" => 4,
+            "load_access_fault" => 5,
+            "store_address_misaligned" => 6,
+            "store_access_fault" => 7,
+            "user_ecall" => 8,
+            "supervisor_ecall" => 9,
+            "machine_ecall" => 11,
+            "instruction_page_fault" => 12,
+            "load_page_fault" => 13,
+            "store_page_fault" => 15,
+            _ => return None,
+        },
+    };
+    Some(Entry::Exception { cause, epc: parse_hex(epc)? })
+}
+
+/// Parses a line of QEMU's execlog plugin output, such as
+/// `0, 0x80000000, 0x297, "auipc t0,0", t0 -> 0x0000000080000000`. Registers are only logged
+/// when the plugin is given the `reg` option, and memory accesses are logged as `load, <addr>`
+/// or `store, <addr>` without their values.
+pub fn parse_qemu(line: &str) -> Option<Commit> {
+    let (head, rest) = line.split_once('"')?;
+    let (_, effects) = rest.split_once('"')?;
+
+    let mut fields = head.split(',').map(str::trim);
+    fields.next()?.parse::<u32>().ok()?;
+    let pc = parse_hex(fields.next()?)?;
+    let raw = parse_hex(fields.next()?)? as u32;
+    // Privilege levels are not logged, so assume machine mode.
+    let mut commit = Commit { privilege: 3, pc, raw, ..Default::default() };
+
+    let mut fields = effects.split(',').map(str::trim).filter(|f| !f.is_empty());
+    while let Some(field) = fields.next() {
+        match field {
+            "load" | "store" => {
+                let addr = parse_hex(fields.next()?)?;
+                let access = MemAccess { addr, size: Size::DoubleWord, value: 0 };
+                match field {
+                    "load" => commit.loads.push(access),
+                    _ => commit.stores.push(access),
+                }
+            },
+            _ => {
+                let (reg, value) = field.split_once("->")?;
+                if let Some(reg) = parse_reg(reg.trim()) {
+                    commit.reg_writes.push((reg, parse_hex(value.trim())?));
+                }
+            },
+        }
+    }
+    Some(commit)
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.strip_prefix("0x")?, 16).ok()
+}
+
+fn parse_reg(name: &str) -> Option<u8> {
+    if let Some(num) = name.strip_prefix('x').and_then(|n| n.parse::<u8>().ok()) {
+        return (num < 32).then_some(num);
+    }
+    XREG_ABI_NAMES.iter().position(|&n| n == name).map(|n| n as u8)
+}
+
+fn size_of_digits(digits: usize) -> Option<Size> {
+    Some(match digits {
+        2 => Size::Byte,
+        4 => Size::HalfWord,
+        8 => Size::Word,
+        16 => Size::DoubleWord,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{components::memory::Size, trace::MemAccess};
+
+    use super::{parse_qemu, parse_spike, parse_spike_exception, Entry, ReferenceFormat, ReferenceTrace};
+
+    #[test]
+    fn it_parses_spike_commits() {
+        let commit = parse_spike("core   0: 3 0x0000000080000004 (0x0182b283) x5  0x0000000080001000 mem 0x0000000080000018").unwrap();
+        assert_eq!((commit.privilege, commit.pc, commit.raw), (3, 0x8000_0004, 0x0182b283));
+        assert_eq!(commit.reg_writes, vec![(5, 0x8000_1000)]);
+        assert_eq!(commit.loads.len(), 1);
+        assert!(commit.stores.is_empty());
+
+        let commit = parse_spike("core   0: 3 0x0000000080000008 (0x00b11023) mem 0x0000000080000f00 0x0020").unwrap();
+        assert_eq!(commit.stores, vec![MemAccess { addr: 0x8000_0f00, size: Size::HalfWord, value: 0x20 }]);
+
+        let commit = parse_spike("core   0: 3 0x0000000000001010 (0x30529073) c773_mtvec 0x0000000080000004").unwrap();
+        assert!(commit.reg_writes.is_empty());
+
+        assert!(parse_spike("core   0: exception trap_illegal_instruction, epc 0x0000000080000000").is_none());
+    }
+
+    #[test]
+    fn it_parses_spike_exceptions() {
+        let entry = parse_spike_exception("core   0: exception trap_illegal_instruction, epc 0x0000000080000000");
+        assert_eq!(entry, Some(Entry::Exception { cause: 2, epc: 0x8000_0000 }));
+        let entry = parse_spike_exception("core   0: exception trap_machine_ecall, epc 0x0000000080000010");
+        assert_eq!(entry, Some(Entry::Exception { cause: 11, epc: 0x8000_0010 }));
+
+        // Interrupts aren't compared, as their timing depends on the simulator.
+        assert!(parse_spike_exception("core   0: exception interrupt #7, epc 0x0000000080000010").is_none());
+        assert!(parse_spike_exception("core   0:           tval 0x0000000000000000").is_none());
+    }
+
+    #[test]
+    fn it_parses_qemu_commits() {
+        let commit = parse_qemu("0, 0x80000000, 0x297, \"auipc t0,0\", t0 -> 0x0000000080000000").unwrap();
+        assert_eq!((commit.pc, commit.raw), (0x8000_0000, 0x297));
+        assert_eq!(commit.reg_writes, vec![(5, 0x8000_0000)]);
+
+        let commit = parse_qemu("0, 0x80000010, 0xa2b023, \"sd a0,0(t0)\", store, 0x80000018").unwrap();
+        assert_eq!(commit.stores.len(), 1);
+        assert_eq!(commit.stores[0].addr, 0x8000_0018);
+    }
+
+    #[test]
+    fn it_detects_the_format_and_skips_noise() {
+        let log = "\
+core   0: >>>>  _start
+core   0: 3 0x0000000080000000 (0x00700513) x10 0x0000000000000007
+core   0: exception trap_breakpoint, epc 0x0000000080000004
+core   0: 3 0x0000000080000004 (0x00000067)
+";
+        let mut trace = ReferenceTrace::new(Cursor::new(log));
+        let entries: Vec<Entry> = trace.by_ref().map(|e| e.unwrap()).collect();
+        let pcs: Vec<u64> = entries.iter().map(Entry::pc).collect();
+        assert_eq!(pcs, vec![0x8000_0000, 0x8000_0004, 0x8000_0004]);
+        assert_eq!(entries[1], Entry::Exception { cause: 3, epc: 0x8000_0004 });
+        assert_eq!(trace.format(), Some(ReferenceFormat::Spike));
+
+        let mut trace = ReferenceTrace::new(Cursor::new("not a trace\n"));
+        assert!(trace.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::io::{self, Write};
+
+use super::{Commit, Tracer};
+
+/// Writes commits in the format of Spike's `--log-commits`, so that traces can be diffed against
+/// the reference simulator:
+///
+/// ```text
+/// core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+/// core   0: 3 0x0000000080000004 (0x0182b283) x5  0x0000000080001000 mem 0x0000000080000018
+/// core   0: 3 0x0000000080000008 (0x00b13023) mem 0x0000000080000f00 0x0000000080000020
+/// ```
+pub struct SpikeTracer<W: Write> {
+    out: W,
+    hart: u32,
+}
+
+impl<W: Write> SpikeTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, hart: 0 }
+    }
+}
+
+impl<W: Write> Tracer for SpikeTracer<W> {
+    fn commit(&mut self, commit: &Commit) -> io::Result<()> {
+        writeln!(self.out, "{}", format_commit(self.hart, commit))
+    }
+}
+
+/// Formats a commit as a single line of a Spike commit log.
+pub fn format_commit(hart: u32, commit: &Commit) -> String {
+    let mut line = format!(
+        "core{:>4}: {} 0x{:016x} (0x{:08x})",
+        hart, commit.privilege, commit.pc, commit.raw
+    );
+    for (reg, value) in commit.reg_writes.iter() {
+        line += &format!(" x{:<2} 0x{:016x}", reg, value);
+    }
+    for load in commit.loads.iter() {
+        line += &format!(" mem 0x{:016x}", load.addr);
+    }
+    for store in commit.stores.iter() {
+        let width = store.size as usize * 2;
+        line += &format!(" mem 0x{:016x} 0x{:0width$x}", store.addr, store.value, width = width);
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{components::memory::Size, trace::{Commit, MemAccess}};
+
+    use super::format_commit;
+
+    #[test]
+    fn it_formats_like_spike() {
+        let commit = Commit {
+            privilege: 3,
+            pc: 0x8000_0000,
+            raw: 0x00000297,
+            reg_writes: vec![(5, 0x8000_0000)],
+            ..Default::default()
+        };
+        assert_eq!(
+            format_commit(0, &commit),
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000"
+        );
+
+        let commit = Commit {
+            privilege: 3,
+            pc: 0x8000_0004,
+            raw: 0x0182b283,
+            reg_writes: vec![(11, 0x10)],
+            loads: vec![MemAccess { addr: 0x8000_0018, size: Size::DoubleWord, value: 0x10 }],
+            ..Default::default()
+        };
+        assert_eq!(
+            format_commit(0, &commit),
+            "core   0: 3 0x0000000080000004 (0x0182b283) x11 0x0000000000000010 mem 0x0000000080000018"
+        );
+
+        let commit = Commit {
+            privilege: 3,
+            pc: 0x8000_0008,
+            raw: 0x00b10023,
+            stores: vec![MemAccess { addr: 0x8000_0f00, size: Size::Byte, value: 0x20 }],
+            ..Default::default()
+        };
+        assert_eq!(
+            format_commit(0, &commit),
+            "core   0: 3 0x0000000080000008 (0x00b10023) mem 0x0000000080000f00 0x20"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::{fs::File, io::{self, ErrorKind, Read}};
+
 use num_traits::PrimInt;
 
 
@@ -6,7 +8,7 @@ pub fn get_bits<T>(n: T, start: usize, end: usize) -> T
 where
     T: Copy + PrimInt,
 {
-    let mask = (T::one() << end - start + 1) - T::one();
+    let mask = (T::one() << (end - start + 1)) - T::one();
     (n >> start) & mask
 }
 
@@ -24,6 +26,51 @@ pub fn unsigned_32(value: u64) -> u64 {
     get_bits(value, 0, 31)
 }
 
+/// Parses an address written in hex with a `0x` prefix, or in decimal.
+pub fn parse_addr(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Appends a LEB128 varint.
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads a LEB128 varint.
+pub fn read_varint(input: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(input)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "varint is too long"))
+}
+
+pub fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Reads random bytes from the host.
+pub fn host_random(len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut data))
+        .expect("failed to read random bytes from the host");
+    data
+}
+
 #[cfg(test)]
 mod test {
     use crate::util::unsigned_32;